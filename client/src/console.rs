@@ -1,76 +1,578 @@
 use chrono::{DateTime, Utc};
-use std::collections::VecDeque;
+use std::collections::{BTreeMap, VecDeque};
 use tokio::io::AsyncReadExt;
 
 use crate::MessageFromClient;
 
+/// How many messages of each room are kept.
+const SCROLLBACK_LEN: usize = 1000;
+
+/// How many messages of the current room are shown at once.
+const VIEW_LEN: usize = 50;
+
 pub struct Console {
   stdin: tokio::io::Stdin,
+  /// The rooms the client is a member of.
+  rooms: BTreeMap<String, Room>,
+  /// The room whose conversation is shown, messages typed by the user are sent to it.
+  current_room: Option<String>,
+  /// A room joined with `/join`, it becomes the current room once the server confirms it.
+  switch_to: Option<String>,
+  /// Errors and the output of commands, shown below the conversation.
+  notices: MaxLengthVec<Message>,
+}
+
+/// The scrollback of a room.
+struct Room {
   messages: MaxLengthVec<Message>,
+  /// How many messages were received while the room wasn't the current one.
+  unread: usize,
+  /// The seq of the last message received in the room.
+  last_seq: Option<u64>,
+  /// How many messages are hidden below the view, 0 shows the latest ones.
+  scroll: usize,
+  /// The server may have messages older than the first one in `messages`.
+  more_history: bool,
+  /// Older messages were asked for and are shown once they arrive.
+  loading_history: bool,
+  /// The seq of a message to show once the history up to it is loaded, see `/jump`.
+  jump_to: Option<u64>,
+}
+
+impl Room {
+  fn new() -> Self {
+    Self {
+      messages: MaxLengthVec::new(SCROLLBACK_LEN),
+      unread: 0,
+      last_seq: None,
+      scroll: 0,
+      more_history: true,
+      loading_history: false,
+      jump_to: None,
+    }
+  }
+
+  /// Adds a message below the others, the view doesn't move if it's scrolled back.
+  fn push(&mut self, message: Message) {
+    self.messages.push(message);
+    if self.scroll > 0 {
+      self.scroll = (self.scroll + 1).min(self.max_scroll());
+    }
+  }
+
+  fn max_scroll(&self) -> usize {
+    self.messages.items.len().saturating_sub(VIEW_LEN)
+  }
+
+  /// The seq of the oldest message the client has.
+  fn first_seq(&self) -> Option<u64> {
+    self.messages.items.iter().filter_map(Message::seq).min()
+  }
+
+  /// Scrolls so the message is in the middle of the view, returns false if the client doesn't have it.
+  fn scroll_to(&mut self, seq: u64) -> bool {
+    let items = &self.messages.items;
+    let index = match items.iter().position(|message| message.seq() == Some(seq)) {
+      Some(index) => index,
+      None => return false,
+    };
+    let end = (index + VIEW_LEN / 2 + 1).min(items.len());
+    self.scroll = (items.len() - end).min(self.max_scroll());
+    true
+  }
+
+  /// How many more messages fit in the scrollback before the oldest ones are dropped.
+  fn free_scrollback(&self) -> usize {
+    SCROLLBACK_LEN - self.messages.items.len()
+  }
+
+  /// Adds messages sent before the client joined or before the oldest ones it has, ordered by seq.
+  fn insert_history(&mut self, history: messages::server_to_client::HistoryMessage) {
+    for entry in history.messages {
+      let items = &self.messages.items;
+      if items.iter().any(|message| message.seq() == Some(entry.seq)) {
+        continue;
+      }
+      let index = items
+        .iter()
+        .position(|message| message.seq().is_some_and(|seq| seq > entry.seq))
+        .unwrap_or(items.len());
+      self.messages.insert(
+        index,
+        Message::FromPeer {
+          room_id: history.room_id.clone(),
+          seq: entry.seq,
+          username: entry.username,
+          contents: entry.contents,
+          sent_at: entry.sent_at,
+        },
+      );
+    }
+
+    self.more_history = history.more;
+    if self.loading_history {
+      // Shows the messages that were just loaded.
+      self.loading_history = false;
+      self.scroll = self.max_scroll();
+    }
+  }
+}
+
+/// Something the user asked for that needs the server.
+pub enum Action {
+  JoinRoom(String),
+  LeaveRoom(String),
+  ListMembers(String),
+  /// Lists the rooms on the server whose id comes after the given one.
+  ListRooms(Option<String>),
+  CreateRoom {
+    room_id: String,
+    display_name: String,
+    topic: String,
+  },
+  DescribeRoom(String),
+  /// Loads the messages of the room before `before`, or the latest ones.
+  FetchHistory {
+    room_id: String,
+    before: Option<u64>,
+  },
+  /// Finds the latest messages of the room containing every word of `query`.
+  Search {
+    room_id: String,
+    query: String,
+  },
+  /// Every message in the room up to `seq` has been shown to the user.
+  MarkAsRead {
+    room_id: String,
+    seq: u64,
+  },
+  ListSessions,
+  /// Logs out this session, or every other session of the account.
+  LogOut {
+    others: bool,
+  },
 }
 
 #[derive(Debug)]
 enum Message {
   FromPeer {
+    room_id: String,
+    seq: u64,
     username: String,
     contents: String,
-    received_at: DateTime<Utc>,
+    sent_at: DateTime<Utc>,
   },
   FromClient {
+    room_id: String,
     username: String,
     message_id: u64,
+    /// Assigned by the server once it accepts the message.
+    seq: Option<u64>,
     contents: String,
+    /// The local time until the server accepts the message, the server time after that.
     sent_at: DateTime<Utc>,
-    delivered: bool,
-    read: bool,
+    /// How many members of the room were sent the message, known once a receipt arrives.
+    recipients: u32,
+    delivered_to: u32,
+    read_by: u32,
+    /// When each recipient received and read the message, by username.
+    receipts: BTreeMap<String, RecipientReceipt>,
+    /// The server refused the message.
+    failed: bool,
+  },
+  /// Someone joined or left the room.
+  Presence {
+    room_id: String,
+    username: String,
+    joined: bool,
+    at: DateTime<Utc>,
+  },
+  Error {
+    description: &'static str,
+    message: String,
+    received_at: DateTime<Utc>,
+  },
+  /// The output of a command.
+  Notice {
+    lines: Vec<String>,
+    shown_at: DateTime<Utc>,
   },
 }
 
+impl Message {
+  /// The seq of chat messages accepted by the server.
+  fn seq(&self) -> Option<u64> {
+    match self {
+      Message::FromPeer { seq, .. } => Some(*seq),
+      Message::FromClient { seq, .. } => *seq,
+      _ => None,
+    }
+  }
+}
+
+#[derive(Debug, Default)]
+struct RecipientReceipt {
+  delivered_at: Option<DateTime<Utc>>,
+  read_at: Option<DateTime<Utc>>,
+}
+
 impl Console {
   pub fn new() -> Self {
     Self {
       stdin: tokio::io::stdin(),
-      messages: MaxLengthVec::new(50),
+      rooms: BTreeMap::new(),
+      current_room: None,
+      switch_to: None,
+      notices: MaxLengthVec::new(5),
     }
   }
 
-  pub async fn read_input(&mut self) -> std::io::Result<String> {
+  /// Returns `None` once stdin is closed.
+  pub async fn read_input(&mut self) -> std::io::Result<Option<String>> {
     let mut buffer = [0_u8; messages::MAX_MESSAGE_BYTES];
     let bytes_read = self.stdin.read(&mut buffer).await?;
-    Ok(String::from_utf8_lossy(&buffer[0..bytes_read]).to_string())
+    if bytes_read == 0 {
+      return Ok(None);
+    }
+    Ok(Some(
+      String::from_utf8_lossy(&buffer[0..bytes_read]).to_string(),
+    ))
+  }
+
+  /// The room messages typed by the user are sent to.
+  pub fn current_room(&self) -> Option<&str> {
+    self.current_room.as_deref()
+  }
+
+  pub fn room_joined(&mut self, room_id: String) -> Option<Action> {
+    self.rooms.entry(room_id.clone()).or_insert_with(Room::new);
+
+    let mut action = None;
+    if self.current_room.is_none() || self.switch_to.as_ref() == Some(&room_id) {
+      self.switch_to = None;
+      action = self.switch_room(room_id);
+    }
+
+    self.show_conversation();
+
+    action
+  }
+
+  /// The first of the remaining rooms becomes the current room if `room_id` was.
+  pub fn room_left(&mut self, room_id: String) -> Option<Action> {
+    self.rooms.remove(&room_id);
+
+    let mut action = None;
+    if self.current_room.as_ref() == Some(&room_id) {
+      self.current_room = None;
+      if let Some(room_id) = self.rooms.keys().next().cloned() {
+        action = self.switch_room(room_id);
+      }
+    }
+
+    self.show_conversation();
+
+    action
   }
 
-  pub fn message_received(&mut self, message: messages::server_to_client::ChatMessage) {
-    self.messages.push(Message::FromPeer {
+  /// Returns true if the message is shown to the user, it's in the current room.
+  pub fn message_received(&mut self, message: messages::server_to_client::ChatMessage) -> bool {
+    let shown = self.current_room.as_ref() == Some(&message.room_id);
+
+    let room = match self.rooms.get_mut(&message.room_id) {
+      Some(room) => room,
+      // Messages sent right before the room was left.
+      None => return false,
+    };
+
+    room.last_seq = Some(message.seq);
+    if !shown {
+      room.unread += 1;
+    }
+    room.push(Message::FromPeer {
+      room_id: message.room_id,
+      seq: message.seq,
       username: message.username,
       contents: message.contents,
-      received_at: Utc::now(),
+      sent_at: message.sent_at,
     });
 
     self.show_conversation();
+
+    shown
+  }
+
+  pub fn user_joined(&mut self, message: messages::server_to_client::UserJoinedMessage) {
+    self.presence_changed(message.room_id, message.username, true, message.at);
+  }
+
+  pub fn user_left(&mut self, message: messages::server_to_client::UserLeftMessage) {
+    self.presence_changed(message.room_id, message.username, false, message.at);
+  }
+
+  fn presence_changed(
+    &mut self,
+    room_id: String,
+    username: String,
+    joined: bool,
+    at: DateTime<Utc>,
+  ) {
+    if let Some(room) = self.rooms.get_mut(&room_id) {
+      room.push(Message::Presence {
+        room_id,
+        username,
+        joined,
+        at,
+      });
+    }
+
+    self.show_conversation();
+  }
+
+  /// Returns the next page to load when the message to jump to isn't loaded yet.
+  pub fn history(&mut self, history: messages::server_to_client::HistoryMessage) -> Option<Action> {
+    let room_id = history.room_id.clone();
+    let room = match self.rooms.get_mut(&room_id) {
+      Some(room) => room,
+      None => {
+        self.show_conversation();
+        return None;
+      }
+    };
+    room.insert_history(history);
+
+    let mut action = None;
+    if let Some(seq) = room.jump_to.take() {
+      if room.scroll_to(seq) {
+        room.loading_history = false;
+      } else if room.more_history
+        && room.first_seq().is_some_and(|first_seq| first_seq > seq)
+        && room.free_scrollback() > 0
+      {
+        room.jump_to = Some(seq);
+        action = Some(Action::FetchHistory {
+          before: room.first_seq(),
+          room_id,
+        });
+      } else {
+        self.notices.push(Message::Notice {
+          lines: vec![format!("message #{seq} isn't there anymore")],
+          shown_at: Utc::now(),
+        });
+      }
+    }
+
+    self.show_conversation();
+
+    action
+  }
+
+  pub fn search_results(&mut self, results: messages::server_to_client::SearchResultsMessage) {
+    if results.results.is_empty() {
+      self.notice(vec![format!(
+        "no messages in #{} contain {:?}",
+        results.room_id, results.query
+      )]);
+      return;
+    }
+
+    let mut lines = vec![format!(
+      "messages in #{} containing {:?}, jump to one with /jump <seq>:",
+      results.room_id, results.query
+    )];
+    for result in &results.results {
+      lines.push(format!(
+        "  {} [{}] {}: {}",
+        result.seq,
+        format_date(result.sent_at),
+        result.username,
+        // One line per result.
+        result
+          .snippet
+          .split_whitespace()
+          .collect::<Vec<_>>()
+          .join(" ")
+      ));
+    }
+    if results.more {
+      lines.push(format!(
+        "older messages contain it too, only the latest {} are listed",
+        results.results.len()
+      ));
+    }
+
+    self.notice(lines);
+  }
+
+  pub fn session_list(&mut self, list: messages::server_to_client::SessionListMessage) {
+    let mut lines =
+      vec!["sessions of your account, log the others out with /logout others:".to_owned()];
+    for session in &list.sessions {
+      let mut line = format!(
+        "  {:016x} started {}, last used {}, expires {}",
+        session.session_id,
+        format_day(session.created_at),
+        format_day(session.last_used_at),
+        format_day(session.expires_at)
+      );
+      if session.current {
+        line.push_str(" (this one)");
+      } else if session.connected {
+        line.push_str(" (connected)");
+      }
+      lines.push(line);
+    }
+
+    self.notice(lines);
+  }
+
+  pub fn logged_out(&mut self, logged_out: messages::server_to_client::LoggedOutMessage) {
+    self.notice(vec![format!(
+      "logged out of {} other sessions",
+      logged_out.sessions
+    )]);
+  }
+
+  pub fn member_list(&mut self, list: messages::server_to_client::MemberListMessage) {
+    let mut line = format!(
+      "#{} has {} members: {}",
+      list.room_id,
+      list.members,
+      list.usernames.join(", ")
+    );
+    let unlisted = (list.members as usize).saturating_sub(list.usernames.len());
+    if unlisted > 0 {
+      line.push_str(&format!(" and {unlisted} more"));
+    }
+
+    self.notice(vec![line]);
+  }
+
+  /// Lets the user pick a room to join.
+  pub fn room_list(&mut self, list: messages::server_to_client::RoomListMessage) {
+    if list.rooms.is_empty() {
+      self.notice(vec![
+        "there are no rooms yet, create one with /create <room> [name] [| topic]".to_owned(),
+      ]);
+      return;
+    }
+
+    let mut lines = vec!["rooms on the server, join one with /join <room>:".to_owned()];
+    for room in &list.rooms {
+      let joined = if self.rooms.contains_key(&room.room_id) {
+        ", joined"
+      } else {
+        ""
+      };
+      lines.push(format!(
+        "  #{} {} ({} members{joined})",
+        room.room_id, room.display_name, room.members
+      ));
+    }
+    if let Some(last) = list.rooms.last().filter(|_| list.more) {
+      lines.push(format!("more rooms with /list {}", last.room_id));
+    }
+
+    self.notice(lines);
+  }
+
+  pub fn room_info(&mut self, info: messages::server_to_client::RoomInfoMessage) {
+    let mut lines = vec![format!("#{}: {}", info.room_id, info.display_name)];
+    if !info.topic.is_empty() {
+      lines.push(format!("  topic: {}", info.topic));
+    }
+    lines.push(format!(
+      "  {} members, created at {}",
+      info.members,
+      format_date(info.created_at)
+    ));
+
+    self.notice(lines);
   }
 
   pub fn message_sent(&mut self, message: MessageFromClient) {
-    self.messages.push(Message::FromClient {
-      username: message.username,
-      message_id: message.message_id,
-      contents: message.contents,
-      sent_at: message.sent_at,
-      delivered: false,
-      read: false,
+    if let Some(room) = self.rooms.get_mut(&message.room_id) {
+      room.push(Message::FromClient {
+        room_id: message.room_id,
+        username: message.username,
+        message_id: message.message_id,
+        seq: None,
+        contents: message.contents,
+        sent_at: message.sent_at,
+        recipients: 0,
+        delivered_to: 0,
+        read_by: 0,
+        receipts: BTreeMap::new(),
+        failed: false,
+      });
+    }
+
+    self.show_conversation();
+  }
+
+  pub fn message_accepted(&mut self, accepted: messages::server_to_client::MessageAcceptedMessage) {
+    for message in self.room_messages(&accepted.room_id) {
+      if let Message::FromClient {
+        message_id,
+        seq,
+        sent_at,
+        ..
+      } = message
+      {
+        if *message_id == accepted.message_id {
+          *seq = Some(accepted.seq);
+          *sent_at = accepted.sent_at;
+        }
+      }
+    }
+
+    self.show_conversation();
+  }
+
+  pub fn error(&mut self, error: messages::server_to_client::ErrorMessage) {
+    if let Some(related_message_id) = error.related_message_id {
+      for room in self.rooms.values_mut() {
+        for message in room.messages.items.iter_mut() {
+          if let Message::FromClient {
+            message_id, failed, ..
+          } = message
+          {
+            if *message_id == related_message_id {
+              *failed = true;
+            }
+          }
+        }
+      }
+    }
+
+    self.notices.push(Message::Error {
+      description: error.code.description(),
+      message: error.message,
+      received_at: Utc::now(),
     });
 
     self.show_conversation();
   }
 
-  pub fn message_read(&mut self, read_message_id: u64) {
-    for message in self.messages.items.iter_mut() {
+  pub fn message_read(&mut self, receipt: messages::server_to_client::MessageReadMessage) {
+    for message in self.room_messages(&receipt.room_id) {
       if let Message::FromClient {
-        message_id, read, ..
+        seq,
+        recipients,
+        read_by,
+        receipts,
+        ..
       } = message
       {
-        if *message_id <= read_message_id {
-          *read = true;
+        if *seq == Some(receipt.seq) {
+          *recipients = receipt.recipients;
+          *read_by = receipt.read_by;
+          if let Some(recipient) = receipt.recipient.clone() {
+            let recipient = receipts.entry(recipient).or_default();
+            // Reading a message implies receiving it.
+            recipient.delivered_at.get_or_insert(receipt.at);
+            recipient.read_at.get_or_insert(receipt.at);
+          }
         }
       }
     }
@@ -78,16 +580,29 @@ impl Console {
     self.show_conversation();
   }
 
-  pub fn message_delivered(&mut self, delivered_message_id: u64) {
-    for message in self.messages.items.iter_mut() {
+  pub fn message_delivered(
+    &mut self,
+    receipt: messages::server_to_client::MessageDeliveredMessage,
+  ) {
+    for message in self.room_messages(&receipt.room_id) {
       if let Message::FromClient {
-        message_id,
-        delivered,
+        seq,
+        recipients,
+        delivered_to,
+        receipts,
         ..
       } = message
       {
-        if *message_id <= delivered_message_id {
-          *delivered = true;
+        if *seq == Some(receipt.seq) {
+          *recipients = receipt.recipients;
+          *delivered_to = receipt.delivered_to;
+          if let Some(recipient) = receipt.recipient.clone() {
+            receipts
+              .entry(recipient)
+              .or_default()
+              .delivered_at
+              .get_or_insert(receipt.at);
+          }
         }
       }
     }
@@ -95,35 +610,389 @@ impl Console {
     self.show_conversation();
   }
 
+  /// Shows `lines` below the conversation.
+  pub fn notice(&mut self, lines: Vec<String>) {
+    self.notices.push(Message::Notice {
+      lines,
+      shown_at: Utc::now(),
+    });
+
+    self.show_conversation();
+  }
+
+  /// Runs a command typed by the user, `input` is what follows the `/`.
+  pub fn command(&mut self, input: &str) -> Option<Action> {
+    let mut args = input.split_whitespace();
+
+    let (lines, action) = match (args.next(), args.next()) {
+      (Some("join"), Some(room_id)) => (Vec::new(), Some(self.join_room(room_id))),
+      (Some("leave"), room_id) => match room_id.or(self.current_room()) {
+        Some(room_id) if self.rooms.contains_key(room_id) => {
+          (Vec::new(), Some(Action::LeaveRoom(room_id.to_owned())))
+        }
+        Some(room_id) => (vec![format!("you are not in #{room_id}")], None),
+        None => (vec!["usage: /leave [room]".to_owned()], None),
+      },
+      (Some("switch"), Some(room_id)) if self.rooms.contains_key(room_id) => {
+        (Vec::new(), self.switch_room(room_id.to_owned()))
+      }
+      (Some("switch"), Some(room_id)) => (
+        vec![format!(
+          "you are not in #{room_id}, join it with /join {room_id}"
+        )],
+        None,
+      ),
+      (Some("rooms"), None) => (self.list_rooms(), None),
+      (Some("list"), after) => (
+        Vec::new(),
+        Some(Action::ListRooms(after.map(str::to_owned))),
+      ),
+      (Some("create"), Some(room_id)) => {
+        let rest = args.collect::<Vec<_>>().join(" ");
+        let (display_name, topic) = rest.split_once('|').unwrap_or((&rest, ""));
+        self.switch_to = Some(room_id.to_owned());
+        (
+          Vec::new(),
+          Some(Action::CreateRoom {
+            room_id: room_id.to_owned(),
+            display_name: display_name.trim().to_owned(),
+            topic: topic.trim().to_owned(),
+          }),
+        )
+      }
+      (Some("info"), room_id) => match room_id.or(self.current_room()) {
+        Some(room_id) => (Vec::new(), Some(Action::DescribeRoom(room_id.to_owned()))),
+        None => (vec!["usage: /info [room]".to_owned()], None),
+      },
+      (Some("who"), room_id) => match room_id.or(self.current_room()) {
+        Some(room_id) if self.rooms.contains_key(room_id) => {
+          (Vec::new(), Some(Action::ListMembers(room_id.to_owned())))
+        }
+        Some(room_id) => (vec![format!("you are not in #{room_id}")], None),
+        None => (vec!["usage: /who [room]".to_owned()], None),
+      },
+      (Some("up"), None) => match self.scroll_up() {
+        Ok(action) => (Vec::new(), action),
+        Err(line) => (vec![line], None),
+      },
+      (Some("down"), None) => match self.scroll_down() {
+        Ok(()) => (Vec::new(), None),
+        Err(line) => (vec![line], None),
+      },
+      (Some("search"), Some(_)) => match self.current_room() {
+        Some(room_id) => {
+          let query = input.trim_start()["search".len()..].trim();
+          if query.len() > messages::MAX_QUERY_BYTES {
+            (
+              vec![format!(
+                "the query is too long, at most {} bytes",
+                messages::MAX_QUERY_BYTES
+              )],
+              None,
+            )
+          } else {
+            (
+              Vec::new(),
+              Some(Action::Search {
+                room_id: room_id.to_owned(),
+                query: query.to_owned(),
+              }),
+            )
+          }
+        }
+        None => (vec!["you are not in any room".to_owned()], None),
+      },
+      (Some("jump"), Some(seq)) => match seq.parse() {
+        Ok(seq) => match self.jump(seq) {
+          Ok(action) => (Vec::new(), action),
+          Err(line) => (vec![line], None),
+        },
+        Err(_) => (vec!["usage: /jump <seq>".to_owned()], None),
+      },
+      (Some("sessions"), None) => (Vec::new(), Some(Action::ListSessions)),
+      (Some("logout"), None) => (Vec::new(), Some(Action::LogOut { others: false })),
+      (Some("logout"), Some("others")) => (Vec::new(), Some(Action::LogOut { others: true })),
+      (Some("receipts"), n) => match n.map(str::parse::<usize>) {
+        None => (self.receipts(1), None),
+        Some(Ok(n)) if n > 0 => (self.receipts(n), None),
+        Some(_) => (
+          vec!["usage: /receipts [n], n counts back from your last message".to_owned()],
+          None,
+        ),
+      },
+      _ => (
+        vec![
+          format!("unknown command: /{}", input.trim_end()),
+          "commands: /list, /create <room> [name] [| topic], /info [room], /join <room>, \
+           /leave [room], /switch <room>, /rooms, /who [room], /receipts [n], /up, /down, \
+           /search <words>, /jump <seq>, /sessions, /logout [others]"
+            .to_owned(),
+        ],
+        None,
+      ),
+    };
+
+    if lines.is_empty() {
+      self.show_conversation();
+    } else {
+      self.notice(lines);
+    }
+
+    action
+  }
+
+  fn join_room(&mut self, room_id: &str) -> Action {
+    self.switch_to = Some(room_id.to_owned());
+    Action::JoinRoom(room_id.to_owned())
+  }
+
+  /// Makes `room_id` the current room, the messages received in it are now read.
+  fn switch_room(&mut self, room_id: String) -> Option<Action> {
+    let room = self.rooms.get_mut(&room_id)?;
+    room.unread = 0;
+    let seq = room.last_seq;
+    self.current_room = Some(room_id.clone());
+
+    seq.map(|seq| Action::MarkAsRead { room_id, seq })
+  }
+
+  /// Shows the previous page of the current room,
+  /// older messages are loaded from the server once the first one is shown.
+  fn scroll_up(&mut self) -> Result<Option<Action>, String> {
+    let room_id = self.current_room.clone().ok_or("you are not in any room")?;
+    let room = self
+      .rooms
+      .get_mut(&room_id)
+      .ok_or("you are not in any room")?;
+
+    if room.scroll < room.max_scroll() {
+      room.scroll = (room.scroll + VIEW_LEN).min(room.max_scroll());
+      return Ok(None);
+    }
+    if !room.more_history {
+      return Err(format!("this is the first message of #{room_id}"));
+    }
+    room.loading_history = true;
+    Ok(Some(Action::FetchHistory {
+      before: room.first_seq(),
+      room_id,
+    }))
+  }
+
+  fn scroll_down(&mut self) -> Result<(), String> {
+    let room = self
+      .current_room
+      .as_ref()
+      .and_then(|room_id| self.rooms.get_mut(room_id))
+      .ok_or("you are not in any room")?;
+    if room.scroll == 0 {
+      return Err("these are the latest messages".to_owned());
+    }
+    room.scroll = room.scroll.saturating_sub(VIEW_LEN);
+    Ok(())
+  }
+
+  /// Shows the message of the current room with the seq,
+  /// the messages before the oldest one the client has are loaded up to it first.
+  fn jump(&mut self, seq: u64) -> Result<Option<Action>, String> {
+    let room_id = self.current_room.clone().ok_or("you are not in any room")?;
+    let room = self
+      .rooms
+      .get_mut(&room_id)
+      .ok_or("you are not in any room")?;
+
+    if room.scroll_to(seq) {
+      return Ok(None);
+    }
+    let first_seq = room.first_seq();
+    if first_seq.is_some_and(|first_seq| first_seq < seq) || !room.more_history {
+      return Err(format!("there's no message #{seq} in #{room_id}"));
+    }
+    let missing = first_seq.map_or(0, |first_seq| first_seq - seq);
+    if missing > room.free_scrollback() as u64 {
+      return Err(format!(
+        "message #{seq} is too old, only {SCROLLBACK_LEN} messages of a room are kept"
+      ));
+    }
+
+    room.jump_to = Some(seq);
+    room.loading_history = true;
+    Ok(Some(Action::FetchHistory {
+      before: first_seq,
+      room_id,
+    }))
+  }
+
+  fn list_rooms(&self) -> Vec<String> {
+    if self.rooms.is_empty() {
+      return vec!["you are not in any room, join one with /join <room>".to_owned()];
+    }
+
+    self
+      .rooms
+      .iter()
+      .map(|(room_id, room)| {
+        let current = if self.current_room.as_ref() == Some(room_id) {
+          " (current)"
+        } else {
+          ""
+        };
+        format!("#{room_id}{current}: {} unread", room.unread)
+      })
+      .collect()
+  }
+
+  /// Describes who received and read the `n`th message sent by the client in the current room,
+  /// counting back from the last one.
+  fn receipts(&self, n: usize) -> Vec<String> {
+    let message = self
+      .current_room
+      .as_ref()
+      .and_then(|room_id| self.rooms.get(room_id))
+      .into_iter()
+      .flat_map(|room| room.messages.items.iter().rev())
+      .filter(|message| matches!(message, Message::FromClient { .. }))
+      .nth(n - 1);
+
+    let (contents, recipients, read_by, receipts) = match message {
+      Some(Message::FromClient {
+        contents,
+        recipients,
+        read_by,
+        receipts,
+        ..
+      }) => (contents, *recipients, *read_by, receipts),
+      _ => return vec![format!("you haven't sent {n} messages in this room")],
+    };
+
+    let mut lines = vec![format!("receipts for: {}", contents.trim_end())];
+    for (username, receipt) in receipts {
+      let status = match (receipt.delivered_at, receipt.read_at) {
+        (_, Some(read_at)) => format!("read at {}", format_date(read_at)),
+        (Some(delivered_at), None) => format!("delivered at {}", format_date(delivered_at)),
+        (None, None) => continue,
+      };
+      lines.push(format!("  {username}: {status}"));
+    }
+    if read_by < recipients {
+      lines.push(format!(
+        "  {} of {recipients} haven't read it yet",
+        recipients - read_by
+      ));
+    }
+
+    lines
+  }
+
+  fn room_messages(&mut self, room_id: &str) -> impl Iterator<Item = &mut Message> {
+    self
+      .rooms
+      .get_mut(room_id)
+      .into_iter()
+      .flat_map(|room| room.messages.items.iter_mut())
+  }
+
   pub fn show_conversation(&self) {
     clear_console();
 
-    for message in self.messages.items.iter() {
+    let rooms: Vec<String> = self
+      .rooms
+      .iter()
+      .map(|(room_id, room)| {
+        if self.current_room.as_ref() == Some(room_id) {
+          format!("[#{room_id}]")
+        } else if room.unread > 0 {
+          format!("#{room_id} ({})", room.unread)
+        } else {
+          format!("#{room_id}")
+        }
+      })
+      .collect();
+    println!("rooms: {}", rooms.join(" "));
+
+    let room = self
+      .current_room
+      .as_ref()
+      .and_then(|room_id| self.rooms.get(room_id));
+
+    let mut messages = Vec::new();
+    if let Some(room) = room {
+      let end = room.messages.items.len() - room.scroll;
+      let start = end.saturating_sub(VIEW_LEN);
+      if start > 0 || room.more_history {
+        println!("    ... older messages with /up");
+      }
+      if room.scroll > 0 {
+        println!("    ... {} newer messages with /down", room.scroll);
+      }
+      messages.extend(room.messages.items.range(start..end));
+    }
+
+    for message in messages.into_iter().chain(self.notices.items.iter()) {
       match message {
         Message::FromPeer {
+          room_id,
           username,
           contents,
-          received_at,
+          sent_at,
           ..
         } => {
-          println!("    [{}] {username}: {contents}", format_date(*received_at));
+          println!(
+            "    [{}] #{room_id} {username}: {contents}",
+            format_date(*sent_at)
+          );
         }
         Message::FromClient {
+          room_id,
           username,
           contents,
           sent_at,
-          delivered,
-          read,
+          recipients,
+          delivered_to,
+          read_by,
+          failed,
           ..
         } => {
-          let check = if *read {
+          let check = if *failed {
+            "✗"
+          } else if *recipients > 0 && read_by == recipients {
             "✓✓"
-          } else if *delivered {
+          } else if *recipients > 0 && delivered_to == recipients {
             "✓"
           } else {
             ""
           };
-          println!("[{}] {check} {username}: {contents}", format_date(*sent_at));
+          // In groups, show how far along the message is until everyone has read it.
+          let progress = if *recipients > 1 && read_by < recipients {
+            format!("(delivered to {delivered_to}/{recipients}, read by {read_by}/{recipients}) ")
+          } else {
+            String::new()
+          };
+          println!(
+            "[{}] {check} {progress}#{room_id} {username}: {contents}",
+            format_date(*sent_at)
+          );
+        }
+        Message::Presence {
+          room_id,
+          username,
+          joined,
+          at,
+        } => {
+          let action = if *joined { "joined" } else { "left" };
+          println!("    [{}] {username} {action} #{room_id}", format_date(*at));
+        }
+        Message::Error {
+          description,
+          message,
+          received_at,
+        } => {
+          println!("[{}] ! {description}: {message}", format_date(*received_at));
+        }
+        Message::Notice { lines, shown_at } => {
+          for line in lines {
+            println!("[{}] * {line}", format_date(*shown_at));
+          }
         }
       }
     }
@@ -136,6 +1005,13 @@ fn format_date(date: DateTime<Utc>) -> String {
     .to_string()
 }
 
+/// Like `format_date`, for dates that can be days away.
+fn format_day(date: DateTime<Utc>) -> String {
+  (date - chrono::Duration::hours(3))
+    .format("%Y-%m-%d %H:%M")
+    .to_string()
+}
+
 fn clear_console() {
   println!("\x1B[2J");
 }
@@ -159,4 +1035,131 @@ impl<T> MaxLengthVec<T> {
       let _ = self.items.pop_front();
     }
   }
+
+  /// Inserts `value` at `index`, the first item is dropped if there's no room left.
+  fn insert(&mut self, index: usize, value: T) {
+    self.items.insert(index, value);
+    if self.items.len() > self.max_len {
+      let _ = self.items.pop_front();
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// A console in `room` where the client sent "hi", accepted as seq 0.
+  fn console_with_sent_message() -> Console {
+    let mut console = Console::new();
+    console.room_joined("room".to_owned());
+    console.message_sent(MessageFromClient {
+      username: "ann".to_owned(),
+      room_id: "room".to_owned(),
+      message_id: 1,
+      contents: "hi".to_owned(),
+      sent_at: Utc::now(),
+    });
+    console.message_accepted(messages::server_to_client::MessageAcceptedMessage {
+      room_id: "room".to_owned(),
+      message_id: 1,
+      seq: 0,
+      sent_at: Utc::now(),
+    });
+    console
+  }
+
+  fn delivered(
+    recipient: Option<&str>,
+    delivered_to: u32,
+    recipients: u32,
+  ) -> messages::server_to_client::MessageDeliveredMessage {
+    messages::server_to_client::MessageDeliveredMessage {
+      seq: 0,
+      room_id: "room".to_owned(),
+      delivered_to,
+      recipients,
+      recipient: recipient.map(str::to_owned),
+      at: Utc::now(),
+    }
+  }
+
+  fn read(
+    recipient: Option<&str>,
+    read_by: u32,
+    recipients: u32,
+  ) -> messages::server_to_client::MessageReadMessage {
+    messages::server_to_client::MessageReadMessage {
+      seq: 0,
+      room_id: "room".to_owned(),
+      read_by,
+      recipients,
+      recipient: recipient.map(str::to_owned),
+      at: Utc::now(),
+    }
+  }
+
+  /// The lines of `/receipts` without their times.
+  fn receipts(console: &Console) -> Vec<String> {
+    console
+      .receipts(1)
+      .into_iter()
+      .map(|line| match line.split_once(" at ") {
+        Some((status, _)) => status.to_owned(),
+        None => line,
+      })
+      .collect()
+  }
+
+  #[test]
+  fn receipts_are_tracked_per_recipient() {
+    let mut console = console_with_sent_message();
+    console.message_delivered(delivered(Some("bob"), 1, 3));
+    console.message_delivered(delivered(Some("cat"), 2, 3));
+    console.message_read(read(Some("bob"), 1, 3));
+    // Reading a message implies receiving it.
+    console.message_read(read(Some("dan"), 2, 3));
+    assert_eq!(
+      receipts(&console),
+      [
+        "receipts for: hi",
+        "  bob: read",
+        "  cat: delivered",
+        "  dan: read",
+        "  1 of 3 haven't read it yet",
+      ]
+    );
+
+    // Recipients leaving the room change the counts without a recipient.
+    console.message_read(read(None, 2, 2));
+    assert_eq!(
+      receipts(&console),
+      [
+        "receipts for: hi",
+        "  bob: read",
+        "  cat: delivered",
+        "  dan: read"
+      ]
+    );
+  }
+
+  #[test]
+  fn queries_of_the_longest_length_are_searched() {
+    let mut console = Console::new();
+    console.room_joined("room".to_owned());
+
+    let query = "w".repeat(messages::MAX_QUERY_BYTES);
+    match console.command(&format!("  search  {query} ")) {
+      Some(Action::Search {
+        room_id,
+        query: searched,
+      }) => {
+        assert_eq!(room_id, "room");
+        assert_eq!(searched, query);
+      }
+      _ => panic!("the query wasn't searched"),
+    }
+
+    assert!(console.command(&format!("search {query}w")).is_none());
+  }
 }
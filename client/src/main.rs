@@ -1,11 +1,23 @@
+use std::{
+  collections::HashMap,
+  io::Write,
+  path::{Path, PathBuf},
+  time::Duration,
+};
+
 use anyhow::{anyhow, Result};
 
+use bytes::BytesMut;
 use chrono::{DateTime, Utc};
 use clap::Parser;
-use console::Console;
+use console::{Action, Console};
 
-use tokio::net::{TcpSocket, TcpStream};
-use tracing::{error, info};
+use tokio::{
+  io::AsyncReadExt,
+  net::{TcpSocket, TcpStream},
+  time::Instant,
+};
+use tracing::{error, info, warn};
 
 mod console;
 
@@ -18,23 +30,59 @@ struct Config {
   /// Your username.
   #[arg(long)]
   username: String,
-  /// The room to which messages will be sent and received from.
+  /// The password of your account, not needed to resume a session saved in `--session-file`.
+  #[arg(long, env = "CHAT_PASSWORD", hide_env_values = true)]
+  password: Option<String>,
+  /// Create the account instead of logging in with it.
+  #[arg(long)]
+  register: bool,
+  /// Where to keep the session token once logged in, it's as secret as the password.
+  /// The next time the session is resumed with it instead of logging in again.
   #[arg(long)]
-  room: String,
+  session_file: Option<PathBuf>,
+  /// A room to join once connected, can be repeated. The first one becomes the current room,
+  /// more rooms can be joined with `/join <room>`. Without it the rooms on the server are listed.
+  #[arg(long = "room")]
+  rooms: Vec<String>,
   #[arg(long)]
   /// The port that the client should use.
   port: Option<u16>,
+  /// How often to send a ping to the server.
+  #[arg(long, default_value_t = 15)]
+  heartbeat_interval_secs: u64,
+  /// The connection is closed if the server doesn't send anything for this long.
+  #[arg(long, default_value_t = 45)]
+  idle_timeout_secs: u64,
+  /// How long to wait before acknowledging received and read messages,
+  /// every message received in the meantime is acknowledged at once.
+  #[arg(long, default_value_t = 100)]
+  ack_delay_ms: u64,
 }
 
 struct ChatClient {
   config: Config,
   server_stream: TcpStream,
+  /// Bytes received from the server that don't make a whole frame yet.
+  read_buf: BytesMut,
+  /// When the last message was received from the server.
+  last_received_at: Instant,
   next_message_id: u64,
+  /// The capabilities negotiated with the server.
+  capabilities: messages::Capabilities,
+  /// The last seq received in each room that hasn't been acknowledged yet.
+  unacknowledged_received: HashMap<String, u64>,
+  /// The last seq read in each room that hasn't been acknowledged yet.
+  unacknowledged_read: HashMap<String, u64>,
+  /// When to acknowledge the messages received and read so far.
+  acknowledge_at: Option<Instant>,
+  /// Whether the client asked to log its own session out, it exits once it's done.
+  logging_out: bool,
 }
 
 #[derive(Debug, Clone)]
 pub struct MessageFromClient {
   username: String,
+  room_id: String,
   message_id: u64,
   contents: String,
   sent_at: DateTime<Utc>,
@@ -53,6 +101,22 @@ fn try_bind_to_port(socket: &TcpSocket, port: u16) -> Result<()> {
   Err(anyhow!("unable to bind socket to port. port={port}"))
 }
 
+/// Writes the session token to `path`, readable by the user only where the platform allows it.
+fn save_session(path: &Path, token: &str) -> std::io::Result<()> {
+  #[cfg(unix)]
+  use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+
+  let mut options = std::fs::OpenOptions::new();
+  options.write(true).create(true).truncate(true);
+  #[cfg(unix)]
+  options.mode(0o600);
+  let mut file = options.open(path)?;
+  // The mode only applies to new files, a file saved before keeps its permissions otherwise.
+  #[cfg(unix)]
+  file.set_permissions(std::fs::Permissions::from_mode(0o600))?;
+  file.write_all(token.as_bytes())
+}
+
 impl ChatClient {
   async fn new(config: Config) -> Result<Self> {
     let server_stream = {
@@ -68,10 +132,25 @@ impl ChatClient {
     let mut client = Self {
       config,
       server_stream,
+      read_buf: BytesMut::new(),
+      last_received_at: Instant::now(),
       next_message_id: 0,
+      capabilities: messages::Capabilities::NONE,
+      unacknowledged_received: HashMap::new(),
+      unacknowledged_read: HashMap::new(),
+      acknowledge_at: None,
+      logging_out: false,
     };
 
-    client.join_room().await?;
+    client.hello().await?;
+    client.log_in().await?;
+    for room_id in client.config.rooms.clone() {
+      client.join_room(room_id).await?;
+    }
+    // Let the user pick a room.
+    if client.config.rooms.is_empty() {
+      client.list_rooms(None).await?;
+    }
 
     Ok(client)
   }
@@ -82,16 +161,29 @@ impl ChatClient {
     id
   }
 
-  fn port(&self) -> std::io::Result<u16> {
-    self.server_stream.local_addr().map(|addr| addr.port())
+  /// The account the client is logged in with, the server keeps its rooms
+  /// and undelivered messages between connections.
+  fn username(&self) -> String {
+    self.config.username.clone()
   }
 
-  fn room(&self) -> &str {
-    &self.config.room
+  fn heartbeats(&self) -> bool {
+    self
+      .capabilities
+      .contains(messages::Capabilities::HEARTBEATS)
   }
 
-  fn username(&self) -> std::io::Result<String> {
-    Ok(format!("{}({})", &self.config.username, self.port()?))
+  fn ack_delay(&self) -> Duration {
+    Duration::from_millis(self.config.ack_delay_ms)
+  }
+
+  fn heartbeat_interval(&self) -> Duration {
+    Duration::from_secs(self.config.heartbeat_interval_secs)
+  }
+
+  /// When the connection is considered dead if nothing else is received from the server.
+  fn idle_deadline(&self) -> Instant {
+    self.last_received_at + Duration::from_secs(self.config.idle_timeout_secs)
   }
 
   async fn send_chat_message(
@@ -103,9 +195,166 @@ impl ChatClient {
     Ok(())
   }
 
-  async fn join_room(&mut self) -> Result<()> {
-    let room_id = self.room().to_owned();
+  async fn hello(&mut self) -> Result<()> {
+    messages::client_to_server::write_hello_message(
+      &mut self.server_stream,
+      messages::client_to_server::HelloMessage {
+        protocol_version: messages::PROTOCOL_VERSION,
+        capabilities: messages::Capabilities::all(),
+      },
+    )
+    .await?;
+
+    match messages::read_server_message(&mut self.server_stream).await? {
+      messages::ServerToClientMessage::Welcome(welcome) => {
+        info!(
+          "connected to server. protocol_version={} capabilities={:?}",
+          welcome.protocol_version, welcome.capabilities
+        );
+        self.capabilities = welcome.capabilities;
+        Ok(())
+      }
+      messages::ServerToClientMessage::HelloRejected(rejected) => Err(anyhow!(
+        "server rejected connection. server_protocol_version={} reason={}",
+        rejected.protocol_version,
+        rejected.reason
+      )),
+      message => Err(anyhow!(
+        "expected Welcome message from server. message={:?}",
+        message
+      )),
+    }
+  }
+
+  /// Resumes the session saved in the session file, or registers or logs in with the username
+  /// and password of the config if there's none or it can't be resumed.
+  async fn log_in(&mut self) -> Result<()> {
+    if !self.config.register {
+      if let Some(token) = self.saved_token() {
+        messages::client_to_server::write_resume_message(
+          &mut self.server_stream,
+          messages::client_to_server::ResumeMessage { token },
+        )
+        .await?;
+        match self.authenticated().await {
+          Ok(()) => return Ok(()),
+          Err(err) if self.config.password.is_some() => {
+            warn!("unable to resume the session, logging in. error={}", err);
+            self.forget_session();
+          }
+          Err(err) => {
+            self.forget_session();
+            return Err(err);
+          }
+        }
+      }
+    }
+
+    let username = self.config.username.clone();
+    let password = self.config.password.clone().ok_or_else(|| {
+      anyhow!("a password is needed to log in, there's no saved session to resume")
+    })?;
+    if self.config.register {
+      messages::client_to_server::write_register_message(
+        &mut self.server_stream,
+        messages::client_to_server::RegisterMessage { username, password },
+      )
+      .await?;
+    } else {
+      messages::client_to_server::write_login_message(
+        &mut self.server_stream,
+        messages::client_to_server::LoginMessage { username, password },
+      )
+      .await?;
+    }
+
+    self.authenticated().await
+  }
+
+  /// Waits for the server to confirm that the client is logged in, then saves the session token.
+  async fn authenticated(&mut self) -> Result<()> {
+    match messages::read_server_message(&mut self.server_stream).await? {
+      messages::ServerToClientMessage::Authenticated(authenticated) => {
+        if authenticated.username != self.config.username {
+          return Err(anyhow!(
+            "the saved session is for {}, not {}",
+            authenticated.username,
+            self.config.username
+          ));
+        }
+        info!(
+          "logged in. username={} expires_at={}",
+          authenticated.username, authenticated.expires_at
+        );
+        if let Some(path) = &self.config.session_file {
+          if let Err(err) = save_session(path, &authenticated.token) {
+            warn!("unable to save the session. path={:?} error={}", path, err);
+          }
+        }
+        Ok(())
+      }
+      messages::ServerToClientMessage::Error(error) => Err(anyhow!(
+        "unable to log in: {}: {}",
+        error.code.description(),
+        error.message
+      )),
+      message => Err(anyhow!(
+        "expected Authenticated message from server. message={:?}",
+        message
+      )),
+    }
+  }
+
+  /// The session token saved in the session file, if any.
+  fn saved_token(&self) -> Option<String> {
+    let path = self.config.session_file.as_ref()?;
+    match std::fs::read_to_string(path) {
+      Ok(token) if !token.trim().is_empty() => Some(token.trim().to_owned()),
+      Ok(_) => None,
+      Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+      Err(err) => {
+        warn!("unable to read the session. path={:?} error={}", path, err);
+        None
+      }
+    }
+  }
+
+  /// Deletes the session file, its token doesn't work anymore.
+  fn forget_session(&self) {
+    if let Some(path) = &self.config.session_file {
+      if let Err(err) = std::fs::remove_file(path) {
+        if err.kind() != std::io::ErrorKind::NotFound {
+          warn!(
+            "unable to delete the session. path={:?} error={}",
+            path, err
+          );
+        }
+      }
+    }
+  }
+
+  async fn list_sessions(&mut self) -> Result<()> {
+    messages::client_to_server::write_list_sessions_message(
+      &mut self.server_stream,
+      messages::client_to_server::ListSessionsMessage {},
+    )
+    .await?;
+
+    Ok(())
+  }
+
+  async fn log_out(&mut self, others: bool) -> Result<()> {
+    messages::client_to_server::write_log_out_message(
+      &mut self.server_stream,
+      messages::client_to_server::LogOutMessage { others },
+    )
+    .await?;
+    self.logging_out |= !others;
+
+    Ok(())
+  }
 
+  async fn join_room(&mut self, room_id: String) -> Result<()> {
     messages::client_to_server::write_join_room_message(
       &mut self.server_stream,
       messages::client_to_server::JoinRoomMessage { room_id },
@@ -115,12 +364,48 @@ impl ChatClient {
     Ok(())
   }
 
-  async fn mark_message_as_read(&mut self, message_id: u64, room_id: String) -> Result<()> {
-    messages::client_to_server::write_message_read(
+  async fn leave_room(&mut self, room_id: String) -> Result<()> {
+    messages::client_to_server::write_leave_room_message(
       &mut self.server_stream,
-      messages::client_to_server::MessageReadMessage {
-        message_id,
+      messages::client_to_server::LeaveRoomMessage { room_id },
+    )
+    .await?;
+
+    Ok(())
+  }
+
+  async fn list_members(&mut self, room_id: String) -> Result<()> {
+    messages::client_to_server::write_list_members_message(
+      &mut self.server_stream,
+      messages::client_to_server::ListMembersMessage { room_id },
+    )
+    .await?;
+
+    Ok(())
+  }
+
+  async fn list_rooms(&mut self, after: Option<String>) -> Result<()> {
+    messages::client_to_server::write_list_rooms_message(
+      &mut self.server_stream,
+      messages::client_to_server::ListRoomsMessage { after },
+    )
+    .await?;
+
+    Ok(())
+  }
+
+  async fn create_room(
+    &mut self,
+    room_id: String,
+    display_name: String,
+    topic: String,
+  ) -> Result<()> {
+    messages::client_to_server::write_create_room_message(
+      &mut self.server_stream,
+      messages::client_to_server::CreateRoomMessage {
         room_id,
+        display_name,
+        topic,
       },
     )
     .await?;
@@ -128,25 +413,160 @@ impl ChatClient {
     Ok(())
   }
 
-  async fn recv(&mut self) -> Result<Option<messages::ServerToClientMessage>> {
-    self.server_stream.readable().await?;
+  async fn describe_room(&mut self, room_id: String) -> Result<()> {
+    messages::client_to_server::write_describe_room_message(
+      &mut self.server_stream,
+      messages::client_to_server::DescribeRoomMessage { room_id },
+    )
+    .await?;
 
-    let message = messages::read_server_message(&mut self.server_stream).await?;
+    Ok(())
+  }
 
-    if let messages::ServerToClientMessage::ChatMessage(ref message) = message {
-      let room_id = self.room().to_string();
+  /// Asks for the page of messages sent before `before`, or for the latest ones.
+  async fn fetch_history(&mut self, room_id: String, before: Option<u64>) -> Result<()> {
+    messages::client_to_server::write_fetch_history_message(
+      &mut self.server_stream,
+      messages::client_to_server::FetchHistoryMessage {
+        room_id,
+        before,
+        after: None,
+        limit: messages::MAX_HISTORY_MESSAGES as u32,
+      },
+    )
+    .await?;
+
+    Ok(())
+  }
+
+  /// Asks for the latest messages of the room containing every word of `query`.
+  async fn search(&mut self, room_id: String, query: String) -> Result<()> {
+    messages::client_to_server::write_search_message(
+      &mut self.server_stream,
+      messages::client_to_server::SearchMessage {
+        room_id,
+        query,
+        limit: messages::MAX_SEARCH_RESULTS as u32,
+      },
+    )
+    .await?;
+
+    Ok(())
+  }
+
+  async fn ping(&mut self, nonce: u64) -> Result<()> {
+    messages::client_to_server::write_ping_message(
+      &mut self.server_stream,
+      messages::client_to_server::PingMessage { nonce },
+    )
+    .await?;
+
+    Ok(())
+  }
 
+  async fn pong(&mut self, nonce: u64) -> Result<()> {
+    messages::client_to_server::write_pong_message(
+      &mut self.server_stream,
+      messages::client_to_server::PongMessage { nonce },
+    )
+    .await?;
+
+    Ok(())
+  }
+
+  /// The message is acknowledged by the next call to `send_acknowledgements`.
+  fn mark_message_as_received(&mut self, seq: u64, room_id: String) {
+    let last_seq = self.unacknowledged_received.entry(room_id).or_insert(seq);
+    *last_seq = seq.max(*last_seq);
+    self.schedule_acknowledgements();
+  }
+
+  /// The message is acknowledged by the next call to `send_acknowledgements`.
+  fn mark_message_as_read(&mut self, seq: u64, room_id: String) {
+    let last_seq = self.unacknowledged_read.entry(room_id).or_insert(seq);
+    *last_seq = seq.max(*last_seq);
+    self.schedule_acknowledgements();
+  }
+
+  fn schedule_acknowledgements(&mut self) {
+    if self.acknowledge_at.is_none() {
+      self.acknowledge_at = Some(Instant::now() + self.ack_delay());
+    }
+  }
+
+  /// Tells the server which messages have been received and read since the last call,
+  /// acknowledgements are cumulative so only the last seq of each room is sent.
+  async fn send_acknowledgements(&mut self) -> Result<()> {
+    self.acknowledge_at = None;
+
+    for (room_id, seq) in std::mem::take(&mut self.unacknowledged_received) {
       messages::client_to_server::write_message_received(
         &mut self.server_stream,
-        messages::client_to_server::MessageReceivedMessage {
-          room_id,
-          message_id: message.message_id,
-        },
+        messages::client_to_server::MessageReceivedMessage { seq, room_id },
       )
       .await?;
     }
 
-    Ok(Some(message))
+    for (room_id, seq) in std::mem::take(&mut self.unacknowledged_read) {
+      messages::client_to_server::write_message_read(
+        &mut self.server_stream,
+        messages::client_to_server::MessageReadMessage { seq, room_id },
+      )
+      .await?;
+    }
+
+    Ok(())
+  }
+
+  async fn run(&mut self, action: Action) -> Result<()> {
+    match action {
+      Action::JoinRoom(room_id) => self.join_room(room_id).await,
+      Action::LeaveRoom(room_id) => self.leave_room(room_id).await,
+      Action::ListMembers(room_id) => self.list_members(room_id).await,
+      Action::ListRooms(after) => self.list_rooms(after).await,
+      Action::CreateRoom {
+        room_id,
+        display_name,
+        topic,
+      } => self.create_room(room_id, display_name, topic).await,
+      Action::DescribeRoom(room_id) => self.describe_room(room_id).await,
+      Action::FetchHistory { room_id, before } => self.fetch_history(room_id, before).await,
+      Action::Search { room_id, query } => self.search(room_id, query).await,
+      Action::MarkAsRead { room_id, seq } => {
+        self.mark_message_as_read(seq, room_id);
+        Ok(())
+      }
+      Action::ListSessions => self.list_sessions().await,
+      Action::LogOut { others } => self.log_out(others).await,
+    }
+  }
+
+  /// Waits for the next message from the server.
+  ///
+  /// Cancel safe: bytes read before the future is dropped are kept in `read_buf`.
+  async fn recv(&mut self) -> Result<Option<messages::ServerToClientMessage>> {
+    let message = loop {
+      if let Some(message) = messages::server_to_client::decode(&mut self.read_buf) {
+        break message;
+      }
+
+      if self.server_stream.read_buf(&mut self.read_buf).await? == 0 {
+        return Err(anyhow!("server closed the connection"));
+      }
+    };
+
+    self.last_received_at = Instant::now();
+
+    match message {
+      Err(messages::ProtocolError::UnknownMessageType(message_type)) => {
+        warn!(
+          "skipping unknown message type. message_type={}",
+          message_type
+        );
+        Ok(None)
+      }
+      result => Ok(Some(result?)),
+    }
   }
 }
 
@@ -158,38 +578,145 @@ async fn main() -> Result<()> {
 
   let mut console = Console::new();
 
+  let mut heartbeat_interval = tokio::time::interval(client.heartbeat_interval());
+  let mut next_ping_nonce = 0_u64;
+
   loop {
+    let heartbeats = client.heartbeats();
+    let idle_deadline = client.idle_deadline();
+    let acknowledge_at = client.acknowledge_at;
+
     tokio::select! {
       message = client.recv() => {
-        if let Ok(Some(message)) = message {
+        if let Some(message) = message? {
           match message {
             messages::ServerToClientMessage::ChatMessage(message) => {
-              let message_id = message.message_id;
-              console.message_received(message);
+              let seq = message.seq;
+              let room_id = message.room_id.clone();
+              let shown = console.message_received(message);
+
+              if client.capabilities.contains(messages::Capabilities::DELIVERY_RECEIPTS) {
+                client.mark_message_as_received(seq, room_id.clone());
+              }
 
-              if let Err(err) = client.mark_message_as_read(message_id,client.room().to_owned()).await {
-                error!("unable to mark message as read. message_id={} error={:?}", message_id,err);
+              // Messages in the other rooms are read once the user switches to them.
+              if shown {
+                client.mark_message_as_read(seq, room_id);
+              }
+            },
+            messages::ServerToClientMessage::RoomJoined(message) => {
+              if let Some(action) = console.room_joined(message.room_id) {
+                client.run(action).await?;
+              }
+            },
+            messages::ServerToClientMessage::UserJoined(message) => {
+              console.user_joined(message);
+            },
+            messages::ServerToClientMessage::UserLeft(message) => {
+              console.user_left(message);
+            },
+            messages::ServerToClientMessage::MemberList(message) => {
+              console.member_list(message);
+            },
+            messages::ServerToClientMessage::RoomList(message) => {
+              console.room_list(message);
+            },
+            messages::ServerToClientMessage::RoomInfo(message) => {
+              console.room_info(message);
+            },
+            messages::ServerToClientMessage::History(message) => {
+              if let Some(action) = console.history(message) {
+                client.run(action).await?;
+              }
+            },
+            messages::ServerToClientMessage::SearchResults(message) => {
+              console.search_results(message);
+            },
+            messages::ServerToClientMessage::RoomLeft(message) => {
+              if let Some(action) = console.room_left(message.room_id) {
+                client.run(action).await?;
               }
             },
+            messages::ServerToClientMessage::MessageAccepted(message) => {
+              console.message_accepted(message);
+            },
             messages::ServerToClientMessage::MessageDelivered(message) => {
-              console.message_delivered(message.message_id);
+              console.message_delivered(message);
             },
             messages::ServerToClientMessage::MessageRead(message) => {
-              console.message_read(message.message_id);
+              console.message_read(message);
+            },
+            messages::ServerToClientMessage::SessionList(message) => {
+              console.session_list(message);
+            },
+            messages::ServerToClientMessage::LoggedOut(message) => {
+              if client.logging_out {
+                // The server closes the connection, the rooms are kept for the next login.
+                client.forget_session();
+                info!("logged out");
+                return Ok(());
+              }
+              console.logged_out(message);
+            },
+            messages::ServerToClientMessage::Error(message) => {
+              if message.code == messages::server_to_client::ErrorCode::SessionRevoked {
+                client.forget_session();
+                return Err(anyhow!("logged out by another session: {}", message.message));
+              }
+              console.error(message);
+            },
+            messages::ServerToClientMessage::Ping(message) => {
+              client.pong(message.nonce).await?;
+            },
+            messages::ServerToClientMessage::Pong(_) => {},
+            message @ (messages::ServerToClientMessage::Welcome(_)
+            | messages::ServerToClientMessage::HelloRejected(_)
+            | messages::ServerToClientMessage::Authenticated(_)) => {
+              error!("unexpected handshake message. message={:?}", message);
             },
           }
         }
       }
+      _ = heartbeat_interval.tick(), if heartbeats => {
+        client.ping(next_ping_nonce).await?;
+        next_ping_nonce += 1;
+      }
+      _ = tokio::time::sleep_until(idle_deadline) => {
+        return Err(anyhow!("server is not responding, closing connection"));
+      }
+      _ = tokio::time::sleep_until(acknowledge_at.unwrap_or(idle_deadline)), if acknowledge_at.is_some() => {
+        client.send_acknowledgements().await?;
+      }
       input = console.read_input() => {
         match input {
           Err(err) => {
             println!("unable to read input. error={:?}",err);
           }
-          Ok(input) => {
+          Ok(None) => {
+            // The rooms are kept, the messages sent to them meanwhile are received next time.
+            info!("input closed, disconnecting");
+            client.send_acknowledgements().await?;
+            return Ok(());
+          }
+          Ok(Some(input)) if input.starts_with('/') => {
+            if let Some(action) = console.command(&input[1..]) {
+              client.run(action).await?;
+            }
+          }
+          Ok(Some(input)) => {
+            let room_id = match console.current_room() {
+              Some(room_id) => room_id.to_owned(),
+              None => {
+                console.notice(vec!["join a room with /join <room> before sending messages".to_owned()]);
+                continue;
+              }
+            };
+
             let message_id = client.next_message_id();
 
             let message = MessageFromClient {
-              username: client.username()?,
+              username: client.username(),
+              room_id,
               message_id,
               contents: input,
               sent_at: Utc::now()
@@ -197,9 +724,8 @@ async fn main() -> Result<()> {
 
             client.send_chat_message( messages::client_to_server::ChatMessage {
               message_id,
-              username: message.username.clone(),
               contents: message.contents.clone(),
-              room_id: client.room().to_owned()
+              room_id: message.room_id.clone(),
             })
             .await?;
 
@@ -1,107 +1,603 @@
+use bytes::BytesMut;
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+#[cfg(feature = "tokio")]
+use tokio::io::AsyncWrite;
 
-use crate::MessageType;
+use crate::{
+  codec::{
+    self, Decoder, Encoder, ProtocolError, MAX_PASSWORD_BYTES, MAX_ROOM_ID_BYTES,
+    MAX_ROOM_NAME_BYTES, MAX_TOKEN_BYTES, MAX_TOPIC_BYTES, MAX_USERNAME_BYTES,
+  },
+  Capabilities, ClientToServerMessage, MessageType, MAX_MESSAGE_BYTES, MAX_QUERY_BYTES,
+};
+
+/// First message sent by a client, used to agree on a protocol version.
+#[derive(Debug)]
+pub struct HelloMessage {
+  pub protocol_version: u16,
+  pub capabilities: Capabilities,
+}
+
+/// Creates an account and logs in with it, answered with an `AuthenticatedMessage`.
+/// Sent after the handshake, the server only answers `Register`, `Login` and `Ping` until then.
+#[derive(Debug)]
+pub struct RegisterMessage {
+  pub username: String,
+  pub password: String,
+}
+
+/// Logs in with an account created with a `RegisterMessage`, answered with an `AuthenticatedMessage`.
+#[derive(Debug)]
+pub struct LoginMessage {
+  pub username: String,
+  pub password: String,
+}
+
+/// Logs in again with the token of an `AuthenticatedMessage`, after reconnecting for example,
+/// without the password. Answered with an `AuthenticatedMessage` carrying the same token.
+#[derive(Debug)]
+pub struct ResumeMessage {
+  pub token: String,
+}
+
+/// Asks which sessions of the account are still valid, answered with a `SessionListMessage`.
+#[derive(Debug)]
+pub struct ListSessionsMessage {}
+
+/// Revokes the token of the session, or of every other session of the account with `others`,
+/// answered with a `LoggedOutMessage`. The connections of the revoked sessions are closed.
+#[derive(Debug)]
+pub struct LogOutMessage {
+  pub others: bool,
+}
 
 #[derive(Debug, Serialize, Deserialize)]
 pub struct JoinRoomMessage {
   pub room_id: String,
 }
 
+/// Tells the server that the client is leaving the room, instead of just dropping the connection.
+#[derive(Debug, Serialize, Deserialize)]
+pub struct LeaveRoomMessage {
+  pub room_id: String,
+}
+
+/// Asks the server who is in a room the client is a member of, answered with a `MemberListMessage`.
+#[derive(Debug)]
+pub struct ListMembersMessage {
+  pub room_id: String,
+}
+
+/// Asks the server which rooms exist, answered with a `RoomListMessage`.
+#[derive(Debug)]
+pub struct ListRoomsMessage {
+  /// Only rooms whose id comes after this one are listed, to get the next page of a list.
+  pub after: Option<String>,
+}
+
+/// Creates a room and joins it, answered with a `RoomJoinedMessage`.
+/// Unlike rooms created by joining them, the room is kept once its last member leaves.
+#[derive(Debug)]
+pub struct CreateRoomMessage {
+  pub room_id: String,
+  pub display_name: String,
+  pub topic: String,
+}
+
+/// Asks the server about a room, answered with a `RoomInfoMessage`.
+#[derive(Debug)]
+pub struct DescribeRoomMessage {
+  pub room_id: String,
+}
+
+/// Asks for messages sent to a room the client is a member of, answered with a `HistoryMessage`.
+///
+/// Without `after`, the page holds the latest messages before `before`, or the latest messages
+/// if `before` is `None`. With `after`, it holds the first messages after it, up to `before`.
+#[derive(Debug)]
+pub struct FetchHistoryMessage {
+  pub room_id: String,
+  pub before: Option<u64>,
+  pub after: Option<u64>,
+  /// How many messages to send, at most `MAX_HISTORY_MESSAGES`.
+  pub limit: u32,
+}
+
+/// Looks for the messages of a room the client is a member of that contain every word of `query`,
+/// answered with a `SearchResultsMessage`.
+#[derive(Debug)]
+pub struct SearchMessage {
+  pub room_id: String,
+  /// At most `MAX_QUERY_BYTES`, words are matched regardless of case.
+  pub query: String,
+  /// How many results to send, at most `MAX_SEARCH_RESULTS`.
+  pub limit: u32,
+}
+
 #[derive(Debug, Serialize, Deserialize)]
 pub struct ChatMessage {
+  /// Chosen by the client, echoed back in `MessageAcceptedMessage`.
   pub message_id: u64,
-  pub username: String,
   pub room_id: String,
   pub contents: String,
 }
 
+/// Tells the server that every message in the room up to `seq` has been read.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MessageReadMessage {
-  pub message_id: u64,
+  pub seq: u64,
   pub room_id: String,
 }
 
+/// Tells the server that every message in the room up to `seq` has been received.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct MessageReceivedMessage {
-  pub message_id: u64,
+  pub seq: u64,
   pub room_id: String,
 }
 
-pub async fn write_chat_message(
-  writer: &mut (impl AsyncWrite + Unpin),
-  message: ChatMessage,
-) -> std::io::Result<()> {
-  let mut writer = BufWriter::new(writer);
+/// Sent periodically to check that the connection is alive, the peer answers with a `PongMessage`.
+#[derive(Debug)]
+pub struct PingMessage {
+  pub nonce: u64,
+}
+
+/// The answer to a `PingMessage`, carries the same nonce.
+#[derive(Debug)]
+pub struct PongMessage {
+  pub nonce: u64,
+}
 
-  writer.write_u8(MessageType::ChatMessage.as_u8()).await?;
+impl HelloMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::Hello);
+    encoder
+      .u16(self.protocol_version)
+      .u32(self.capabilities.bits());
+    encoder.finish()
+  }
+}
 
-  writer.write_u64(message.message_id).await?;
+impl RegisterMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::Register);
+    encoder
+      .string("username", &self.username, MAX_USERNAME_BYTES)?
+      .string("password", &self.password, MAX_PASSWORD_BYTES)?;
+    encoder.finish()
+  }
+}
 
-  writer.write_u32(message.room_id.len() as u32).await?;
-  writer.write_all(message.room_id.as_bytes()).await?;
+impl LoginMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::Login);
+    encoder
+      .string("username", &self.username, MAX_USERNAME_BYTES)?
+      .string("password", &self.password, MAX_PASSWORD_BYTES)?;
+    encoder.finish()
+  }
+}
 
-  writer.write_u32(message.username.len() as u32).await?;
-  writer.write_all(message.username.as_bytes()).await?;
+impl ResumeMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::Resume);
+    encoder.string("token", &self.token, MAX_TOKEN_BYTES)?;
+    encoder.finish()
+  }
+}
 
-  writer.write_u32(message.contents.len() as u32).await?;
-  writer.write_all(message.contents.as_bytes()).await?;
+impl ListSessionsMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let encoder = Encoder::new(dst, MessageType::ListSessions);
+    encoder.finish()
+  }
+}
 
-  writer.flush().await?;
+impl LogOutMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::LogOut);
+    encoder.bool(self.others);
+    encoder.finish()
+  }
+}
+
+impl JoinRoomMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::JoinRoom);
+    encoder.string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?;
+    encoder.finish()
+  }
+}
+
+impl LeaveRoomMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::LeaveRoom);
+    encoder.string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?;
+    encoder.finish()
+  }
+}
+
+impl ListMembersMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::ListMembers);
+    encoder.string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?;
+    encoder.finish()
+  }
+}
+
+impl ListRoomsMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::ListRooms);
+    encoder.optional_string("after", self.after.as_deref(), MAX_ROOM_ID_BYTES)?;
+    encoder.finish()
+  }
+}
+
+impl CreateRoomMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::CreateRoom);
+    encoder
+      .string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?
+      .string("display_name", &self.display_name, MAX_ROOM_NAME_BYTES)?
+      .string("topic", &self.topic, MAX_TOPIC_BYTES)?;
+    encoder.finish()
+  }
+}
+
+impl DescribeRoomMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::DescribeRoom);
+    encoder.string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?;
+    encoder.finish()
+  }
+}
+
+impl FetchHistoryMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::FetchHistory);
+    encoder
+      .string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?
+      .optional_u64(self.before)
+      .optional_u64(self.after)
+      .u32(self.limit);
+    encoder.finish()
+  }
+}
 
-  Ok(())
+impl SearchMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::Search);
+    encoder
+      .string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?
+      .string("query", &self.query, MAX_QUERY_BYTES)?
+      .u32(self.limit);
+    encoder.finish()
+  }
 }
 
+impl ChatMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::ChatMessage);
+    encoder
+      .u64(self.message_id)
+      .string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?
+      .string("contents", &self.contents, MAX_MESSAGE_BYTES)?;
+    encoder.finish()
+  }
+}
+
+impl MessageReadMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::MessageRead);
+    encoder
+      .u64(self.seq)
+      .string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?;
+    encoder.finish()
+  }
+}
+
+impl MessageReceivedMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::MessageReceived);
+    encoder
+      .u64(self.seq)
+      .string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?;
+    encoder.finish()
+  }
+}
+
+impl PingMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::Ping);
+    encoder.u64(self.nonce);
+    encoder.finish()
+  }
+}
+
+impl PongMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::Pong);
+    encoder.u64(self.nonce);
+    encoder.finish()
+  }
+}
+
+/// Appends `message` as a frame to `dst`.
+pub fn encode(message: &ClientToServerMessage, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+  match message {
+    ClientToServerMessage::Ping(message) => message.encode(dst),
+    ClientToServerMessage::Pong(message) => message.encode(dst),
+    ClientToServerMessage::Hello(message) => message.encode(dst),
+    ClientToServerMessage::Register(message) => message.encode(dst),
+    ClientToServerMessage::Login(message) => message.encode(dst),
+    ClientToServerMessage::Resume(message) => message.encode(dst),
+    ClientToServerMessage::ListSessions(message) => message.encode(dst),
+    ClientToServerMessage::LogOut(message) => message.encode(dst),
+    ClientToServerMessage::JoinRoom(message) => message.encode(dst),
+    ClientToServerMessage::LeaveRoom(message) => message.encode(dst),
+    ClientToServerMessage::ListMembers(message) => message.encode(dst),
+    ClientToServerMessage::ListRooms(message) => message.encode(dst),
+    ClientToServerMessage::CreateRoom(message) => message.encode(dst),
+    ClientToServerMessage::DescribeRoom(message) => message.encode(dst),
+    ClientToServerMessage::FetchHistory(message) => message.encode(dst),
+    ClientToServerMessage::Search(message) => message.encode(dst),
+    ClientToServerMessage::ChatMessage(message) => message.encode(dst),
+    ClientToServerMessage::MessageReceived(message) => message.encode(dst),
+    ClientToServerMessage::MessageRead(message) => message.encode(dst),
+  }
+}
+
+/// Removes the first message from `src`.
+/// Returns `None` if `src` does not contain a whole frame yet.
+pub fn decode(src: &mut BytesMut) -> Option<Result<ClientToServerMessage, ProtocolError>> {
+  codec::decode_frame(src).map(|frame| frame.and_then(|frame| decode_frame(&frame)))
+}
+
+/// Decodes a frame without its length prefix.
+pub(crate) fn decode_frame(frame: &[u8]) -> Result<ClientToServerMessage, ProtocolError> {
+  let mut decoder = Decoder::new(frame);
+
+  let message_type = decoder.u8("message_type")?;
+
+  match MessageType::try_from(message_type)? {
+    MessageType::Ping => Ok(ClientToServerMessage::Ping(PingMessage {
+      nonce: decoder.u64("nonce")?,
+    })),
+    MessageType::Pong => Ok(ClientToServerMessage::Pong(PongMessage {
+      nonce: decoder.u64("nonce")?,
+    })),
+    MessageType::Hello => Ok(ClientToServerMessage::Hello(HelloMessage {
+      protocol_version: decoder.u16("protocol_version")?,
+      capabilities: Capabilities::from_bits(decoder.u32("capabilities")?),
+    })),
+    MessageType::Register => Ok(ClientToServerMessage::Register(RegisterMessage {
+      username: decoder.string("username", MAX_USERNAME_BYTES)?,
+      password: decoder.string("password", MAX_PASSWORD_BYTES)?,
+    })),
+    MessageType::Login => Ok(ClientToServerMessage::Login(LoginMessage {
+      username: decoder.string("username", MAX_USERNAME_BYTES)?,
+      password: decoder.string("password", MAX_PASSWORD_BYTES)?,
+    })),
+    MessageType::Resume => Ok(ClientToServerMessage::Resume(ResumeMessage {
+      token: decoder.string("token", MAX_TOKEN_BYTES)?,
+    })),
+    MessageType::ListSessions => Ok(ClientToServerMessage::ListSessions(ListSessionsMessage {})),
+    MessageType::LogOut => Ok(ClientToServerMessage::LogOut(LogOutMessage {
+      others: decoder.bool("others")?,
+    })),
+    message_type @ (MessageType::Welcome
+    | MessageType::HelloRejected
+    | MessageType::Authenticated
+    | MessageType::SessionList
+    | MessageType::LoggedOut
+    | MessageType::Error
+    | MessageType::MessageAccepted
+    | MessageType::RoomJoined
+    | MessageType::RoomLeft
+    | MessageType::UserJoined
+    | MessageType::UserLeft
+    | MessageType::MemberList
+    | MessageType::RoomList
+    | MessageType::RoomInfo
+    | MessageType::History
+    | MessageType::SearchResults) => Err(ProtocolError::UnexpectedMessageType(message_type)),
+    MessageType::JoinRoom => Ok(ClientToServerMessage::JoinRoom(JoinRoomMessage {
+      room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+    })),
+    MessageType::LeaveRoom => Ok(ClientToServerMessage::LeaveRoom(LeaveRoomMessage {
+      room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+    })),
+    MessageType::ListMembers => Ok(ClientToServerMessage::ListMembers(ListMembersMessage {
+      room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+    })),
+    MessageType::ListRooms => Ok(ClientToServerMessage::ListRooms(ListRoomsMessage {
+      after: decoder.optional_string("after", MAX_ROOM_ID_BYTES)?,
+    })),
+    MessageType::CreateRoom => Ok(ClientToServerMessage::CreateRoom(CreateRoomMessage {
+      room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+      display_name: decoder.string("display_name", MAX_ROOM_NAME_BYTES)?,
+      topic: decoder.string("topic", MAX_TOPIC_BYTES)?,
+    })),
+    MessageType::DescribeRoom => Ok(ClientToServerMessage::DescribeRoom(DescribeRoomMessage {
+      room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+    })),
+    MessageType::FetchHistory => Ok(ClientToServerMessage::FetchHistory(FetchHistoryMessage {
+      room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+      before: decoder.optional_u64("before")?,
+      after: decoder.optional_u64("after")?,
+      limit: decoder.u32("limit")?,
+    })),
+    MessageType::Search => Ok(ClientToServerMessage::Search(SearchMessage {
+      room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+      query: decoder.string("query", MAX_QUERY_BYTES)?,
+      limit: decoder.u32("limit")?,
+    })),
+    MessageType::ChatMessage => Ok(ClientToServerMessage::ChatMessage(ChatMessage {
+      message_id: decoder.u64("message_id")?,
+      room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+      contents: decoder.string("contents", MAX_MESSAGE_BYTES)?,
+    })),
+    MessageType::MessageRead => Ok(ClientToServerMessage::MessageRead(MessageReadMessage {
+      seq: decoder.u64("seq")?,
+      room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+    })),
+    MessageType::MessageReceived => Ok(ClientToServerMessage::MessageReceived(
+      MessageReceivedMessage {
+        seq: decoder.u64("seq")?,
+        room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+      },
+    )),
+  }
+}
+
+#[cfg(feature = "tokio")]
+pub async fn write_chat_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: ChatMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
+
+#[cfg(feature = "tokio")]
 pub async fn write_message_received(
   writer: &mut (impl AsyncWrite + Unpin),
   message: MessageReceivedMessage,
-) -> std::io::Result<()> {
-  let mut writer = BufWriter::new(writer);
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
 
-  writer
-    .write_u8(MessageType::MessageReceived.as_u8())
-    .await?;
+#[cfg(feature = "tokio")]
+pub async fn write_message_read(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: MessageReadMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
+
+#[cfg(feature = "tokio")]
+pub async fn write_join_room_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: JoinRoomMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
 
-  writer.write_u64(message.message_id).await?;
+#[cfg(feature = "tokio")]
+pub async fn write_leave_room_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: LeaveRoomMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
 
-  writer.write_u32(message.room_id.len() as u32).await?;
-  writer.write_all(message.room_id.as_bytes()).await?;
+#[cfg(feature = "tokio")]
+pub async fn write_list_members_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: ListMembersMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
 
-  writer.flush().await?;
+#[cfg(feature = "tokio")]
+pub async fn write_list_rooms_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: ListRoomsMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
 
-  Ok(())
+#[cfg(feature = "tokio")]
+pub async fn write_create_room_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: CreateRoomMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
 }
 
-pub async fn write_message_read(
+#[cfg(feature = "tokio")]
+pub async fn write_describe_room_message(
   writer: &mut (impl AsyncWrite + Unpin),
-  message: MessageReadMessage,
-) -> std::io::Result<()> {
-  let mut writer = BufWriter::new(writer);
+  message: DescribeRoomMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
 
-  writer.write_u8(MessageType::MessageRead.as_u8()).await?;
+#[cfg(feature = "tokio")]
+pub async fn write_fetch_history_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: FetchHistoryMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
 
-  writer.write_u64(message.message_id).await?;
+#[cfg(feature = "tokio")]
+pub async fn write_search_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: SearchMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
 
-  writer.write_u32(message.room_id.len() as u32).await?;
-  writer.write_all(message.room_id.as_bytes()).await?;
+#[cfg(feature = "tokio")]
+pub async fn write_register_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: RegisterMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
 
-  writer.flush().await?;
+#[cfg(feature = "tokio")]
+pub async fn write_login_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: LoginMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
 
-  Ok(())
+#[cfg(feature = "tokio")]
+pub async fn write_resume_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: ResumeMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
 }
 
-pub async fn write_join_room_message(
+#[cfg(feature = "tokio")]
+pub async fn write_list_sessions_message(
   writer: &mut (impl AsyncWrite + Unpin),
-  message: JoinRoomMessage,
-) -> std::io::Result<()> {
-  let mut writer = BufWriter::new(writer);
+  message: ListSessionsMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
 
-  writer.write_u8(MessageType::JoinRoom.as_u8()).await?;
+#[cfg(feature = "tokio")]
+pub async fn write_log_out_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: LogOutMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
 
-  writer.write_u32(message.room_id.len() as u32).await?;
-  writer.write_all(message.room_id.as_bytes()).await?;
+#[cfg(feature = "tokio")]
+pub async fn write_hello_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: HelloMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
 
-  writer.flush().await?;
+#[cfg(feature = "tokio")]
+pub async fn write_ping_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: PingMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
 
-  Ok(())
+#[cfg(feature = "tokio")]
+pub async fn write_pong_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: PongMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
 }
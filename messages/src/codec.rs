@@ -0,0 +1,674 @@
+//! Framing used by every message.
+//!
+//! A frame is a `u32` length followed by that many bytes: the message type
+//! and then the message fields. Strings are a `u32` length followed by utf-8 bytes.
+//! Fields past the ones known by the reader are ignored so messages can grow new fields.
+
+use std::fmt;
+
+use bytes::{Buf, BufMut, BytesMut};
+use chrono::{DateTime, TimeZone, Utc};
+#[cfg(feature = "tokio")]
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use crate::MessageType;
+
+/// Maximum size of a frame, not including the length prefix.
+pub const MAX_FRAME_BYTES: usize = 16 * 1024;
+
+/// Maximum size of a room id.
+pub const MAX_ROOM_ID_BYTES: usize = 128;
+
+/// Maximum size of a username.
+pub const MAX_USERNAME_BYTES: usize = 128;
+
+/// Maximum size of a password.
+pub const MAX_PASSWORD_BYTES: usize = 1024;
+
+/// Maximum size of a session token.
+pub const MAX_TOKEN_BYTES: usize = 128;
+
+/// Maximum size of the display name of a room.
+pub const MAX_ROOM_NAME_BYTES: usize = 128;
+
+/// Maximum size of the topic of a room.
+pub const MAX_TOPIC_BYTES: usize = 512;
+
+/// Maximum size of human readable text sent by the server, like a rejection reason.
+pub const MAX_REASON_BYTES: usize = 1024;
+
+const FRAME_LENGTH_BYTES: usize = std::mem::size_of::<u32>();
+
+#[derive(Debug)]
+pub enum ProtocolError {
+  Io(std::io::Error),
+  /// The frame length is greater than `MAX_FRAME_BYTES`.
+  FrameTooLarge {
+    len: usize,
+    max: usize,
+  },
+  /// A field is longer than allowed.
+  FieldTooLarge {
+    field: &'static str,
+    len: usize,
+    max: usize,
+  },
+  /// The frame ended before `field` could be read.
+  Truncated {
+    field: &'static str,
+  },
+  /// A string field is not valid utf-8.
+  InvalidUtf8 {
+    field: &'static str,
+  },
+  /// A timestamp field is out of range.
+  InvalidTimestamp {
+    field: &'static str,
+  },
+  /// The message type is not known by this build of the crate.
+  /// The whole frame has been consumed so the stream can still be used.
+  UnknownMessageType(u8),
+  /// The message type is known but is not expected in this direction.
+  UnexpectedMessageType(MessageType),
+}
+
+impl fmt::Display for ProtocolError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      ProtocolError::Io(err) => write!(f, "io error: {err}"),
+      ProtocolError::FrameTooLarge { len, max } => {
+        write!(f, "frame is too large. len={len} max={max}")
+      }
+      ProtocolError::FieldTooLarge { field, len, max } => {
+        write!(f, "field is too large. field={field} len={len} max={max}")
+      }
+      ProtocolError::Truncated { field } => write!(f, "frame is truncated. field={field}"),
+      ProtocolError::InvalidUtf8 { field } => write!(f, "field is not valid utf-8. field={field}"),
+      ProtocolError::InvalidTimestamp { field } => {
+        write!(f, "timestamp is out of range. field={field}")
+      }
+      ProtocolError::UnknownMessageType(message_type) => {
+        write!(f, "unknown message type. message_type={message_type}")
+      }
+      ProtocolError::UnexpectedMessageType(message_type) => {
+        write!(f, "unexpected message type. message_type={message_type:?}")
+      }
+    }
+  }
+}
+
+impl ProtocolError {
+  /// Returns true if the stream can't be read anymore after this error.
+  ///
+  /// Other errors happen after the whole frame has been consumed, the next frame can still be read.
+  pub fn is_fatal(&self) -> bool {
+    matches!(
+      self,
+      ProtocolError::Io(_) | ProtocolError::FrameTooLarge { .. }
+    )
+  }
+}
+
+impl std::error::Error for ProtocolError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      ProtocolError::Io(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl From<std::io::Error> for ProtocolError {
+  fn from(err: std::io::Error) -> Self {
+    ProtocolError::Io(err)
+  }
+}
+
+/// Reads the fields of a frame.
+pub(crate) struct Decoder<'a> {
+  buf: &'a [u8],
+}
+
+impl<'a> Decoder<'a> {
+  pub(crate) fn new(buf: &'a [u8]) -> Self {
+    Self { buf }
+  }
+
+  fn take(&mut self, field: &'static str, n: usize) -> Result<&'a [u8], ProtocolError> {
+    if self.buf.len() < n {
+      return Err(ProtocolError::Truncated { field });
+    }
+
+    let (bytes, rest) = self.buf.split_at(n);
+    self.buf = rest;
+    Ok(bytes)
+  }
+
+  pub(crate) fn u8(&mut self, field: &'static str) -> Result<u8, ProtocolError> {
+    Ok(self.take(field, 1)?[0])
+  }
+
+  pub(crate) fn bool(&mut self, field: &'static str) -> Result<bool, ProtocolError> {
+    Ok(self.u8(field)? != 0)
+  }
+
+  pub(crate) fn u16(&mut self, field: &'static str) -> Result<u16, ProtocolError> {
+    let bytes = self.take(field, 2)?;
+    Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+  }
+
+  pub(crate) fn u32(&mut self, field: &'static str) -> Result<u32, ProtocolError> {
+    let bytes = self.take(field, 4)?;
+    Ok(u32::from_be_bytes(bytes.try_into().unwrap()))
+  }
+
+  pub(crate) fn u64(&mut self, field: &'static str) -> Result<u64, ProtocolError> {
+    let bytes = self.take(field, 8)?;
+    Ok(u64::from_be_bytes(bytes.try_into().unwrap()))
+  }
+
+  pub(crate) fn optional_u64(&mut self, field: &'static str) -> Result<Option<u64>, ProtocolError> {
+    match self.u8(field)? {
+      0 => Ok(None),
+      _ => Ok(Some(self.u64(field)?)),
+    }
+  }
+
+  /// Reads a timestamp encoded as milliseconds since the unix epoch.
+  pub(crate) fn timestamp(&mut self, field: &'static str) -> Result<DateTime<Utc>, ProtocolError> {
+    let millis = self.u64(field)? as i64;
+    Utc
+      .timestamp_millis_opt(millis)
+      .single()
+      .ok_or(ProtocolError::InvalidTimestamp { field })
+  }
+
+  pub(crate) fn string(
+    &mut self,
+    field: &'static str,
+    max: usize,
+  ) -> Result<String, ProtocolError> {
+    let len = self.u32(field)? as usize;
+    if len > max {
+      return Err(ProtocolError::FieldTooLarge { field, len, max });
+    }
+
+    let bytes = self.take(field, len)?;
+    String::from_utf8(bytes.to_vec()).map_err(|_| ProtocolError::InvalidUtf8 { field })
+  }
+
+  pub(crate) fn optional_string(
+    &mut self,
+    field: &'static str,
+    max: usize,
+  ) -> Result<Option<String>, ProtocolError> {
+    match self.u8(field)? {
+      0 => Ok(None),
+      _ => Ok(Some(self.string(field, max)?)),
+    }
+  }
+
+  /// The number of items in a list, the items follow it.
+  pub(crate) fn count(&mut self, field: &'static str, max: usize) -> Result<usize, ProtocolError> {
+    let count = self.u32(field)? as usize;
+    if count > max {
+      return Err(ProtocolError::FieldTooLarge {
+        field,
+        len: count,
+        max,
+      });
+    }
+    Ok(count)
+  }
+
+  pub(crate) fn strings(
+    &mut self,
+    field: &'static str,
+    max_count: usize,
+    max: usize,
+  ) -> Result<Vec<String>, ProtocolError> {
+    let count = self.count(field, max_count)?;
+    (0..count).map(|_| self.string(field, max)).collect()
+  }
+}
+
+/// Writes a frame into a buffer.
+///
+/// If the frame is not finished, because a field is too large for example,
+/// the bytes written so far are removed from the buffer.
+pub(crate) struct Encoder<'a> {
+  dst: &'a mut BytesMut,
+  /// Where the frame starts in `dst`.
+  start: usize,
+  finished: bool,
+}
+
+impl<'a> Encoder<'a> {
+  pub(crate) fn new(dst: &'a mut BytesMut, message_type: MessageType) -> Self {
+    let start = dst.len();
+    // Placeholder for the frame length, filled by `finish`.
+    dst.put_u32(0);
+    dst.put_u8(message_type.as_u8());
+    Self {
+      dst,
+      start,
+      finished: false,
+    }
+  }
+
+  pub(crate) fn bool(&mut self, value: bool) -> &mut Self {
+    self.dst.put_u8(value as u8);
+    self
+  }
+
+  pub(crate) fn u16(&mut self, value: u16) -> &mut Self {
+    self.dst.put_u16(value);
+    self
+  }
+
+  pub(crate) fn u32(&mut self, value: u32) -> &mut Self {
+    self.dst.put_u32(value);
+    self
+  }
+
+  pub(crate) fn u64(&mut self, value: u64) -> &mut Self {
+    self.dst.put_u64(value);
+    self
+  }
+
+  pub(crate) fn optional_u64(&mut self, value: Option<u64>) -> &mut Self {
+    match value {
+      None => self.dst.put_u8(0),
+      Some(value) => {
+        self.dst.put_u8(1);
+        self.dst.put_u64(value);
+      }
+    }
+    self
+  }
+
+  /// Writes a timestamp as milliseconds since the unix epoch.
+  pub(crate) fn timestamp(&mut self, value: DateTime<Utc>) -> &mut Self {
+    self.u64(value.timestamp_millis() as u64)
+  }
+
+  pub(crate) fn string(
+    &mut self,
+    field: &'static str,
+    value: &str,
+    max: usize,
+  ) -> Result<&mut Self, ProtocolError> {
+    if value.len() > max {
+      return Err(ProtocolError::FieldTooLarge {
+        field,
+        len: value.len(),
+        max,
+      });
+    }
+
+    self.dst.put_u32(value.len() as u32);
+    self.dst.put_slice(value.as_bytes());
+    Ok(self)
+  }
+
+  pub(crate) fn optional_string(
+    &mut self,
+    field: &'static str,
+    value: Option<&str>,
+    max: usize,
+  ) -> Result<&mut Self, ProtocolError> {
+    match value {
+      None => {
+        self.dst.put_u8(0);
+        Ok(self)
+      }
+      Some(value) => {
+        self.dst.put_u8(1);
+        self.string(field, value, max)
+      }
+    }
+  }
+
+  /// The number of items in a list, the items are written after it.
+  pub(crate) fn count(
+    &mut self,
+    field: &'static str,
+    count: usize,
+    max: usize,
+  ) -> Result<&mut Self, ProtocolError> {
+    if count > max {
+      return Err(ProtocolError::FieldTooLarge {
+        field,
+        len: count,
+        max,
+      });
+    }
+
+    self.dst.put_u32(count as u32);
+    Ok(self)
+  }
+
+  pub(crate) fn strings(
+    &mut self,
+    field: &'static str,
+    values: &[String],
+    max_count: usize,
+    max: usize,
+  ) -> Result<&mut Self, ProtocolError> {
+    self.count(field, values.len(), max_count)?;
+    for value in values {
+      self.string(field, value, max)?;
+    }
+    Ok(self)
+  }
+
+  /// Fills the frame length.
+  pub(crate) fn finish(mut self) -> Result<(), ProtocolError> {
+    let len = self.dst.len() - self.start - FRAME_LENGTH_BYTES;
+    if len > MAX_FRAME_BYTES {
+      return Err(ProtocolError::FrameTooLarge {
+        len,
+        max: MAX_FRAME_BYTES,
+      });
+    }
+
+    self.dst[self.start..self.start + FRAME_LENGTH_BYTES]
+      .copy_from_slice(&(len as u32).to_be_bytes());
+    self.finished = true;
+    Ok(())
+  }
+}
+
+impl<'a> Drop for Encoder<'a> {
+  fn drop(&mut self) {
+    if !self.finished {
+      self.dst.truncate(self.start);
+    }
+  }
+}
+
+/// Removes the first frame from `src`, the length prefix is not included in the returned buffer.
+/// Returns `None` if `src` does not contain a whole frame yet.
+pub(crate) fn decode_frame(src: &mut BytesMut) -> Option<Result<BytesMut, ProtocolError>> {
+  if src.len() < FRAME_LENGTH_BYTES {
+    return None;
+  }
+
+  let len = u32::from_be_bytes(src[0..FRAME_LENGTH_BYTES].try_into().unwrap()) as usize;
+  if len > MAX_FRAME_BYTES {
+    return Some(Err(ProtocolError::FrameTooLarge {
+      len,
+      max: MAX_FRAME_BYTES,
+    }));
+  }
+
+  if src.len() < FRAME_LENGTH_BYTES + len {
+    src.reserve(FRAME_LENGTH_BYTES + len - src.len());
+    return None;
+  }
+
+  src.advance(FRAME_LENGTH_BYTES);
+  Some(Ok(src.split_to(len)))
+}
+
+/// Reads exactly one frame, the length prefix is not included in the returned buffer.
+#[cfg(feature = "tokio")]
+pub(crate) async fn read_frame(
+  reader: &mut (impl AsyncRead + Unpin),
+) -> Result<Vec<u8>, ProtocolError> {
+  let len = reader.read_u32().await? as usize;
+  if len > MAX_FRAME_BYTES {
+    return Err(ProtocolError::FrameTooLarge {
+      len,
+      max: MAX_FRAME_BYTES,
+    });
+  }
+
+  let mut frame = vec![0_u8; len];
+  reader.read_exact(&mut frame).await?;
+
+  Ok(frame)
+}
+
+/// Encodes a frame with `encode` and writes it.
+#[cfg(feature = "tokio")]
+pub(crate) async fn write_frame(
+  writer: &mut (impl AsyncWrite + Unpin),
+  encode: impl FnOnce(&mut BytesMut) -> Result<(), ProtocolError>,
+) -> Result<(), ProtocolError> {
+  let mut buf = BytesMut::new();
+  encode(&mut buf)?;
+  writer.write_all(&buf).await?;
+  writer.flush().await?;
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use crate::{
+    client_to_server::{self, JoinRoomMessage},
+    server_to_client, ClientToServerMessage, ServerToClientMessage,
+  };
+
+  /// A frame of `len` bytes with its length prefix.
+  fn frame(len: usize) -> BytesMut {
+    let mut frame = BytesMut::new();
+    frame.put_u32(len as u32);
+    frame.put_bytes(0, len);
+    frame
+  }
+
+  fn join_room(room_id: &str) -> Result<BytesMut, ProtocolError> {
+    let mut dst = BytesMut::new();
+    JoinRoomMessage {
+      room_id: room_id.to_owned(),
+    }
+    .encode(&mut dst)?;
+    Ok(dst)
+  }
+
+  #[test]
+  fn frames_are_decoded_once_whole() {
+    let mut src = frame(MAX_FRAME_BYTES);
+    let last = src.split_off(src.len() - 1);
+    assert!(decode_frame(&mut src).is_none());
+
+    src.unsplit(last);
+    src.extend_from_slice(&frame(1)[..2]);
+    let decoded = decode_frame(&mut src).unwrap().unwrap();
+    assert_eq!(decoded.len(), MAX_FRAME_BYTES);
+    // The start of the next frame is left in the buffer.
+    assert_eq!(src.len(), 2);
+    assert!(decode_frame(&mut src).is_none());
+  }
+
+  #[test]
+  fn messages_are_decoded_one_at_a_time_from_a_buffer() {
+    let mut src = BytesMut::new();
+    client_to_server::encode(
+      &ClientToServerMessage::JoinRoom(JoinRoomMessage {
+        room_id: "first".to_owned(),
+      }),
+      &mut src,
+    )
+    .unwrap();
+    let second = join_room("second").unwrap();
+    src.extend_from_slice(&second[..3]);
+
+    assert!(matches!(
+      client_to_server::decode(&mut src),
+      Some(Ok(ClientToServerMessage::JoinRoom(message))) if message.room_id == "first"
+    ));
+    // Only part of the second frame is there, nothing is consumed until the rest arrives.
+    assert!(client_to_server::decode(&mut src).is_none());
+    assert_eq!(src.len(), 3);
+
+    src.extend_from_slice(&second[3..]);
+    assert!(matches!(
+      client_to_server::decode(&mut src),
+      Some(Ok(ClientToServerMessage::JoinRoom(message))) if message.room_id == "second"
+    ));
+    assert!(src.is_empty());
+  }
+
+  #[test]
+  fn fields_past_the_known_ones_are_ignored() {
+    let mut src = BytesMut::new();
+    server_to_client::ChatMessage {
+      seq: 1,
+      username: "alice".to_owned(),
+      contents: "hello".to_owned(),
+      room_id: "room".to_owned(),
+      sent_at: chrono::Utc::now(),
+    }
+    .encode(&mut src)
+    .unwrap();
+    // A field added by a newer version of the protocol.
+    src.put_u64(42);
+    let len = (src.len() - FRAME_LENGTH_BYTES) as u32;
+    src[..FRAME_LENGTH_BYTES].copy_from_slice(&len.to_be_bytes());
+
+    match server_to_client::decode(&mut src) {
+      Some(Ok(ServerToClientMessage::ChatMessage(message))) => {
+        assert_eq!(message.contents, "hello");
+        assert_eq!(message.room_id, "room");
+      }
+      message => panic!("unexpected message: {message:?}"),
+    }
+    assert!(src.is_empty());
+  }
+
+  #[test]
+  fn hellos_with_the_username_of_older_versions_are_decoded() {
+    let mut src = BytesMut::new();
+    let mut encoder = Encoder::new(&mut src, MessageType::Hello);
+    encoder
+      .u16(15)
+      .u32(0)
+      .string("username", "alice", MAX_USERNAME_BYTES)
+      .unwrap();
+    encoder.finish().unwrap();
+
+    // The server has to know the version of the client to tell it it's too old.
+    match client_to_server::decode(&mut src) {
+      Some(Ok(ClientToServerMessage::Hello(message))) => assert_eq!(message.protocol_version, 15),
+      message => panic!("unexpected message: {message:?}"),
+    }
+    assert!(src.is_empty());
+  }
+
+  #[test]
+  fn error_codes_unknown_to_the_crate_are_kept() {
+    let mut src = BytesMut::new();
+    server_to_client::ErrorMessage {
+      message: "slow down".to_owned(),
+      code: server_to_client::ErrorCode::from_u16(1000),
+      related_message_id: Some(7),
+    }
+    .encode(&mut src)
+    .unwrap();
+
+    match server_to_client::decode(&mut src) {
+      Some(Ok(ServerToClientMessage::Error(message))) => {
+        assert_eq!(message.code, server_to_client::ErrorCode::Unknown(1000));
+        assert_eq!(message.related_message_id, Some(7));
+      }
+      message => panic!("unexpected message: {message:?}"),
+    }
+  }
+
+  #[test]
+  fn frames_larger_than_the_limit_are_rejected() {
+    // The length is enough, the frame itself isn't waited for.
+    let mut src = frame(MAX_FRAME_BYTES + 1);
+    src.truncate(FRAME_LENGTH_BYTES);
+    assert!(matches!(
+      decode_frame(&mut src),
+      Some(Err(ProtocolError::FrameTooLarge { len, max: MAX_FRAME_BYTES })) if len == MAX_FRAME_BYTES + 1
+    ));
+
+    let mut dst = BytesMut::from(&b"queued"[..]);
+    let encoder = Encoder::new(&mut dst, MessageType::ChatMessage);
+    encoder.dst.put_bytes(0, MAX_FRAME_BYTES);
+    assert!(matches!(
+      encoder.finish(),
+      Err(ProtocolError::FrameTooLarge { .. })
+    ));
+    // What was already in the buffer is kept.
+    assert_eq!(&dst[..], b"queued");
+  }
+
+  #[test]
+  fn room_ids_up_to_the_limit_are_accepted() {
+    let room_id = "r".repeat(MAX_ROOM_ID_BYTES);
+    let mut src = join_room(&room_id).unwrap();
+    match client_to_server::decode(&mut src) {
+      Some(Ok(ClientToServerMessage::JoinRoom(message))) => assert_eq!(message.room_id, room_id),
+      message => panic!("unexpected message: {message:?}"),
+    }
+
+    assert!(matches!(
+      join_room(&format!("{room_id}r")),
+      Err(ProtocolError::FieldTooLarge {
+        field: "room_id",
+        ..
+      })
+    ));
+  }
+
+  #[test]
+  fn room_ids_longer_than_the_limit_are_rejected_when_decoded() {
+    let room_id = "r".repeat(MAX_ROOM_ID_BYTES + 1);
+    let mut src = BytesMut::new();
+    src.put_u32((1 + 4 + room_id.len()) as u32);
+    src.put_u8(MessageType::JoinRoom.as_u8());
+    src.put_u32(room_id.len() as u32);
+    src.put_slice(room_id.as_bytes());
+    src.extend_from_slice(&join_room("next").unwrap());
+
+    assert!(matches!(
+      client_to_server::decode(&mut src),
+      Some(Err(ProtocolError::FieldTooLarge { field: "room_id", len, max: MAX_ROOM_ID_BYTES })) if len == room_id.len()
+    ));
+    // The error isn't fatal, the next frame is read.
+    assert!(matches!(
+      client_to_server::decode(&mut src),
+      Some(Ok(ClientToServerMessage::JoinRoom(message))) if message.room_id == "next"
+    ));
+  }
+
+  #[test]
+  fn full_history_pages_fit_in_a_frame() {
+    let room_id = "r".repeat(MAX_ROOM_ID_BYTES);
+    let username = "u".repeat(MAX_USERNAME_BYTES);
+    let contents_bytes = crate::MAX_HISTORY_BYTES / crate::MAX_HISTORY_MESSAGES - username.len();
+    let history = server_to_client::HistoryMessage {
+      room_id: room_id.clone(),
+      messages: (0..crate::MAX_HISTORY_MESSAGES as u64)
+        .map(|seq| server_to_client::HistoryEntry {
+          seq,
+          username: username.clone(),
+          contents: "c".repeat(contents_bytes),
+          sent_at: Utc::now(),
+        })
+        .collect(),
+      more: true,
+    };
+
+    let mut src = BytesMut::new();
+    history.encode(&mut src).unwrap();
+    match server_to_client::decode(&mut src) {
+      Some(Ok(ServerToClientMessage::History(message))) => {
+        assert_eq!(message.room_id, room_id);
+        assert_eq!(message.messages.len(), crate::MAX_HISTORY_MESSAGES);
+        assert_eq!(
+          message.messages.last().unwrap().seq,
+          crate::MAX_HISTORY_MESSAGES as u64 - 1
+        );
+        assert!(message.more);
+      }
+      message => panic!("unexpected message: {message:?}"),
+    }
+  }
+}
@@ -1,17 +1,157 @@
-use tokio::io::{AsyncRead, AsyncReadExt, BufReader};
+#[cfg(feature = "tokio")]
+use tokio::io::AsyncRead;
+
+pub use codec::{
+  ProtocolError, MAX_FRAME_BYTES, MAX_PASSWORD_BYTES, MAX_REASON_BYTES, MAX_ROOM_ID_BYTES,
+  MAX_ROOM_NAME_BYTES, MAX_TOKEN_BYTES, MAX_TOPIC_BYTES, MAX_USERNAME_BYTES,
+};
 
 pub mod client_to_server;
+mod codec;
 pub mod server_to_client;
 
+/// Maximum size of the contents of a chat message.
 pub const MAX_MESSAGE_BYTES: usize = 4096;
 
+/// Maximum number of usernames in a `MemberListMessage`, so the list always fits in a frame.
+pub const MAX_LISTED_MEMBERS: usize = 100;
+
+/// Maximum number of rooms in a `RoomListMessage`, so the list always fits in a frame.
+pub const MAX_LISTED_ROOMS: usize = 50;
+
+/// Maximum number of messages in a `HistoryMessage`.
+pub const MAX_HISTORY_MESSAGES: usize = 50;
+
+/// Maximum size of the usernames and contents of the messages in a `HistoryMessage` added up,
+/// so the history always fits in a frame. A page has fewer messages when they are large.
+pub const MAX_HISTORY_BYTES: usize = 12 * 1024;
+
+/// Maximum size of the query of a `SearchMessage`.
+pub const MAX_QUERY_BYTES: usize = 256;
+
+/// Maximum number of results in a `SearchResultsMessage`, so the results always fit in a frame.
+pub const MAX_SEARCH_RESULTS: usize = 25;
+
+/// Maximum size of the excerpt of a message in a `SearchResultsMessage`.
+pub const MAX_SNIPPET_BYTES: usize = 256;
+
+/// Maximum number of sessions in a `SessionListMessage`, so the list always fits in a frame.
+pub const MAX_LISTED_SESSIONS: usize = 100;
+
+/// The protocol version spoken by this build of the crate, bumped by every change to the protocol.
+///
+/// Version 2 introduced length prefixed frames.
+/// Version 3 added `ErrorMessage`.
+/// Version 4 added the room id to `ChatMessage`, `MessageReadMessage` and `MessageDeliveredMessage`.
+/// Version 5 numbered chat messages with a seq assigned by the server, receipts carry it
+/// instead of the id chosen by the author, and added `MessageAcceptedMessage`.
+/// Version 6 added a code and the related message id to `ErrorMessage`.
+/// Version 7 added `PingMessage` and `PongMessage`.
+/// Version 8 added `LeaveRoomMessage`.
+/// Version 9 sent receipts to the author of the message only, with how many recipients received
+/// and read it, clients acknowledge every message up to a seq at once.
+/// Version 10 added the username to `HelloMessage`, receipts are counted per user
+/// and tell which recipient received or read the message, and when.
+/// Version 11 added `RoomJoinedMessage` and `RoomLeftMessage`, clients join and leave
+/// any number of rooms over one connection.
+/// Version 12 added `UserJoinedMessage`, `UserLeftMessage`, `ListMembersMessage`
+/// and `MemberListMessage`.
+/// Version 13 added `ListRoomsMessage`, `RoomListMessage`, `CreateRoomMessage`,
+/// `DescribeRoomMessage` and `RoomInfoMessage`.
+/// Version 14 added `FetchHistoryMessage` and `HistoryMessage`.
+/// Version 15 added `SearchMessage` and `SearchResultsMessage`.
+/// Version 16 added `RegisterMessage`, `LoginMessage` and `AuthenticatedMessage`, clients log in
+/// after the handshake, and removed the usernames of `HelloMessage` and `ChatMessage`.
+/// Version 17 added `ResumeMessage`, `ListSessionsMessage`, `SessionListMessage`, `LogOutMessage`
+/// and `LoggedOutMessage`, and a session token and its expiry to `AuthenticatedMessage`.
+pub const PROTOCOL_VERSION: u16 = 17;
+
+/// The oldest protocol version this build of the crate is able to talk to.
+///
+/// Peers older than version 2 don't use length prefixed frames and can't be understood.
+/// Version 16 introduced accounts: clients log in after the handshake and the server tells
+/// who sent a message instead of trusting the username sent with it, older clients can't log in.
+pub const MIN_PROTOCOL_VERSION: u16 = 16;
+
+/// Optional features a peer supports, exchanged during the handshake.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Capabilities(u32);
+
+impl Capabilities {
+  pub const NONE: Capabilities = Capabilities(0);
+  /// The peer wants to know when its messages are delivered.
+  pub const DELIVERY_RECEIPTS: Capabilities = Capabilities(1 << 0);
+  /// The peer wants to know when its messages are read.
+  pub const READ_RECEIPTS: Capabilities = Capabilities(1 << 1);
+  /// The peer pings idle connections to keep them alive and answers `Ping` messages.
+  pub const HEARTBEATS: Capabilities = Capabilities(1 << 2);
+
+  /// Every capability known by this build of the crate.
+  pub const fn all() -> Self {
+    Capabilities(Self::DELIVERY_RECEIPTS.0 | Self::READ_RECEIPTS.0 | Self::HEARTBEATS.0)
+  }
+
+  pub const fn from_bits(bits: u32) -> Self {
+    Capabilities(bits)
+  }
+
+  pub const fn bits(&self) -> u32 {
+    self.0
+  }
+
+  pub const fn contains(&self, other: Capabilities) -> bool {
+    self.0 & other.0 == other.0
+  }
+
+  /// The capabilities supported by both sides.
+  pub const fn intersection(&self, other: Capabilities) -> Self {
+    Capabilities(self.0 & other.0)
+  }
+
+  /// The capabilities of `self` that are not in `other`.
+  pub const fn difference(&self, other: Capabilities) -> Self {
+    Capabilities(self.0 & !other.0)
+  }
+}
+
 /// The type of the message.
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MessageType {
   JoinRoom,
   ChatMessage,
   MessageRead,
   MessageReceived,
+  Hello,
+  Welcome,
+  HelloRejected,
+  Error,
+  MessageAccepted,
+  Ping,
+  Pong,
+  LeaveRoom,
+  RoomJoined,
+  RoomLeft,
+  UserJoined,
+  UserLeft,
+  ListMembers,
+  MemberList,
+  ListRooms,
+  RoomList,
+  CreateRoom,
+  DescribeRoom,
+  RoomInfo,
+  FetchHistory,
+  History,
+  Search,
+  SearchResults,
+  Register,
+  Login,
+  Authenticated,
+  Resume,
+  ListSessions,
+  SessionList,
+  LogOut,
+  LoggedOut,
 }
 
 impl MessageType {
@@ -21,152 +161,210 @@ impl MessageType {
       MessageType::ChatMessage => 1,
       MessageType::MessageRead => 2,
       MessageType::MessageReceived => 3,
+      MessageType::Hello => 4,
+      MessageType::Welcome => 5,
+      MessageType::HelloRejected => 6,
+      MessageType::Error => 7,
+      MessageType::MessageAccepted => 8,
+      MessageType::Ping => 9,
+      MessageType::Pong => 10,
+      MessageType::LeaveRoom => 11,
+      MessageType::RoomJoined => 12,
+      MessageType::RoomLeft => 13,
+      MessageType::UserJoined => 14,
+      MessageType::UserLeft => 15,
+      MessageType::ListMembers => 16,
+      MessageType::MemberList => 17,
+      MessageType::ListRooms => 18,
+      MessageType::RoomList => 19,
+      MessageType::CreateRoom => 20,
+      MessageType::DescribeRoom => 21,
+      MessageType::RoomInfo => 22,
+      MessageType::FetchHistory => 23,
+      MessageType::History => 24,
+      MessageType::Search => 25,
+      MessageType::SearchResults => 26,
+      MessageType::Register => 27,
+      MessageType::Login => 28,
+      MessageType::Authenticated => 29,
+      MessageType::Resume => 30,
+      MessageType::ListSessions => 31,
+      MessageType::SessionList => 32,
+      MessageType::LogOut => 33,
+      MessageType::LoggedOut => 34,
+    }
+  }
+
+  /// The protocol version that introduced the message type, peers speaking an older version don't know it.
+  pub fn since_version(&self) -> u16 {
+    match self {
+      MessageType::JoinRoom
+      | MessageType::ChatMessage
+      | MessageType::MessageRead
+      | MessageType::MessageReceived
+      | MessageType::Hello
+      | MessageType::Welcome
+      | MessageType::HelloRejected => 1,
+      MessageType::Error => 3,
+      MessageType::MessageAccepted => 5,
+      MessageType::Ping | MessageType::Pong => 7,
+      MessageType::LeaveRoom => 8,
+      MessageType::RoomJoined | MessageType::RoomLeft => 11,
+      MessageType::UserJoined
+      | MessageType::UserLeft
+      | MessageType::ListMembers
+      | MessageType::MemberList => 12,
+      MessageType::ListRooms
+      | MessageType::RoomList
+      | MessageType::CreateRoom
+      | MessageType::DescribeRoom
+      | MessageType::RoomInfo => 13,
+      MessageType::FetchHistory | MessageType::History => 14,
+      MessageType::Search | MessageType::SearchResults => 15,
+      MessageType::Register | MessageType::Login | MessageType::Authenticated => 16,
+      MessageType::Resume
+      | MessageType::ListSessions
+      | MessageType::SessionList
+      | MessageType::LogOut
+      | MessageType::LoggedOut => 17,
     }
   }
 }
 
-impl From<u8> for MessageType {
-  fn from(input: u8) -> Self {
+impl TryFrom<u8> for MessageType {
+  type Error = ProtocolError;
+
+  fn try_from(input: u8) -> Result<Self, ProtocolError> {
     match input {
-      0 => MessageType::JoinRoom,
-      1 => MessageType::ChatMessage,
-      2 => MessageType::MessageRead,
-      3 => MessageType::MessageRead,
-      _ => unreachable!(),
+      0 => Ok(MessageType::JoinRoom),
+      1 => Ok(MessageType::ChatMessage),
+      2 => Ok(MessageType::MessageRead),
+      3 => Ok(MessageType::MessageReceived),
+      4 => Ok(MessageType::Hello),
+      5 => Ok(MessageType::Welcome),
+      6 => Ok(MessageType::HelloRejected),
+      7 => Ok(MessageType::Error),
+      8 => Ok(MessageType::MessageAccepted),
+      9 => Ok(MessageType::Ping),
+      10 => Ok(MessageType::Pong),
+      11 => Ok(MessageType::LeaveRoom),
+      12 => Ok(MessageType::RoomJoined),
+      13 => Ok(MessageType::RoomLeft),
+      14 => Ok(MessageType::UserJoined),
+      15 => Ok(MessageType::UserLeft),
+      16 => Ok(MessageType::ListMembers),
+      17 => Ok(MessageType::MemberList),
+      18 => Ok(MessageType::ListRooms),
+      19 => Ok(MessageType::RoomList),
+      20 => Ok(MessageType::CreateRoom),
+      21 => Ok(MessageType::DescribeRoom),
+      22 => Ok(MessageType::RoomInfo),
+      23 => Ok(MessageType::FetchHistory),
+      24 => Ok(MessageType::History),
+      25 => Ok(MessageType::Search),
+      26 => Ok(MessageType::SearchResults),
+      27 => Ok(MessageType::Register),
+      28 => Ok(MessageType::Login),
+      29 => Ok(MessageType::Authenticated),
+      30 => Ok(MessageType::Resume),
+      31 => Ok(MessageType::ListSessions),
+      32 => Ok(MessageType::SessionList),
+      33 => Ok(MessageType::LogOut),
+      34 => Ok(MessageType::LoggedOut),
+      _ => Err(ProtocolError::UnknownMessageType(input)),
     }
   }
 }
 
 #[derive(Debug)]
 pub enum ClientToServerMessage {
+  Hello(client_to_server::HelloMessage),
+  Register(client_to_server::RegisterMessage),
+  Login(client_to_server::LoginMessage),
+  Resume(client_to_server::ResumeMessage),
+  ListSessions(client_to_server::ListSessionsMessage),
+  LogOut(client_to_server::LogOutMessage),
   JoinRoom(client_to_server::JoinRoomMessage),
+  LeaveRoom(client_to_server::LeaveRoomMessage),
+  ListMembers(client_to_server::ListMembersMessage),
+  ListRooms(client_to_server::ListRoomsMessage),
+  CreateRoom(client_to_server::CreateRoomMessage),
+  DescribeRoom(client_to_server::DescribeRoomMessage),
+  FetchHistory(client_to_server::FetchHistoryMessage),
+  Search(client_to_server::SearchMessage),
   ChatMessage(client_to_server::ChatMessage),
   MessageReceived(client_to_server::MessageReceivedMessage),
   MessageRead(client_to_server::MessageReadMessage),
+  Ping(client_to_server::PingMessage),
+  Pong(client_to_server::PongMessage),
 }
 
 #[derive(Debug)]
 pub enum ServerToClientMessage {
+  Welcome(server_to_client::WelcomeMessage),
+  HelloRejected(server_to_client::HelloRejectedMessage),
+  Authenticated(server_to_client::AuthenticatedMessage),
+  SessionList(server_to_client::SessionListMessage),
+  LoggedOut(server_to_client::LoggedOutMessage),
+  Error(server_to_client::ErrorMessage),
   ChatMessage(server_to_client::ChatMessage),
+  MessageAccepted(server_to_client::MessageAcceptedMessage),
   MessageDelivered(server_to_client::MessageDeliveredMessage),
   MessageRead(server_to_client::MessageReadMessage),
+  Ping(server_to_client::PingMessage),
+  Pong(server_to_client::PongMessage),
+  RoomJoined(server_to_client::RoomJoinedMessage),
+  RoomLeft(server_to_client::RoomLeftMessage),
+  UserJoined(server_to_client::UserJoinedMessage),
+  UserLeft(server_to_client::UserLeftMessage),
+  MemberList(server_to_client::MemberListMessage),
+  RoomList(server_to_client::RoomListMessage),
+  RoomInfo(server_to_client::RoomInfoMessage),
+  History(server_to_client::HistoryMessage),
+  SearchResults(server_to_client::SearchResultsMessage),
 }
 
-pub async fn read_client_message(
-  reader: impl AsyncRead + Unpin,
-) -> Result<ClientToServerMessage, tokio::io::Error> {
-  let mut reader = BufReader::new(reader);
-
-  let message_type = reader.read_u8().await?;
-
-  match MessageType::from(message_type) {
-    MessageType::JoinRoom => {
-      let room_id_len = reader.read_u32().await?;
-
-      let mut room_id = vec![0_u8; room_id_len as usize];
-      reader.read_exact(&mut room_id).await?;
-
-      Ok(ClientToServerMessage::JoinRoom(
-        client_to_server::JoinRoomMessage {
-          room_id: String::from_utf8_lossy(&room_id).to_string(),
-        },
-      ))
-    }
-    MessageType::ChatMessage => {
-      let message_id = reader.read_u64().await?;
-
-      let room_id_len = reader.read_u32().await?;
-      let mut room_id = vec![0_u8; room_id_len as usize];
-      reader.read_exact(&mut room_id).await?;
-
-      let username_len = reader.read_u32().await?;
-      let mut username = vec![0_u8; username_len as usize];
-      reader.read_exact(&mut username).await?;
-
-      let contents_len = reader.read_u32().await?;
-      let mut contents = vec![0_u8; contents_len as usize];
-      reader.read_exact(&mut contents).await?;
-
-      Ok(ClientToServerMessage::ChatMessage(
-        client_to_server::ChatMessage {
-          message_id,
-          room_id: String::from_utf8_lossy(&room_id).to_string(),
-          username: String::from_utf8_lossy(&username).to_string(),
-          contents: String::from_utf8_lossy(&contents).to_string(),
-        },
-      ))
-    }
-    MessageType::MessageRead => {
-      let message_id = reader.read_u64().await?;
-
-      let room_id_len = reader.read_u32().await?;
-      let mut room_id = vec![0_u8; room_id_len as usize];
-      reader.read_exact(&mut room_id).await?;
-
-      Ok(ClientToServerMessage::MessageRead(
-        client_to_server::MessageReadMessage {
-          message_id,
-          room_id: String::from_utf8_lossy(&room_id).to_string(),
-        },
-      ))
-    }
-    MessageType::MessageReceived => {
-      let message_id = reader.read_u64().await?;
-
-      let room_id_len = reader.read_u32().await?;
-      let mut room_id = vec![0_u8; room_id_len as usize];
-      reader.read_exact(&mut room_id).await?;
-
-      Ok(ClientToServerMessage::MessageReceived(
-        client_to_server::MessageReceivedMessage {
-          message_id,
-          room_id: String::from_utf8_lossy(&room_id).to_string(),
-        },
-      ))
+impl ServerToClientMessage {
+  /// The type the message is encoded with.
+  pub fn message_type(&self) -> MessageType {
+    match self {
+      ServerToClientMessage::Welcome(_) => MessageType::Welcome,
+      ServerToClientMessage::HelloRejected(_) => MessageType::HelloRejected,
+      ServerToClientMessage::Authenticated(_) => MessageType::Authenticated,
+      ServerToClientMessage::SessionList(_) => MessageType::SessionList,
+      ServerToClientMessage::LoggedOut(_) => MessageType::LoggedOut,
+      ServerToClientMessage::Error(_) => MessageType::Error,
+      ServerToClientMessage::ChatMessage(_) => MessageType::ChatMessage,
+      ServerToClientMessage::MessageAccepted(_) => MessageType::MessageAccepted,
+      ServerToClientMessage::MessageDelivered(_) => MessageType::MessageReceived,
+      ServerToClientMessage::MessageRead(_) => MessageType::MessageRead,
+      ServerToClientMessage::Ping(_) => MessageType::Ping,
+      ServerToClientMessage::Pong(_) => MessageType::Pong,
+      ServerToClientMessage::RoomJoined(_) => MessageType::RoomJoined,
+      ServerToClientMessage::RoomLeft(_) => MessageType::RoomLeft,
+      ServerToClientMessage::UserJoined(_) => MessageType::UserJoined,
+      ServerToClientMessage::UserLeft(_) => MessageType::UserLeft,
+      ServerToClientMessage::MemberList(_) => MessageType::MemberList,
+      ServerToClientMessage::RoomList(_) => MessageType::RoomList,
+      ServerToClientMessage::RoomInfo(_) => MessageType::RoomInfo,
+      ServerToClientMessage::History(_) => MessageType::History,
+      ServerToClientMessage::SearchResults(_) => MessageType::SearchResults,
     }
   }
 }
 
-pub async fn read_server_message(
-  reader: impl AsyncRead + Unpin,
-) -> Result<ServerToClientMessage, tokio::io::Error> {
-  let mut reader = BufReader::new(reader);
-
-  let message_type = reader.read_u8().await?;
-
-  match MessageType::from(message_type) {
-    MessageType::JoinRoom => unreachable!(),
-    MessageType::ChatMessage => {
-      let message_id = reader.read_u64().await?;
-
-      let username_len = reader.read_u32().await?;
-      let mut username = vec![0_u8; username_len as usize];
-      reader.read_exact(&mut username).await?;
-
-      let contents_len = reader.read_u32().await?;
-      let mut contents = vec![0_u8; contents_len as usize];
-      reader.read_exact(&mut contents).await?;
-
-      Ok(ServerToClientMessage::ChatMessage(
-        server_to_client::ChatMessage {
-          message_id,
-          username: String::from_utf8_lossy(&username).to_string(),
-          contents: String::from_utf8_lossy(&contents).to_string(),
-        },
-      ))
-    }
-    MessageType::MessageRead => {
-      let message_id = reader.read_u64().await?;
-      Ok(ServerToClientMessage::MessageRead(
-        server_to_client::MessageReadMessage { message_id },
-      ))
-    }
-    MessageType::MessageReceived => {
-      let message_id = reader.read_u64().await?;
+#[cfg(feature = "tokio")]
+pub async fn read_client_message(
+  mut reader: impl AsyncRead + Unpin,
+) -> Result<ClientToServerMessage, ProtocolError> {
+  let frame = codec::read_frame(&mut reader).await?;
+  client_to_server::decode_frame(&frame)
+}
 
-      Ok(ServerToClientMessage::MessageDelivered(
-        server_to_client::MessageDeliveredMessage { message_id },
-      ))
-    }
-  }
+#[cfg(feature = "tokio")]
+pub async fn read_server_message(
+  mut reader: impl AsyncRead + Unpin,
+) -> Result<ServerToClientMessage, ProtocolError> {
+  let frame = codec::read_frame(&mut reader).await?;
+  server_to_client::decode_frame(&frame)
 }
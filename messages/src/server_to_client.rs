@@ -1,68 +1,892 @@
+use bytes::BytesMut;
+use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
-use tokio::io::{AsyncWrite, AsyncWriteExt, BufWriter};
+#[cfg(feature = "tokio")]
+use tokio::io::AsyncWrite;
 
-use crate::MessageType;
+use crate::{
+  codec::{
+    self, Decoder, Encoder, ProtocolError, MAX_REASON_BYTES, MAX_ROOM_ID_BYTES,
+    MAX_ROOM_NAME_BYTES, MAX_TOKEN_BYTES, MAX_TOPIC_BYTES, MAX_USERNAME_BYTES,
+  },
+  Capabilities, MessageType, ServerToClientMessage, MAX_HISTORY_MESSAGES, MAX_LISTED_MEMBERS,
+  MAX_LISTED_ROOMS, MAX_LISTED_SESSIONS, MAX_MESSAGE_BYTES, MAX_QUERY_BYTES, MAX_SEARCH_RESULTS,
+  MAX_SNIPPET_BYTES,
+};
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Sent in response to a compatible `HelloMessage`.
+#[derive(Debug, Clone)]
+pub struct WelcomeMessage {
+  /// The protocol version both sides will speak.
+  pub protocol_version: u16,
+  /// The capabilities supported by both sides.
+  pub capabilities: Capabilities,
+}
+
+/// Sent in response to an incompatible `HelloMessage`, the server closes the connection after it.
+#[derive(Debug, Clone)]
+pub struct HelloRejectedMessage {
+  /// The protocol version spoken by the server.
+  pub protocol_version: u16,
+  pub reason: String,
+}
+
+/// The answer to a successful `RegisterMessage`, `LoginMessage` or `ResumeMessage`.
+#[derive(Debug, Clone)]
+pub struct AuthenticatedMessage {
+  /// The account the client is logged in with, shown to other members as the author of its messages.
+  pub username: String,
+  /// Logs in again with a `ResumeMessage`, it's only sent once and the server can't tell it again.
+  pub token: String,
+  /// When the token stops working, unless it's used again before.
+  pub expires_at: DateTime<Utc>,
+}
+
+/// The answer to a `ListSessionsMessage`.
+#[derive(Debug, Clone)]
+pub struct SessionListMessage {
+  /// The most recently used sessions first, at most `MAX_LISTED_SESSIONS`.
+  pub sessions: Vec<SessionSummary>,
+}
+
+/// A session of the account, started by registering or logging in and resumed with its token.
+#[derive(Debug, Clone)]
+pub struct SessionSummary {
+  pub session_id: u64,
+  pub created_at: DateTime<Utc>,
+  pub last_used_at: DateTime<Utc>,
+  pub expires_at: DateTime<Utc>,
+  /// A client is connected with the session right now.
+  pub connected: bool,
+  /// The session of the client that asked for the list.
+  pub current: bool,
+}
+
+/// The answer to a `LogOutMessage`, the connection is closed after it unless it logged out other sessions.
+#[derive(Debug, Clone)]
+pub struct LoggedOutMessage {
+  /// How many sessions were logged out.
+  pub sessions: u32,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct ChatMessage {
-  pub message_id: u64,
+  /// Assigned by the server, increases by one for each message sent to the room.
+  pub seq: u64,
   pub username: String,
   pub contents: String,
+  pub room_id: String,
+  /// When the server received the message.
+  pub sent_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
-pub struct MessageReadMessage {
+/// Sent to the author of a chat message once the server has assigned it a sequence number.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageAcceptedMessage {
+  pub room_id: String,
+  /// The id chosen by the client.
   pub message_id: u64,
+  pub seq: u64,
+  pub sent_at: DateTime<Utc>,
 }
 
-#[derive(Debug, Serialize, Deserialize)]
+/// Sent to the author of the message `seq` when more of its recipients have read it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct MessageReadMessage {
+  pub seq: u64,
+  pub room_id: String,
+  /// How many recipients have read the message.
+  pub read_by: u32,
+  /// How many members of the room were sent the message and are still in the room.
+  pub recipients: u32,
+  /// The username of the recipient that read the message,
+  /// `None` if the counts changed because a recipient left the room.
+  pub recipient: Option<String>,
+  /// When the server was told about it.
+  pub at: DateTime<Utc>,
+}
+
+/// Sent to the author of the message `seq` when more of its recipients have received it.
+#[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct MessageDeliveredMessage {
-  pub message_id: u64,
+  pub seq: u64,
+  pub room_id: String,
+  /// How many recipients have received the message.
+  pub delivered_to: u32,
+  /// How many members of the room were sent the message and are still in the room.
+  pub recipients: u32,
+  /// The username of the recipient that received the message,
+  /// `None` if the counts changed because a recipient left the room.
+  pub recipient: Option<String>,
+  /// When the server was told about it.
+  pub at: DateTime<Utc>,
 }
 
-pub async fn write_chat_message(
-  writer: &mut (impl AsyncWrite + Unpin),
-  message: &ChatMessage,
-) -> std::io::Result<()> {
-  let mut writer = BufWriter::new(writer);
+/// Sent once the client is a member of the room, in response to a `JoinRoomMessage`.
+#[derive(Debug, Clone)]
+pub struct RoomJoinedMessage {
+  pub room_id: String,
+}
+
+/// Sent once the client is no longer a member of the room, in response to a `LeaveRoomMessage`.
+#[derive(Debug, Clone)]
+pub struct RoomLeftMessage {
+  pub room_id: String,
+}
 
-  writer.write_u8(MessageType::ChatMessage.as_u8()).await?;
+/// Sent to the members of a room when someone joins it.
+#[derive(Debug, Clone)]
+pub struct UserJoinedMessage {
+  pub room_id: String,
+  pub username: String,
+  pub at: DateTime<Utc>,
+}
 
-  writer.write_u64(message.message_id).await?;
+/// Sent to the members of a room when someone leaves it, or is disconnected.
+#[derive(Debug, Clone)]
+pub struct UserLeftMessage {
+  pub room_id: String,
+  pub username: String,
+  pub at: DateTime<Utc>,
+}
 
-  writer.write_u32(message.username.len() as u32).await?;
-  writer.write_all(message.username.as_bytes()).await?;
+/// The answer to a `ListMembersMessage`.
+#[derive(Debug, Clone)]
+pub struct MemberListMessage {
+  pub room_id: String,
+  /// The usernames of the members, in alphabetical order.
+  /// At most `MAX_LISTED_MEMBERS`, the first ones are sent if the room has more.
+  pub usernames: Vec<String>,
+  /// How many members are in the room.
+  pub members: u32,
+}
 
-  writer.write_u32(message.contents.len() as u32).await?;
-  writer.write_all(message.contents.as_bytes()).await?;
+/// The answer to a `ListRoomsMessage`.
+#[derive(Debug, Clone)]
+pub struct RoomListMessage {
+  /// Ordered by room id, at most `MAX_LISTED_ROOMS`.
+  pub rooms: Vec<RoomSummary>,
+  /// There are more rooms after the last one listed.
+  pub more: bool,
+}
 
-  writer.flush().await?;
+/// A room in a `RoomListMessage`.
+#[derive(Debug, Clone)]
+pub struct RoomSummary {
+  pub room_id: String,
+  pub display_name: String,
+  pub members: u32,
+}
 
-  Ok(())
+/// The answer to a `DescribeRoomMessage`.
+#[derive(Debug, Clone)]
+pub struct RoomInfoMessage {
+  pub room_id: String,
+  pub display_name: String,
+  pub topic: String,
+  pub members: u32,
+  pub created_at: DateTime<Utc>,
 }
 
+/// The answer to a `FetchHistoryMessage`, also sent after a `RoomJoinedMessage`
+/// with the messages sent to the room before the client joined it.
+#[derive(Debug, Clone)]
+pub struct HistoryMessage {
+  pub room_id: String,
+  /// Ordered by seq, at most `MAX_HISTORY_MESSAGES` and `MAX_HISTORY_BYTES`.
+  pub messages: Vec<HistoryEntry>,
+  /// There are more messages in the direction of the request,
+  /// before the first message or after the last one.
+  pub more: bool,
+}
+
+/// A message in a `HistoryMessage`.
+#[derive(Debug, Clone)]
+pub struct HistoryEntry {
+  pub seq: u64,
+  pub username: String,
+  pub contents: String,
+  pub sent_at: DateTime<Utc>,
+}
+
+/// Tells the client that something went wrong.
+#[derive(Debug, Clone)]
+pub struct ErrorMessage {
+  /// A human readable description of the error.
+  pub message: String,
+  pub code: ErrorCode,
+  /// The `message_id` of the chat message that caused the error, if any.
+  pub related_message_id: Option<u64>,
+}
+
+/// The reason for an `ErrorMessage`. The numeric value of each code never changes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ErrorCode {
+  /// Something went wrong in the server.
+  Internal,
+  /// The message type is not known by the server.
+  UnknownMessageType,
+  /// The message could not be decoded.
+  InvalidMessage,
+  /// The message or one of its fields is too large.
+  MessageTooLarge,
+  /// The message is not expected at this point, like a second `Hello`.
+  UnexpectedMessage,
+  /// The client tried to use a room it has not joined.
+  NotJoined,
+  /// The room has reached its maximum number of members.
+  RoomFull,
+  /// The client tried to create a room that already exists.
+  RoomExists,
+  /// The client asked about a room that doesn't exist.
+  RoomNotFound,
+  /// The client sent something else than `Register` or `Login` before logging in.
+  NotAuthenticated,
+  /// The username or the password is wrong.
+  AuthenticationFailed,
+  /// The client tried to register a username that already has an account.
+  UsernameTaken,
+  /// Another session of the account logged this one out, the connection is closed after it.
+  SessionRevoked,
+  /// A code unknown by this build of the crate.
+  Unknown(u16),
+}
+
+/// Sent periodically to check that the connection is alive, the peer answers with a `PongMessage`.
+#[derive(Debug, Clone)]
+pub struct PingMessage {
+  pub nonce: u64,
+}
+
+/// The answer to a `PingMessage`, carries the same nonce.
+#[derive(Debug, Clone)]
+pub struct PongMessage {
+  pub nonce: u64,
+}
+
+impl ErrorCode {
+  pub fn as_u16(&self) -> u16 {
+    match self {
+      ErrorCode::Internal => 0,
+      ErrorCode::UnknownMessageType => 1,
+      ErrorCode::InvalidMessage => 2,
+      ErrorCode::MessageTooLarge => 3,
+      ErrorCode::UnexpectedMessage => 4,
+      ErrorCode::NotJoined => 5,
+      ErrorCode::RoomFull => 6,
+      ErrorCode::RoomExists => 7,
+      ErrorCode::RoomNotFound => 8,
+      ErrorCode::NotAuthenticated => 9,
+      ErrorCode::AuthenticationFailed => 10,
+      ErrorCode::UsernameTaken => 11,
+      ErrorCode::SessionRevoked => 12,
+      ErrorCode::Unknown(code) => *code,
+    }
+  }
+
+  pub fn from_u16(code: u16) -> Self {
+    match code {
+      0 => ErrorCode::Internal,
+      1 => ErrorCode::UnknownMessageType,
+      2 => ErrorCode::InvalidMessage,
+      3 => ErrorCode::MessageTooLarge,
+      4 => ErrorCode::UnexpectedMessage,
+      5 => ErrorCode::NotJoined,
+      6 => ErrorCode::RoomFull,
+      7 => ErrorCode::RoomExists,
+      8 => ErrorCode::RoomNotFound,
+      9 => ErrorCode::NotAuthenticated,
+      10 => ErrorCode::AuthenticationFailed,
+      11 => ErrorCode::UsernameTaken,
+      12 => ErrorCode::SessionRevoked,
+      code => ErrorCode::Unknown(code),
+    }
+  }
+
+  /// A short description of the code that can be shown to users.
+  pub fn description(&self) -> &'static str {
+    match self {
+      ErrorCode::Internal => "internal server error",
+      ErrorCode::UnknownMessageType => "unknown message type",
+      ErrorCode::InvalidMessage => "invalid message",
+      ErrorCode::MessageTooLarge => "message too large",
+      ErrorCode::UnexpectedMessage => "unexpected message",
+      ErrorCode::NotJoined => "not joined",
+      ErrorCode::RoomFull => "room full",
+      ErrorCode::RoomExists => "room exists",
+      ErrorCode::RoomNotFound => "room not found",
+      ErrorCode::NotAuthenticated => "not logged in",
+      ErrorCode::AuthenticationFailed => "authentication failed",
+      ErrorCode::UsernameTaken => "username taken",
+      ErrorCode::SessionRevoked => "logged out",
+      ErrorCode::Unknown(_) => "unknown error",
+    }
+  }
+}
+
+impl AuthenticatedMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::Authenticated);
+    encoder
+      .string("username", &self.username, MAX_USERNAME_BYTES)?
+      .string("token", &self.token, MAX_TOKEN_BYTES)?
+      .timestamp(self.expires_at);
+    encoder.finish()
+  }
+}
+
+impl SessionListMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::SessionList);
+    encoder.count("sessions", self.sessions.len(), MAX_LISTED_SESSIONS)?;
+    for session in &self.sessions {
+      encoder
+        .u64(session.session_id)
+        .timestamp(session.created_at)
+        .timestamp(session.last_used_at)
+        .timestamp(session.expires_at)
+        .bool(session.connected)
+        .bool(session.current);
+    }
+    encoder.finish()
+  }
+}
+
+impl LoggedOutMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::LoggedOut);
+    encoder.u32(self.sessions);
+    encoder.finish()
+  }
+}
+
+impl WelcomeMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::Welcome);
+    encoder
+      .u16(self.protocol_version)
+      .u32(self.capabilities.bits());
+    encoder.finish()
+  }
+}
+
+impl HelloRejectedMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::HelloRejected);
+    encoder
+      .u16(self.protocol_version)
+      .string("reason", &self.reason, MAX_REASON_BYTES)?;
+    encoder.finish()
+  }
+}
+
+impl ChatMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::ChatMessage);
+    encoder
+      .u64(self.seq)
+      .string("username", &self.username, MAX_USERNAME_BYTES)?
+      .string("contents", &self.contents, MAX_MESSAGE_BYTES)?
+      .string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?
+      .timestamp(self.sent_at);
+    encoder.finish()
+  }
+}
+
+impl MessageAcceptedMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::MessageAccepted);
+    encoder
+      .string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?
+      .u64(self.message_id)
+      .u64(self.seq)
+      .timestamp(self.sent_at);
+    encoder.finish()
+  }
+}
+
+impl MessageReadMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::MessageRead);
+    encoder
+      .u64(self.seq)
+      .string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?
+      .u32(self.read_by)
+      .u32(self.recipients)
+      .optional_string("recipient", self.recipient.as_deref(), MAX_USERNAME_BYTES)?
+      .timestamp(self.at);
+    encoder.finish()
+  }
+}
+
+impl MessageDeliveredMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::MessageReceived);
+    encoder
+      .u64(self.seq)
+      .string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?
+      .u32(self.delivered_to)
+      .u32(self.recipients)
+      .optional_string("recipient", self.recipient.as_deref(), MAX_USERNAME_BYTES)?
+      .timestamp(self.at);
+    encoder.finish()
+  }
+}
+
+impl ErrorMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::Error);
+    encoder
+      .string("message", &self.message, MAX_REASON_BYTES)?
+      .u16(self.code.as_u16())
+      .optional_u64(self.related_message_id);
+    encoder.finish()
+  }
+}
+
+impl PingMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::Ping);
+    encoder.u64(self.nonce);
+    encoder.finish()
+  }
+}
+
+impl PongMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::Pong);
+    encoder.u64(self.nonce);
+    encoder.finish()
+  }
+}
+
+impl RoomJoinedMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::RoomJoined);
+    encoder.string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?;
+    encoder.finish()
+  }
+}
+
+impl RoomLeftMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::RoomLeft);
+    encoder.string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?;
+    encoder.finish()
+  }
+}
+
+impl UserJoinedMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::UserJoined);
+    encoder
+      .string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?
+      .string("username", &self.username, MAX_USERNAME_BYTES)?
+      .timestamp(self.at);
+    encoder.finish()
+  }
+}
+
+impl UserLeftMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::UserLeft);
+    encoder
+      .string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?
+      .string("username", &self.username, MAX_USERNAME_BYTES)?
+      .timestamp(self.at);
+    encoder.finish()
+  }
+}
+
+impl MemberListMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::MemberList);
+    encoder
+      .string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?
+      .strings(
+        "usernames",
+        &self.usernames,
+        MAX_LISTED_MEMBERS,
+        MAX_USERNAME_BYTES,
+      )?
+      .u32(self.members);
+    encoder.finish()
+  }
+}
+
+impl RoomListMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::RoomList);
+    encoder.count("rooms", self.rooms.len(), MAX_LISTED_ROOMS)?;
+    for room in &self.rooms {
+      encoder
+        .string("room_id", &room.room_id, MAX_ROOM_ID_BYTES)?
+        .string("display_name", &room.display_name, MAX_ROOM_NAME_BYTES)?
+        .u32(room.members);
+    }
+    encoder.bool(self.more);
+    encoder.finish()
+  }
+}
+
+impl RoomInfoMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::RoomInfo);
+    encoder
+      .string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?
+      .string("display_name", &self.display_name, MAX_ROOM_NAME_BYTES)?
+      .string("topic", &self.topic, MAX_TOPIC_BYTES)?
+      .u32(self.members)
+      .timestamp(self.created_at);
+    encoder.finish()
+  }
+}
+
+/// The answer to a `SearchMessage`.
+#[derive(Debug, Clone)]
+pub struct SearchResultsMessage {
+  pub room_id: String,
+  pub query: String,
+  /// The latest matching messages first, at most `MAX_SEARCH_RESULTS`.
+  pub results: Vec<SearchResult>,
+  /// Older messages match too.
+  pub more: bool,
+}
+
+/// A message matching a search, it can be fetched with a `FetchHistoryMessage` around its seq.
+#[derive(Debug, Clone)]
+pub struct SearchResult {
+  pub seq: u64,
+  pub username: String,
+  pub sent_at: DateTime<Utc>,
+  /// The part of the contents where the words were found, at most `MAX_SNIPPET_BYTES`.
+  pub snippet: String,
+}
+
+impl SearchResultsMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::SearchResults);
+    encoder
+      .string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?
+      .string("query", &self.query, MAX_QUERY_BYTES)?
+      .count("results", self.results.len(), MAX_SEARCH_RESULTS)?;
+    for result in &self.results {
+      encoder
+        .u64(result.seq)
+        .string("username", &result.username, MAX_USERNAME_BYTES)?
+        .timestamp(result.sent_at)
+        .string("snippet", &result.snippet, MAX_SNIPPET_BYTES)?;
+    }
+    encoder.bool(self.more);
+    encoder.finish()
+  }
+}
+
+impl HistoryMessage {
+  pub fn encode(&self, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+    let mut encoder = Encoder::new(dst, MessageType::History);
+    encoder
+      .string("room_id", &self.room_id, MAX_ROOM_ID_BYTES)?
+      .count("messages", self.messages.len(), MAX_HISTORY_MESSAGES)?;
+    for message in &self.messages {
+      encoder
+        .u64(message.seq)
+        .string("username", &message.username, MAX_USERNAME_BYTES)?
+        .string("contents", &message.contents, MAX_MESSAGE_BYTES)?
+        .timestamp(message.sent_at);
+    }
+    encoder.bool(self.more);
+    encoder.finish()
+  }
+}
+
+/// Appends `message` as a frame to `dst`.
+pub fn encode(message: &ServerToClientMessage, dst: &mut BytesMut) -> Result<(), ProtocolError> {
+  match message {
+    ServerToClientMessage::Ping(message) => message.encode(dst),
+    ServerToClientMessage::Pong(message) => message.encode(dst),
+    ServerToClientMessage::Welcome(message) => message.encode(dst),
+    ServerToClientMessage::HelloRejected(message) => message.encode(dst),
+    ServerToClientMessage::Authenticated(message) => message.encode(dst),
+    ServerToClientMessage::SessionList(message) => message.encode(dst),
+    ServerToClientMessage::LoggedOut(message) => message.encode(dst),
+    ServerToClientMessage::Error(message) => message.encode(dst),
+    ServerToClientMessage::ChatMessage(message) => message.encode(dst),
+    ServerToClientMessage::MessageAccepted(message) => message.encode(dst),
+    ServerToClientMessage::MessageDelivered(message) => message.encode(dst),
+    ServerToClientMessage::MessageRead(message) => message.encode(dst),
+    ServerToClientMessage::RoomJoined(message) => message.encode(dst),
+    ServerToClientMessage::RoomLeft(message) => message.encode(dst),
+    ServerToClientMessage::UserJoined(message) => message.encode(dst),
+    ServerToClientMessage::UserLeft(message) => message.encode(dst),
+    ServerToClientMessage::MemberList(message) => message.encode(dst),
+    ServerToClientMessage::RoomList(message) => message.encode(dst),
+    ServerToClientMessage::RoomInfo(message) => message.encode(dst),
+    ServerToClientMessage::History(message) => message.encode(dst),
+    ServerToClientMessage::SearchResults(message) => message.encode(dst),
+  }
+}
+
+/// Removes the first message from `src`.
+/// Returns `None` if `src` does not contain a whole frame yet.
+pub fn decode(src: &mut BytesMut) -> Option<Result<ServerToClientMessage, ProtocolError>> {
+  codec::decode_frame(src).map(|frame| frame.and_then(|frame| decode_frame(&frame)))
+}
+
+/// Decodes a frame without its length prefix.
+pub(crate) fn decode_frame(frame: &[u8]) -> Result<ServerToClientMessage, ProtocolError> {
+  let mut decoder = Decoder::new(frame);
+
+  let message_type = decoder.u8("message_type")?;
+
+  match MessageType::try_from(message_type)? {
+    MessageType::Ping => Ok(ServerToClientMessage::Ping(PingMessage {
+      nonce: decoder.u64("nonce")?,
+    })),
+    MessageType::Pong => Ok(ServerToClientMessage::Pong(PongMessage {
+      nonce: decoder.u64("nonce")?,
+    })),
+    message_type @ (MessageType::JoinRoom
+    | MessageType::LeaveRoom
+    | MessageType::ListMembers
+    | MessageType::ListRooms
+    | MessageType::CreateRoom
+    | MessageType::DescribeRoom
+    | MessageType::FetchHistory
+    | MessageType::Search
+    | MessageType::Hello
+    | MessageType::Register
+    | MessageType::Login
+    | MessageType::Resume
+    | MessageType::ListSessions
+    | MessageType::LogOut) => Err(ProtocolError::UnexpectedMessageType(message_type)),
+    MessageType::RoomJoined => Ok(ServerToClientMessage::RoomJoined(RoomJoinedMessage {
+      room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+    })),
+    MessageType::RoomLeft => Ok(ServerToClientMessage::RoomLeft(RoomLeftMessage {
+      room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+    })),
+    MessageType::UserJoined => Ok(ServerToClientMessage::UserJoined(UserJoinedMessage {
+      room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+      username: decoder.string("username", MAX_USERNAME_BYTES)?,
+      at: decoder.timestamp("at")?,
+    })),
+    MessageType::UserLeft => Ok(ServerToClientMessage::UserLeft(UserLeftMessage {
+      room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+      username: decoder.string("username", MAX_USERNAME_BYTES)?,
+      at: decoder.timestamp("at")?,
+    })),
+    MessageType::MemberList => Ok(ServerToClientMessage::MemberList(MemberListMessage {
+      room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+      usernames: decoder.strings("usernames", MAX_LISTED_MEMBERS, MAX_USERNAME_BYTES)?,
+      members: decoder.u32("members")?,
+    })),
+    MessageType::RoomList => {
+      let count = decoder.count("rooms", MAX_LISTED_ROOMS)?;
+      let rooms = (0..count)
+        .map(|_| {
+          Ok(RoomSummary {
+            room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+            display_name: decoder.string("display_name", MAX_ROOM_NAME_BYTES)?,
+            members: decoder.u32("members")?,
+          })
+        })
+        .collect::<Result<_, ProtocolError>>()?;
+      Ok(ServerToClientMessage::RoomList(RoomListMessage {
+        rooms,
+        more: decoder.bool("more")?,
+      }))
+    }
+    MessageType::RoomInfo => Ok(ServerToClientMessage::RoomInfo(RoomInfoMessage {
+      room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+      display_name: decoder.string("display_name", MAX_ROOM_NAME_BYTES)?,
+      topic: decoder.string("topic", MAX_TOPIC_BYTES)?,
+      members: decoder.u32("members")?,
+      created_at: decoder.timestamp("created_at")?,
+    })),
+    MessageType::History => {
+      let room_id = decoder.string("room_id", MAX_ROOM_ID_BYTES)?;
+      let count = decoder.count("messages", MAX_HISTORY_MESSAGES)?;
+      let messages = (0..count)
+        .map(|_| {
+          Ok(HistoryEntry {
+            seq: decoder.u64("seq")?,
+            username: decoder.string("username", MAX_USERNAME_BYTES)?,
+            contents: decoder.string("contents", MAX_MESSAGE_BYTES)?,
+            sent_at: decoder.timestamp("sent_at")?,
+          })
+        })
+        .collect::<Result<_, ProtocolError>>()?;
+      Ok(ServerToClientMessage::History(HistoryMessage {
+        room_id,
+        messages,
+        more: decoder.bool("more")?,
+      }))
+    }
+    MessageType::SearchResults => {
+      let room_id = decoder.string("room_id", MAX_ROOM_ID_BYTES)?;
+      let query = decoder.string("query", MAX_QUERY_BYTES)?;
+      let count = decoder.count("results", MAX_SEARCH_RESULTS)?;
+      let results = (0..count)
+        .map(|_| {
+          Ok(SearchResult {
+            seq: decoder.u64("seq")?,
+            username: decoder.string("username", MAX_USERNAME_BYTES)?,
+            sent_at: decoder.timestamp("sent_at")?,
+            snippet: decoder.string("snippet", MAX_SNIPPET_BYTES)?,
+          })
+        })
+        .collect::<Result<_, ProtocolError>>()?;
+      Ok(ServerToClientMessage::SearchResults(SearchResultsMessage {
+        room_id,
+        query,
+        results,
+        more: decoder.bool("more")?,
+      }))
+    }
+    MessageType::Welcome => Ok(ServerToClientMessage::Welcome(WelcomeMessage {
+      protocol_version: decoder.u16("protocol_version")?,
+      capabilities: Capabilities::from_bits(decoder.u32("capabilities")?),
+    })),
+    MessageType::HelloRejected => Ok(ServerToClientMessage::HelloRejected(HelloRejectedMessage {
+      protocol_version: decoder.u16("protocol_version")?,
+      reason: decoder.string("reason", MAX_REASON_BYTES)?,
+    })),
+    MessageType::Authenticated => Ok(ServerToClientMessage::Authenticated(AuthenticatedMessage {
+      username: decoder.string("username", MAX_USERNAME_BYTES)?,
+      token: decoder.string("token", MAX_TOKEN_BYTES)?,
+      expires_at: decoder.timestamp("expires_at")?,
+    })),
+    MessageType::SessionList => {
+      let count = decoder.count("sessions", MAX_LISTED_SESSIONS)?;
+      let sessions = (0..count)
+        .map(|_| {
+          Ok(SessionSummary {
+            session_id: decoder.u64("session_id")?,
+            created_at: decoder.timestamp("created_at")?,
+            last_used_at: decoder.timestamp("last_used_at")?,
+            expires_at: decoder.timestamp("expires_at")?,
+            connected: decoder.bool("connected")?,
+            current: decoder.bool("current")?,
+          })
+        })
+        .collect::<Result<_, ProtocolError>>()?;
+      Ok(ServerToClientMessage::SessionList(SessionListMessage {
+        sessions,
+      }))
+    }
+    MessageType::LoggedOut => Ok(ServerToClientMessage::LoggedOut(LoggedOutMessage {
+      sessions: decoder.u32("sessions")?,
+    })),
+    MessageType::Error => Ok(ServerToClientMessage::Error(ErrorMessage {
+      message: decoder.string("message", MAX_REASON_BYTES)?,
+      code: ErrorCode::from_u16(decoder.u16("code")?),
+      related_message_id: decoder.optional_u64("related_message_id")?,
+    })),
+    MessageType::ChatMessage => Ok(ServerToClientMessage::ChatMessage(ChatMessage {
+      seq: decoder.u64("seq")?,
+      username: decoder.string("username", MAX_USERNAME_BYTES)?,
+      contents: decoder.string("contents", MAX_MESSAGE_BYTES)?,
+      room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+      sent_at: decoder.timestamp("sent_at")?,
+    })),
+    MessageType::MessageAccepted => Ok(ServerToClientMessage::MessageAccepted(
+      MessageAcceptedMessage {
+        room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+        message_id: decoder.u64("message_id")?,
+        seq: decoder.u64("seq")?,
+        sent_at: decoder.timestamp("sent_at")?,
+      },
+    )),
+    MessageType::MessageRead => Ok(ServerToClientMessage::MessageRead(MessageReadMessage {
+      seq: decoder.u64("seq")?,
+      room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+      read_by: decoder.u32("read_by")?,
+      recipients: decoder.u32("recipients")?,
+      recipient: decoder.optional_string("recipient", MAX_USERNAME_BYTES)?,
+      at: decoder.timestamp("at")?,
+    })),
+    MessageType::MessageReceived => Ok(ServerToClientMessage::MessageDelivered(
+      MessageDeliveredMessage {
+        seq: decoder.u64("seq")?,
+        room_id: decoder.string("room_id", MAX_ROOM_ID_BYTES)?,
+        delivered_to: decoder.u32("delivered_to")?,
+        recipients: decoder.u32("recipients")?,
+        recipient: decoder.optional_string("recipient", MAX_USERNAME_BYTES)?,
+        at: decoder.timestamp("at")?,
+      },
+    )),
+  }
+}
+
+#[cfg(feature = "tokio")]
+pub async fn write_chat_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: &ChatMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
+
+#[cfg(feature = "tokio")]
+pub async fn write_message_accepted(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: &MessageAcceptedMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
+
+#[cfg(feature = "tokio")]
 pub async fn write_message_read(
   writer: &mut (impl AsyncWrite + Unpin),
   message: &MessageReadMessage,
-) -> std::io::Result<()> {
-  let mut writer = BufWriter::new(writer);
-  writer.write_u8(MessageType::MessageRead.as_u8()).await?;
-  writer.write_u64(message.message_id).await?;
-  writer.flush().await?;
-
-  Ok(())
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
 }
 
+#[cfg(feature = "tokio")]
 pub async fn write_message_delivered(
   writer: &mut (impl AsyncWrite + Unpin),
   message: &MessageDeliveredMessage,
-) -> std::io::Result<()> {
-  let mut writer = BufWriter::new(writer);
-  writer
-    .write_u8(MessageType::MessageReceived.as_u8())
-    .await?;
-  writer.write_u64(message.message_id).await?;
-  writer.flush().await?;
-
-  Ok(())
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
+
+#[cfg(feature = "tokio")]
+pub async fn write_welcome_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: &WelcomeMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
+
+#[cfg(feature = "tokio")]
+pub async fn write_hello_rejected_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: &HelloRejectedMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
+
+#[cfg(feature = "tokio")]
+pub async fn write_error_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: &ErrorMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
+
+#[cfg(feature = "tokio")]
+pub async fn write_ping_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: &PingMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
+}
+
+#[cfg(feature = "tokio")]
+pub async fn write_pong_message(
+  writer: &mut (impl AsyncWrite + Unpin),
+  message: &PongMessage,
+) -> Result<(), ProtocolError> {
+  codec::write_frame(writer, |dst| message.encode(dst)).await
 }
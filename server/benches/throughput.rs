@@ -0,0 +1,195 @@
+//! Measures how many chat messages per second the server delivers as the number of rooms grows.
+//!
+//! Every member of every room sends the same number of messages at the same time,
+//! the run ends once every member has received the messages sent by the others.
+//!
+//! Run with `cargo bench -p server`.
+
+use std::{
+  net::SocketAddr,
+  sync::Arc,
+  time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, Result};
+use clap::Parser;
+use messages::{client_to_server, ServerToClientMessage};
+use server::Config;
+use tokio::net::{
+  tcp::{OwnedReadHalf, OwnedWriteHalf},
+  TcpListener, TcpStream,
+};
+
+const ROOMS: [usize; 5] = [1, 2, 4, 8, 16];
+const MEMBERS_PER_ROOM: usize = 4;
+const MESSAGES_PER_MEMBER: usize = 500;
+
+fn main() -> Result<()> {
+  let runtime = tokio::runtime::Builder::new_multi_thread()
+    .enable_all()
+    .build()?;
+
+  runtime.block_on(async {
+    for rooms in ROOMS {
+      let delivered = rooms * MEMBERS_PER_ROOM * (MEMBERS_PER_ROOM - 1) * MESSAGES_PER_MEMBER;
+      let elapsed = run(rooms).await?;
+
+      println!(
+        "rooms={:<3} members_per_room={} delivered={:<7} elapsed={:>8.1?} throughput={:.0} messages/s",
+        rooms,
+        MEMBERS_PER_ROOM,
+        delivered,
+        elapsed,
+        delivered as f64 / elapsed.as_secs_f64()
+      );
+    }
+
+    Ok(())
+  })
+}
+
+async fn run(rooms: usize) -> Result<Duration> {
+  let listener = TcpListener::bind("127.0.0.1:0").await?;
+  let server_addr = listener.local_addr()?;
+  // Nothing should be dropped, slow readers slow down the senders instead.
+  let config = Arc::new(Config::parse_from([
+    "server",
+    "--outbox-overflow-policy",
+    "backpressure",
+  ]));
+  let server = tokio::spawn(server::serve(listener, config));
+
+  let mut members = Vec::new();
+  for room in 0..rooms {
+    for member in 0..MEMBERS_PER_ROOM {
+      members.push(
+        connect(
+          server_addr,
+          format!("bench-{room}-{member}"),
+          format!("room-{room}"),
+        )
+        .await?,
+      );
+    }
+  }
+
+  let start = Instant::now();
+
+  let tasks: Vec<_> = members
+    .into_iter()
+    .map(|(room_id, read_half, write_half)| {
+      tokio::spawn(async move {
+        let sender = tokio::spawn(send_messages(room_id, write_half));
+        receive_messages(read_half).await?;
+        // Closing the connection before everything is received would remove the client from the room.
+        let _write_half = sender.await??;
+        Ok::<_, anyhow::Error>(())
+      })
+    })
+    .collect();
+
+  for task in tasks {
+    task.await??;
+  }
+
+  let elapsed = start.elapsed();
+  server.abort();
+
+  Ok(elapsed)
+}
+
+/// Connects a client, registers `username` and waits until it has joined `room_id`.
+async fn connect(
+  server_addr: SocketAddr,
+  username: String,
+  room_id: String,
+) -> Result<(String, OwnedReadHalf, OwnedWriteHalf)> {
+  let (mut read_half, mut write_half) = TcpStream::connect(server_addr).await?.into_split();
+
+  client_to_server::write_hello_message(
+    &mut write_half,
+    client_to_server::HelloMessage {
+      protocol_version: messages::PROTOCOL_VERSION,
+      capabilities: messages::Capabilities::NONE,
+    },
+  )
+  .await?;
+
+  match messages::read_server_message(&mut read_half).await? {
+    ServerToClientMessage::Welcome(_) => {}
+    message => return Err(anyhow!("expected Welcome message. message={:?}", message)),
+  }
+
+  client_to_server::write_register_message(
+    &mut write_half,
+    client_to_server::RegisterMessage {
+      username,
+      password: "bench-password".to_owned(),
+    },
+  )
+  .await?;
+
+  match messages::read_server_message(&mut read_half).await? {
+    ServerToClientMessage::Authenticated(_) => {}
+    message => {
+      return Err(anyhow!(
+        "expected Authenticated message. message={:?}",
+        message
+      ))
+    }
+  }
+
+  client_to_server::write_join_room_message(
+    &mut write_half,
+    client_to_server::JoinRoomMessage {
+      room_id: room_id.clone(),
+    },
+  )
+  .await?;
+
+  match messages::read_server_message(&mut read_half).await? {
+    ServerToClientMessage::RoomJoined(_) => {}
+    message => {
+      return Err(anyhow!(
+        "expected RoomJoined message. message={:?}",
+        message
+      ))
+    }
+  }
+
+  Ok((room_id, read_half, write_half))
+}
+
+/// Returns `write_half` so the connection stays open.
+async fn send_messages(room_id: String, mut write_half: OwnedWriteHalf) -> Result<OwnedWriteHalf> {
+  for message_id in 0..MESSAGES_PER_MEMBER as u64 {
+    client_to_server::write_chat_message(
+      &mut write_half,
+      client_to_server::ChatMessage {
+        message_id,
+        room_id: room_id.clone(),
+        contents: "hello".to_owned(),
+      },
+    )
+    .await?;
+  }
+
+  Ok(write_half)
+}
+
+async fn receive_messages(mut read_half: OwnedReadHalf) -> Result<()> {
+  let mut received = 0;
+  let mut accepted = 0;
+
+  while received < (MEMBERS_PER_ROOM - 1) * MESSAGES_PER_MEMBER || accepted < MESSAGES_PER_MEMBER {
+    match messages::read_server_message(&mut read_half).await? {
+      ServerToClientMessage::ChatMessage(_) => received += 1,
+      ServerToClientMessage::MessageAccepted(_) => accepted += 1,
+      // The members that joined the room after this one.
+      ServerToClientMessage::UserJoined(_) => {}
+      message => return Err(anyhow!("unexpected message. message={:?}", message)),
+    }
+  }
+
+  Ok(())
+}
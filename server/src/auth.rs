@@ -0,0 +1,155 @@
+//! Accounts: clients register a username with a password, then log in with it after the handshake.
+//!
+//! Only an Argon2id hash of the password is stored, with its own random salt.
+//!
+//! Logging in starts a session, its random token lets a client that reconnects resume it without
+//! the password. Tokens are long enough that a fast hash is enough to store them.
+
+use std::sync::OnceLock;
+
+use anyhow::{anyhow, Result};
+use argon2::{
+  password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString},
+  Argon2,
+};
+use blake2::{Blake2b, Digest};
+use messages::server_to_client::ErrorCode;
+use password_hash::rand_core::{OsRng, RngCore};
+use tracing::warn;
+
+use crate::error::ClientError;
+
+/// Shorter passwords are refused when registering.
+const MIN_PASSWORD_CHARS: usize = 8;
+
+const TOKEN_BYTES: usize = 32;
+
+/// Usernames are shown to other members and typed in commands, they can't contain spaces.
+pub(crate) fn validate_username(username: &str) -> Result<(), ClientError> {
+  if username.is_empty()
+    || username
+      .chars()
+      .any(|c| c.is_whitespace() || c.is_control())
+  {
+    return Err(ClientError::new(
+      ErrorCode::InvalidMessage,
+      "usernames can't be empty or contain spaces",
+    ));
+  }
+
+  Ok(())
+}
+
+pub(crate) fn validate_password(password: &str) -> Result<(), ClientError> {
+  if password.chars().count() < MIN_PASSWORD_CHARS {
+    return Err(ClientError::new(
+      ErrorCode::InvalidMessage,
+      format!("passwords must have at least {MIN_PASSWORD_CHARS} characters"),
+    ));
+  }
+
+  Ok(())
+}
+
+/// Takes a while on purpose, call it on the blocking thread pool.
+pub(crate) fn hash_password(password: &str) -> Result<String> {
+  let salt = SaltString::generate(&mut OsRng);
+  let hash = Argon2::default()
+    .hash_password(password.as_bytes(), &salt)
+    .map_err(|err| anyhow!("unable to hash password: {err}"))?;
+  Ok(hash.to_string())
+}
+
+/// Checks `password` against the hash stored for the user, `None` if there's no such user.
+/// Unknown users take as long as wrong passwords, so they can't be told apart.
+pub(crate) fn verify_password(password: &str, hash: Option<&str>) -> bool {
+  static UNKNOWN_USER_HASH: OnceLock<String> = OnceLock::new();
+
+  let (hash, known) = match hash {
+    Some(hash) => (hash, true),
+    None => (
+      UNKNOWN_USER_HASH
+        .get_or_init(|| hash_password("").unwrap_or_default())
+        .as_str(),
+      false,
+    ),
+  };
+
+  let hash = match PasswordHash::new(hash) {
+    Ok(hash) => hash,
+    Err(err) => {
+      warn!("unable to parse password hash. error={}", err);
+      return false;
+    }
+  };
+  let verified = Argon2::default()
+    .verify_password(password.as_bytes(), &hash)
+    .is_ok();
+
+  verified && known
+}
+
+/// Returns a new session token, and the hash to store for it.
+pub(crate) fn new_token() -> (String, String) {
+  let mut bytes = [0; TOKEN_BYTES];
+  OsRng.fill_bytes(&mut bytes);
+  let token = to_hex(&bytes);
+  let hash = hash_token(&token);
+  (token, hash)
+}
+
+pub(crate) fn hash_token(token: &str) -> String {
+  to_hex(&Blake2b::<blake2::digest::consts::U32>::digest(
+    token.as_bytes(),
+  ))
+}
+
+/// Session ids are shown to clients instead of tokens, they fit in an SQLite integer.
+pub(crate) fn new_token_id() -> u64 {
+  OsRng.next_u64() >> 1
+}
+
+fn to_hex(bytes: &[u8]) -> String {
+  bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn passwords_are_verified_against_their_hash() {
+    let hash = hash_password("correct horse").unwrap();
+    assert!(hash.starts_with("$argon2id$"));
+    // Every hash has its own salt.
+    assert_ne!(hash, hash_password("correct horse").unwrap());
+
+    assert!(verify_password("correct horse", Some(&hash)));
+    assert!(!verify_password("correct hors", Some(&hash)));
+    assert!(!verify_password("correct horse", Some("not a hash")));
+    assert!(!verify_password("correct horse", None));
+    // Not even the password of the hash used for unknown users.
+    assert!(!verify_password("", None));
+  }
+
+  #[test]
+  fn usernames_and_passwords_are_validated() {
+    assert!(validate_username("ann").is_ok());
+    assert!(validate_username("").is_err());
+    assert!(validate_username("ann bob").is_err());
+    assert!(validate_username("ann\u{7}").is_err());
+
+    assert!(validate_password("éééééééé").is_ok());
+    assert!(validate_password("ééééééé").is_err());
+  }
+
+  #[test]
+  fn tokens_are_stored_as_their_hash() {
+    let (token, hash) = new_token();
+    assert_eq!(token.len(), 2 * TOKEN_BYTES);
+    assert_eq!(hash, hash_token(&token));
+    assert_ne!(hash, token);
+    assert_ne!(new_token().0, token);
+    assert!(new_token_id() <= i64::MAX as u64);
+  }
+}
@@ -0,0 +1,2397 @@
+use std::{
+  collections::{BTreeMap, HashMap},
+  sync::{Arc, Weak},
+  time::Duration,
+};
+
+use anyhow::Context;
+use chrono::{DateTime, Utc};
+use messages::{server_to_client::ErrorCode, Capabilities, ServerToClientMessage};
+use tokio::sync::{Mutex, MutexGuard, OwnedMutexGuard, RwLock};
+use tracing::{debug, info, warn};
+
+use crate::{
+  auth,
+  error::ClientError,
+  outbox::{self, Outbox, OutboxError},
+  retention,
+  search::{self, SearchIndex},
+  session::{Session, SessionId},
+  storage::{
+    self, FsyncPolicy, Receipt, Storage, StoredMember, StoredMessage, StoredRoom, StoredToken,
+    StoredUser,
+  },
+  Config,
+};
+
+/// Maximum number of messages per room whose receipts are tracked,
+/// the oldest messages stop getting receipts once the limit is reached.
+const MAX_TRACKED_RECEIPTS: usize = 4096;
+
+pub(crate) struct ChatManager {
+  config: Arc<Config>,
+  /// Every room has its own lock so messages sent to different rooms don't wait for each other,
+  /// this lock is only held to find, create or remove a room.
+  rooms: RwLock<HashMap<String, Arc<Mutex<Room>>>>,
+  storage: Arc<dyn Storage>,
+  search_index: Arc<SearchIndex>,
+  /// The connected clients of each session token, to close them when it's logged out.
+  connections: Mutex<HashMap<u64, HashMap<SessionId, Outbox>>>,
+}
+
+struct Room {
+  /// Shown to users instead of the room id.
+  display_name: String,
+  topic: String,
+  created_at: DateTime<Utc>,
+  /// The room was created with a `CreateRoomMessage`, it's kept once its last client leaves.
+  persistent: bool,
+  clients: HashMap<SessionId, Client>,
+  /// The members of the room by username, connected or not. Members whose clients are all
+  /// disconnected are recipients of the messages sent meanwhile and receive them when they connect again.
+  members: HashMap<String, Member>,
+  /// The sequence number that will be assigned to the next message sent to the room.
+  next_seq: u64,
+  /// The messages that haven't been read by all their recipients yet, by seq.
+  receipts: BTreeMap<u64, Receipts>,
+  /// The room has no members left and is being removed from `ChatManager::rooms`,
+  /// clients that want to join it have to create a new one.
+  removed: bool,
+}
+
+/// How many of the recipients of a message have received and read it.
+struct Receipts {
+  sender: SessionId,
+  /// The username of the sender, its other clients aren't recipients of the message.
+  sender_username: String,
+  /// The session token of the sender, a client resuming it is sent the receipts instead.
+  token_id: u64,
+  /// The members of the room when the message was sent, connected or not, other than the sender,
+  /// that are still in the room.
+  recipients: u32,
+  delivered_to: u32,
+  read_by: u32,
+}
+
+impl Receipts {
+  /// `recipient` is the member that received the message,
+  /// `None` if the counts changed because a member left.
+  fn delivered_message(
+    &self,
+    room_id: &str,
+    seq: u64,
+    recipient: Option<String>,
+    at: DateTime<Utc>,
+  ) -> ServerToClientMessage {
+    ServerToClientMessage::MessageDelivered(messages::server_to_client::MessageDeliveredMessage {
+      seq,
+      room_id: room_id.to_owned(),
+      delivered_to: self.delivered_to,
+      recipients: self.recipients,
+      recipient,
+      at,
+    })
+  }
+
+  /// Like `delivered_message`.
+  fn read_message(
+    &self,
+    room_id: &str,
+    seq: u64,
+    recipient: Option<String>,
+    at: DateTime<Utc>,
+  ) -> ServerToClientMessage {
+    ServerToClientMessage::MessageRead(messages::server_to_client::MessageReadMessage {
+      seq,
+      room_id: room_id.to_owned(),
+      read_by: self.read_by,
+      recipients: self.recipients,
+      recipient,
+      at,
+    })
+  }
+}
+
+impl Receipt {
+  /// The capability a client needs to be told about this kind of receipt.
+  fn capability(self) -> Capabilities {
+    match self {
+      Receipt::Delivered => Capabilities::DELIVERY_RECEIPTS,
+      Receipt::Read => Capabilities::READ_RECEIPTS,
+    }
+  }
+}
+
+impl Room {
+  fn new(stored: StoredRoom) -> Self {
+    Self {
+      display_name: stored.display_name,
+      topic: stored.topic,
+      created_at: stored.created_at,
+      persistent: stored.persistent,
+      clients: HashMap::new(),
+      members: HashMap::new(),
+      next_seq: 0,
+      receipts: BTreeMap::new(),
+      removed: false,
+    }
+  }
+
+  fn info(&self, room_id: &str) -> messages::server_to_client::RoomInfoMessage {
+    messages::server_to_client::RoomInfoMessage {
+      room_id: room_id.to_owned(),
+      display_name: self.display_name.clone(),
+      topic: self.topic.clone(),
+      members: self.members.len() as u32,
+      created_at: self.created_at,
+    }
+  }
+
+  /// Records that the client has received, or read, every message up to `seq`,
+  /// on behalf of its user, the other clients of the user don't count again.
+  /// Returns the receipts to send to the authors of those messages.
+  fn acknowledge(
+    &mut self,
+    room_id: &str,
+    session_id: SessionId,
+    receipt: Receipt,
+    seq: u64,
+  ) -> Vec<(SessionId, ServerToClientMessage)> {
+    let mut updates = match receipt {
+      // A message that has been read has been received.
+      Receipt::Read => self.acknowledge(room_id, session_id, Receipt::Delivered, seq),
+      Receipt::Delivered => Vec::new(),
+    };
+
+    let username = match self.clients.get(&session_id) {
+      Some(client) => client.username.clone(),
+      None => return updates,
+    };
+    let member = self
+      .members
+      .get_mut(&username)
+      .expect("every client has a member");
+
+    // Messages that haven't been sent yet can't be acknowledged.
+    let until = seq.saturating_add(1).min(self.next_seq);
+    let acknowledged_until = match receipt {
+      Receipt::Delivered => &mut member.delivered_until,
+      Receipt::Read => &mut member.read_until,
+    };
+    if until <= *acknowledged_until {
+      return updates;
+    }
+    let from = std::mem::replace(acknowledged_until, until);
+    let now = Utc::now();
+
+    let mut fully_read = Vec::new();
+    for (&seq, receipts) in self.receipts.range_mut(from..until) {
+      if receipts.sender_username == username {
+        continue;
+      }
+
+      let message = match receipt {
+        Receipt::Delivered => {
+          receipts.delivered_to += 1;
+          receipts.delivered_message(room_id, seq, Some(username.clone()), now)
+        }
+        Receipt::Read => {
+          receipts.read_by += 1;
+          if receipts.read_by == receipts.recipients {
+            fully_read.push(seq);
+          }
+          receipts.read_message(room_id, seq, Some(username.clone()), now)
+        }
+      };
+
+      if self
+        .clients
+        .get(&receipts.sender)
+        .is_some_and(|sender| sender.capabilities.contains(receipt.capability()))
+      {
+        updates.push((receipts.sender, message));
+      }
+    }
+
+    for seq in fully_read {
+      self.receipts.remove(&seq);
+    }
+
+    updates
+  }
+
+  /// Removes the client. Once the last client of its user is gone,
+  /// the user isn't a member anymore and the messages it hasn't read stop waiting for it.
+  /// Returns the receipts to send to the authors of the messages that are now read by everyone,
+  /// and the `UserLeft` messages to send to the remaining members.
+  fn remove_client(
+    &mut self,
+    room_id: &str,
+    session_id: SessionId,
+  ) -> Vec<(SessionId, ServerToClientMessage)> {
+    let client = match self.clients.remove(&session_id) {
+      Some(client) => client,
+      None => return Vec::new(),
+    };
+
+    if self.is_connected(&client.username) {
+      // Nobody is left to tell about the receipts of its own messages.
+      self
+        .receipts
+        .retain(|_, receipts| receipts.sender != session_id);
+      return Vec::new();
+    }
+    // Nor about those of its user's other clients, their sessions won't resume them in this room.
+    self
+      .receipts
+      .retain(|_, receipts| receipts.sender_username != client.username);
+    let member = self
+      .members
+      .remove(&client.username)
+      .expect("every client has a member");
+
+    let now = Utc::now();
+    let mut updates = Vec::new();
+    let mut fully_read = Vec::new();
+    for (&seq, receipts) in self.receipts.range_mut(member.joined_seq..) {
+      receipts.recipients -= 1;
+
+      if seq < member.delivered_until {
+        receipts.delivered_to -= 1;
+      } else if receipts.delivered_to == receipts.recipients {
+        updates.push((
+          receipts.sender,
+          Receipt::Delivered,
+          receipts.delivered_message(room_id, seq, None, now),
+        ));
+      }
+
+      if seq < member.read_until {
+        receipts.read_by -= 1;
+      } else if receipts.read_by == receipts.recipients {
+        fully_read.push(seq);
+        updates.push((
+          receipts.sender,
+          Receipt::Read,
+          receipts.read_message(room_id, seq, None, now),
+        ));
+      }
+    }
+
+    for seq in fully_read {
+      self.receipts.remove(&seq);
+    }
+
+    let mut updates: Vec<_> = updates
+      .into_iter()
+      .filter(|(sender, receipt, _)| {
+        self
+          .clients
+          .get(sender)
+          .is_some_and(|sender| sender.capabilities.contains(receipt.capability()))
+      })
+      .map(|(sender, _, message)| (sender, message))
+      .collect();
+
+    updates.extend(self.user_left(room_id, client.username));
+
+    updates
+  }
+
+  /// Removes the client of a closed connection. Its user stays a member, the messages it hasn't
+  /// received keep waiting for it, and the receipts of its own messages wait for a client resuming
+  /// its session. Returns the `UserLeft` messages to send to the remaining members once the last
+  /// client of the user is gone.
+  fn disconnect_client(
+    &mut self,
+    room_id: &str,
+    session_id: SessionId,
+  ) -> Vec<(SessionId, ServerToClientMessage)> {
+    let client = match self.clients.remove(&session_id) {
+      Some(client) => client,
+      None => return Vec::new(),
+    };
+
+    if self.is_connected(&client.username) {
+      return Vec::new();
+    }
+    self.user_left(room_id, client.username)
+  }
+
+  /// Returns true if a client of `username` is in the room.
+  fn is_connected(&self, username: &str) -> bool {
+    self
+      .clients
+      .values()
+      .any(|client| client.username == username)
+  }
+
+  fn user_joined(&self, room_id: &str, username: &str) -> Vec<(SessionId, ServerToClientMessage)> {
+    let joined = messages::server_to_client::UserJoinedMessage {
+      room_id: room_id.to_owned(),
+      username: username.to_owned(),
+      at: Utc::now(),
+    };
+    self
+      .clients
+      .keys()
+      .map(|&session_id| {
+        (
+          session_id,
+          ServerToClientMessage::UserJoined(joined.clone()),
+        )
+      })
+      .collect()
+  }
+
+  fn user_left(&self, room_id: &str, username: String) -> Vec<(SessionId, ServerToClientMessage)> {
+    let left = messages::server_to_client::UserLeftMessage {
+      room_id: room_id.to_owned(),
+      username,
+      at: Utc::now(),
+    };
+    self
+      .clients
+      .keys()
+      .map(|&session_id| (session_id, ServerToClientMessage::UserLeft(left.clone())))
+      .collect()
+  }
+
+  /// Gives the client the receipts of the messages sent by a disconnected client of its session.
+  /// Returns their current counts, to send to the client.
+  fn resume_receipts(
+    &mut self,
+    room_id: &str,
+    session_id: SessionId,
+    token_id: u64,
+  ) -> Vec<(SessionId, ServerToClientMessage)> {
+    let capabilities = match self.clients.get(&session_id) {
+      Some(client) => client.capabilities,
+      None => return Vec::new(),
+    };
+
+    let now = Utc::now();
+    let mut updates = Vec::new();
+    for (&seq, receipts) in self.receipts.iter_mut() {
+      if receipts.token_id != token_id || self.clients.contains_key(&receipts.sender) {
+        continue;
+      }
+
+      receipts.sender = session_id;
+      if capabilities.contains(Receipt::Delivered.capability()) {
+        updates.push((
+          session_id,
+          receipts.delivered_message(room_id, seq, None, now),
+        ));
+      }
+      if capabilities.contains(Receipt::Read.capability()) {
+        updates.push((session_id, receipts.read_message(room_id, seq, None, now)));
+      }
+    }
+
+    updates
+  }
+
+  /// Removes the clients whose outbox is closed, their connection is closing.
+  /// Returns the updates to send because of it, like `disconnect_client`.
+  fn remove_failed_clients(
+    &mut self,
+    room_id: &str,
+    results: Vec<(SessionId, Result<(), OutboxError>)>,
+  ) -> Vec<(SessionId, ServerToClientMessage)> {
+    let mut updates = Vec::new();
+
+    for (session_id, result) in results {
+      if let Err(err) = result {
+        warn!(
+          "unable to send message to client, removing it from the room. session_id={} error={}",
+          session_id, err
+        );
+        updates.extend(self.disconnect_client(room_id, session_id));
+      }
+    }
+
+    updates
+  }
+
+  /// Sends receipts to the authors of messages and presence changes to the members.
+  ///
+  /// Clients that can't be sent their updates are removed from the room once their connection closes.
+  async fn send_updates(&self, receipts: Vec<(SessionId, ServerToClientMessage)>) {
+    for (session_id, message) in receipts {
+      if let Some(client) = self.clients.get(&session_id) {
+        if let Err(err) = client.outbox.send(message).await {
+          info!(
+            "unable to send update. session_id={} error={}",
+            session_id, err
+          );
+        }
+      }
+    }
+  }
+}
+
+/// A client connected to a room.
+struct Client {
+  outbox: Outbox,
+  /// The account the client logged in or resumed as, shown to authors in receipts.
+  username: String,
+  /// The session token the client logged in or resumed with.
+  token_id: u64,
+  /// The capabilities negotiated during the handshake.
+  capabilities: Capabilities,
+}
+
+/// A user that joined the room, receipts count users rather than clients.
+#[derive(Clone, Copy)]
+struct Member {
+  /// The seq of the first message sent to the room after the user joined.
+  joined_seq: u64,
+  /// Every message before this seq has been received by one of the clients of the user.
+  delivered_until: u64,
+  /// Every message before this seq has been read by one of the clients of the user.
+  read_until: u64,
+}
+
+impl ChatManager {
+  /// Opens the storage and recovers its rooms,
+  /// their next messages continue the sequence numbers of the stored ones.
+  pub(crate) fn open(config: Arc<Config>) -> anyhow::Result<Arc<Self>> {
+    let storage = storage::open(&config)?;
+
+    let search_index = Arc::new(SearchIndex::default());
+    let mut rooms = HashMap::new();
+    for (stored, last_seq) in storage.rooms()? {
+      let room_id = stored.room_id.clone();
+      search_index
+        .build(&room_id, &*storage)
+        .with_context(|| format!("unable to index the messages of room {room_id}"))?;
+      let mut room = Room::new(stored);
+      room.next_seq = last_seq.map_or(0, |seq| seq + 1);
+      info!(
+        "recovered room. room_id={} next_seq={}",
+        room_id, room.next_seq
+      );
+      rooms.insert(room_id, room);
+    }
+
+    // Nobody is connected yet, every member is offline.
+    for member in storage.members()? {
+      if let Some(room) = rooms.get_mut(&member.room_id) {
+        room.members.insert(
+          member.username,
+          Member {
+            joined_seq: member.delivered_until,
+            delivered_until: member.delivered_until,
+            read_until: member.read_until,
+          },
+        );
+      }
+    }
+    let rooms = rooms
+      .into_iter()
+      .map(|(room_id, room)| (room_id, Arc::new(Mutex::new(room))))
+      .collect();
+
+    if config.fsync_policy == FsyncPolicy::Interval {
+      tokio::spawn(sync_storage(
+        Arc::downgrade(&storage),
+        Duration::from_millis(config.fsync_interval_ms.max(1)),
+      ));
+    }
+    if config.has_retention() {
+      tokio::spawn(retention::enforce(
+        Arc::downgrade(&storage),
+        Arc::clone(&search_index),
+        Arc::clone(&config),
+        Duration::from_secs(config.retention_interval_secs.max(1)),
+      ));
+    }
+
+    Ok(Arc::new(Self {
+      config,
+      rooms: RwLock::new(rooms),
+      storage,
+      search_index,
+      connections: Mutex::default(),
+    }))
+  }
+
+  /// Runs `f` with the storage, failures are reported to the client as internal errors.
+  async fn store<T: Send + 'static>(
+    &self,
+    what: &str,
+    f: impl FnOnce(&dyn Storage) -> anyhow::Result<T> + Send + 'static,
+  ) -> Result<T, ClientError> {
+    storage::blocking(&self.storage, f).await.map_err(|err| {
+      warn!("unable to {}. error={:?}", what, err);
+      ClientError::new(ErrorCode::Internal, format!("unable to {what}"))
+    })
+  }
+
+  /// Creates an account and starts a session of it.
+  /// Returns the id of the session token and the message telling the client about it.
+  pub(crate) async fn register(
+    &self,
+    body: messages::client_to_server::RegisterMessage,
+  ) -> Result<(u64, messages::server_to_client::AuthenticatedMessage), ClientError> {
+    auth::validate_username(&body.username)?;
+    auth::validate_password(&body.password)?;
+
+    let username = body.username.clone();
+    let created = self
+      .store("register the user", move |storage| {
+        let user = StoredUser {
+          password_hash: auth::hash_password(&body.password)?,
+          username: body.username,
+          registered_at: Utc::now(),
+        };
+        storage.create_user(&user)
+      })
+      .await?;
+    if !created {
+      return Err(ClientError::new(
+        ErrorCode::UsernameTaken,
+        format!("{username} is already registered"),
+      ));
+    }
+
+    info!("user registered. username={}", username);
+    self.start_session(username).await
+  }
+
+  /// Checks the password of the account and starts a session of it, like `register`.
+  pub(crate) async fn login(
+    &self,
+    body: messages::client_to_server::LoginMessage,
+  ) -> Result<(u64, messages::server_to_client::AuthenticatedMessage), ClientError> {
+    let username = body.username.clone();
+    let verified = self
+      .store("log in", move |storage| {
+        let user = storage.user(&body.username)?;
+        Ok(auth::verify_password(
+          &body.password,
+          user.as_ref().map(|user| user.password_hash.as_str()),
+        ))
+      })
+      .await?;
+    if !verified {
+      return Err(ClientError::new(
+        ErrorCode::AuthenticationFailed,
+        "wrong username or password",
+      ));
+    }
+
+    self.start_session(username).await
+  }
+
+  fn session_expiry(&self, now: DateTime<Utc>) -> DateTime<Utc> {
+    let ttl = chrono::Duration::seconds(self.config.session_ttl_secs.min(i64::MAX as u64) as i64);
+    now
+      .checked_add_signed(ttl)
+      .unwrap_or(DateTime::<Utc>::MAX_UTC)
+  }
+
+  /// Issues a session token for `username`, its expired tokens are deleted meanwhile.
+  async fn start_session(
+    &self,
+    username: String,
+  ) -> Result<(u64, messages::server_to_client::AuthenticatedMessage), ClientError> {
+    let now = Utc::now();
+    let (token, token_hash) = auth::new_token();
+    let stored = StoredToken {
+      token_id: auth::new_token_id(),
+      username: username.clone(),
+      token_hash,
+      created_at: now,
+      last_used_at: now,
+      expires_at: self.session_expiry(now),
+    };
+    let (token_id, expires_at) = (stored.token_id, stored.expires_at);
+    self
+      .store("start the session", move |storage| {
+        storage.delete_expired_tokens(&stored.username, now)?;
+        storage.create_token(&stored)
+      })
+      .await?;
+
+    Ok((
+      token_id,
+      messages::server_to_client::AuthenticatedMessage {
+        username,
+        token,
+        expires_at,
+      },
+    ))
+  }
+
+  /// Resumes the session of a token, it expires later since it's been used.
+  /// Returns the same as `login`.
+  pub(crate) async fn resume(
+    &self,
+    body: messages::client_to_server::ResumeMessage,
+  ) -> Result<(u64, messages::server_to_client::AuthenticatedMessage), ClientError> {
+    let now = Utc::now();
+    let expires_at = self.session_expiry(now);
+    let token_hash = auth::hash_token(&body.token);
+    let stored = self
+      .store("resume the session", move |storage| {
+        let token = storage.token(&token_hash)?;
+        if let Some(token) = token.as_ref().filter(|token| token.expires_at > now) {
+          storage.touch_token(token.token_id, now, expires_at)?;
+        }
+        Ok(token)
+      })
+      .await?;
+
+    match stored {
+      Some(stored) if stored.expires_at > now => Ok((
+        stored.token_id,
+        messages::server_to_client::AuthenticatedMessage {
+          username: stored.username,
+          token: body.token,
+          expires_at,
+        },
+      )),
+      _ => Err(ClientError::new(
+        ErrorCode::AuthenticationFailed,
+        "the session expired or was logged out",
+      )),
+    }
+  }
+
+  /// Forgets the client once its connection is closed,
+  /// its session token expires later since it's just been used.
+  pub(crate) async fn session_ended(&self, session: &Session) {
+    {
+      let mut connections = self.connections.lock().await;
+      if let Some(clients) = connections.get_mut(&session.token_id) {
+        clients.remove(&session.id);
+        if clients.is_empty() {
+          connections.remove(&session.token_id);
+        }
+      }
+    }
+
+    let now = Utc::now();
+    let (token_id, expires_at) = (session.token_id, self.session_expiry(now));
+    if let Err(err) = self
+      .store("update the session", move |storage| {
+        storage.touch_token(token_id, now, expires_at)
+      })
+      .await
+    {
+      info!(
+        "session token not updated. session_id={} error={}",
+        session.id, err.message
+      );
+    }
+  }
+
+  /// Lists the sessions of the user of `session` that haven't expired, the most recently used first.
+  pub(crate) async fn list_sessions(
+    &self,
+    session: &Session,
+  ) -> Result<messages::server_to_client::SessionListMessage, ClientError> {
+    let username = session.username.clone();
+    let mut tokens = self
+      .store("list the sessions", move |storage| {
+        storage.tokens(&username)
+      })
+      .await?;
+    let now = Utc::now();
+    tokens.retain(|token| token.expires_at > now);
+    tokens.sort_unstable_by_key(|token| std::cmp::Reverse(token.last_used_at));
+    tokens.truncate(messages::MAX_LISTED_SESSIONS);
+
+    let connections = self.connections.lock().await;
+    let sessions = tokens
+      .into_iter()
+      .map(|token| messages::server_to_client::SessionSummary {
+        session_id: token.token_id,
+        created_at: token.created_at,
+        last_used_at: token.last_used_at,
+        expires_at: token.expires_at,
+        connected: connections.contains_key(&token.token_id),
+        current: token.token_id == session.token_id,
+      })
+      .collect();
+
+    Ok(messages::server_to_client::SessionListMessage { sessions })
+  }
+
+  /// Logs out the session of the client, or every other session of its user if `others` is true.
+  /// Their tokens stop working and their connected clients, other than this one, are disconnected.
+  /// Returns how many sessions were logged out.
+  pub(crate) async fn log_out(&self, session: &Session, others: bool) -> Result<u32, ClientError> {
+    let (username, token_id) = (session.username.clone(), session.token_id);
+    let token_ids = self
+      .store("log out", move |storage| {
+        let token_ids: Vec<u64> = if others {
+          storage
+            .tokens(&username)?
+            .into_iter()
+            .map(|token| token.token_id)
+            .filter(|&id| id != token_id)
+            .collect()
+        } else {
+          vec![token_id]
+        };
+        for &token_id in &token_ids {
+          storage.delete_token(token_id)?;
+        }
+        Ok(token_ids)
+      })
+      .await?;
+
+    let revoked: Vec<(SessionId, Outbox)> = {
+      let mut connections = self.connections.lock().await;
+      token_ids
+        .iter()
+        .filter_map(|token_id| connections.remove(token_id))
+        .flatten()
+        .filter(|(session_id, _)| *session_id != session.id)
+        .collect()
+    };
+    let error = ClientError::new(ErrorCode::SessionRevoked, "the session was logged out");
+    for (session_id, outbox) in revoked {
+      info!(
+        "disconnecting client of a logged out session. session_id={}",
+        session_id
+      );
+      let _ = outbox
+        .send(ServerToClientMessage::Error(error.to_message()))
+        .await;
+      outbox.close();
+    }
+
+    info!(
+      "logged out. session_id={} username={} sessions={}",
+      session.id,
+      session.username,
+      token_ids.len()
+    );
+    Ok(token_ids.len() as u32)
+  }
+
+  /// Records that the user of the session has connected and adds the client to the rooms
+  /// its user is a member of. The session goes on if the storage fails.
+  /// Returns the ids of the rooms the client was added to.
+  pub(crate) async fn user_connected(&self, session: &Session) -> Vec<String> {
+    self
+      .connections
+      .lock()
+      .await
+      .entry(session.token_id)
+      .or_default()
+      .insert(session.id, session.outbox.clone());
+
+    let (username, at) = (session.username.clone(), Utc::now());
+    let memberships = storage::blocking(&self.storage, move |storage| {
+      storage.save_user(&username, at)?;
+      storage.memberships(&username)
+    })
+    .await;
+    let memberships = match memberships {
+      Ok(memberships) => memberships,
+      Err(err) => {
+        warn!(
+          "unable to store user. session_id={} error={:?}",
+          session.id, err
+        );
+        return Vec::new();
+      }
+    };
+
+    let mut room_ids = Vec::new();
+    for member in memberships {
+      let room_id = member.room_id.clone();
+      match self.rejoin_room(session, member).await {
+        Ok(true) => room_ids.push(room_id),
+        Ok(false) => {}
+        Err(err) => warn!(
+          "unable to rejoin room. session_id={} room_id={} error={}",
+          session.id, room_id, err.message
+        ),
+      }
+    }
+
+    room_ids
+  }
+
+  /// Adds the client to a room its user is a member of. It's sent `RoomJoined`, then the messages
+  /// sent to the room while its user was disconnected, in order and with their original seq.
+  /// Returns false if the room doesn't exist anymore.
+  async fn rejoin_room(
+    &self,
+    session: &Session,
+    stored: StoredMember,
+  ) -> Result<bool, ClientError> {
+    let room_lock = match self.room(&stored.room_id).await {
+      Some(room_lock) => room_lock,
+      None => return Ok(false),
+    };
+    let mut room = room_lock.lock().await;
+    if room.removed {
+      return Ok(false);
+    }
+
+    let next_seq = room.next_seq;
+    let connected = room.is_connected(&session.username);
+    let member = *room
+      .members
+      .entry(session.username.clone())
+      .or_insert(Member {
+        joined_seq: next_seq,
+        delivered_until: stored.delivered_until,
+        read_until: stored.read_until,
+      });
+
+    // Another client of the user already received the messages.
+    let mut from = if connected {
+      next_seq
+    } else {
+      member.delivered_until.min(next_seq)
+    };
+    let max_queued = self.config.max_queued_messages as u64;
+    if next_seq - from > max_queued {
+      info!(
+        "too many queued messages, the oldest ones are only in the history. session_id={} room_id={} queued={}",
+        session.id,
+        stored.room_id,
+        next_seq - from
+      );
+      from = next_seq - max_queued;
+    }
+    let queued = if from < next_seq {
+      let room_id = stored.room_id.clone();
+      self
+        .store("read the queued messages", move |storage| {
+          storage.messages(&room_id, from.checked_sub(1), None, max_queued as usize)
+        })
+        .await?
+    } else {
+      Vec::new()
+    };
+
+    let joined = messages::server_to_client::RoomJoinedMessage {
+      room_id: stored.room_id.clone(),
+    };
+    let mut result = session
+      .outbox
+      .send(ServerToClientMessage::RoomJoined(joined))
+      .await;
+    for message in queued {
+      if result.is_err() {
+        break;
+      }
+      let message = messages::server_to_client::ChatMessage {
+        seq: message.seq,
+        username: message.username,
+        contents: message.contents,
+        room_id: stored.room_id.clone(),
+        sent_at: message.sent_at,
+      };
+      result = session
+        .outbox
+        .send(ServerToClientMessage::ChatMessage(message))
+        .await;
+    }
+    if let Err(err) = result {
+      // The connection is closing, the user stays offline.
+      return Err(ClientError::new(
+        ErrorCode::Internal,
+        format!("unable to send queued messages: {err}"),
+      ));
+    }
+
+    if !connected {
+      let updates = room.user_joined(&stored.room_id, &session.username);
+      room.send_updates(updates).await;
+    }
+    room.clients.insert(
+      session.id,
+      Client {
+        outbox: session.outbox.clone(),
+        username: session.username.clone(),
+        token_id: session.token_id,
+        capabilities: session.capabilities,
+      },
+    );
+    let receipts = room.resume_receipts(&stored.room_id, session.id, session.token_id);
+    room.send_updates(receipts).await;
+
+    Ok(true)
+  }
+
+  /// Creates a room and stores it, returns it locked or `None` if a room with this id exists.
+  ///
+  /// The room is added to `rooms` before it's stored so it's only created once, without keeping
+  /// `rooms` locked meanwhile. Whoever finds it there waits for it to be stored.
+  async fn new_room(
+    &self,
+    stored: StoredRoom,
+  ) -> Result<Option<OwnedMutexGuard<Room>>, ClientError> {
+    let room_id = stored.room_id.clone();
+    let room_lock = Arc::new(Mutex::new(Room::new(stored.clone())));
+    // Nobody else has the room yet, locking it can't wait.
+    let mut room = Arc::clone(&room_lock).try_lock_owned().unwrap();
+    {
+      let mut rooms = self.rooms.write().await;
+      if rooms.contains_key(&room_id) {
+        return Ok(None);
+      }
+      rooms.insert(room_id.clone(), Arc::clone(&room_lock));
+    }
+
+    if let Err(err) = self
+      .store("store the room", move |storage| {
+        storage.create_room(&stored)
+      })
+      .await
+    {
+      room.removed = true;
+      drop(room);
+      self.forget_room(&room_id, &room_lock).await;
+      return Err(err);
+    }
+    // A room created again with the same id has no messages yet.
+    self.search_index.remove_room(&room_id);
+
+    Ok(Some(room))
+  }
+
+  /// Takes a removed room out of `rooms`, unless it has been replaced already.
+  async fn forget_room(&self, room_id: &str, room_lock: &Arc<Mutex<Room>>) {
+    let mut rooms = self.rooms.write().await;
+    if rooms
+      .get(room_id)
+      .is_some_and(|room| Arc::ptr_eq(room, room_lock))
+    {
+      rooms.remove(room_id);
+    }
+  }
+
+  async fn room(&self, room_id: &str) -> Option<Arc<Mutex<Room>>> {
+    self.rooms.read().await.get(room_id).cloned()
+  }
+
+  /// Removes the room, and what's stored about it, if it has no members left and no messages
+  /// were ever sent to it, unless it's persistent. Rooms with messages are kept like the rooms
+  /// recovered on startup.
+  async fn remove_room_if_empty(
+    &self,
+    room_id: &str,
+    room_lock: &Arc<Mutex<Room>>,
+    mut room: MutexGuard<'_, Room>,
+  ) {
+    if !room.members.is_empty() || room.persistent || room.next_seq > 0 {
+      return;
+    }
+
+    room.removed = true;
+    self.search_index.remove_room(room_id);
+    // Deleted before the room leaves `rooms`, a room created again with the same id is stored after.
+    let stored_room_id = room_id.to_owned();
+    if let Err(err) = storage::blocking(&self.storage, move |storage| {
+      storage.delete_room(&stored_room_id)
+    })
+    .await
+    {
+      warn!("unable to delete stored room. error={:?}", err);
+    }
+
+    // `rooms` is never locked while holding the lock of a room in it.
+    drop(room);
+    self.forget_room(room_id, room_lock).await;
+  }
+
+  /// Adds the client to the room, the room is created if it doesn't exist.
+  /// Returns the seq of the first message the client is sent.
+  pub(crate) async fn join_room(
+    &self,
+    session: &Session,
+    room_id: &str,
+  ) -> Result<u64, ClientError> {
+    let mut room = loop {
+      let room_lock = match self.room(room_id).await {
+        Some(room_lock) => room_lock,
+        None => {
+          let stored = StoredRoom {
+            room_id: room_id.to_owned(),
+            display_name: room_id.to_owned(),
+            topic: String::new(),
+            created_at: Utc::now(),
+            persistent: false,
+          };
+          match self.new_room(stored).await? {
+            Some(room) => break room,
+            // Another client created it meanwhile.
+            None => continue,
+          }
+        }
+      };
+
+      let room = Arc::clone(&room_lock).lock_owned().await;
+      if !room.removed {
+        break room;
+      }
+      // It's replaced by a new room once it's out of `rooms`.
+      drop(room);
+      self.forget_room(room_id, &room_lock).await;
+    };
+
+    // Another client of a member doesn't take another place.
+    if !room.members.contains_key(&session.username)
+      && room.members.len() >= self.config.max_room_members
+    {
+      return Err(ClientError::new(
+        ErrorCode::RoomFull,
+        format!(
+          "the room has reached its limit of {} members",
+          self.config.max_room_members
+        ),
+      ));
+    }
+
+    // A member that couldn't be added back when it connected keeps its place.
+    if !room.members.contains_key(&session.username) {
+      let joined_seq = room.next_seq;
+      let (stored_room_id, username) = (room_id.to_owned(), session.username.clone());
+      self
+        .store("store the membership", move |storage| {
+          storage.add_member(&stored_room_id, &username, joined_seq)
+        })
+        .await?;
+
+      room.members.insert(
+        session.username.clone(),
+        Member {
+          joined_seq,
+          delivered_until: joined_seq,
+          read_until: joined_seq,
+        },
+      );
+    }
+    if !room.is_connected(&session.username) {
+      let updates = room.user_joined(room_id, &session.username);
+      room.send_updates(updates).await;
+    }
+    room.clients.insert(
+      session.id,
+      Client {
+        outbox: session.outbox.clone(),
+        username: session.username.clone(),
+        token_id: session.token_id,
+        capabilities: session.capabilities,
+      },
+    );
+
+    Ok(room.next_seq)
+  }
+
+  /// The latest messages sent to the room before the client joined it,
+  /// `None` if there are none or backfill is disabled.
+  pub(crate) async fn backfill(
+    &self,
+    room_id: &str,
+    joined_seq: u64,
+  ) -> Result<Option<messages::server_to_client::HistoryMessage>, ClientError> {
+    let limit = (self.config.backfill_messages as usize).min(messages::MAX_HISTORY_MESSAGES);
+    if limit == 0 || joined_seq == 0 {
+      return Ok(None);
+    }
+
+    let history = self
+      .history(room_id.to_owned(), None, Some(joined_seq), limit)
+      .await?;
+    Ok((!history.messages.is_empty()).then_some(history))
+  }
+
+  pub(crate) async fn fetch_history(
+    &self,
+    session_id: SessionId,
+    body: messages::client_to_server::FetchHistoryMessage,
+  ) -> Result<messages::server_to_client::HistoryMessage, ClientError> {
+    let room_lock = match self.room(&body.room_id).await {
+      Some(room_lock) => room_lock,
+      None => return Err(ClientError::not_joined(&body.room_id)),
+    };
+    if !room_lock.lock().await.clients.contains_key(&session_id) {
+      return Err(ClientError::not_joined(&body.room_id));
+    }
+
+    let limit = (body.limit as usize).clamp(1, messages::MAX_HISTORY_MESSAGES);
+    self
+      .history(body.room_id, body.after, body.before, limit)
+      .await
+  }
+
+  /// Finds the latest messages of the room containing every word of the query.
+  pub(crate) async fn search(
+    &self,
+    session_id: SessionId,
+    body: messages::client_to_server::SearchMessage,
+  ) -> Result<messages::server_to_client::SearchResultsMessage, ClientError> {
+    let room_lock = match self.room(&body.room_id).await {
+      Some(room_lock) => room_lock,
+      None => return Err(ClientError::not_joined(&body.room_id)),
+    };
+    if !room_lock.lock().await.clients.contains_key(&session_id) {
+      return Err(ClientError::not_joined(&body.room_id));
+    }
+
+    let mut words: Vec<String> = search::words(&body.query).map(|(_, word)| word).collect();
+    words.sort_unstable();
+    words.dedup();
+    if words.is_empty() {
+      return Err(ClientError::new(
+        ErrorCode::InvalidMessage,
+        "the query has no words",
+      ));
+    }
+
+    let limit = (body.limit as usize).clamp(1, messages::MAX_SEARCH_RESULTS);
+    let (seqs, more) = self.search_index.search(&body.room_id, &words, limit);
+
+    let room_id = body.room_id.clone();
+    let found = self
+      .store("read the search results", move |storage| {
+        let mut found = Vec::with_capacity(seqs.len());
+        for seq in seqs {
+          // Deleted by the retention meanwhile when it's not there anymore.
+          found.extend(storage.messages(&room_id, seq.checked_sub(1), Some(seq + 1), 1)?);
+        }
+        Ok(found)
+      })
+      .await?;
+
+    let results = found
+      .into_iter()
+      .map(|message| messages::server_to_client::SearchResult {
+        seq: message.seq,
+        snippet: search::snippet(&message.contents, &words),
+        username: message.username,
+        sent_at: message.sent_at,
+      })
+      .collect();
+
+    Ok(messages::server_to_client::SearchResultsMessage {
+      room_id: body.room_id,
+      query: body.query,
+      results,
+      more,
+    })
+  }
+
+  /// Reads a page of stored messages, like `Storage::messages`.
+  async fn history(
+    &self,
+    room_id: String,
+    after: Option<u64>,
+    before: Option<u64>,
+    limit: usize,
+  ) -> Result<messages::server_to_client::HistoryMessage, ClientError> {
+    let stored_room_id = room_id.clone();
+    // One more message than asked for tells if there are more.
+    let mut page = self
+      .store("read the history", move |storage| {
+        storage.messages(&stored_room_id, after, before, limit + 1)
+      })
+      .await?;
+
+    let forward = after.is_some();
+    let mut more = page.len() > limit;
+    if more {
+      if forward {
+        page.pop();
+      } else {
+        page.remove(0);
+      }
+    }
+
+    // The messages furthest from where the page starts are left for the next page
+    // when they don't fit in a frame.
+    let mut bytes = 0;
+    let fits = |message: &&StoredMessage| {
+      bytes += message.username.len() + message.contents.len();
+      bytes <= messages::MAX_HISTORY_BYTES
+    };
+    let fitting = if forward {
+      page.iter().take_while(fits).count()
+    } else {
+      page.iter().rev().take_while(fits).count()
+    };
+    if fitting < page.len() {
+      more = true;
+      if forward {
+        page.truncate(fitting);
+      } else {
+        page.drain(..page.len() - fitting);
+      }
+    }
+
+    let messages = page
+      .into_iter()
+      .map(|message| messages::server_to_client::HistoryEntry {
+        seq: message.seq,
+        username: message.username,
+        contents: message.contents,
+        sent_at: message.sent_at,
+      })
+      .collect();
+
+    Ok(messages::server_to_client::HistoryMessage {
+      room_id,
+      messages,
+      more,
+    })
+  }
+
+  /// Removes the client from the room and, unless another of its clients is in the room,
+  /// its user from the members, unlike `remove_client` which keeps the user a member until it leaves.
+  pub(crate) async fn leave_room(&self, session: &Session, room_id: &str) {
+    let room_lock = match self.room(room_id).await {
+      Some(room_lock) => room_lock,
+      None => return,
+    };
+    let mut room = room_lock.lock().await;
+    let updates = room.remove_client(room_id, session.id);
+    room.send_updates(updates).await;
+
+    if !room.members.contains_key(&session.username) {
+      let (stored_room_id, username) = (room_id.to_owned(), session.username.clone());
+      if let Err(err) = self
+        .store("remove the membership", move |storage| {
+          storage.remove_member(&stored_room_id, &username)
+        })
+        .await
+      {
+        // The client leaves the room anyway.
+        info!(
+          "left room without removing the membership. room_id={} error={}",
+          room_id, err.message
+        );
+      }
+    }
+
+    self.remove_room_if_empty(room_id, &room_lock, room).await;
+  }
+
+  /// Removes the client of a closed connection from the room, its user stays a member.
+  /// The room is removed once its last member is gone.
+  pub(crate) async fn remove_client(&self, room_id: &str, session_id: SessionId) {
+    if let Some(room_lock) = self.room(room_id).await {
+      let mut room = room_lock.lock().await;
+      let receipts = room.disconnect_client(room_id, session_id);
+      room.send_updates(receipts).await;
+      self.remove_room_if_empty(room_id, &room_lock, room).await;
+    }
+  }
+
+  /// Creates a persistent room, the client still has to join it.
+  pub(crate) async fn create_room(
+    &self,
+    body: &messages::client_to_server::CreateRoomMessage,
+  ) -> Result<(), ClientError> {
+    let display_name = if body.display_name.is_empty() {
+      body.room_id.clone()
+    } else {
+      body.display_name.clone()
+    };
+    let room = self
+      .new_room(StoredRoom {
+        room_id: body.room_id.clone(),
+        display_name,
+        topic: body.topic.clone(),
+        created_at: Utc::now(),
+        persistent: true,
+      })
+      .await?;
+    // A room being removed is still in `rooms` for a moment, it's reported as existing.
+    if room.is_none() {
+      return Err(ClientError::new(
+        ErrorCode::RoomExists,
+        format!("room {} already exists", body.room_id),
+      ));
+    }
+
+    Ok(())
+  }
+
+  /// Lists the rooms in the order of their ids, at most `MAX_LISTED_ROOMS` at a time.
+  pub(crate) async fn list_rooms(
+    &self,
+    body: messages::client_to_server::ListRoomsMessage,
+  ) -> messages::server_to_client::RoomListMessage {
+    let mut room_locks: Vec<_> = self
+      .rooms
+      .read()
+      .await
+      .iter()
+      .filter(|(room_id, _)| body.after.as_ref().is_none_or(|after| *room_id > after))
+      .map(|(room_id, room_lock)| (room_id.clone(), Arc::clone(room_lock)))
+      .collect();
+    room_locks.sort_unstable_by(|(a, _), (b, _)| a.cmp(b));
+
+    let more = room_locks.len() > messages::MAX_LISTED_ROOMS;
+    room_locks.truncate(messages::MAX_LISTED_ROOMS);
+
+    let mut rooms = Vec::with_capacity(room_locks.len());
+    // `rooms` is never locked while holding the lock of a room.
+    for (room_id, room_lock) in room_locks {
+      let room = room_lock.lock().await;
+      if room.removed {
+        continue;
+      }
+      rooms.push(messages::server_to_client::RoomSummary {
+        room_id,
+        display_name: room.display_name.clone(),
+        members: room.members.len() as u32,
+      });
+    }
+
+    messages::server_to_client::RoomListMessage { rooms, more }
+  }
+
+  pub(crate) async fn describe_room(
+    &self,
+    body: messages::client_to_server::DescribeRoomMessage,
+  ) -> Result<messages::server_to_client::RoomInfoMessage, ClientError> {
+    let not_found = || {
+      ClientError::new(
+        ErrorCode::RoomNotFound,
+        format!("room {} does not exist", body.room_id),
+      )
+    };
+
+    let room_lock = self.room(&body.room_id).await.ok_or_else(not_found)?;
+    let room = room_lock.lock().await;
+    if room.removed {
+      return Err(not_found());
+    }
+
+    Ok(room.info(&body.room_id))
+  }
+
+  pub(crate) async fn list_members(
+    &self,
+    session_id: SessionId,
+    body: messages::client_to_server::ListMembersMessage,
+  ) -> Result<messages::server_to_client::MemberListMessage, ClientError> {
+    let room_lock = match self.room(&body.room_id).await {
+      Some(room_lock) => room_lock,
+      None => return Err(ClientError::not_joined(&body.room_id)),
+    };
+
+    let room = room_lock.lock().await;
+    if !room.clients.contains_key(&session_id) {
+      return Err(ClientError::not_joined(&body.room_id));
+    }
+
+    let mut usernames: Vec<_> = room.members.keys().cloned().collect();
+    usernames.sort_unstable();
+    usernames.truncate(messages::MAX_LISTED_MEMBERS);
+
+    Ok(messages::server_to_client::MemberListMessage {
+      room_id: body.room_id,
+      usernames,
+      members: room.members.len() as u32,
+    })
+  }
+
+  pub(crate) async fn message_received(
+    &self,
+    sender_id: SessionId,
+    body: messages::client_to_server::ChatMessage,
+  ) -> Result<(), ClientError> {
+    let room_lock = match self.room(&body.room_id).await {
+      Some(room_lock) => room_lock,
+      None => return Err(ClientError::not_joined(&body.room_id).related_to(body.message_id)),
+    };
+
+    let mut room = room_lock.lock().await;
+    let sender_username = match room.clients.get(&sender_id) {
+      Some(sender) => sender.username.clone(),
+      None => return Err(ClientError::not_joined(&body.room_id).related_to(body.message_id)),
+    };
+
+    let seq = room.next_seq;
+
+    let stored = StoredMessage {
+      seq,
+      // Whoever the client logged in as, never what it claims.
+      username: room.clients[&sender_id].username.clone(),
+      contents: body.contents,
+      sent_at: Utc::now(),
+    };
+    let sent_at = stored.sent_at;
+    let message = messages::server_to_client::ChatMessage {
+      seq,
+      username: stored.username.clone(),
+      contents: stored.contents.clone(),
+      room_id: body.room_id.clone(),
+      sent_at,
+    };
+
+    debug!("sending message to room. message={:?}", &message);
+
+    // Encoded once, every member gets the same bytes.
+    // Before the message is stored, a message that can't be sent doesn't take a seq.
+    let frame = outbox::encode(&ServerToClientMessage::ChatMessage(message))
+      .map_err(|err| ClientError::from(&err).related_to(body.message_id))?;
+
+    // Stored before anyone is told about the message, a message that was accepted is never lost on restart.
+    let room_id = body.room_id.clone();
+    let stored = self
+      .store("store the message", move |storage| {
+        storage.append_message(&room_id, &stored)?;
+        Ok(stored)
+      })
+      .await
+      .map_err(|err| err.related_to(body.message_id))?;
+    room.next_seq += 1;
+    self.search_index.add(&body.room_id, seq, &stored.contents);
+
+    let accepted = messages::server_to_client::MessageAcceptedMessage {
+      room_id: body.room_id.clone(),
+      message_id: body.message_id,
+      seq,
+      sent_at,
+    };
+
+    let recipients = room.members.len() - 1;
+    if recipients > 0 {
+      let token_id = room.clients[&sender_id].token_id;
+      room.receipts.insert(
+        seq,
+        Receipts {
+          sender: sender_id,
+          sender_username,
+          token_id,
+          recipients: recipients as u32,
+          delivered_to: 0,
+          read_by: 0,
+        },
+      );
+      if room.receipts.len() > MAX_TRACKED_RECEIPTS {
+        room.receipts.pop_first();
+      }
+    }
+
+    // Sent while holding the lock so that every client gets the messages in seq order,
+    // see `OverflowPolicy::Backpressure`.
+    let results =
+      futures::future::join_all(room.clients.iter().map(|(session_id, client)| async {
+        let result = if *session_id == sender_id {
+          client
+            .outbox
+            .send(ServerToClientMessage::MessageAccepted(accepted.clone()))
+            .await
+        } else {
+          client.outbox.send_frame(frame.clone()).await
+        };
+
+        (*session_id, result)
+      }))
+      .await;
+
+    let receipts = room.remove_failed_clients(&body.room_id, results);
+    room.send_updates(receipts).await;
+    self
+      .remove_room_if_empty(&body.room_id, &room_lock, room)
+      .await;
+
+    Ok(())
+  }
+
+  pub(crate) async fn message_read(
+    &self,
+    sender_id: SessionId,
+    message: messages::client_to_server::MessageReadMessage,
+  ) -> Result<(), ClientError> {
+    self
+      .acknowledge(sender_id, &message.room_id, Receipt::Read, message.seq)
+      .await
+  }
+
+  pub(crate) async fn message_delivered(
+    &self,
+    sender_id: SessionId,
+    message: messages::client_to_server::MessageReceivedMessage,
+  ) -> Result<(), ClientError> {
+    self
+      .acknowledge(sender_id, &message.room_id, Receipt::Delivered, message.seq)
+      .await
+  }
+
+  async fn acknowledge(
+    &self,
+    sender_id: SessionId,
+    room_id: &str,
+    receipt: Receipt,
+    seq: u64,
+  ) -> Result<(), ClientError> {
+    let room_lock = match self.room(room_id).await {
+      Some(room_lock) => room_lock,
+      None => return Err(ClientError::not_joined(room_id)),
+    };
+
+    let mut room = room_lock.lock().await;
+    if !room.clients.contains_key(&sender_id) {
+      return Err(ClientError::not_joined(room_id));
+    }
+
+    let receipts = room.acknowledge(room_id, sender_id, receipt, seq);
+    room.send_updates(receipts).await;
+
+    let until = seq.saturating_add(1).min(room.next_seq);
+    let (room_id, username) = (
+      room_id.to_owned(),
+      room.clients[&sender_id].username.clone(),
+    );
+    self
+      .store("store the receipt", move |storage| {
+        storage.acknowledge(&room_id, &username, receipt, until)
+      })
+      .await
+  }
+}
+
+/// Flushes the storage every `period` until it's dropped.
+async fn sync_storage(storage: Weak<dyn Storage>, period: Duration) {
+  let mut interval = tokio::time::interval(period);
+  interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+  loop {
+    interval.tick().await;
+
+    let storage = match storage.upgrade() {
+      Some(storage) => storage,
+      None => return,
+    };
+    if let Err(err) = storage::blocking(&storage, |storage| storage.sync()).await {
+      warn!("unable to sync storage. error={:?}", err);
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::collections::HashSet;
+
+  use clap::Parser;
+  use messages::{MessageType, PROTOCOL_VERSION};
+  use tokio::net::{TcpListener, TcpStream};
+
+  use super::*;
+  use crate::outbox::OverflowPolicy;
+
+  fn chat_manager() -> Arc<ChatManager> {
+    ChatManager::open(Arc::new(Config::parse_from(["server"]))).unwrap()
+  }
+
+  /// A session of `username` speaking `protocol_version`, with the other end of its connection.
+  async fn session(username: &str, protocol_version: u16) -> (Session, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let peer = TcpStream::connect(listener.local_addr().unwrap())
+      .await
+      .unwrap();
+    let (socket, socket_addr) = listener.accept().await.unwrap();
+    let (_read_half, write_half) = socket.into_split();
+    let (outbox, _writer) = Outbox::spawn(write_half, 16, OverflowPolicy::Disconnect);
+    outbox.set_protocol_version(protocol_version);
+
+    let session = Session {
+      id: SessionId::next(),
+      socket_addr,
+      username: username.to_owned(),
+      token_id: auth::new_token_id(),
+      capabilities: Capabilities::all(),
+      outbox,
+      rooms: HashSet::new(),
+    };
+    (session, peer)
+  }
+
+  /// Joins `room` with a new session of `username` speaking `protocol_version`,
+  /// returns its id and its end of the socket.
+  async fn join(
+    chat_manager: &ChatManager,
+    room: &str,
+    username: &str,
+    protocol_version: u16,
+  ) -> (SessionId, TcpStream) {
+    let (session, peer) = session(username, protocol_version).await;
+    chat_manager.join_room(&session, room).await.unwrap();
+    (session.id, peer)
+  }
+
+  /// The next message sent to the peer, presence changes aside.
+  async fn read_message(peer: &mut TcpStream) -> ServerToClientMessage {
+    loop {
+      match messages::read_server_message(&mut *peer).await.unwrap() {
+        ServerToClientMessage::UserJoined(_) | ServerToClientMessage::UserLeft(_) => {}
+        message => return message,
+      }
+    }
+  }
+
+  fn chat_message(message_id: u64, contents: &str) -> messages::client_to_server::ChatMessage {
+    messages::client_to_server::ChatMessage {
+      message_id,
+      room_id: "room".to_owned(),
+      contents: contents.to_owned(),
+    }
+  }
+
+  #[tokio::test]
+  async fn messages_are_numbered_and_accepted() {
+    let chat_manager = chat_manager();
+    let (alice_id, mut alice) = join(&chat_manager, "room", "alice", PROTOCOL_VERSION).await;
+    let (_, mut bob) = join(&chat_manager, "room", "bob", PROTOCOL_VERSION).await;
+
+    for (message_id, expected_seq) in [(7, 0), (8, 1)] {
+      chat_manager
+        .message_received(alice_id, chat_message(message_id, "hello"))
+        .await
+        .unwrap();
+
+      match read_message(&mut alice).await {
+        messages::ServerToClientMessage::MessageAccepted(accepted) => {
+          assert_eq!(accepted.message_id, message_id);
+          assert_eq!(accepted.seq, expected_seq);
+        }
+        message => panic!("expected MessageAccepted, got {message:?}"),
+      }
+      match read_message(&mut bob).await {
+        messages::ServerToClientMessage::ChatMessage(message) => {
+          assert_eq!(message.seq, expected_seq);
+          assert_eq!(message.contents, "hello");
+        }
+        message => panic!("expected ChatMessage, got {message:?}"),
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn older_clients_are_not_sent_message_accepted() {
+    let chat_manager = chat_manager();
+    let (alice_id, mut alice) = join(
+      &chat_manager,
+      "room",
+      "alice",
+      MessageType::MessageAccepted.since_version() - 1,
+    )
+    .await;
+    let (bob_id, _bob) = join(&chat_manager, "room", "bob", PROTOCOL_VERSION).await;
+
+    chat_manager
+      .message_received(alice_id, chat_message(7, "hello"))
+      .await
+      .unwrap();
+    chat_manager
+      .message_received(bob_id, chat_message(1, "hi"))
+      .await
+      .unwrap();
+
+    // The first message alice gets is the one from bob.
+    match read_message(&mut alice).await {
+      messages::ServerToClientMessage::ChatMessage(message) => assert_eq!(message.contents, "hi"),
+      message => panic!("expected ChatMessage, got {message:?}"),
+    }
+  }
+
+  #[tokio::test]
+  async fn joining_a_full_room_is_refused() {
+    let chat_manager = ChatManager::open(Arc::new(Config::parse_from([
+      "server",
+      "--max-room-members",
+      "1",
+    ])))
+    .unwrap();
+    join(&chat_manager, "room", "alice", PROTOCOL_VERSION).await;
+
+    let (bob, _bob) = session("bob", PROTOCOL_VERSION).await;
+    let err = chat_manager.join_room(&bob, "room").await.unwrap_err();
+
+    assert_eq!(err.code, ErrorCode::RoomFull);
+  }
+
+  #[tokio::test]
+  async fn messages_to_rooms_not_joined_are_refused() {
+    let chat_manager = chat_manager();
+    join(&chat_manager, "room", "alice", PROTOCOL_VERSION).await;
+
+    let err = chat_manager
+      .message_received(SessionId::next(), chat_message(7, "hello"))
+      .await
+      .unwrap_err();
+
+    assert_eq!(err.code, ErrorCode::NotJoined);
+    assert_eq!(err.related_message_id, Some(7));
+  }
+
+  #[tokio::test]
+  async fn rooms_are_removed_with_their_last_member() {
+    let chat_manager = chat_manager();
+    let (alice, _alice) = session("alice", PROTOCOL_VERSION).await;
+    let (bob, _bob) = session("bob", PROTOCOL_VERSION).await;
+    chat_manager.join_room(&alice, "room").await.unwrap();
+    chat_manager.join_room(&bob, "room").await.unwrap();
+
+    chat_manager.leave_room(&alice, "room").await;
+    assert!(chat_manager.room("room").await.is_some());
+    chat_manager.leave_room(&bob, "room").await;
+    assert!(chat_manager.room("room").await.is_none());
+
+    // Disconnected members are still members.
+    chat_manager.join_room(&alice, "other").await.unwrap();
+    chat_manager.remove_client("other", alice.id).await;
+    assert!(chat_manager.room("other").await.is_some());
+  }
+
+  async fn send(chat_manager: &ChatManager, sender_id: SessionId, room_id: &str) {
+    let message = messages::client_to_server::ChatMessage {
+      message_id: 0,
+      room_id: room_id.to_owned(),
+      contents: "hello".to_owned(),
+    };
+    chat_manager
+      .message_received(sender_id, message)
+      .await
+      .unwrap();
+  }
+
+  /// The recipients, delivered_to and read_by counts of the message, if its receipts are tracked.
+  async fn receipts(
+    chat_manager: &ChatManager,
+    room_id: &str,
+    seq: u64,
+  ) -> Option<(u32, u32, u32)> {
+    let room_lock = chat_manager.room(room_id).await.unwrap();
+    let room = room_lock.lock().await;
+    room
+      .receipts
+      .get(&seq)
+      .map(|receipts| (receipts.recipients, receipts.delivered_to, receipts.read_by))
+  }
+
+  async fn delivered(chat_manager: &ChatManager, session_id: SessionId, room_id: &str, seq: u64) {
+    let received = messages::client_to_server::MessageReceivedMessage {
+      seq,
+      room_id: room_id.to_owned(),
+    };
+    chat_manager
+      .message_delivered(session_id, received)
+      .await
+      .unwrap();
+  }
+
+  async fn read(chat_manager: &ChatManager, session_id: SessionId, room_id: &str, seq: u64) {
+    let read = messages::client_to_server::MessageReadMessage {
+      seq,
+      room_id: room_id.to_owned(),
+    };
+    chat_manager.message_read(session_id, read).await.unwrap();
+  }
+
+  #[tokio::test]
+  async fn receipts_are_cumulative_and_count_every_member_once() {
+    let chat_manager = chat_manager();
+    let (ann, _ann) = join(&chat_manager, "lobby", "ann", PROTOCOL_VERSION).await;
+    let (bob, _bob) = join(&chat_manager, "lobby", "bob", PROTOCOL_VERSION).await;
+    let (cat, _cat) = join(&chat_manager, "lobby", "cat", PROTOCOL_VERSION).await;
+    for _ in 0..3 {
+      send(&chat_manager, ann, "lobby").await;
+    }
+
+    // One receipt acknowledges every message up to its seq.
+    delivered(&chat_manager, bob, "lobby", 2).await;
+    for seq in 0..3 {
+      assert_eq!(receipts(&chat_manager, "lobby", seq).await, Some((2, 1, 0)));
+    }
+    // Reading a message delivers it, receipts going backward or repeated count nothing.
+    read(&chat_manager, cat, "lobby", 1).await;
+    read(&chat_manager, cat, "lobby", 0).await;
+    delivered(&chat_manager, bob, "lobby", 1).await;
+    assert_eq!(receipts(&chat_manager, "lobby", 1).await, Some((2, 2, 1)));
+    assert_eq!(receipts(&chat_manager, "lobby", 2).await, Some((2, 1, 0)));
+
+    // Messages read by everyone aren't tracked anymore.
+    read(&chat_manager, bob, "lobby", 1).await;
+    assert_eq!(receipts(&chat_manager, "lobby", 0).await, None);
+    assert_eq!(receipts(&chat_manager, "lobby", 1).await, None);
+    assert_eq!(receipts(&chat_manager, "lobby", 2).await, Some((2, 1, 0)));
+
+    // Members who join later aren't waited for, and can't acknowledge what was sent before.
+    let (dan, _dan) = join(&chat_manager, "lobby", "dan", PROTOCOL_VERSION).await;
+    read(&chat_manager, dan, "lobby", 2).await;
+    assert_eq!(receipts(&chat_manager, "lobby", 2).await, Some((2, 1, 0)));
+
+    // Messages that haven't been sent yet can't be acknowledged.
+    read(&chat_manager, cat, "lobby", 100).await;
+    send(&chat_manager, ann, "lobby").await;
+    assert_eq!(receipts(&chat_manager, "lobby", 2).await, Some((2, 2, 1)));
+    assert_eq!(receipts(&chat_manager, "lobby", 3).await, Some((3, 0, 0)));
+  }
+
+  #[tokio::test]
+  async fn receipts_count_users_rather_than_clients() {
+    let chat_manager = chat_manager();
+    let (ann, _ann) = join(&chat_manager, "lobby", "ann", PROTOCOL_VERSION).await;
+    let (ann_phone, _ann_phone) = join(&chat_manager, "lobby", "ann", PROTOCOL_VERSION).await;
+    let (bob, _bob) = join(&chat_manager, "lobby", "bob", PROTOCOL_VERSION).await;
+    let (bob_phone, _bob_phone) = join(&chat_manager, "lobby", "bob", PROTOCOL_VERSION).await;
+    join(&chat_manager, "lobby", "cat", PROTOCOL_VERSION).await;
+    send(&chat_manager, ann, "lobby").await;
+    assert_eq!(receipts(&chat_manager, "lobby", 0).await, Some((2, 0, 0)));
+
+    // The other clients of the author aren't recipients.
+    read(&chat_manager, ann_phone, "lobby", 0).await;
+    assert_eq!(receipts(&chat_manager, "lobby", 0).await, Some((2, 0, 0)));
+
+    // A user counts once whichever of its clients acknowledges the message.
+    delivered(&chat_manager, bob, "lobby", 0).await;
+    delivered(&chat_manager, bob_phone, "lobby", 0).await;
+    assert_eq!(receipts(&chat_manager, "lobby", 0).await, Some((2, 1, 0)));
+    read(&chat_manager, bob_phone, "lobby", 0).await;
+    read(&chat_manager, bob, "lobby", 0).await;
+    assert_eq!(receipts(&chat_manager, "lobby", 0).await, Some((2, 1, 1)));
+
+    // A user stays a recipient once its clients are gone, until it leaves the room.
+    chat_manager.remove_client("lobby", bob).await;
+    chat_manager.remove_client("lobby", bob_phone).await;
+    assert_eq!(receipts(&chat_manager, "lobby", 0).await, Some((2, 1, 1)));
+  }
+
+  #[tokio::test]
+  async fn receipts_are_sent_to_the_author_only() {
+    let chat_manager = chat_manager();
+    let (ann, mut ann_peer) = join(&chat_manager, "lobby", "ann", PROTOCOL_VERSION).await;
+    let (bob, _bob) = join(&chat_manager, "lobby", "bob", PROTOCOL_VERSION).await;
+    let (_, mut cat) = join(&chat_manager, "lobby", "cat", PROTOCOL_VERSION).await;
+    send(&chat_manager, ann, "lobby").await;
+
+    read(&chat_manager, bob, "lobby", 0).await;
+
+    assert!(matches!(
+      read_message(&mut ann_peer).await,
+      messages::ServerToClientMessage::MessageAccepted(_)
+    ));
+    match read_message(&mut ann_peer).await {
+      messages::ServerToClientMessage::MessageDelivered(receipt) => {
+        assert_eq!(
+          (receipt.seq, receipt.delivered_to, receipt.recipients),
+          (0, 1, 2)
+        );
+      }
+      message => panic!("expected MessageDelivered, got {message:?}"),
+    }
+    match read_message(&mut ann_peer).await {
+      messages::ServerToClientMessage::MessageRead(receipt) => {
+        assert_eq!(
+          (receipt.seq, receipt.read_by, receipt.recipients),
+          (0, 1, 2)
+        );
+      }
+      message => panic!("expected MessageRead, got {message:?}"),
+    }
+
+    // The other recipient only gets the messages themselves.
+    send(&chat_manager, ann, "lobby").await;
+    for seq in 0..2 {
+      match read_message(&mut cat).await {
+        messages::ServerToClientMessage::ChatMessage(message) => assert_eq!(message.seq, seq),
+        message => panic!("expected ChatMessage, got {message:?}"),
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn messages_sent_while_disconnected_are_delivered_once_connected_again() {
+    let chat_manager = chat_manager();
+    let (alice_id, mut alice) = join(&chat_manager, "room", "alice", PROTOCOL_VERSION).await;
+    let (bob_id, _bob) = join(&chat_manager, "room", "bob", PROTOCOL_VERSION).await;
+    chat_manager.remove_client("room", bob_id).await;
+
+    let mut sent_at = Vec::new();
+    for (message_id, contents) in [(1, "one"), (2, "two")] {
+      let before = Utc::now().timestamp_millis();
+      chat_manager
+        .message_received(alice_id, chat_message(message_id, contents))
+        .await
+        .unwrap();
+      assert!(matches!(
+        read_message(&mut alice).await,
+        ServerToClientMessage::MessageAccepted(_)
+      ));
+      sent_at.push(before..=Utc::now().timestamp_millis());
+    }
+
+    let (bob, mut bob_peer) = session("bob", PROTOCOL_VERSION).await;
+    assert_eq!(chat_manager.user_connected(&bob).await, ["room"]);
+
+    match read_message(&mut bob_peer).await {
+      ServerToClientMessage::RoomJoined(joined) => assert_eq!(joined.room_id, "room"),
+      message => panic!("expected RoomJoined, got {message:?}"),
+    }
+    for (seq, contents) in [(0, "one"), (1, "two")] {
+      match read_message(&mut bob_peer).await {
+        ServerToClientMessage::ChatMessage(message) => {
+          assert_eq!((message.seq, message.contents.as_str()), (seq, contents));
+          // Timestamps are sent to the millisecond.
+          assert!(sent_at[seq as usize].contains(&message.sent_at.timestamp_millis()));
+        }
+        message => panic!("expected ChatMessage, got {message:?}"),
+      }
+    }
+  }
+
+  #[tokio::test]
+  async fn members_are_announced_and_listed_once_per_user() {
+    let chat_manager = chat_manager();
+    let (ann, mut ann_peer) = join(&chat_manager, "lobby", "ann", PROTOCOL_VERSION).await;
+    let (bob, _bob) = join(&chat_manager, "lobby", "bob", PROTOCOL_VERSION).await;
+    let (bob_phone, _bob_phone) = join(&chat_manager, "lobby", "bob", PROTOCOL_VERSION).await;
+
+    let list = messages::client_to_server::ListMembersMessage {
+      room_id: "lobby".to_owned(),
+    };
+    let members = chat_manager.list_members(ann, list).await.unwrap();
+    assert_eq!(members.usernames, ["ann", "bob"]);
+    assert_eq!(members.members, 2);
+
+    // bob is announced as gone once every client of bob is, but stays a member.
+    chat_manager.remove_client("lobby", bob).await;
+    chat_manager.remove_client("lobby", bob_phone).await;
+    let list = messages::client_to_server::ListMembersMessage {
+      room_id: "lobby".to_owned(),
+    };
+    let members = chat_manager.list_members(ann, list).await.unwrap();
+    assert_eq!(members.usernames, ["ann", "bob"]);
+    match messages::read_server_message(&mut ann_peer).await.unwrap() {
+      ServerToClientMessage::UserJoined(joined) => assert_eq!(joined.username, "bob"),
+      message => panic!("expected UserJoined, got {message:?}"),
+    }
+    match messages::read_server_message(&mut ann_peer).await.unwrap() {
+      ServerToClientMessage::UserLeft(left) => assert_eq!(left.username, "bob"),
+      message => panic!("expected UserLeft, got {message:?}"),
+    }
+  }
+
+  #[tokio::test]
+  async fn created_rooms_are_described_listed_and_kept_once_empty() {
+    let chat_manager = chat_manager();
+    let create = messages::client_to_server::CreateRoomMessage {
+      room_id: "lobby".to_owned(),
+      display_name: "The lobby".to_owned(),
+      topic: "say hi".to_owned(),
+    };
+    chat_manager.create_room(&create).await.unwrap();
+    assert_eq!(
+      chat_manager.create_room(&create).await.unwrap_err().code,
+      ErrorCode::RoomExists
+    );
+
+    let (ann, _ann) = session("ann", PROTOCOL_VERSION).await;
+    let (ann_phone, _ann_phone) = session("ann", PROTOCOL_VERSION).await;
+    let (bob, _bob) = session("bob", PROTOCOL_VERSION).await;
+    chat_manager.join_room(&ann, "lobby").await.unwrap();
+    chat_manager.join_room(&ann_phone, "lobby").await.unwrap();
+    chat_manager.join_room(&bob, "other").await.unwrap();
+
+    let describe = |room_id: &str| messages::client_to_server::DescribeRoomMessage {
+      room_id: room_id.to_owned(),
+    };
+    let info = chat_manager.describe_room(describe("lobby")).await.unwrap();
+    assert_eq!(
+      (
+        info.display_name.as_str(),
+        info.topic.as_str(),
+        info.members
+      ),
+      ("The lobby", "say hi", 1)
+    );
+
+    let list = chat_manager
+      .list_rooms(messages::client_to_server::ListRoomsMessage { after: None })
+      .await;
+    let rooms: Vec<_> = list
+      .rooms
+      .iter()
+      .map(|room| {
+        (
+          room.room_id.as_str(),
+          room.display_name.as_str(),
+          room.members,
+        )
+      })
+      .collect();
+    assert_eq!(rooms, [("lobby", "The lobby", 1), ("other", "other", 1)]);
+    assert!(!list.more);
+    let list = chat_manager
+      .list_rooms(messages::client_to_server::ListRoomsMessage {
+        after: Some("lobby".to_owned()),
+      })
+      .await;
+    assert_eq!(list.rooms.len(), 1);
+    assert_eq!(list.rooms[0].room_id, "other");
+
+    // Only rooms created by joining them are removed with their last member.
+    chat_manager.leave_room(&ann, "lobby").await;
+    chat_manager.leave_room(&ann_phone, "lobby").await;
+    chat_manager.leave_room(&bob, "other").await;
+    assert!(chat_manager.describe_room(describe("lobby")).await.is_ok());
+    assert_eq!(
+      chat_manager
+        .describe_room(describe("other"))
+        .await
+        .unwrap_err()
+        .code,
+      ErrorCode::RoomNotFound
+    );
+  }
+
+  #[tokio::test]
+  async fn messages_that_cant_be_sent_are_not_stored() {
+    let data_dir = std::env::temp_dir().join(format!("chat-manager-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&data_dir);
+    let config = Arc::new(Config::parse_from([
+      "server",
+      "--storage",
+      "log",
+      "--data-dir",
+      data_dir.to_str().unwrap(),
+      "--fsync-policy",
+      "always",
+    ]));
+    let chat_manager = ChatManager::open(Arc::clone(&config)).unwrap();
+    let (ann, _ann) = join(&chat_manager, "lobby", "ann", PROTOCOL_VERSION).await;
+
+    let too_long = chat_message(0, &"long ".repeat(messages::MAX_MESSAGE_BYTES));
+    let too_long = messages::client_to_server::ChatMessage {
+      room_id: "lobby".to_owned(),
+      ..too_long
+    };
+    assert!(chat_manager.message_received(ann, too_long).await.is_err());
+    send(&chat_manager, ann, "lobby").await;
+    drop(chat_manager);
+
+    // The message that was sent took the first seq, the next one follows it.
+    let chat_manager = ChatManager::open(config).unwrap();
+    let room_lock = chat_manager.room("lobby").await.unwrap();
+    assert_eq!(room_lock.lock().await.next_seq, 1);
+    std::fs::remove_dir_all(&data_dir).unwrap();
+  }
+
+  #[tokio::test(flavor = "multi_thread")]
+  async fn concurrent_joins_create_the_room_once() {
+    let chat_manager = chat_manager();
+    let mut sessions = Vec::new();
+    for i in 0..16 {
+      sessions.push(session(&format!("user{i}"), PROTOCOL_VERSION).await);
+    }
+
+    let joins = sessions.iter().map(|(session, _)| {
+      let chat_manager = Arc::clone(&chat_manager);
+      async move { chat_manager.join_room(session, "lobby").await.unwrap() }
+    });
+    futures::future::join_all(joins).await;
+
+    let room_lock = chat_manager.room("lobby").await.unwrap();
+    assert_eq!(room_lock.lock().await.clients.len(), sessions.len());
+  }
+
+  #[tokio::test]
+  async fn rooms_with_messages_are_kept_once_everyone_left() {
+    let chat_manager = chat_manager();
+    let (ann, _ann) = session("ann", PROTOCOL_VERSION).await;
+
+    chat_manager.join_room(&ann, "lobby").await.unwrap();
+    send(&chat_manager, ann.id, "lobby").await;
+    chat_manager.leave_room(&ann, "lobby").await;
+    chat_manager.join_room(&ann, "empty").await.unwrap();
+    chat_manager.leave_room(&ann, "empty").await;
+
+    assert!(chat_manager.room("lobby").await.is_some());
+    assert!(chat_manager.room("empty").await.is_none());
+
+    // Joining again continues the sequence.
+    chat_manager.join_room(&ann, "lobby").await.unwrap();
+    send(&chat_manager, ann.id, "lobby").await;
+    let room_lock = chat_manager.room("lobby").await.unwrap();
+    assert_eq!(room_lock.lock().await.next_seq, 2);
+  }
+
+  #[tokio::test]
+  async fn history_is_fetched_in_pages_and_backfilled() {
+    let chat_manager = chat_manager();
+    let (ann, _ann) = join(&chat_manager, "lobby", "ann", PROTOCOL_VERSION).await;
+    for _ in 0..5 {
+      send(&chat_manager, ann, "lobby").await;
+    }
+
+    let fetch = |before: Option<u64>, after: Option<u64>, limit: u32| {
+      let chat_manager = Arc::clone(&chat_manager);
+      async move {
+        let fetch = messages::client_to_server::FetchHistoryMessage {
+          room_id: "lobby".to_owned(),
+          before,
+          after,
+          limit,
+        };
+        let history = chat_manager.fetch_history(ann, fetch).await.unwrap();
+        let seqs: Vec<_> = history.messages.iter().map(|message| message.seq).collect();
+        (seqs, history.more)
+      }
+    };
+    // Backward from the latest messages, then forward.
+    assert_eq!(fetch(None, None, 2).await, (vec![3, 4], true));
+    assert_eq!(fetch(Some(3), None, 2).await, (vec![1, 2], true));
+    assert_eq!(fetch(Some(1), None, 2).await, (vec![0], false));
+    assert_eq!(fetch(None, Some(2), 50).await, (vec![3, 4], false));
+    assert_eq!(fetch(Some(4), Some(0), 2).await, (vec![1, 2], true));
+
+    // Clients that join later are sent the messages before them.
+    let (bob, _bob) = session("bob", PROTOCOL_VERSION).await;
+    let joined_seq = chat_manager.join_room(&bob, "lobby").await.unwrap();
+    assert_eq!(joined_seq, 5);
+    let backfill = chat_manager
+      .backfill("lobby", joined_seq)
+      .await
+      .unwrap()
+      .unwrap();
+    assert_eq!(backfill.messages.len(), 5);
+
+    // Only members can read the history.
+    let fetch = messages::client_to_server::FetchHistoryMessage {
+      room_id: "lobby".to_owned(),
+      before: None,
+      after: None,
+      limit: 1,
+    };
+    let err = chat_manager
+      .fetch_history(SessionId::next(), fetch)
+      .await
+      .unwrap_err();
+    assert_eq!(err.code, ErrorCode::NotJoined);
+  }
+
+  #[tokio::test]
+  async fn history_pages_are_cut_to_fit_in_a_frame() {
+    let chat_manager = chat_manager();
+    let (ann, _ann) = join(&chat_manager, "room", "ann", PROTOCOL_VERSION).await;
+    // Three of them with their username don't fit in `MAX_HISTORY_BYTES`.
+    let large = "l".repeat(messages::MAX_MESSAGE_BYTES);
+    for _ in 0..3 {
+      chat_manager
+        .message_received(ann, chat_message(0, &large))
+        .await
+        .unwrap();
+    }
+
+    let fetch = messages::client_to_server::FetchHistoryMessage {
+      room_id: "room".to_owned(),
+      before: None,
+      after: None,
+      limit: 3,
+    };
+    let history = chat_manager.fetch_history(ann, fetch).await.unwrap();
+    // The latest messages fit, the first one is left for the next page.
+    let seqs: Vec<_> = history.messages.iter().map(|message| message.seq).collect();
+    assert_eq!(seqs, [1, 2]);
+    assert!(history.more);
+  }
+
+  fn credentials(username: &str, password: &str) -> (String, String) {
+    (username.to_owned(), password.to_owned())
+  }
+
+  async fn register(
+    chat_manager: &ChatManager,
+    (username, password): (String, String),
+  ) -> Result<(u64, messages::server_to_client::AuthenticatedMessage), ClientError> {
+    let body = messages::client_to_server::RegisterMessage { username, password };
+    chat_manager.register(body).await
+  }
+
+  async fn login(
+    chat_manager: &ChatManager,
+    (username, password): (String, String),
+  ) -> Result<(u64, messages::server_to_client::AuthenticatedMessage), ClientError> {
+    let body = messages::client_to_server::LoginMessage { username, password };
+    chat_manager.login(body).await
+  }
+
+  #[tokio::test]
+  async fn users_log_in_with_the_password_they_registered() {
+    let chat_manager = chat_manager();
+    let (token_id, authenticated) = register(&chat_manager, credentials("ann", "password"))
+      .await
+      .unwrap();
+    assert_eq!(authenticated.username, "ann");
+
+    let err = register(&chat_manager, credentials("ann", "other password"))
+      .await
+      .unwrap_err();
+    assert_eq!(err.code, ErrorCode::UsernameTaken);
+
+    let (other_token_id, authenticated) = login(&chat_manager, credentials("ann", "password"))
+      .await
+      .unwrap();
+    assert_eq!(authenticated.username, "ann");
+    assert_ne!(other_token_id, token_id);
+
+    for credentials in [
+      credentials("ann", "other password"),
+      credentials("bob", "password"),
+    ] {
+      let err = login(&chat_manager, credentials).await.unwrap_err();
+      assert_eq!(err.code, ErrorCode::AuthenticationFailed);
+    }
+
+    let err = register(&chat_manager, credentials("bob", "short"))
+      .await
+      .unwrap_err();
+    assert_eq!(err.code, ErrorCode::InvalidMessage);
+  }
+
+  #[tokio::test]
+  async fn leaving_leaves_the_receipts_of_own_messages_alone() {
+    let chat_manager = chat_manager();
+    let (ann, _ann) = session("ann", PROTOCOL_VERSION).await;
+    let (bob, _bob) = session("bob", PROTOCOL_VERSION).await;
+    let (cat, _cat) = session("cat", PROTOCOL_VERSION).await;
+
+    chat_manager.join_room(&ann, "lobby").await.unwrap();
+    chat_manager.join_room(&bob, "lobby").await.unwrap();
+    chat_manager.remove_client("lobby", bob.id).await;
+    send(&chat_manager, ann.id, "lobby").await;
+    chat_manager.join_room(&cat, "lobby").await.unwrap();
+    send(&chat_manager, cat.id, "lobby").await;
+    let received = messages::client_to_server::MessageReceivedMessage {
+      seq: 1,
+      room_id: "lobby".to_owned(),
+    };
+    chat_manager
+      .message_delivered(ann.id, received)
+      .await
+      .unwrap();
+    assert_eq!(receipts(&chat_manager, "lobby", 0).await, Some((1, 0, 0)));
+    assert_eq!(receipts(&chat_manager, "lobby", 1).await, Some((2, 1, 0)));
+
+    chat_manager.leave_room(&ann, "lobby").await;
+
+    // Nobody is left to tell about the receipts of ann's message, cat's only waits for bob now.
+    assert_eq!(receipts(&chat_manager, "lobby", 0).await, None);
+    assert_eq!(receipts(&chat_manager, "lobby", 1).await, Some((1, 0, 0)));
+  }
+
+  /// The session that sent the message, if its receipts are tracked.
+  async fn sender(chat_manager: &ChatManager, room_id: &str, seq: u64) -> Option<SessionId> {
+    let room_lock = chat_manager.room(room_id).await.unwrap();
+    let room = room_lock.lock().await;
+    room.receipts.get(&seq).map(|receipts| receipts.sender)
+  }
+
+  /// A new client of the session of `session`.
+  async fn resumed(session: &Session) -> (Session, TcpStream) {
+    let (mut resumed, peer) = self::session(&session.username, PROTOCOL_VERSION).await;
+    resumed.token_id = session.token_id;
+    (resumed, peer)
+  }
+
+  async fn resume(chat_manager: &ChatManager, token: &str) -> Result<u64, ClientError> {
+    let body = messages::client_to_server::ResumeMessage {
+      token: token.to_owned(),
+    };
+    chat_manager
+      .resume(body)
+      .await
+      .map(|(token_id, _)| token_id)
+  }
+
+  #[tokio::test]
+  async fn receipts_wait_for_clients_resuming_their_session() {
+    let chat_manager = chat_manager();
+    let (ann, _ann) = session("ann", PROTOCOL_VERSION).await;
+    let (bob, _bob) = session("bob", PROTOCOL_VERSION).await;
+    let (cat, _cat) = session("cat", PROTOCOL_VERSION).await;
+
+    chat_manager.join_room(&ann, "lobby").await.unwrap();
+    chat_manager.join_room(&bob, "lobby").await.unwrap();
+    chat_manager.join_room(&cat, "lobby").await.unwrap();
+    send(&chat_manager, ann.id, "lobby").await;
+    delivered(&chat_manager, bob.id, "lobby", 0).await;
+
+    // Disconnected members stay recipients, with what they had acknowledged.
+    chat_manager.remove_client("lobby", ann.id).await;
+    chat_manager.remove_client("lobby", bob.id).await;
+    read(&chat_manager, cat.id, "lobby", 0).await;
+    assert_eq!(receipts(&chat_manager, "lobby", 0).await, Some((2, 2, 1)));
+    assert_eq!(sender(&chat_manager, "lobby", 0).await, Some(ann.id));
+
+    // Another session of ann isn't told about the receipts of ann's message, a new client of
+    // the same session is.
+    let (ann_phone, _ann_phone) = session("ann", PROTOCOL_VERSION).await;
+    assert_eq!(chat_manager.user_connected(&ann_phone).await, ["lobby"]);
+    assert_eq!(sender(&chat_manager, "lobby", 0).await, Some(ann.id));
+    let (resumed_ann, _resumed_ann) = resumed(&ann).await;
+    assert_eq!(chat_manager.user_connected(&resumed_ann).await, ["lobby"]);
+    assert_eq!(
+      sender(&chat_manager, "lobby", 0).await,
+      Some(resumed_ann.id)
+    );
+
+    // Bob's new client picks up where the old one left off, what it received isn't counted twice.
+    let (resumed_bob, _resumed_bob) = resumed(&bob).await;
+    assert_eq!(chat_manager.user_connected(&resumed_bob).await, ["lobby"]);
+    delivered(&chat_manager, resumed_bob.id, "lobby", 0).await;
+    assert_eq!(receipts(&chat_manager, "lobby", 0).await, Some((2, 2, 1)));
+    read(&chat_manager, resumed_bob.id, "lobby", 0).await;
+    assert_eq!(receipts(&chat_manager, "lobby", 0).await, None);
+  }
+
+  #[tokio::test]
+  async fn expired_and_logged_out_sessions_cant_be_resumed() {
+    let chat_manager = chat_manager();
+    let (token_id, authenticated) = chat_manager.start_session("ann".to_owned()).await.unwrap();
+    assert_eq!(
+      resume(&chat_manager, &authenticated.token).await.unwrap(),
+      token_id
+    );
+    let err = resume(&chat_manager, "not a token").await.unwrap_err();
+    assert_eq!(err.code, ErrorCode::AuthenticationFailed);
+
+    let (mut ann, _ann) = session("ann", PROTOCOL_VERSION).await;
+    ann.token_id = token_id;
+    assert_eq!(chat_manager.log_out(&ann, false).await.unwrap(), 1);
+    let err = resume(&chat_manager, &authenticated.token)
+      .await
+      .unwrap_err();
+    assert_eq!(err.code, ErrorCode::AuthenticationFailed);
+
+    let (token_id, authenticated) = chat_manager.start_session("ann".to_owned()).await.unwrap();
+    let now = Utc::now();
+    chat_manager
+      .storage
+      .touch_token(token_id, now, now - chrono::Duration::seconds(1))
+      .unwrap();
+    let err = resume(&chat_manager, &authenticated.token)
+      .await
+      .unwrap_err();
+    assert_eq!(err.code, ErrorCode::AuthenticationFailed);
+
+    // Expired tokens are deleted once the user starts another session.
+    let (other_token_id, _) = chat_manager.start_session("ann".to_owned()).await.unwrap();
+    let token_ids: Vec<u64> = chat_manager
+      .storage
+      .tokens("ann")
+      .unwrap()
+      .iter()
+      .map(|token| token.token_id)
+      .collect();
+    assert_eq!(token_ids, [other_token_id]);
+  }
+}
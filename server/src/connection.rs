@@ -0,0 +1,868 @@
+use std::{collections::HashSet, net::SocketAddr, sync::Arc, time::Duration};
+
+use anyhow::Result;
+use messages::{
+  server_to_client::ErrorCode, Capabilities, ProtocolError, ServerToClientMessage,
+  MIN_PROTOCOL_VERSION, PROTOCOL_VERSION,
+};
+use tokio::net::{tcp::OwnedReadHalf, TcpStream};
+use tracing::{error, info, warn};
+
+use crate::{
+  chat_manager::ChatManager,
+  error::ClientError,
+  outbox::{Outbox, OutboxError},
+  session::{Session, SessionId},
+  Config, UnknownFramePolicy,
+};
+
+/// How long to wait for the messages queued for a client to be written once its connection is closing.
+const OUTBOX_FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a client has to send Hello once connected.
+const HELLO_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How long a client has to register or log in once the handshake is done.
+const AUTHENTICATION_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// The connection is closed after this many failed attempts to register or log in.
+const MAX_AUTHENTICATION_ATTEMPTS: u32 = 5;
+
+pub(crate) async fn handle_connection(
+  socket: TcpStream,
+  socket_addr: SocketAddr,
+  config: Arc<Config>,
+  chat_manager: Arc<ChatManager>,
+) {
+  let (read_half, write_half) = socket.into_split();
+  let (outbox, mut writer) = Outbox::spawn(
+    write_half,
+    config.outbox_capacity,
+    config.outbox_overflow_policy,
+  );
+
+  run_connection(read_half, &outbox, socket_addr, config, chat_manager).await;
+
+  // Let the writer send what's left, an error message explaining why the connection is closed for example.
+  outbox.close();
+  if tokio::time::timeout(OUTBOX_FLUSH_TIMEOUT, &mut writer)
+    .await
+    .is_err()
+  {
+    warn!(
+      "unable to write queued messages in time, dropping them. socket_addr={:?}",
+      socket_addr
+    );
+    writer.abort();
+  }
+}
+
+async fn run_connection(
+  mut read_half: OwnedReadHalf,
+  outbox: &Outbox,
+  socket_addr: SocketAddr,
+  config: Arc<Config>,
+  chat_manager: Arc<ChatManager>,
+) {
+  let message = tokio::time::timeout(HELLO_TIMEOUT, async {
+    if let Err(err) = read_half.readable().await {
+      error!("socket is not readable. error={:?}", err);
+    }
+    read_message(&config, socket_addr, &mut read_half, outbox).await
+  })
+  .await;
+  let message = match message {
+    Ok(Some(message)) => message,
+    Ok(None) => return,
+    Err(_) => {
+      info!(
+        "client didn't send Hello in time, closing connection. socket_addr={:?}",
+        socket_addr
+      );
+      return;
+    }
+  };
+
+  let hello = match message {
+    messages::ClientToServerMessage::Hello(hello) => hello,
+    message => {
+      error!(
+        "expected Hello message, closing connection. socket_addr={:?} message={:?}",
+        socket_addr, message
+      );
+      let _ = send_error(
+        outbox,
+        &ClientError::new(ErrorCode::UnexpectedMessage, "expected Hello message"),
+      )
+      .await;
+      return;
+    }
+  };
+
+  let welcome = match handshake(outbox, hello).await {
+    Err(err) => {
+      error!(
+        "handshake failed. socket_addr={:?} error={:?}",
+        socket_addr, err
+      );
+      return;
+    }
+    Ok(None) => return,
+    Ok(Some(welcome)) => welcome,
+  };
+
+  let authenticated = tokio::time::timeout(
+    AUTHENTICATION_TIMEOUT,
+    authenticate(&config, socket_addr, &mut read_half, outbox, &chat_manager),
+  )
+  .await;
+  let (username, token_id) = match authenticated {
+    Ok(Some(authenticated)) => authenticated,
+    Ok(None) => return,
+    Err(_) => {
+      info!(
+        "client didn't log in in time, closing connection. socket_addr={:?}",
+        socket_addr
+      );
+      return;
+    }
+  };
+
+  let mut session = Session {
+    id: SessionId::next(),
+    socket_addr,
+    username,
+    token_id,
+    capabilities: welcome.capabilities,
+    outbox: outbox.clone(),
+    rooms: HashSet::new(),
+  };
+
+  info!(
+    "session started. socket_addr={:?} session_id={}",
+    socket_addr, session.id
+  );
+  for room_id in chat_manager.user_connected(&session).await {
+    info!(
+      "client rejoined room. socket_addr={:?} room_id={}",
+      socket_addr, room_id
+    );
+    session.rooms.insert(room_id);
+  }
+
+  let heartbeat_task = welcome
+    .capabilities
+    .contains(Capabilities::HEARTBEATS)
+    .then(|| {
+      tokio::spawn(send_heartbeats(
+        Duration::from_secs(config.heartbeat_interval_secs),
+        outbox.clone(),
+      ))
+    });
+
+  loop {
+    let message = tokio::select! {
+      message = read_message(&config, socket_addr, &mut read_half, outbox) => message,
+      _ = tokio::time::sleep(Duration::from_secs(config.idle_timeout_secs)) => {
+        info!(
+          "client has been idle for too long, closing connection. socket_addr={:?}",
+          socket_addr
+        );
+        break;
+      }
+      _ = outbox.closed() => {
+        info!(
+          "client is not reading its messages or was logged out, closing connection. socket_addr={:?}",
+          socket_addr
+        );
+        break;
+      }
+    };
+
+    let message = match message {
+      None => break,
+      Some(v) => v,
+    };
+
+    let message = match message {
+      messages::ClientToServerMessage::Ping(ping) => {
+        let pong = messages::server_to_client::PongMessage { nonce: ping.nonce };
+        if let Err(err) = outbox.send(ServerToClientMessage::Pong(pong)).await {
+          error!("unable to send pong message. error={}", err);
+          break;
+        }
+        continue;
+      }
+      // Receiving anything resets the idle timeout, there's nothing else to do.
+      messages::ClientToServerMessage::Pong(_) => continue,
+      message => message,
+    };
+
+    if let Err(err) = handle_message(&chat_manager, &mut session, message).await {
+      info!(
+        "unable to handle message. socket_addr={:?} error={}",
+        socket_addr, err
+      );
+      if let Err(err) = send_error(outbox, &err).await {
+        error!("unable to send error message. error={}", err);
+        break;
+      }
+    }
+  }
+
+  for room_id in session.rooms.drain() {
+    chat_manager.remove_client(&room_id, session.id).await;
+  }
+  chat_manager.session_ended(&session).await;
+
+  if let Some(heartbeat_task) = heartbeat_task {
+    heartbeat_task.abort();
+  }
+}
+
+/// Reads the next message sent by the client, frames with an unknown message type
+/// are handled according to `Config::unknown_frame_policy`.
+/// Returns `None` if the connection should be closed.
+async fn read_message(
+  config: &Config,
+  socket_addr: SocketAddr,
+  read_half: &mut OwnedReadHalf,
+  outbox: &Outbox,
+) -> Option<messages::ClientToServerMessage> {
+  loop {
+    match messages::read_client_message(&mut *read_half).await {
+      Ok(message) => return Some(message),
+      Err(ProtocolError::UnknownMessageType(message_type)) => {
+        warn!(
+          "unknown message type. socket_addr={:?} message_type={} policy={:?}",
+          socket_addr, message_type, config.unknown_frame_policy
+        );
+
+        match config.unknown_frame_policy {
+          UnknownFramePolicy::Skip => {}
+          UnknownFramePolicy::Reply => {
+            let err = ProtocolError::UnknownMessageType(message_type);
+            if let Err(err) = send_error(outbox, &ClientError::from(&err)).await {
+              error!("unable to send error message. error={}", err);
+              return None;
+            }
+          }
+          UnknownFramePolicy::Disconnect => return None,
+        }
+      }
+      Err(err) if err.is_fatal() => {
+        error!(
+          "unable to read message, closing connection. socket_addr={:?} error={:?}",
+          socket_addr, err
+        );
+        if !matches!(err, ProtocolError::Io(_)) {
+          let _ = send_error(outbox, &ClientError::from(&err)).await;
+        }
+        return None;
+      }
+      Err(err) => {
+        info!(
+          "received invalid message. socket_addr={:?} error={:?}",
+          socket_addr, err
+        );
+        if let Err(err) = send_error(outbox, &ClientError::from(&err)).await {
+          error!("unable to send error message. error={}", err);
+          return None;
+        }
+      }
+    }
+  }
+}
+
+/// Replies to a `HelloMessage`, returns the `WelcomeMessage` with the negotiated version and capabilities
+/// or `None` if the client was rejected.
+async fn handshake(
+  outbox: &Outbox,
+  hello: messages::client_to_server::HelloMessage,
+) -> Result<Option<messages::server_to_client::WelcomeMessage>> {
+  if hello.protocol_version < MIN_PROTOCOL_VERSION {
+    let reason = format!(
+      "protocol version {} is not supported, the server supports versions {} to {}",
+      hello.protocol_version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+    );
+    info!("rejecting client. reason={}", &reason);
+
+    outbox
+      .send(ServerToClientMessage::HelloRejected(
+        messages::server_to_client::HelloRejectedMessage {
+          protocol_version: PROTOCOL_VERSION,
+          reason,
+        },
+      ))
+      .await?;
+
+    return Ok(None);
+  }
+
+  // Newer clients are expected to downgrade to the version spoken by the server.
+  let protocol_version = hello.protocol_version.min(PROTOCOL_VERSION);
+  let capabilities = hello.capabilities.intersection(Capabilities::all());
+
+  let welcome = messages::server_to_client::WelcomeMessage {
+    protocol_version,
+    capabilities,
+  };
+
+  outbox.set_protocol_version(protocol_version);
+  outbox
+    .send(ServerToClientMessage::Welcome(welcome.clone()))
+    .await?;
+
+  Ok(Some(welcome))
+}
+
+/// Waits for the client to register, log in or resume a session, heartbeats are answered meanwhile.
+/// Returns the username of its account and the id of its session token,
+/// or `None` if the connection should be closed.
+async fn authenticate(
+  config: &Config,
+  socket_addr: SocketAddr,
+  read_half: &mut OwnedReadHalf,
+  outbox: &Outbox,
+  chat_manager: &ChatManager,
+) -> Option<(String, u64)> {
+  let mut attempts = 0;
+  loop {
+    let result = match read_message(config, socket_addr, read_half, outbox).await? {
+      messages::ClientToServerMessage::Register(message) => chat_manager.register(message).await,
+      messages::ClientToServerMessage::Login(message) => chat_manager.login(message).await,
+      messages::ClientToServerMessage::Resume(message) => chat_manager.resume(message).await,
+      messages::ClientToServerMessage::Ping(ping) => {
+        let pong = messages::server_to_client::PongMessage { nonce: ping.nonce };
+        if let Err(err) = outbox.send(ServerToClientMessage::Pong(pong)).await {
+          error!("unable to send pong message. error={}", err);
+          return None;
+        }
+        continue;
+      }
+      messages::ClientToServerMessage::Pong(_) => continue,
+      _ => {
+        let err = ClientError::new(ErrorCode::NotAuthenticated, "register or log in first");
+        if let Err(err) = send_error(outbox, &err).await {
+          error!("unable to send error message. error={}", err);
+          return None;
+        }
+        continue;
+      }
+    };
+
+    match result {
+      Ok((token_id, authenticated)) => {
+        let username = authenticated.username.clone();
+        info!(
+          "client logged in. socket_addr={:?} username={} token_id={}",
+          socket_addr, username, token_id
+        );
+        if let Err(err) = outbox
+          .send(ServerToClientMessage::Authenticated(authenticated))
+          .await
+        {
+          error!("unable to send authenticated message. error={}", err);
+          return None;
+        }
+        return Some((username, token_id));
+      }
+      Err(err) => {
+        info!(
+          "unable to log client in. socket_addr={:?} error={}",
+          socket_addr, err
+        );
+        if let Err(err) = send_error(outbox, &err).await {
+          error!("unable to send error message. error={}", err);
+          return None;
+        }
+        attempts += 1;
+        if attempts >= MAX_AUTHENTICATION_ATTEMPTS {
+          info!(
+            "too many failed attempts to log in, closing connection. socket_addr={:?}",
+            socket_addr
+          );
+          return None;
+        }
+      }
+    }
+  }
+}
+
+/// Pings the client every `interval` until the outbox is closed.
+async fn send_heartbeats(interval: Duration, outbox: Outbox) {
+  let mut interval = tokio::time::interval(interval);
+  // The first tick completes immediately.
+  interval.tick().await;
+
+  for nonce in 0.. {
+    interval.tick().await;
+
+    let ping = messages::server_to_client::PingMessage { nonce };
+    if let Err(err) = outbox.send(ServerToClientMessage::Ping(ping)).await {
+      info!(
+        "unable to send ping message, stopping heartbeats. error={}",
+        err
+      );
+      return;
+    }
+  }
+}
+
+async fn send_error(outbox: &Outbox, error: &ClientError) -> Result<(), OutboxError> {
+  outbox
+    .send(ServerToClientMessage::Error(error.to_message()))
+    .await
+}
+
+async fn handle_message(
+  chat_manager: &ChatManager,
+  session: &mut Session,
+  message: messages::ClientToServerMessage,
+) -> Result<(), ClientError> {
+  match message {
+    messages::ClientToServerMessage::Hello(_message) => Err(ClientError::new(
+      ErrorCode::UnexpectedMessage,
+      "Hello message received twice",
+    )),
+    messages::ClientToServerMessage::Register(_)
+    | messages::ClientToServerMessage::Login(_)
+    | messages::ClientToServerMessage::Resume(_) => Err(ClientError::new(
+      ErrorCode::UnexpectedMessage,
+      "the client is already logged in",
+    )),
+    messages::ClientToServerMessage::ListSessions(_) => {
+      let sessions = chat_manager.list_sessions(session).await?;
+      reply(session, ServerToClientMessage::SessionList(sessions)).await;
+      Ok(())
+    }
+    messages::ClientToServerMessage::LogOut(message) => {
+      let sessions = chat_manager.log_out(session, message.others).await?;
+      let logged_out = messages::server_to_client::LoggedOutMessage { sessions };
+      reply(session, ServerToClientMessage::LoggedOut(logged_out)).await;
+      if !message.others {
+        // The reply is still written, then the connection is closed.
+        session.outbox.close();
+      }
+      Ok(())
+    }
+    messages::ClientToServerMessage::JoinRoom(message) => {
+      join_room(chat_manager, session, message).await
+    }
+    messages::ClientToServerMessage::LeaveRoom(message) => {
+      leave_room(chat_manager, session, message).await
+    }
+    messages::ClientToServerMessage::ListMembers(message) => {
+      let members = chat_manager.list_members(session.id, message).await?;
+      reply(session, ServerToClientMessage::MemberList(members)).await;
+      Ok(())
+    }
+    messages::ClientToServerMessage::ListRooms(message) => {
+      let rooms = chat_manager.list_rooms(message).await;
+      reply(session, ServerToClientMessage::RoomList(rooms)).await;
+      Ok(())
+    }
+    messages::ClientToServerMessage::DescribeRoom(message) => {
+      let info = chat_manager.describe_room(message).await?;
+      reply(session, ServerToClientMessage::RoomInfo(info)).await;
+      Ok(())
+    }
+    messages::ClientToServerMessage::FetchHistory(message) => {
+      let history = chat_manager.fetch_history(session.id, message).await?;
+      reply(session, ServerToClientMessage::History(history)).await;
+      Ok(())
+    }
+    messages::ClientToServerMessage::Search(message) => {
+      let results = chat_manager.search(session.id, message).await?;
+      reply(session, ServerToClientMessage::SearchResults(results)).await;
+      Ok(())
+    }
+    messages::ClientToServerMessage::CreateRoom(message) => {
+      chat_manager.create_room(&message).await?;
+      info!(
+        "client created room. socket_addr={:?} room_id={}",
+        session.socket_addr, message.room_id
+      );
+      let join = messages::client_to_server::JoinRoomMessage {
+        room_id: message.room_id,
+      };
+      join_room(chat_manager, session, join).await
+    }
+    messages::ClientToServerMessage::ChatMessage(message) => {
+      chat_manager.message_received(session.id, message).await
+    }
+    messages::ClientToServerMessage::MessageReceived(message) => {
+      chat_manager.message_delivered(session.id, message).await
+    }
+    messages::ClientToServerMessage::MessageRead(message) => {
+      chat_manager.message_read(session.id, message).await
+    }
+    messages::ClientToServerMessage::Ping(_) | messages::ClientToServerMessage::Pong(_) => {
+      unreachable!("heartbeats are handled by the connection")
+    }
+  }
+}
+
+/// Joining a room the client is already a member of only confirms it again.
+/// The latest messages of the room follow the confirmation of a new member.
+async fn join_room(
+  chat_manager: &ChatManager,
+  session: &mut Session,
+  message: messages::client_to_server::JoinRoomMessage,
+) -> Result<(), ClientError> {
+  let mut joined_seq = None;
+  if !session.rooms.contains(&message.room_id) {
+    joined_seq = Some(chat_manager.join_room(session, &message.room_id).await?);
+    session.rooms.insert(message.room_id.clone());
+
+    info!(
+      "client joined room. socket_addr={:?} room_id={}",
+      session.socket_addr, message.room_id
+    );
+  }
+
+  let joined = messages::server_to_client::RoomJoinedMessage {
+    room_id: message.room_id.clone(),
+  };
+  reply(session, ServerToClientMessage::RoomJoined(joined)).await;
+
+  if let Some(joined_seq) = joined_seq {
+    if let Some(history) = chat_manager.backfill(&message.room_id, joined_seq).await? {
+      reply(session, ServerToClientMessage::History(history)).await;
+    }
+  }
+
+  Ok(())
+}
+
+async fn leave_room(
+  chat_manager: &ChatManager,
+  session: &mut Session,
+  message: messages::client_to_server::LeaveRoomMessage,
+) -> Result<(), ClientError> {
+  if !session.rooms.remove(&message.room_id) {
+    return Err(ClientError::not_joined(&message.room_id));
+  }
+
+  chat_manager.leave_room(session, &message.room_id).await;
+
+  info!(
+    "client left room. socket_addr={:?} room_id={}",
+    session.socket_addr, message.room_id
+  );
+
+  let left = messages::server_to_client::RoomLeftMessage {
+    room_id: message.room_id,
+  };
+  reply(session, ServerToClientMessage::RoomLeft(left)).await;
+
+  Ok(())
+}
+
+/// Sends the answer to a request.
+async fn reply(session: &Session, message: ServerToClientMessage) {
+  // The connection is closed once the outbox is, there's nothing else to do.
+  if let Err(err) = session.outbox.send(message).await {
+    info!("unable to send reply. error={}", err);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use clap::Parser;
+  use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::TcpListener,
+  };
+
+  use super::*;
+  use crate::outbox::OverflowPolicy;
+
+  /// Returns a connected socket and the peer at the other end of it.
+  async fn connect() -> (TcpStream, SocketAddr, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let peer = TcpStream::connect(listener.local_addr().unwrap())
+      .await
+      .unwrap();
+    let (socket, socket_addr) = listener.accept().await.unwrap();
+    (socket, socket_addr, peer)
+  }
+
+  /// Runs the handshake for `hello` and returns what the server answered.
+  async fn answer(
+    hello: messages::client_to_server::HelloMessage,
+  ) -> messages::ServerToClientMessage {
+    let (socket, _, mut peer) = connect().await;
+    let (_read_half, write_half) = socket.into_split();
+    let (outbox, _writer) = Outbox::spawn(write_half, 16, OverflowPolicy::Disconnect);
+
+    handshake(&outbox, hello).await.unwrap();
+
+    messages::read_server_message(&mut peer).await.unwrap()
+  }
+
+  #[tokio::test]
+  async fn newer_clients_are_welcomed_with_the_version_of_the_server() {
+    let answer = answer(messages::client_to_server::HelloMessage {
+      protocol_version: PROTOCOL_VERSION + 1,
+      capabilities: Capabilities::from_bits(u32::MAX),
+    })
+    .await;
+
+    match answer {
+      messages::ServerToClientMessage::Welcome(welcome) => {
+        assert_eq!(welcome.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(welcome.capabilities, Capabilities::all());
+      }
+      answer => panic!("expected Welcome, got {answer:?}"),
+    }
+  }
+
+  #[tokio::test]
+  async fn clients_older_than_the_minimum_version_are_rejected() {
+    let answer = answer(messages::client_to_server::HelloMessage {
+      protocol_version: MIN_PROTOCOL_VERSION - 1,
+      capabilities: Capabilities::NONE,
+    })
+    .await;
+
+    match answer {
+      messages::ServerToClientMessage::HelloRejected(rejected) => {
+        assert_eq!(rejected.protocol_version, PROTOCOL_VERSION);
+      }
+      answer => panic!("expected HelloRejected, got {answer:?}"),
+    }
+  }
+
+  /// Sends a frame of an unknown message type followed by a `JoinRoom`,
+  /// returns what the server read and what it answered.
+  async fn read_unknown_frame(
+    policy: &str,
+  ) -> (
+    Option<messages::ClientToServerMessage>,
+    Option<messages::ServerToClientMessage>,
+  ) {
+    let config = Config::parse_from(["server", "--unknown-frame-policy", policy]);
+    let (socket, socket_addr, mut peer) = connect().await;
+    let (mut read_half, write_half) = socket.into_split();
+    let (outbox, writer) = Outbox::spawn(write_half, 16, OverflowPolicy::Disconnect);
+
+    // A frame of type 200 with two bytes of fields, the server has to skip them.
+    peer.write_all(&[0, 0, 0, 3, 200, 1, 2]).await.unwrap();
+    messages::client_to_server::write_join_room_message(
+      &mut peer,
+      messages::client_to_server::JoinRoomMessage {
+        room_id: "room".to_owned(),
+      },
+    )
+    .await
+    .unwrap();
+
+    let message = read_message(&config, socket_addr, &mut read_half, &outbox).await;
+    outbox.close();
+    writer.await.unwrap();
+    drop(read_half);
+
+    let answer = messages::read_server_message(&mut peer).await.ok();
+    (message, answer)
+  }
+
+  #[tokio::test]
+  async fn unknown_frames_are_skipped() {
+    let (message, answer) = read_unknown_frame("skip").await;
+    assert!(matches!(
+      message,
+      Some(messages::ClientToServerMessage::JoinRoom(join)) if join.room_id == "room"
+    ));
+    assert!(answer.is_none());
+  }
+
+  #[tokio::test]
+  async fn unknown_frames_are_answered_with_an_error() {
+    let (message, answer) = read_unknown_frame("reply").await;
+    assert!(matches!(
+      message,
+      Some(messages::ClientToServerMessage::JoinRoom(join)) if join.room_id == "room"
+    ));
+    assert!(matches!(
+      answer,
+      Some(messages::ServerToClientMessage::Error(_))
+    ));
+  }
+
+  #[tokio::test]
+  async fn unknown_frames_close_the_connection() {
+    let (message, answer) = read_unknown_frame("disconnect").await;
+    assert!(message.is_none());
+    assert!(answer.is_none());
+  }
+
+  #[tokio::test(start_paused = true)]
+  async fn clients_that_dont_send_hello_are_disconnected() {
+    let config = Arc::new(Config::parse_from(["server"]));
+    let chat_manager = ChatManager::open(Arc::clone(&config)).unwrap();
+    let (socket, socket_addr, mut peer) = connect().await;
+
+    let connection = tokio::spawn(handle_connection(socket, socket_addr, config, chat_manager));
+
+    // Time only advances while the connection is waiting for Hello.
+    let closed = tokio::time::timeout(
+      HELLO_TIMEOUT + Duration::from_secs(1),
+      peer.read(&mut [0; 1]),
+    )
+    .await;
+    assert_eq!(closed.unwrap().unwrap(), 0);
+    connection.await.unwrap();
+  }
+
+  // In real time, a paused clock would jump past the deadline while the closing connection
+  // waits for the storage.
+  #[tokio::test]
+  async fn idle_clients_are_disconnected_without_heartbeats() {
+    let config = Arc::new(Config::parse_from(["server", "--idle-timeout-secs", "1"]));
+    let idle_timeout = Duration::from_secs(config.idle_timeout_secs);
+    let chat_manager = ChatManager::open(Arc::clone(&config)).unwrap();
+    let (socket, socket_addr, mut peer) = connect().await;
+
+    let connection = tokio::spawn(handle_connection(socket, socket_addr, config, chat_manager));
+    enter(&mut peer, "room").await;
+
+    let closed = tokio::time::timeout(
+      idle_timeout + Duration::from_secs(5),
+      peer.read(&mut [0; 1]),
+    )
+    .await;
+    assert_eq!(closed.unwrap().unwrap(), 0);
+    connection.await.unwrap();
+  }
+
+  /// Sends Hello without capabilities, registers ann and joins `room`,
+  /// waits for the server to welcome the client, log it in and confirm that it joined.
+  async fn enter(peer: &mut TcpStream, room: &str) {
+    messages::client_to_server::write_hello_message(
+      &mut *peer,
+      messages::client_to_server::HelloMessage {
+        protocol_version: PROTOCOL_VERSION,
+        capabilities: Capabilities::NONE,
+      },
+    )
+    .await
+    .unwrap();
+    messages::client_to_server::write_register_message(
+      &mut *peer,
+      messages::client_to_server::RegisterMessage {
+        username: "ann".to_owned(),
+        password: "correct horse".to_owned(),
+      },
+    )
+    .await
+    .unwrap();
+    messages::client_to_server::write_join_room_message(
+      &mut *peer,
+      messages::client_to_server::JoinRoomMessage {
+        room_id: room.to_owned(),
+      },
+    )
+    .await
+    .unwrap();
+    assert!(matches!(
+      messages::read_server_message(&mut *peer).await.unwrap(),
+      messages::ServerToClientMessage::Welcome(_)
+    ));
+    assert!(matches!(
+      messages::read_server_message(&mut *peer).await.unwrap(),
+      messages::ServerToClientMessage::Authenticated(_)
+    ));
+    assert!(matches!(
+      messages::read_server_message(&mut *peer).await.unwrap(),
+      messages::ServerToClientMessage::RoomJoined(joined) if joined.room_id == room
+    ));
+  }
+
+  /// Connects a client to a server where rooms hold a single member and makes it join `room`.
+  async fn connect_alone(room: &str) -> (Arc<ChatManager>, TcpStream, tokio::task::JoinHandle<()>) {
+    let config = Arc::new(Config::parse_from(["server", "--max-room-members", "1"]));
+    let chat_manager = ChatManager::open(Arc::clone(&config)).unwrap();
+    let (socket, socket_addr, mut peer) = connect().await;
+
+    let connection = tokio::spawn(handle_connection(
+      socket,
+      socket_addr,
+      config,
+      Arc::clone(&chat_manager),
+    ));
+    enter(&mut peer, room).await;
+    (chat_manager, peer, connection)
+  }
+
+  /// Joins `room` as bob with a client that never reads what it's sent.
+  async fn join(chat_manager: &ChatManager, room: &str) -> Result<u64, ClientError> {
+    let (socket, socket_addr, _peer) = connect().await;
+    let (_read_half, write_half) = socket.into_split();
+    let (outbox, _writer) = Outbox::spawn(write_half, 16, OverflowPolicy::Disconnect);
+    let session = Session {
+      id: SessionId::next(),
+      socket_addr,
+      username: "bob".to_owned(),
+      token_id: crate::auth::new_token_id(),
+      capabilities: Capabilities::all(),
+      outbox,
+      rooms: HashSet::new(),
+    };
+    chat_manager.join_room(&session, room).await
+  }
+
+  async fn leave(peer: &mut TcpStream, room: &str) {
+    messages::client_to_server::write_leave_room_message(
+      &mut *peer,
+      messages::client_to_server::LeaveRoomMessage {
+        room_id: room.to_owned(),
+      },
+    )
+    .await
+    .unwrap();
+  }
+
+  #[tokio::test]
+  async fn leaving_frees_the_place_in_the_room() {
+    let (chat_manager, mut peer, _connection) = connect_alone("room").await;
+    assert_eq!(
+      join(&chat_manager, "room").await.unwrap_err().code,
+      ErrorCode::RoomFull
+    );
+
+    leave(&mut peer, "room").await;
+    assert!(matches!(
+      messages::read_server_message(&mut peer).await.unwrap(),
+      messages::ServerToClientMessage::RoomLeft(left) if left.room_id == "room"
+    ));
+    join(&chat_manager, "room").await.unwrap();
+
+    // The client stays connected and can join other rooms.
+    messages::client_to_server::write_join_room_message(
+      &mut peer,
+      messages::client_to_server::JoinRoomMessage {
+        room_id: "other".to_owned(),
+      },
+    )
+    .await
+    .unwrap();
+    assert!(matches!(
+      messages::read_server_message(&mut peer).await.unwrap(),
+      messages::ServerToClientMessage::RoomJoined(joined) if joined.room_id == "other"
+    ));
+  }
+
+  #[tokio::test]
+  async fn disconnected_members_keep_their_place_in_the_room() {
+    let (chat_manager, peer, connection) = connect_alone("room").await;
+
+    drop(peer);
+    connection.await.unwrap();
+
+    assert_eq!(
+      join(&chat_manager, "room").await.unwrap_err().code,
+      ErrorCode::RoomFull
+    );
+  }
+}
@@ -0,0 +1,74 @@
+use std::fmt;
+
+use messages::{server_to_client::ErrorCode, ProtocolError};
+
+/// An error caused by a client, it is reported back to the client as an `ErrorMessage`.
+#[derive(Debug)]
+pub struct ClientError {
+  pub code: ErrorCode,
+  pub message: String,
+  /// The `message_id` of the chat message that caused the error, if any.
+  pub related_message_id: Option<u64>,
+}
+
+impl ClientError {
+  pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+    Self {
+      code,
+      message: message.into(),
+      related_message_id: None,
+    }
+  }
+
+  pub fn related_to(mut self, message_id: u64) -> Self {
+    self.related_message_id = Some(message_id);
+    self
+  }
+
+  pub fn not_joined(room_id: &str) -> Self {
+    Self::new(
+      ErrorCode::NotJoined,
+      format!("you have not joined room {room_id}"),
+    )
+  }
+
+  pub fn to_message(&self) -> messages::server_to_client::ErrorMessage {
+    messages::server_to_client::ErrorMessage {
+      message: self.message.clone(),
+      code: self.code,
+      related_message_id: self.related_message_id,
+    }
+  }
+}
+
+impl fmt::Display for ClientError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(
+      f,
+      "{}: {}. related_message_id={:?}",
+      self.code.description(),
+      self.message,
+      self.related_message_id
+    )
+  }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<&ProtocolError> for ClientError {
+  fn from(err: &ProtocolError) -> Self {
+    let code = match err {
+      ProtocolError::UnknownMessageType(_) => ErrorCode::UnknownMessageType,
+      ProtocolError::FrameTooLarge { .. } | ProtocolError::FieldTooLarge { .. } => {
+        ErrorCode::MessageTooLarge
+      }
+      ProtocolError::UnexpectedMessageType(_) => ErrorCode::UnexpectedMessage,
+      ProtocolError::Truncated { .. }
+      | ProtocolError::InvalidUtf8 { .. }
+      | ProtocolError::InvalidTimestamp { .. } => ErrorCode::InvalidMessage,
+      ProtocolError::Io(_) => ErrorCode::Internal,
+    };
+
+    Self::new(code, err.to_string())
+  }
+}
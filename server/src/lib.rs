@@ -0,0 +1,107 @@
+use std::{path::PathBuf, sync::Arc};
+
+use anyhow::Result;
+use chat_manager::ChatManager;
+use clap::{Parser, ValueEnum};
+use outbox::OverflowPolicy;
+use retention::RetentionPolicy;
+use storage::{FsyncPolicy, StorageKind};
+use tokio::net::TcpListener;
+
+mod auth;
+mod chat_manager;
+mod connection;
+mod error;
+mod outbox;
+mod retention;
+mod search;
+mod session;
+mod storage;
+
+#[derive(Debug, Clone, Parser)]
+#[command(author, version, about, long_about = None)]
+pub struct Config {
+  /// What to do when a client sends a message type unknown to the server.
+  #[arg(long, value_enum, default_value_t = UnknownFramePolicy::Reply)]
+  unknown_frame_policy: UnknownFramePolicy,
+  /// Maximum number of clients in a room.
+  #[arg(long, default_value_t = 256)]
+  max_room_members: usize,
+  /// How often to send a ping to clients.
+  #[arg(long, default_value_t = 15)]
+  heartbeat_interval_secs: u64,
+  /// Connections that don't send anything for this long are closed.
+  #[arg(long, default_value_t = 45)]
+  idle_timeout_secs: u64,
+  /// Maximum number of messages waiting to be written to a client.
+  #[arg(long, default_value_t = 1024)]
+  outbox_capacity: usize,
+  /// What to do when a client doesn't read its messages fast enough.
+  #[arg(long, value_enum, default_value_t = OverflowPolicy::Disconnect)]
+  outbox_overflow_policy: OverflowPolicy,
+  /// How many of the latest messages of a room are sent to clients that join it.
+  #[arg(long, default_value_t = 20)]
+  backfill_messages: u32,
+  /// How many of the messages sent to a room while a member was disconnected are sent to it
+  /// when it connects again, the oldest ones are left for it to fetch.
+  #[arg(long, default_value_t = 256)]
+  max_queued_messages: u32,
+  /// Where rooms, messages, receipts and users are kept.
+  #[arg(long, value_enum, default_value_t = StorageKind::Memory)]
+  storage: StorageKind,
+  /// Where the `log` and `sqlite` storages keep their files.
+  #[arg(long, default_value = "data")]
+  data_dir: PathBuf,
+  /// When stored data is flushed to the disk.
+  #[arg(long, value_enum, default_value_t = FsyncPolicy::Interval)]
+  fsync_policy: FsyncPolicy,
+  /// How often stored data is flushed to the disk with `--fsync-policy interval`.
+  #[arg(long, default_value_t = 1000)]
+  fsync_interval_ms: u64,
+  /// Messages older than this are deleted, in rooms without a `--room-retention`.
+  #[arg(long)]
+  retention_max_age_secs: Option<u64>,
+  /// Only the latest messages of rooms without a `--room-retention` are kept, up to this many.
+  #[arg(long)]
+  retention_max_messages: Option<u64>,
+  /// Only the latest messages of rooms without a `--room-retention` are kept,
+  /// up to this many bytes of usernames and contents.
+  #[arg(long)]
+  retention_max_bytes: Option<u64>,
+  /// The retention of a room, instead of the `--retention-*` options,
+  /// like `lobby:age=86400,messages=1000,bytes=1048576`. Can be repeated.
+  #[arg(long, value_parser = retention::parse_room_retention)]
+  room_retention: Vec<(String, RetentionPolicy)>,
+  /// How often messages are deleted according to the retention and the storage is compacted.
+  #[arg(long, default_value_t = 60)]
+  retention_interval_secs: u64,
+  /// Session tokens that aren't used for this long stop working, clients have to log in again.
+  #[arg(long, default_value_t = 30 * 24 * 60 * 60)]
+  session_ttl_secs: u64,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+enum UnknownFramePolicy {
+  /// Ignore the frame.
+  Skip,
+  /// Ignore the frame and tell the client about it with an error message.
+  Reply,
+  /// Close the connection.
+  Disconnect,
+}
+
+/// Recovers the rooms kept in the storage,
+/// then accepts connections on `listener` until accepting fails.
+pub async fn serve(listener: TcpListener, config: Arc<Config>) -> Result<()> {
+  let chat_manager = ChatManager::open(Arc::clone(&config))?;
+
+  loop {
+    let (socket, socket_addr) = listener.accept().await?;
+    tokio::spawn(connection::handle_connection(
+      socket,
+      socket_addr,
+      Arc::clone(&config),
+      Arc::clone(&chat_manager),
+    ));
+  }
+}
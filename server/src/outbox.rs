@@ -0,0 +1,453 @@
+//! Messages waiting to be written to a client.
+//!
+//! Every connection has a bounded queue drained by its own writer task,
+//! so a client that reads slowly only delays its own messages.
+//! Messages are queued as encoded frames, a message sent to a whole room is encoded once
+//! and every outbox holds a reference to the same bytes.
+//! Messages of a type the client's protocol version doesn't know are dropped instead of queued.
+
+use std::{
+  collections::VecDeque,
+  fmt,
+  io::IoSlice,
+  sync::{
+    atomic::{AtomicU16, Ordering},
+    Arc, Mutex,
+  },
+};
+
+use bytes::{Buf, Bytes, BytesMut};
+use clap::ValueEnum;
+use messages::{ProtocolError, ServerToClientMessage, PROTOCOL_VERSION};
+use tokio::{
+  io::{AsyncWrite, AsyncWriteExt},
+  net::tcp::OwnedWriteHalf,
+  sync::Notify,
+  task::JoinHandle,
+};
+use tracing::{info, warn};
+
+/// Maximum number of queued frames written with a single syscall.
+const MAX_FRAMES_PER_WRITE: usize = 64;
+
+/// What to do when a message is sent to a client whose outbox is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum OverflowPolicy {
+  /// Drop the oldest message in the outbox to make room.
+  DropOldest,
+  /// Close the connection of the client.
+  Disconnect,
+  /// Wait until the client catches up, this slows down the sender.
+  ///
+  /// Messages are sent to a room while holding its lock, so that every client gets them in order,
+  /// a single slow client stalls the whole room.
+  Backpressure,
+}
+
+#[derive(Debug)]
+pub enum OutboxError {
+  /// The message could not be encoded.
+  Encode(ProtocolError),
+  /// The connection is closing, the message will never be written.
+  Closed,
+  /// The outbox is full and the client has been disconnected.
+  Overflow,
+}
+
+impl fmt::Display for OutboxError {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    match self {
+      OutboxError::Encode(err) => write!(f, "unable to encode message: {err}"),
+      OutboxError::Closed => write!(f, "outbox is closed"),
+      OutboxError::Overflow => write!(f, "outbox is full"),
+    }
+  }
+}
+
+impl std::error::Error for OutboxError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      OutboxError::Encode(err) => Some(err),
+      _ => None,
+    }
+  }
+}
+
+impl From<ProtocolError> for OutboxError {
+  fn from(err: ProtocolError) -> Self {
+    OutboxError::Encode(err)
+  }
+}
+
+/// Encodes `message` into a frame that can be sent to several outboxes.
+pub fn encode(message: &ServerToClientMessage) -> Result<Bytes, ProtocolError> {
+  let mut buf = BytesMut::new();
+  messages::server_to_client::encode(message, &mut buf)?;
+  Ok(buf.freeze())
+}
+
+struct State {
+  queue: VecDeque<Bytes>,
+  closed: bool,
+}
+
+struct Shared {
+  state: Mutex<State>,
+  capacity: usize,
+  policy: OverflowPolicy,
+  /// The protocol version spoken by the client.
+  protocol_version: AtomicU16,
+  /// Wakes the writer when a message is queued or the outbox is closed.
+  queued: Notify,
+  /// Wakes the senders waiting for room in the outbox and the tasks waiting for it to close.
+  dequeued: Notify,
+}
+
+impl Shared {
+  fn close(&self) {
+    self.state.lock().unwrap().closed = true;
+    self.queued.notify_one();
+    self.dequeued.notify_waiters();
+  }
+}
+
+/// The queue of messages waiting to be written to a client, cloning it is cheap.
+#[derive(Clone)]
+pub struct Outbox {
+  shared: Arc<Shared>,
+}
+
+impl Outbox {
+  /// Creates an outbox and spawns the task that writes its messages to `write_half`.
+  pub fn spawn(
+    write_half: OwnedWriteHalf,
+    capacity: usize,
+    policy: OverflowPolicy,
+  ) -> (Self, JoinHandle<()>) {
+    let shared = Arc::new(Shared {
+      state: Mutex::new(State {
+        queue: VecDeque::with_capacity(capacity),
+        closed: false,
+      }),
+      capacity,
+      policy,
+      protocol_version: AtomicU16::new(PROTOCOL_VERSION),
+      queued: Notify::new(),
+      dequeued: Notify::new(),
+    });
+
+    let writer = tokio::spawn(write_messages(Arc::clone(&shared), write_half));
+
+    (Self { shared }, writer)
+  }
+
+  /// Sets the protocol version negotiated with the client, until then the client is assumed to speak ours.
+  pub fn set_protocol_version(&self, protocol_version: u16) {
+    self
+      .shared
+      .protocol_version
+      .store(protocol_version, Ordering::Relaxed);
+  }
+
+  /// Encodes and queues `message`, see `send_frame`.
+  ///
+  /// Messages the client doesn't know are dropped, it could not decode them anyway.
+  pub async fn send(&self, message: ServerToClientMessage) -> Result<(), OutboxError> {
+    if message.message_type().since_version() > self.shared.protocol_version.load(Ordering::Relaxed)
+    {
+      return Ok(());
+    }
+
+    self.send_frame(encode(&message)?).await
+  }
+
+  /// Queues a frame, what happens when the outbox is full depends on the `OverflowPolicy`.
+  ///
+  /// Unlike `send`, the frame is queued whatever the protocol version of the client.
+  pub async fn send_frame(&self, frame: Bytes) -> Result<(), OutboxError> {
+    loop {
+      // Created before checking the queue so a message written in between is not missed.
+      let dequeued = self.shared.dequeued.notified();
+
+      {
+        let mut state = self.shared.state.lock().unwrap();
+
+        if state.closed {
+          return Err(OutboxError::Closed);
+        }
+
+        if state.queue.len() < self.shared.capacity {
+          state.queue.push_back(frame);
+          self.shared.queued.notify_one();
+          return Ok(());
+        }
+
+        match self.shared.policy {
+          OverflowPolicy::DropOldest => {
+            warn!("outbox is full, dropping oldest message");
+            state.queue.pop_front();
+            state.queue.push_back(frame);
+            self.shared.queued.notify_one();
+            return Ok(());
+          }
+          OverflowPolicy::Disconnect => {
+            warn!("outbox is full, disconnecting client");
+            state.queue.clear();
+            drop(state);
+            self.shared.close();
+            return Err(OutboxError::Overflow);
+          }
+          OverflowPolicy::Backpressure => {}
+        }
+      }
+
+      dequeued.await;
+    }
+  }
+
+  /// Stops accepting messages, the writer exits once the messages already queued are written.
+  pub fn close(&self) {
+    self.shared.close();
+  }
+
+  /// Completes once the outbox is closed, because the client could not keep up for example.
+  pub async fn closed(&self) {
+    loop {
+      let dequeued = self.shared.dequeued.notified();
+
+      if self.shared.state.lock().unwrap().closed {
+        return;
+      }
+
+      dequeued.await;
+    }
+  }
+}
+
+async fn write_messages(shared: Arc<Shared>, mut write_half: OwnedWriteHalf) {
+  let mut frames = VecDeque::with_capacity(MAX_FRAMES_PER_WRITE);
+
+  loop {
+    let closed = {
+      let mut state = shared.state.lock().unwrap();
+      let n = state.queue.len().min(MAX_FRAMES_PER_WRITE);
+      frames.extend(state.queue.drain(..n));
+      state.closed
+    };
+
+    if frames.is_empty() {
+      if closed {
+        return;
+      }
+      shared.queued.notified().await;
+      continue;
+    }
+
+    shared.dequeued.notify_waiters();
+
+    let result = match write_frames(&mut write_half, &mut frames).await {
+      Ok(()) => write_half.flush().await,
+      Err(err) => Err(err),
+    };
+
+    if let Err(err) = result {
+      info!("unable to write message, closing outbox. error={:?}", err);
+      shared.close();
+      return;
+    }
+  }
+}
+
+/// Writes every frame in `frames`, several frames at a time, removing them once written.
+async fn write_frames(
+  write_half: &mut (impl AsyncWrite + Unpin),
+  frames: &mut VecDeque<Bytes>,
+) -> std::io::Result<()> {
+  while !frames.is_empty() {
+    let mut written = {
+      let slices: Vec<IoSlice<'_>> = frames.iter().map(|frame| IoSlice::new(frame)).collect();
+      write_half.write_vectored(&slices).await?
+    };
+    if written == 0 {
+      return Err(std::io::ErrorKind::WriteZero.into());
+    }
+
+    while written > 0 {
+      let frame = frames.front_mut().unwrap();
+      if written < frame.len() {
+        frame.advance(written);
+        break;
+      }
+      written -= frame.len();
+      frames.pop_front();
+    }
+  }
+
+  Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+  use std::{
+    pin::Pin,
+    task::{Context, Poll},
+  };
+
+  use futures::FutureExt;
+  use tokio::net::{TcpListener, TcpStream};
+
+  use super::*;
+
+  /// Accepts at most `limit` bytes per write and records what it's given.
+  struct ChunkedWriter {
+    limit: usize,
+    written: Vec<u8>,
+    writes: usize,
+  }
+
+  impl AsyncWrite for ChunkedWriter {
+    fn poll_write(
+      self: Pin<&mut Self>,
+      cx: &mut Context<'_>,
+      buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+      self.poll_write_vectored(cx, &[IoSlice::new(buf)])
+    }
+
+    fn poll_write_vectored(
+      mut self: Pin<&mut Self>,
+      _cx: &mut Context<'_>,
+      bufs: &[IoSlice<'_>],
+    ) -> Poll<std::io::Result<usize>> {
+      let limit = self.limit;
+      let mut written = 0;
+      for buf in bufs {
+        let n = buf.len().min(limit - written);
+        self.written.extend_from_slice(&buf[..n]);
+        written += n;
+      }
+      self.writes += 1;
+      Poll::Ready(Ok(written))
+    }
+
+    fn is_write_vectored(&self) -> bool {
+      true
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+      Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+      Poll::Ready(Ok(()))
+    }
+  }
+
+  /// Spawns an outbox writing to a socket, returns it with the peer at the other end of the socket.
+  ///
+  /// Tests run on a single thread, the writer doesn't run until the test waits for something.
+  async fn spawn(capacity: usize, policy: OverflowPolicy) -> (Outbox, JoinHandle<()>, TcpStream) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let peer = TcpStream::connect(listener.local_addr().unwrap())
+      .await
+      .unwrap();
+    let (socket, _) = listener.accept().await.unwrap();
+    let (_read_half, write_half) = socket.into_split();
+    let (outbox, writer) = Outbox::spawn(write_half, capacity, policy);
+    (outbox, writer, peer)
+  }
+
+  fn ping(nonce: u64) -> ServerToClientMessage {
+    ServerToClientMessage::Ping(messages::server_to_client::PingMessage { nonce })
+  }
+
+  async fn read_nonce(peer: &mut TcpStream) -> u64 {
+    match messages::read_server_message(peer).await.unwrap() {
+      ServerToClientMessage::Ping(ping) => ping.nonce,
+      message => panic!("expected Ping, got {message:?}"),
+    }
+  }
+
+  #[tokio::test]
+  async fn the_oldest_message_is_dropped_when_full() {
+    let (outbox, writer, mut peer) = spawn(2, OverflowPolicy::DropOldest).await;
+
+    for nonce in 0..3 {
+      outbox.send(ping(nonce)).await.unwrap();
+    }
+    outbox.close();
+    writer.await.unwrap();
+
+    assert_eq!(read_nonce(&mut peer).await, 1);
+    assert_eq!(read_nonce(&mut peer).await, 2);
+    assert!(messages::read_server_message(&mut peer).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn the_client_is_disconnected_when_full() {
+    let (outbox, writer, mut peer) = spawn(1, OverflowPolicy::Disconnect).await;
+
+    outbox.send(ping(0)).await.unwrap();
+    assert!(matches!(
+      outbox.send(ping(1)).await,
+      Err(OutboxError::Overflow)
+    ));
+    outbox.closed().await;
+    assert!(matches!(
+      outbox.send(ping(2)).await,
+      Err(OutboxError::Closed)
+    ));
+
+    // The queued messages are dropped with the connection.
+    writer.await.unwrap();
+    assert!(messages::read_server_message(&mut peer).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn senders_wait_for_room_with_backpressure() {
+    let (outbox, writer, mut peer) = spawn(1, OverflowPolicy::Backpressure).await;
+
+    outbox.send(ping(0)).await.unwrap();
+    // Full until the writer takes the first message.
+    assert!(outbox.send(ping(1)).now_or_never().is_none());
+    outbox.send(ping(1)).await.unwrap();
+    outbox.close();
+    writer.await.unwrap();
+
+    assert_eq!(read_nonce(&mut peer).await, 0);
+    assert_eq!(read_nonce(&mut peer).await, 1);
+  }
+
+  #[tokio::test]
+  async fn messages_unknown_to_the_client_are_dropped() {
+    let (outbox, writer, mut peer) = spawn(16, OverflowPolicy::Disconnect).await;
+
+    outbox.set_protocol_version(messages::MessageType::Ping.since_version() - 1);
+    outbox.send(ping(0)).await.unwrap();
+    outbox.close();
+    writer.await.unwrap();
+
+    assert!(messages::read_server_message(&mut peer).await.is_err());
+  }
+
+  #[tokio::test]
+  async fn queued_frames_are_written_together() {
+    let mut writer = ChunkedWriter {
+      limit: 4,
+      written: Vec::new(),
+      writes: 0,
+    };
+    let mut frames = VecDeque::from([
+      Bytes::from_static(b"abc"),
+      Bytes::from_static(b"de"),
+      Bytes::from_static(b"fghij"),
+    ]);
+
+    write_frames(&mut writer, &mut frames).await.unwrap();
+
+    // Frames are split and joined across writes, one write per frame would take four.
+    assert_eq!(writer.written, b"abcdefghij");
+    assert_eq!(writer.writes, 3);
+    assert!(frames.is_empty());
+  }
+}
@@ -0,0 +1,286 @@
+//! Deletes the messages rooms don't keep anymore and reclaims the space they used.
+//!
+//! Every room has a `RetentionPolicy`, the one given with `--room-retention` or the `--retention-*` options.
+//! Messages are deleted from the oldest one, so a room's history is always the latest part of it.
+
+use std::{
+  str::FromStr,
+  sync::{Arc, Weak},
+  time::Duration,
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+use tracing::{info, warn};
+
+use crate::{
+  search::SearchIndex,
+  storage::{self, Storage},
+  Config,
+};
+
+/// Which messages of a room are kept, the oldest ones beyond any of the limits are deleted.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub(crate) struct RetentionPolicy {
+  pub(crate) max_age_secs: Option<u64>,
+  pub(crate) max_messages: Option<u64>,
+  /// Counting the bytes of the usernames and contents of the messages.
+  pub(crate) max_bytes: Option<u64>,
+}
+
+/// What `Storage::apply_retention` deleted.
+#[derive(Debug, Clone, Copy, Default)]
+pub(crate) struct Trimmed {
+  pub(crate) messages: u64,
+  /// The bytes of the usernames and contents of the deleted messages.
+  pub(crate) bytes: u64,
+  /// The messages before this seq are deleted, 0 if none were.
+  pub(crate) until: u64,
+}
+
+impl RetentionPolicy {
+  fn is_unlimited(&self) -> bool {
+    self.max_age_secs.is_none() && self.max_messages.is_none() && self.max_bytes.is_none()
+  }
+
+  /// Returns the seq of the oldest message to keep, `None` if every message is kept.
+  /// `messages` are the seq, send time and size of the messages of the room, from the latest one.
+  pub(crate) fn cutoff(
+    &self,
+    now: DateTime<Utc>,
+    messages: impl IntoIterator<Item = (u64, DateTime<Utc>, u64)>,
+  ) -> Option<u64> {
+    let oldest = self
+      .max_age_secs
+      .and_then(|secs| chrono::Duration::from_std(Duration::from_secs(secs)).ok())
+      .and_then(|max_age| now.checked_sub_signed(max_age));
+
+    let (mut count, mut bytes) = (0, 0);
+    for (seq, sent_at, size) in messages {
+      count += 1;
+      bytes += size;
+      if self.max_messages.is_some_and(|max| count > max)
+        || self.max_bytes.is_some_and(|max| bytes > max)
+        || oldest.is_some_and(|oldest| sent_at < oldest)
+      {
+        return Some(seq + 1);
+      }
+    }
+
+    None
+  }
+}
+
+/// Parses limits like `age=86400,messages=1000,bytes=1048576`.
+impl FromStr for RetentionPolicy {
+  type Err = String;
+
+  fn from_str(s: &str) -> Result<Self, Self::Err> {
+    let mut policy = Self::default();
+    for limit in s.split(',') {
+      let (name, value) = limit
+        .split_once('=')
+        .ok_or_else(|| format!("expected NAME=VALUE, got {limit:?}"))?;
+      let value = value
+        .parse()
+        .map_err(|err| format!("invalid value for {name}: {err}"))?;
+      match name {
+        "age" => policy.max_age_secs = Some(value),
+        "messages" => policy.max_messages = Some(value),
+        "bytes" => policy.max_bytes = Some(value),
+        _ => {
+          return Err(format!(
+            "unknown limit {name:?}, expected age, messages or bytes"
+          ))
+        }
+      }
+    }
+
+    Ok(policy)
+  }
+}
+
+/// Parses a `--room-retention`, like `lobby:messages=1000`.
+pub(crate) fn parse_room_retention(s: &str) -> Result<(String, RetentionPolicy), String> {
+  let (room_id, policy) = s
+    .rsplit_once(':')
+    .ok_or_else(|| format!("expected ROOM_ID:POLICY, got {s:?}"))?;
+  Ok((room_id.to_owned(), policy.parse()?))
+}
+
+impl Config {
+  /// The last `--room-retention` of the room, the `--retention-*` options otherwise.
+  fn retention(&self, room_id: &str) -> RetentionPolicy {
+    self
+      .room_retention
+      .iter()
+      .rev()
+      .find(|(id, _)| id == room_id)
+      .map_or_else(|| self.default_retention(), |(_, policy)| *policy)
+  }
+
+  fn default_retention(&self) -> RetentionPolicy {
+    RetentionPolicy {
+      max_age_secs: self.retention_max_age_secs,
+      max_messages: self.retention_max_messages,
+      max_bytes: self.retention_max_bytes,
+    }
+  }
+
+  /// Some room has a retention, otherwise messages are kept forever.
+  pub(crate) fn has_retention(&self) -> bool {
+    !self.default_retention().is_unlimited()
+      || self
+        .room_retention
+        .iter()
+        .any(|(_, policy)| !policy.is_unlimited())
+  }
+}
+
+/// What one pass of `enforce` did.
+#[derive(Debug, Default)]
+struct Run {
+  trimmed: Trimmed,
+  compacted_bytes: u64,
+}
+
+/// Applies the retention of every room and compacts the storage every `period` until it's dropped.
+/// Deleted messages are removed from `search_index`.
+/// The totals since the server started are logged with every pass that deleted something.
+pub(crate) async fn enforce(
+  storage: Weak<dyn Storage>,
+  search_index: Arc<SearchIndex>,
+  config: Arc<Config>,
+  period: Duration,
+) {
+  let mut interval = tokio::time::interval(period);
+  interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+  let mut total = Run::default();
+  loop {
+    interval.tick().await;
+
+    let storage = match storage.upgrade() {
+      Some(storage) => storage,
+      None => return,
+    };
+    let (search_index, config) = (Arc::clone(&search_index), Arc::clone(&config));
+    let run = match storage::blocking(&storage, move |storage| {
+      run(storage, &search_index, &config)
+    })
+    .await
+    {
+      Ok(run) => run,
+      Err(err) => {
+        warn!("unable to apply retention. error={:?}", err);
+        continue;
+      }
+    };
+
+    total.trimmed.messages += run.trimmed.messages;
+    total.trimmed.bytes += run.trimmed.bytes;
+    total.compacted_bytes += run.compacted_bytes;
+    if run.trimmed.messages > 0 || run.compacted_bytes > 0 {
+      info!(
+        "applied retention. deleted_messages={} deleted_bytes={} reclaimed_bytes={} \
+         total_deleted_messages={} total_deleted_bytes={} total_reclaimed_bytes={}",
+        run.trimmed.messages,
+        run.trimmed.bytes,
+        run.compacted_bytes,
+        total.trimmed.messages,
+        total.trimmed.bytes,
+        total.compacted_bytes
+      );
+    }
+  }
+}
+
+fn run(storage: &dyn Storage, search_index: &SearchIndex, config: &Config) -> Result<Run> {
+  let now = Utc::now();
+  let mut run = Run::default();
+
+  for (room, _) in storage.rooms()? {
+    let policy = config.retention(&room.room_id);
+    if policy.is_unlimited() {
+      continue;
+    }
+
+    // The other rooms are trimmed anyway.
+    match storage.apply_retention(&room.room_id, &policy, now) {
+      Ok(trimmed) => {
+        search_index.trim(&room.room_id, trimmed.until);
+        run.trimmed.messages += trimmed.messages;
+        run.trimmed.bytes += trimmed.bytes;
+      }
+      Err(err) => warn!(
+        "unable to apply retention to room. room_id={} error={:?}",
+        room.room_id, err
+      ),
+    }
+  }
+
+  run.compacted_bytes = storage.compact()?;
+  Ok(run)
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::TimeZone;
+
+  use super::*;
+
+  /// Messages `0..count` sent a second apart, of 10 bytes each, from the latest one.
+  fn messages(count: u64) -> Vec<(u64, DateTime<Utc>, u64)> {
+    (0..count)
+      .rev()
+      .map(|seq| (seq, at(seq as i64), 10))
+      .collect()
+  }
+
+  fn at(secs: i64) -> DateTime<Utc> {
+    Utc.timestamp_opt(secs, 0).unwrap()
+  }
+
+  #[test]
+  fn the_oldest_messages_beyond_a_limit_are_cut_off() {
+    let policy = |limits: &str| limits.parse::<RetentionPolicy>().unwrap();
+    let now = at(100);
+
+    assert_eq!(policy("messages=3").cutoff(now, messages(10)), Some(7));
+    assert_eq!(policy("messages=10").cutoff(now, messages(10)), None);
+    assert_eq!(policy("bytes=35").cutoff(now, messages(10)), Some(7));
+    assert_eq!(policy("bytes=100").cutoff(now, messages(10)), None);
+    // Messages sent at the oldest time allowed are kept.
+    assert_eq!(policy("age=95").cutoff(now, messages(10)), Some(5));
+    assert_eq!(policy("age=100").cutoff(now, messages(10)), None);
+    assert_eq!(RetentionPolicy::default().cutoff(now, messages(10)), None);
+    assert_eq!(policy("messages=0").cutoff(now, messages(0)), None);
+  }
+
+  #[test]
+  fn the_strictest_limit_wins() {
+    let policy: RetentionPolicy = "age=95,messages=8,bytes=20".parse().unwrap();
+    assert_eq!(policy.cutoff(at(100), messages(10)), Some(8));
+
+    let policy: RetentionPolicy = "age=92,messages=9".parse().unwrap();
+    assert_eq!(policy.cutoff(at(100), messages(10)), Some(8));
+  }
+
+  #[test]
+  fn room_retentions_are_parsed() {
+    assert_eq!(
+      parse_room_retention("lobby:with:colons:messages=10,age=60"),
+      Ok((
+        "lobby:with:colons".to_owned(),
+        RetentionPolicy {
+          max_age_secs: Some(60),
+          max_messages: Some(10),
+          max_bytes: None,
+        }
+      ))
+    );
+    assert!(parse_room_retention("messages=10").is_err());
+    assert!(parse_room_retention("lobby:days=1").is_err());
+    assert!(parse_room_retention("lobby:messages=-1").is_err());
+  }
+}
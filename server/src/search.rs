@@ -0,0 +1,297 @@
+//! An inverted index of the words in the messages of every room.
+//!
+//! The index is kept in memory: it's built from the stored messages when the server starts,
+//! then updated as messages are stored and deleted. Search results are read back from the storage.
+
+use std::{
+  collections::{HashMap, HashSet},
+  sync::Mutex,
+};
+
+use messages::MAX_SNIPPET_BYTES;
+
+use crate::storage::Storage;
+
+/// How many messages are read at a time to build the index of a room.
+const BUILD_PAGE_MESSAGES: usize = 1024;
+
+/// Longer words aren't indexed, they're unlikely to be searched for.
+const MAX_WORD_BYTES: usize = 64;
+
+/// How much of the contents before the first word found is shown in a snippet.
+const SNIPPET_LEAD_BYTES: usize = 64;
+
+const ELLIPSIS: &str = "…";
+
+#[derive(Default)]
+pub(crate) struct SearchIndex {
+  rooms: Mutex<HashMap<String, RoomIndex>>,
+}
+
+#[derive(Default)]
+struct RoomIndex {
+  /// The seqs of the messages containing each word, in ascending order.
+  postings: HashMap<String, Vec<u64>>,
+}
+
+impl SearchIndex {
+  /// Indexes the stored messages of a room.
+  pub(crate) fn build(&self, room_id: &str, storage: &dyn Storage) -> anyhow::Result<()> {
+    let mut index = RoomIndex::default();
+    let mut before = None;
+    loop {
+      // Read from the latest message back, the seqs are sorted once they're all indexed.
+      let page = storage.messages(room_id, None, before, BUILD_PAGE_MESSAGES)?;
+      before = match page.first() {
+        Some(message) => Some(message.seq),
+        None => break,
+      };
+      for message in page {
+        for word in unique_words(&message.contents) {
+          index.postings.entry(word).or_default().push(message.seq);
+        }
+      }
+    }
+    for postings in index.postings.values_mut() {
+      postings.sort_unstable();
+    }
+
+    self.rooms.lock().unwrap().insert(room_id.to_owned(), index);
+    Ok(())
+  }
+
+  /// Indexes a message, messages of a room are added in the order of their seqs.
+  pub(crate) fn add(&self, room_id: &str, seq: u64, contents: &str) {
+    let mut rooms = self.rooms.lock().unwrap();
+    let index = rooms.entry(room_id.to_owned()).or_default();
+    for word in unique_words(contents) {
+      index.postings.entry(word).or_default().push(seq);
+    }
+  }
+
+  /// Forgets the messages before `until`, they've been deleted.
+  pub(crate) fn trim(&self, room_id: &str, until: u64) {
+    if let Some(index) = self.rooms.lock().unwrap().get_mut(room_id) {
+      index.postings.retain(|_, postings| {
+        let len = postings.partition_point(|&seq| seq < until);
+        postings.drain(..len);
+        !postings.is_empty()
+      });
+    }
+  }
+
+  pub(crate) fn remove_room(&self, room_id: &str) {
+    self.rooms.lock().unwrap().remove(room_id);
+  }
+
+  /// Returns the seqs of the latest messages containing every word, up to `limit`, from the latest one,
+  /// and whether older messages contain them too.
+  pub(crate) fn search(&self, room_id: &str, words: &[String], limit: usize) -> (Vec<u64>, bool) {
+    let rooms = self.rooms.lock().unwrap();
+    let index = match rooms.get(room_id) {
+      Some(index) => index,
+      None => return (Vec::new(), false),
+    };
+
+    let mut postings = Vec::with_capacity(words.len());
+    for word in words {
+      match index.postings.get(word) {
+        Some(seqs) => postings.push(seqs),
+        None => return (Vec::new(), false),
+      }
+    }
+    // Walks the shortest list, looking the seqs up in the others.
+    postings.sort_unstable_by_key(|seqs| seqs.len());
+    let (shortest, others) = match postings.split_first() {
+      Some(split) => split,
+      None => return (Vec::new(), false),
+    };
+
+    let mut seqs: Vec<u64> = shortest
+      .iter()
+      .rev()
+      .filter(|seq| others.iter().all(|seqs| seqs.binary_search(seq).is_ok()))
+      .take(limit + 1)
+      .copied()
+      .collect();
+    let more = seqs.len() > limit;
+    seqs.truncate(limit);
+    (seqs, more)
+  }
+}
+
+/// The words of `text` in lowercase, with where they start in `text`.
+pub(crate) fn words(text: &str) -> impl Iterator<Item = (usize, String)> + '_ {
+  let mut start = None;
+  text
+    .char_indices()
+    .chain(std::iter::once((text.len(), ' ')))
+    .filter_map(move |(i, c)| match (start, c.is_alphanumeric()) {
+      (None, true) => {
+        start = Some(i);
+        None
+      }
+      (Some(word_start), false) => {
+        start = None;
+        Some((word_start, &text[word_start..i]))
+      }
+      _ => None,
+    })
+    .filter(|(_, word)| word.len() <= MAX_WORD_BYTES)
+    .map(|(i, word)| (i, word.to_lowercase()))
+}
+
+fn unique_words(text: &str) -> HashSet<String> {
+  words(text).map(|(_, word)| word).collect()
+}
+
+/// The part of `contents` around the first of `words` it contains, at most `MAX_SNIPPET_BYTES`.
+pub(crate) fn snippet(contents: &str, words: &[String]) -> String {
+  if contents.len() <= MAX_SNIPPET_BYTES {
+    return contents.to_owned();
+  }
+
+  let found = self::words(contents)
+    .find(|(_, word)| words.contains(word))
+    .map_or(0, |(i, _)| i);
+
+  let budget = MAX_SNIPPET_BYTES - 2 * ELLIPSIS.len();
+  let mut start = found.saturating_sub(SNIPPET_LEAD_BYTES);
+  while !contents.is_char_boundary(start) {
+    start -= 1;
+  }
+  let mut end = (start + budget).min(contents.len());
+  while !contents.is_char_boundary(end) {
+    end -= 1;
+  }
+
+  let mut snippet = String::with_capacity(MAX_SNIPPET_BYTES);
+  if start > 0 {
+    snippet.push_str(ELLIPSIS);
+  }
+  snippet.push_str(&contents[start..end]);
+  if end < contents.len() {
+    snippet.push_str(ELLIPSIS);
+  }
+  snippet
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::Utc;
+
+  use super::*;
+  use crate::storage::{MemoryStorage, StoredMessage, StoredRoom};
+
+  fn query(words: &str) -> Vec<String> {
+    self::words(words).map(|(_, word)| word).collect()
+  }
+
+  fn index() -> SearchIndex {
+    let index = SearchIndex::default();
+    index.add("room", 0, "Hello world");
+    index.add("room", 1, "hello again, World!");
+    index.add("room", 2, "nothing to see");
+    index.add("room", 3, "world hello world");
+    index.add("other", 4, "hello world");
+    index
+  }
+
+  #[test]
+  fn messages_containing_every_word_are_found_from_the_latest() {
+    let index = index();
+    assert_eq!(
+      index.search("room", &query("WORLD hello"), 10),
+      (vec![3, 1, 0], false)
+    );
+    assert_eq!(index.search("room", &query("hello"), 2), (vec![3, 1], true));
+    assert_eq!(
+      index.search("room", &query("hello again"), 10),
+      (vec![1], false)
+    );
+    assert_eq!(
+      index.search("room", &query("hello unknown"), 10),
+      (vec![], false)
+    );
+    assert_eq!(index.search("room", &[], 10), (vec![], false));
+    assert_eq!(
+      index.search("missing", &query("hello"), 10),
+      (vec![], false)
+    );
+  }
+
+  #[test]
+  fn deleted_messages_are_not_found() {
+    let index = index();
+    index.trim("room", 3);
+    assert_eq!(index.search("room", &query("hello"), 10), (vec![3], false));
+    assert_eq!(index.search("room", &query("again"), 10), (vec![], false));
+    assert!(!index.rooms.lock().unwrap()["room"]
+      .postings
+      .contains_key("again"));
+
+    index.remove_room("room");
+    assert_eq!(index.search("room", &query("hello"), 10), (vec![], false));
+    assert_eq!(index.search("other", &query("hello"), 10), (vec![4], false));
+  }
+
+  #[test]
+  fn the_index_is_built_from_stored_messages() {
+    let storage = MemoryStorage::default();
+    storage
+      .create_room(&StoredRoom {
+        room_id: "room".to_owned(),
+        display_name: "room".to_owned(),
+        topic: String::new(),
+        created_at: Utc::now(),
+        persistent: false,
+      })
+      .unwrap();
+    let count = 2 * BUILD_PAGE_MESSAGES as u64 + 1;
+    for seq in 0..count {
+      let message = StoredMessage {
+        seq,
+        username: "ann".to_owned(),
+        contents: format!("message {}", if seq % 2 == 0 { "even" } else { "odd" }),
+        sent_at: Utc::now(),
+      };
+      storage.append_message("room", &message).unwrap();
+    }
+
+    let index = SearchIndex::default();
+    index.build("room", &storage).unwrap();
+    let (seqs, more) = index.search("room", &query("even message"), usize::MAX - 1);
+    assert!(!more);
+    assert_eq!(seqs, (0..count).rev().step_by(2).collect::<Vec<_>>());
+  }
+
+  #[test]
+  fn long_words_are_not_indexed() {
+    let long = "a".repeat(MAX_WORD_BYTES + 1);
+    let words: Vec<_> = words(&format!("Où {long} ÉTÉ-{}", "b".repeat(MAX_WORD_BYTES))).collect();
+    assert_eq!(
+      words,
+      [
+        (0, "où".to_owned()),
+        (MAX_WORD_BYTES + 6, "été".to_owned()),
+        (MAX_WORD_BYTES + 12, "b".repeat(MAX_WORD_BYTES)),
+      ]
+    );
+  }
+
+  #[test]
+  fn snippets_show_the_first_word_found() {
+    assert_eq!(snippet("short message", &query("message")), "short message");
+
+    let contents = format!(
+      "{}needle{}",
+      "é ".repeat(MAX_SNIPPET_BYTES),
+      " x".repeat(MAX_SNIPPET_BYTES)
+    );
+    let snippet = snippet(&contents, &query("needle"));
+    assert!(snippet.len() <= MAX_SNIPPET_BYTES);
+    assert!(snippet.starts_with(ELLIPSIS) && snippet.ends_with(ELLIPSIS));
+    let needle = snippet.find("needle").unwrap();
+    assert!(needle - ELLIPSIS.len() <= SNIPPET_LEAD_BYTES);
+  }
+}
@@ -0,0 +1,42 @@
+use std::{
+  collections::HashSet,
+  fmt,
+  net::SocketAddr,
+  sync::atomic::{AtomicU64, Ordering},
+};
+
+use messages::Capabilities;
+
+use crate::outbox::Outbox;
+
+/// Identifies a session, unlike socket addresses ids are never reused.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub(crate) struct SessionId(u64);
+
+impl SessionId {
+  pub(crate) fn next() -> Self {
+    static NEXT_ID: AtomicU64 = AtomicU64::new(0);
+    SessionId(NEXT_ID.fetch_add(1, Ordering::Relaxed))
+  }
+}
+
+impl fmt::Display for SessionId {
+  fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+    write!(f, "{}", self.0)
+  }
+}
+
+/// A client that completed the handshake, it lasts until its connection is closed.
+pub(crate) struct Session {
+  pub(crate) id: SessionId,
+  pub(crate) socket_addr: SocketAddr,
+  /// The account the client logged in with, shown as the author of its messages and in receipts.
+  pub(crate) username: String,
+  /// The id of the session token the client logged in or resumed with.
+  pub(crate) token_id: u64,
+  /// The capabilities negotiated during the handshake.
+  pub(crate) capabilities: Capabilities,
+  pub(crate) outbox: Outbox,
+  /// The rooms the client is a member of.
+  pub(crate) rooms: HashSet<String>,
+}
@@ -0,0 +1,217 @@
+//! Where rooms, messages, receipts and users are kept.
+//!
+//! Every `Storage` is synchronous, `ChatManager` calls it on the blocking thread pool with `blocking`.
+
+use std::{path::Path, sync::Arc};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use clap::ValueEnum;
+
+use crate::{
+  retention::{RetentionPolicy, Trimmed},
+  Config,
+};
+
+mod journal;
+mod log;
+mod memory;
+mod room_log;
+mod sqlite;
+
+pub(crate) use self::{log::LogStorage, memory::MemoryStorage, sqlite::SqliteStorage};
+
+/// Which `Storage` the server uses.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum StorageKind {
+  /// Nothing survives a restart.
+  Memory,
+  /// An append-only log per room in `--data-dir`,
+  /// and a journal of the members and receipts of rooms, accounts and session tokens.
+  Log,
+  /// An SQLite database in `--data-dir`.
+  Sqlite,
+}
+
+/// When written data is flushed to the disk.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, ValueEnum)]
+pub enum FsyncPolicy {
+  /// Before a message is sent to the members of the room, nothing acknowledged is ever lost.
+  Always,
+  /// Every `--fsync-interval-ms`, a power loss loses the messages of the last interval.
+  Interval,
+  /// Whenever the operating system decides to.
+  Never,
+}
+
+/// What a room looked like when it was created.
+#[derive(Debug, Clone)]
+pub(crate) struct StoredRoom {
+  pub(crate) room_id: String,
+  pub(crate) display_name: String,
+  pub(crate) topic: String,
+  pub(crate) created_at: DateTime<Utc>,
+  pub(crate) persistent: bool,
+}
+
+#[derive(Debug, Clone)]
+pub(crate) struct StoredMessage {
+  pub(crate) seq: u64,
+  pub(crate) username: String,
+  pub(crate) contents: String,
+  pub(crate) sent_at: DateTime<Utc>,
+}
+
+impl StoredMessage {
+  /// The size of the message counted by `RetentionPolicy::max_bytes`.
+  fn size(&self) -> u64 {
+    (self.username.len() + self.contents.len()) as u64
+  }
+}
+
+/// A member of a room and how far it has received and read the messages of the room.
+#[derive(Debug, Clone)]
+pub(crate) struct StoredMember {
+  pub(crate) room_id: String,
+  pub(crate) username: String,
+  pub(crate) delivered_until: u64,
+  pub(crate) read_until: u64,
+}
+
+/// An account, created by registering.
+#[derive(Debug, Clone)]
+pub(crate) struct StoredUser {
+  pub(crate) username: String,
+  /// The hash of the password in the PHC string format, with its salt and parameters.
+  pub(crate) password_hash: String,
+  pub(crate) registered_at: DateTime<Utc>,
+}
+
+/// A session of an account, resumed with its token by clients that reconnect.
+#[derive(Debug, Clone)]
+pub(crate) struct StoredToken {
+  pub(crate) token_id: u64,
+  pub(crate) username: String,
+  /// The token itself is only known by the client.
+  pub(crate) token_hash: String,
+  pub(crate) created_at: DateTime<Utc>,
+  pub(crate) last_used_at: DateTime<Utc>,
+  pub(crate) expires_at: DateTime<Utc>,
+}
+
+/// A kind of receipt a member sends for the messages of a room.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) enum Receipt {
+  Delivered,
+  Read,
+}
+
+pub(crate) trait Storage: Send + Sync {
+  /// Stores a new room, replacing any room with the same id.
+  fn create_room(&self, room: &StoredRoom) -> Result<()>;
+
+  /// Deletes the room, its messages and its members.
+  fn delete_room(&self, room_id: &str) -> Result<()>;
+
+  /// Returns every room and the seq of its last message, even if it was deleted.
+  fn rooms(&self) -> Result<Vec<(StoredRoom, Option<u64>)>>;
+
+  /// Stores a message, its seq follows the seq of the last message of the room.
+  fn append_message(&self, room_id: &str, message: &StoredMessage) -> Result<()>;
+
+  /// Returns up to `limit` messages of the room whose seq is between `after` and `before`, excluded,
+  /// ordered by seq. The first ones if `after` is set, the last ones otherwise.
+  fn messages(
+    &self,
+    room_id: &str,
+    after: Option<u64>,
+    before: Option<u64>,
+    limit: usize,
+  ) -> Result<Vec<StoredMessage>>;
+
+  /// Deletes the oldest messages of the room that `policy` doesn't keep at `now`.
+  /// The seq of the last message is remembered, the messages that follow continue the sequence.
+  fn apply_retention(
+    &self,
+    room_id: &str,
+    policy: &RetentionPolicy,
+    now: DateTime<Utc>,
+  ) -> Result<Trimmed>;
+
+  /// Reclaims the space left by deleted messages, returns how many bytes were reclaimed.
+  fn compact(&self) -> Result<u64>;
+
+  /// Makes `username` a member of the room until it leaves it.
+  /// Members that already joined keep their receipts.
+  fn add_member(&self, room_id: &str, username: &str, joined_seq: u64) -> Result<()>;
+
+  fn remove_member(&self, room_id: &str, username: &str) -> Result<()>;
+
+  /// Returns the members of every room.
+  fn members(&self) -> Result<Vec<StoredMember>>;
+
+  /// Returns the memberships of `username`, one per room.
+  fn memberships(&self, username: &str) -> Result<Vec<StoredMember>>;
+
+  /// Records that the member has received, or read, every message before `until`.
+  /// Receipts never go backward, and a message that has been read has been received.
+  fn acknowledge(&self, room_id: &str, username: &str, receipt: Receipt, until: u64) -> Result<()>;
+
+  /// Stores a new account, returns false if the username already has one.
+  fn create_user(&self, user: &StoredUser) -> Result<bool>;
+
+  /// Returns the account of `username`, `None` if it hasn't registered.
+  fn user(&self, username: &str) -> Result<Option<StoredUser>>;
+
+  /// Records that `username` has connected at `at`.
+  fn save_user(&self, username: &str, at: DateTime<Utc>) -> Result<()>;
+
+  fn create_token(&self, token: &StoredToken) -> Result<()>;
+
+  /// Returns the token with this hash, expired or not.
+  fn token(&self, token_hash: &str) -> Result<Option<StoredToken>>;
+
+  /// Returns the tokens of `username`, expired or not.
+  fn tokens(&self, username: &str) -> Result<Vec<StoredToken>>;
+
+  /// Records that the token was used at `at`, it now expires at `expires_at`.
+  fn touch_token(&self, token_id: u64, at: DateTime<Utc>, expires_at: DateTime<Utc>) -> Result<()>;
+
+  fn delete_token(&self, token_id: u64) -> Result<()>;
+
+  /// Deletes the tokens of `username` that expired before `now`.
+  fn delete_expired_tokens(&self, username: &str, now: DateTime<Utc>) -> Result<()>;
+
+  /// Flushes what was written since the last call, with `FsyncPolicy::Interval`.
+  fn sync(&self) -> Result<()>;
+}
+
+/// Opens the storage selected in `config`.
+pub(crate) fn open(config: &Config) -> Result<Arc<dyn Storage>> {
+  let storage: Arc<dyn Storage> = match config.storage {
+    StorageKind::Memory => Arc::new(MemoryStorage::default()),
+    StorageKind::Log => Arc::new(LogStorage::open(&config.data_dir, config.fsync_policy)?),
+    StorageKind::Sqlite => Arc::new(SqliteStorage::open(
+      &config.data_dir.join("chat.db"),
+      config.fsync_policy,
+    )?),
+  };
+
+  Ok(storage)
+}
+
+/// Runs `f` on the blocking thread pool, storages wait for the disk.
+pub(crate) async fn blocking<T: Send + 'static>(
+  storage: &Arc<dyn Storage>,
+  f: impl FnOnce(&dyn Storage) -> Result<T> + Send + 'static,
+) -> Result<T> {
+  let storage = Arc::clone(storage);
+  tokio::task::spawn_blocking(move || f(&*storage))
+    .await
+    .context("storage task failed")?
+}
+
+/// Creates `dir` if it doesn't exist.
+fn create_dir(dir: &Path) -> Result<()> {
+  std::fs::create_dir_all(dir).with_context(|| format!("unable to create {dir:?}"))
+}
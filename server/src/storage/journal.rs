@@ -0,0 +1,679 @@
+//! The accounts, session tokens and members of `LogStorage`, stored on disk.
+//!
+//! They're kept in a `MemoryStorage`, every change is appended to the journal before it's applied.
+//! Opening the journal applies its changes again. Records are framed like the records of a `RoomLog`,
+//! a partial record left by a crash is truncated and a corrupt journal is moved aside the same way.
+//!
+//! Changes that override each other pile up, once the journal is twice as long as it was last compacted,
+//! a snapshot of what it describes is written to a new file that replaces it.
+
+use std::{
+  fs::{self, File, OpenOptions},
+  io::{self, BufReader, Write},
+  path::{Path, PathBuf},
+};
+
+use anyhow::Result;
+use bytes::{BufMut, BytesMut};
+use chrono::{DateTime, Utc};
+use tracing::warn;
+
+use super::{
+  room_log::{
+    get_string, get_timestamp, get_u64, get_u8, put_frame, put_string, read_record, set_aside, Next,
+  },
+  MemoryStorage, Receipt, Storage, StoredToken, StoredUser,
+};
+
+/// The name of the journal in `--data-dir`. Not a `.log`, it would be taken for the log of a room.
+const FILE_NAME: &str = "accounts.journal";
+
+/// Journals shorter than this are never compacted.
+const MIN_COMPACTED_BYTES: u64 = 1024 * 1024;
+
+const USER_CREATED: u8 = 0;
+const USER_SEEN: u8 = 1;
+const MEMBER_ADDED: u8 = 2;
+const MEMBER_REMOVED: u8 = 3;
+const ACKNOWLEDGED: u8 = 4;
+const ROOM_DELETED: u8 = 5;
+const TOKEN_CREATED: u8 = 6;
+const TOKEN_TOUCHED: u8 = 7;
+const TOKEN_DELETED: u8 = 8;
+const EXPIRED_TOKENS_DELETED: u8 = 9;
+
+/// A call to a `Storage` method of `MemoryStorage` that changes it.
+#[derive(Debug)]
+pub(super) enum Change {
+  UserCreated(StoredUser),
+  UserSeen {
+    username: String,
+    at: DateTime<Utc>,
+  },
+  MemberAdded {
+    room_id: String,
+    username: String,
+    joined_seq: u64,
+  },
+  MemberRemoved {
+    room_id: String,
+    username: String,
+  },
+  Acknowledged {
+    room_id: String,
+    username: String,
+    receipt: Receipt,
+    until: u64,
+  },
+  RoomDeleted(String),
+  TokenCreated(StoredToken),
+  TokenTouched {
+    token_id: u64,
+    at: DateTime<Utc>,
+    expires_at: DateTime<Utc>,
+  },
+  TokenDeleted(u64),
+  ExpiredTokensDeleted {
+    username: String,
+    now: DateTime<Utc>,
+  },
+}
+
+impl Change {
+  pub(super) fn apply(&self, memory: &MemoryStorage) -> Result<()> {
+    match self {
+      Change::UserCreated(user) => memory.create_user(user).map(|_| ()),
+      Change::UserSeen { username, at } => memory.save_user(username, *at),
+      Change::MemberAdded {
+        room_id,
+        username,
+        joined_seq,
+      } => memory.add_member(room_id, username, *joined_seq),
+      Change::MemberRemoved { room_id, username } => memory.remove_member(room_id, username),
+      Change::Acknowledged {
+        room_id,
+        username,
+        receipt,
+        until,
+      } => memory.acknowledge(room_id, username, *receipt, *until),
+      Change::RoomDeleted(room_id) => memory.delete_room(room_id),
+      Change::TokenCreated(token) => memory.create_token(token),
+      Change::TokenTouched {
+        token_id,
+        at,
+        expires_at,
+      } => memory.touch_token(*token_id, *at, *expires_at),
+      Change::TokenDeleted(token_id) => memory.delete_token(*token_id),
+      Change::ExpiredTokensDeleted { username, now } => {
+        memory.delete_expired_tokens(username, *now)
+      }
+    }
+  }
+}
+
+pub(super) struct Journal {
+  dir: PathBuf,
+  path: PathBuf,
+  file: File,
+  /// The length of the file once every record appended so far is written.
+  len: u64,
+  /// The length of the file when it was opened or last compacted.
+  compacted_len: u64,
+  /// Records have been appended since the file was last synced.
+  dirty: bool,
+}
+
+impl Journal {
+  /// Opens the journal in `dir` and applies its changes to `memory`.
+  /// A record torn by a crash at its end is truncated, the journal is moved aside
+  /// to `*.journal.corrupt` if an invalid record is followed by others.
+  pub(super) fn open(dir: &Path, memory: &MemoryStorage) -> Result<Self> {
+    let path = dir.join(FILE_NAME);
+    let file = OpenOptions::new()
+      .read(true)
+      .append(true)
+      .create(true)
+      .open(&path)?;
+    let file_len = file.metadata()?.len();
+
+    let mut reader = BufReader::new(&file);
+    let mut len = 0;
+    loop {
+      let (change, record_len) = match read_record(&mut reader, file_len - len, decode_change)? {
+        Next::Record(change, record_len) => (change, record_len),
+        Next::End | Next::Torn => break,
+        Next::Corrupt => {
+          drop(reader);
+          drop(file);
+          return Err(set_aside(&path, len).into());
+        }
+      };
+      change.apply(memory)?;
+      len += record_len as u64;
+    }
+
+    if len < file_len {
+      warn!(
+        "journal ends with a partial record, truncating it. path={:?} len={} valid_len={}",
+        path, file_len, len
+      );
+      file.set_len(len)?;
+      file.sync_all()?;
+    }
+
+    Ok(Self {
+      dir: dir.to_owned(),
+      path,
+      file,
+      len,
+      compacted_len: len,
+      dirty: false,
+    })
+  }
+
+  /// Appends a change, it's flushed to the disk before returning if `sync` is true.
+  pub(super) fn append(&mut self, change: &Change, sync: bool) -> io::Result<()> {
+    let mut buf = BytesMut::new();
+    encode_change(change, &mut buf);
+
+    let result = (&self.file).write_all(&buf).and_then(|()| {
+      if sync {
+        self.file.sync_data()?;
+      }
+      Ok(())
+    });
+
+    match result {
+      Ok(()) => {
+        self.len += buf.len() as u64;
+        self.dirty |= !sync;
+        Ok(())
+      }
+      Err(err) => {
+        // A partial record would hide the records appended after it.
+        if let Err(err) = self.file.set_len(self.len) {
+          warn!(
+            "unable to remove partial record from journal. path={:?} error={:?}",
+            self.path, err
+          );
+        }
+        Err(err)
+      }
+    }
+  }
+
+  /// Replaces the journal with a snapshot of `memory`, once it's twice as long as it was last compacted.
+  /// Returns how many bytes were reclaimed.
+  pub(super) fn compact(&mut self, memory: &MemoryStorage) -> io::Result<u64> {
+    if self.len < MIN_COMPACTED_BYTES.max(self.compacted_len * 2) {
+      return Ok(0);
+    }
+
+    let tmp_path = self.path.with_extension("journal.tmp");
+    let len = match write_snapshot(&tmp_path, memory) {
+      Ok(len) => len,
+      Err(err) => {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err);
+      }
+    };
+
+    fs::rename(&tmp_path, &self.path)?;
+    File::open(&self.dir)?.sync_all()?;
+    let file = OpenOptions::new().append(true).open(&self.path)?;
+
+    let reclaimed = self.len.saturating_sub(len);
+    self.file = file;
+    self.len = len;
+    self.compacted_len = len;
+    self.dirty = false;
+    Ok(reclaimed)
+  }
+
+  /// Flushes the records appended since the last call.
+  pub(super) fn sync(&mut self) -> io::Result<()> {
+    if self.dirty {
+      self.file.sync_data()?;
+      self.dirty = false;
+    }
+    Ok(())
+  }
+}
+
+/// Writes the changes that make an empty `MemoryStorage` like `memory` to a synced file at `path`,
+/// returns its length.
+fn write_snapshot(path: &Path, memory: &MemoryStorage) -> io::Result<u64> {
+  let mut buf = BytesMut::new();
+  for user in memory.accounts() {
+    encode_change(&Change::UserCreated(user), &mut buf);
+  }
+  for (username, at) in memory.last_connected() {
+    encode_change(&Change::UserSeen { username, at }, &mut buf);
+  }
+  // Members are added where they've read until, then they've received what they've received.
+  for member in memory.members().map_err(io::Error::other)? {
+    encode_change(
+      &Change::MemberAdded {
+        room_id: member.room_id.clone(),
+        username: member.username.clone(),
+        joined_seq: member.read_until,
+      },
+      &mut buf,
+    );
+    encode_change(
+      &Change::Acknowledged {
+        room_id: member.room_id,
+        username: member.username,
+        receipt: Receipt::Delivered,
+        until: member.delivered_until,
+      },
+      &mut buf,
+    );
+  }
+  for token in memory.all_tokens() {
+    encode_change(&Change::TokenCreated(token), &mut buf);
+  }
+
+  let mut file = File::create(path)?;
+  file.write_all(&buf)?;
+  file.sync_all()?;
+  Ok(buf.len() as u64)
+}
+
+fn encode_change(change: &Change, dst: &mut BytesMut) {
+  let mut body = BytesMut::new();
+  match change {
+    Change::UserCreated(user) => {
+      body.put_u8(USER_CREATED);
+      put_string(&mut body, &user.username);
+      put_string(&mut body, &user.password_hash);
+      body.put_i64(user.registered_at.timestamp_millis());
+    }
+    Change::UserSeen { username, at } => {
+      body.put_u8(USER_SEEN);
+      put_string(&mut body, username);
+      body.put_i64(at.timestamp_millis());
+    }
+    Change::MemberAdded {
+      room_id,
+      username,
+      joined_seq,
+    } => {
+      body.put_u8(MEMBER_ADDED);
+      put_string(&mut body, room_id);
+      put_string(&mut body, username);
+      body.put_u64(*joined_seq);
+    }
+    Change::MemberRemoved { room_id, username } => {
+      body.put_u8(MEMBER_REMOVED);
+      put_string(&mut body, room_id);
+      put_string(&mut body, username);
+    }
+    Change::Acknowledged {
+      room_id,
+      username,
+      receipt,
+      until,
+    } => {
+      body.put_u8(ACKNOWLEDGED);
+      put_string(&mut body, room_id);
+      put_string(&mut body, username);
+      body.put_u8((*receipt == Receipt::Read) as u8);
+      body.put_u64(*until);
+    }
+    Change::RoomDeleted(room_id) => {
+      body.put_u8(ROOM_DELETED);
+      put_string(&mut body, room_id);
+    }
+    Change::TokenCreated(token) => {
+      body.put_u8(TOKEN_CREATED);
+      body.put_u64(token.token_id);
+      put_string(&mut body, &token.username);
+      put_string(&mut body, &token.token_hash);
+      body.put_i64(token.created_at.timestamp_millis());
+      body.put_i64(token.last_used_at.timestamp_millis());
+      body.put_i64(token.expires_at.timestamp_millis());
+    }
+    Change::TokenTouched {
+      token_id,
+      at,
+      expires_at,
+    } => {
+      body.put_u8(TOKEN_TOUCHED);
+      body.put_u64(*token_id);
+      body.put_i64(at.timestamp_millis());
+      body.put_i64(expires_at.timestamp_millis());
+    }
+    Change::TokenDeleted(token_id) => {
+      body.put_u8(TOKEN_DELETED);
+      body.put_u64(*token_id);
+    }
+    Change::ExpiredTokensDeleted { username, now } => {
+      body.put_u8(EXPIRED_TOKENS_DELETED);
+      put_string(&mut body, username);
+      body.put_i64(now.timestamp_millis());
+    }
+  }
+
+  put_frame(&body, dst);
+}
+
+fn decode_change(mut body: &[u8]) -> Option<Change> {
+  let change = match get_u8(&mut body)? {
+    USER_CREATED => Change::UserCreated(StoredUser {
+      username: get_string(&mut body)?,
+      password_hash: get_string(&mut body)?,
+      registered_at: get_timestamp(&mut body)?,
+    }),
+    USER_SEEN => Change::UserSeen {
+      username: get_string(&mut body)?,
+      at: get_timestamp(&mut body)?,
+    },
+    MEMBER_ADDED => Change::MemberAdded {
+      room_id: get_string(&mut body)?,
+      username: get_string(&mut body)?,
+      joined_seq: get_u64(&mut body)?,
+    },
+    MEMBER_REMOVED => Change::MemberRemoved {
+      room_id: get_string(&mut body)?,
+      username: get_string(&mut body)?,
+    },
+    ACKNOWLEDGED => Change::Acknowledged {
+      room_id: get_string(&mut body)?,
+      username: get_string(&mut body)?,
+      receipt: match get_u8(&mut body)? {
+        0 => Receipt::Delivered,
+        _ => Receipt::Read,
+      },
+      until: get_u64(&mut body)?,
+    },
+    ROOM_DELETED => Change::RoomDeleted(get_string(&mut body)?),
+    TOKEN_CREATED => Change::TokenCreated(StoredToken {
+      token_id: get_u64(&mut body)?,
+      username: get_string(&mut body)?,
+      token_hash: get_string(&mut body)?,
+      created_at: get_timestamp(&mut body)?,
+      last_used_at: get_timestamp(&mut body)?,
+      expires_at: get_timestamp(&mut body)?,
+    }),
+    TOKEN_TOUCHED => Change::TokenTouched {
+      token_id: get_u64(&mut body)?,
+      at: get_timestamp(&mut body)?,
+      expires_at: get_timestamp(&mut body)?,
+    },
+    TOKEN_DELETED => Change::TokenDeleted(get_u64(&mut body)?),
+    EXPIRED_TOKENS_DELETED => Change::ExpiredTokensDeleted {
+      username: get_string(&mut body)?,
+      now: get_timestamp(&mut body)?,
+    },
+    _ => return None,
+  };
+
+  Some(change)
+}
+
+#[cfg(test)]
+mod tests {
+  use chrono::TimeZone;
+
+  use super::*;
+
+  /// An empty directory of its own for the test.
+  fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("journal-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn at(secs: i64) -> DateTime<Utc> {
+    Utc.timestamp_millis_opt(secs * 1000).unwrap()
+  }
+
+  fn user_created(username: &str) -> Change {
+    Change::UserCreated(StoredUser {
+      username: username.to_owned(),
+      password_hash: "$argon2id$hash".to_owned(),
+      registered_at: at(1),
+    })
+  }
+
+  fn token(token_id: u64, username: &str) -> StoredToken {
+    StoredToken {
+      token_id,
+      username: username.to_owned(),
+      token_hash: format!("hash {token_id}"),
+      created_at: at(0),
+      last_used_at: at(0),
+      expires_at: at(100),
+    }
+  }
+
+  fn acknowledged(receipt: Receipt, until: u64) -> Change {
+    Change::Acknowledged {
+      room_id: "room".to_owned(),
+      username: "ann".to_owned(),
+      receipt,
+      until,
+    }
+  }
+
+  /// Applies the changes to `memory` the way `LogStorage` does.
+  fn change(journal: &mut Journal, memory: &MemoryStorage, changes: Vec<Change>) {
+    for change in changes {
+      journal.append(&change, false).unwrap();
+      change.apply(memory).unwrap();
+    }
+  }
+
+  /// Everything the journal describes, comparable.
+  fn describe(memory: &MemoryStorage) -> Vec<String> {
+    let mut described: Vec<_> = memory
+      .accounts()
+      .iter()
+      .map(|user| format!("{user:?}"))
+      .chain(
+        memory
+          .last_connected()
+          .iter()
+          .map(|seen| format!("{seen:?}")),
+      )
+      .chain(
+        memory
+          .members()
+          .unwrap()
+          .iter()
+          .map(|member| format!("{member:?}")),
+      )
+      .chain(memory.all_tokens().iter().map(|token| format!("{token:?}")))
+      .collect();
+    described.sort_unstable();
+    described
+  }
+
+  fn usernames(memory: &MemoryStorage) -> Vec<String> {
+    let mut usernames: Vec<_> = memory
+      .accounts()
+      .into_iter()
+      .map(|user| user.username)
+      .collect();
+    usernames.sort_unstable();
+    usernames
+  }
+
+  #[test]
+  fn changes_are_applied_again_when_opened() {
+    let dir = test_dir("reopened");
+    let memory = MemoryStorage::default();
+    let mut journal = Journal::open(&dir, &memory).unwrap();
+    change(
+      &mut journal,
+      &memory,
+      vec![
+        user_created("ann"),
+        Change::UserSeen {
+          username: "ann".to_owned(),
+          at: at(2),
+        },
+        Change::MemberAdded {
+          room_id: "room".to_owned(),
+          username: "ann".to_owned(),
+          joined_seq: 3,
+        },
+        acknowledged(Receipt::Delivered, 10),
+        acknowledged(Receipt::Read, 7),
+        Change::MemberAdded {
+          room_id: "other".to_owned(),
+          username: "ann".to_owned(),
+          joined_seq: 0,
+        },
+        Change::MemberRemoved {
+          room_id: "other".to_owned(),
+          username: "ann".to_owned(),
+        },
+        Change::MemberAdded {
+          room_id: "deleted".to_owned(),
+          username: "ann".to_owned(),
+          joined_seq: 0,
+        },
+        Change::RoomDeleted("deleted".to_owned()),
+        Change::TokenCreated(token(1, "ann")),
+        Change::TokenCreated(token(2, "ann")),
+        Change::TokenCreated(token(3, "bob")),
+        Change::TokenTouched {
+          token_id: 1,
+          at: at(50),
+          expires_at: at(150),
+        },
+        Change::TokenDeleted(3),
+        Change::ExpiredTokensDeleted {
+          username: "ann".to_owned(),
+          now: at(120),
+        },
+      ],
+    );
+    drop(journal);
+
+    let reopened = MemoryStorage::default();
+    Journal::open(&dir, &reopened).unwrap();
+    assert_eq!(describe(&reopened), describe(&memory));
+    let members = reopened.members().unwrap();
+    assert_eq!(members.len(), 1);
+    assert_eq!((members[0].delivered_until, members[0].read_until), (10, 7));
+    let tokens = reopened.all_tokens();
+    assert_eq!(tokens.len(), 1);
+    assert_eq!(tokens[0].expires_at, at(150));
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn partial_records_are_truncated() {
+    let dir = test_dir("partial");
+    let memory = MemoryStorage::default();
+    let mut journal = Journal::open(&dir, &memory).unwrap();
+    change(&mut journal, &memory, vec![user_created("ann")]);
+    drop(journal);
+
+    let mut buf = BytesMut::new();
+    encode_change(&user_created("bob"), &mut buf);
+    let mut file = OpenOptions::new()
+      .append(true)
+      .open(dir.join(FILE_NAME))
+      .unwrap();
+    file.write_all(&buf[..buf.len() - 1]).unwrap();
+    drop(file);
+
+    // The changes appended after the partial record are kept.
+    let memory = MemoryStorage::default();
+    let mut journal = Journal::open(&dir, &memory).unwrap();
+    assert_eq!(usernames(&memory), ["ann"]);
+    change(&mut journal, &memory, vec![user_created("cat")]);
+    drop(journal);
+
+    let memory = MemoryStorage::default();
+    Journal::open(&dir, &memory).unwrap();
+    assert_eq!(usernames(&memory), ["ann", "cat"]);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn journals_corrupt_before_their_end_are_moved_aside() {
+    let dir = test_dir("corrupt");
+    let memory = MemoryStorage::default();
+    let mut journal = Journal::open(&dir, &memory).unwrap();
+    change(
+      &mut journal,
+      &memory,
+      vec![user_created("ann"), user_created("bob")],
+    );
+    drop(journal);
+
+    // A bit of the body of the first record flips.
+    let path = dir.join(FILE_NAME);
+    let mut bytes = fs::read(&path).unwrap();
+    bytes[10] ^= 1;
+    fs::write(&path, &bytes).unwrap();
+
+    assert!(Journal::open(&dir, &MemoryStorage::default()).is_err());
+    assert!(!path.exists());
+    assert_eq!(
+      fs::read(dir.join("accounts.journal.corrupt")).unwrap(),
+      bytes
+    );
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn compaction_keeps_what_the_journal_describes() {
+    let dir = test_dir("compacted");
+    let memory = MemoryStorage::default();
+    let mut journal = Journal::open(&dir, &memory).unwrap();
+    change(
+      &mut journal,
+      &memory,
+      vec![
+        user_created("ann"),
+        Change::MemberAdded {
+          room_id: "room".to_owned(),
+          username: "ann".to_owned(),
+          joined_seq: 0,
+        },
+        Change::TokenCreated(token(1, "ann")),
+      ],
+    );
+    assert_eq!(journal.compact(&memory).unwrap(), 0);
+
+    let mut until = 0;
+    while journal.len < MIN_COMPACTED_BYTES {
+      until += 1;
+      change(
+        &mut journal,
+        &memory,
+        vec![
+          acknowledged(Receipt::Delivered, until),
+          acknowledged(Receipt::Read, until - 1),
+        ],
+      );
+    }
+    let len = journal.len;
+    let reclaimed = journal.compact(&memory).unwrap();
+    assert!(reclaimed > 0);
+    assert_eq!(
+      fs::metadata(dir.join(FILE_NAME)).unwrap().len(),
+      len - reclaimed
+    );
+    change(&mut journal, &memory, vec![user_created("bob")]);
+    drop(journal);
+
+    let reopened = MemoryStorage::default();
+    Journal::open(&dir, &reopened).unwrap();
+    assert_eq!(describe(&reopened), describe(&memory));
+    let members = reopened.members().unwrap();
+    assert_eq!(
+      (members[0].delivered_until, members[0].read_until),
+      (until, until - 1)
+    );
+    assert_eq!(usernames(&reopened), ["ann", "bob"]);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
@@ -0,0 +1,336 @@
+use std::{
+  collections::HashMap,
+  path::{Path, PathBuf},
+  sync::{Arc, Mutex},
+};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, Utc};
+use tracing::warn;
+
+use super::{
+  journal::{Change, Journal},
+  room_log::RoomLog,
+  FsyncPolicy, MemoryStorage, Receipt, Storage, StoredMember, StoredMessage, StoredRoom,
+  StoredToken, StoredUser,
+};
+use crate::retention::{RetentionPolicy, Trimmed};
+
+/// Stores the rooms and their messages in a `RoomLog` each,
+/// everything else is kept in a `MemoryStorage` whose changes are stored in a `Journal`.
+pub(crate) struct LogStorage {
+  dir: PathBuf,
+  fsync_policy: FsyncPolicy,
+  /// Every log has its own lock so messages sent to different rooms don't wait for each other.
+  logs: Mutex<HashMap<String, Arc<Mutex<RoomLog>>>>,
+  /// Held while `memory` is changed, so changes are applied in the order of the journal.
+  journal: Mutex<Journal>,
+  memory: MemoryStorage,
+}
+
+impl LogStorage {
+  /// Opens the logs and the journal in `dir`, those ending with a partial record are truncated
+  /// and a corrupt one is moved aside before failing.
+  pub(crate) fn open(dir: &Path, fsync_policy: FsyncPolicy) -> Result<Self> {
+    super::create_dir(dir)?;
+
+    let logs = RoomLog::open_all(dir)
+      .with_context(|| format!("unable to open room logs in {dir:?}"))?
+      .into_iter()
+      .map(|log| (log.room.room_id.clone(), Arc::new(Mutex::new(log))))
+      .collect();
+
+    let memory = MemoryStorage::default();
+    let journal =
+      Journal::open(dir, &memory).with_context(|| format!("unable to open journal in {dir:?}"))?;
+
+    Ok(Self {
+      dir: dir.to_owned(),
+      fsync_policy,
+      logs: Mutex::new(logs),
+      journal: Mutex::new(journal),
+      memory,
+    })
+  }
+
+  /// Stores the change in the journal, then applies it.
+  fn change(&self, change: Change) -> Result<()> {
+    let mut journal = self.journal.lock().unwrap();
+    self.apply(&mut journal, change)
+  }
+
+  fn apply(&self, journal: &mut Journal, change: Change) -> Result<()> {
+    let sync = self.fsync_policy == FsyncPolicy::Always;
+    journal
+      .append(&change, sync)
+      .context("unable to append to journal")?;
+    change.apply(&self.memory)?;
+
+    // The change is stored anyway.
+    if let Err(err) = journal.compact(&self.memory) {
+      warn!("unable to compact journal. error={:?}", err);
+    }
+    Ok(())
+  }
+
+  fn log(&self, room_id: &str) -> Result<Arc<Mutex<RoomLog>>> {
+    self
+      .logs
+      .lock()
+      .unwrap()
+      .get(room_id)
+      .cloned()
+      .with_context(|| format!("room {room_id} has no log"))
+  }
+}
+
+impl Storage for LogStorage {
+  fn create_room(&self, room: &StoredRoom) -> Result<()> {
+    let log = RoomLog::create(&self.dir, room.clone())
+      .with_context(|| format!("unable to create log of room {}", room.room_id))?;
+    self
+      .logs
+      .lock()
+      .unwrap()
+      .insert(room.room_id.clone(), Arc::new(Mutex::new(log)));
+    Ok(())
+  }
+
+  fn delete_room(&self, room_id: &str) -> Result<()> {
+    let log = self.logs.lock().unwrap().remove(room_id);
+    if let Some(log) = log {
+      log.lock().unwrap().delete()?;
+    }
+    self.change(Change::RoomDeleted(room_id.to_owned()))
+  }
+
+  fn rooms(&self) -> Result<Vec<(StoredRoom, Option<u64>)>> {
+    let logs: Vec<_> = self.logs.lock().unwrap().values().cloned().collect();
+    Ok(
+      logs
+        .iter()
+        .map(|log| {
+          let log = log.lock().unwrap();
+          (log.room.clone(), log.last_seq())
+        })
+        .collect(),
+    )
+  }
+
+  fn append_message(&self, room_id: &str, message: &StoredMessage) -> Result<()> {
+    let sync = self.fsync_policy == FsyncPolicy::Always;
+    self
+      .log(room_id)?
+      .lock()
+      .unwrap()
+      .append_message(message.clone(), sync)
+      .with_context(|| format!("unable to append to log of room {room_id}"))
+  }
+
+  fn messages(
+    &self,
+    room_id: &str,
+    after: Option<u64>,
+    before: Option<u64>,
+    limit: usize,
+  ) -> Result<Vec<StoredMessage>> {
+    self
+      .log(room_id)?
+      .lock()
+      .unwrap()
+      .read_messages(after, before, limit)
+      .with_context(|| format!("unable to read log of room {room_id}"))
+  }
+
+  fn apply_retention(
+    &self,
+    room_id: &str,
+    policy: &RetentionPolicy,
+    now: DateTime<Utc>,
+  ) -> Result<Trimmed> {
+    let sync = self.fsync_policy == FsyncPolicy::Always;
+    self
+      .log(room_id)?
+      .lock()
+      .unwrap()
+      .apply_retention(policy, now, sync)
+      .with_context(|| format!("unable to trim log of room {room_id}"))
+  }
+
+  fn compact(&self) -> Result<u64> {
+    let logs: Vec<_> = self
+      .logs
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|(room_id, log)| (room_id.clone(), Arc::clone(log)))
+      .collect();
+
+    let mut reclaimed = 0;
+    for (room_id, log) in logs {
+      // The other logs are compacted anyway.
+      match log.lock().unwrap().compact(&self.dir) {
+        Ok(bytes) => reclaimed += bytes,
+        Err(err) => warn!(
+          "unable to compact room log. room_id={} error={:?}",
+          room_id, err
+        ),
+      }
+    }
+
+    Ok(reclaimed)
+  }
+
+  fn add_member(&self, room_id: &str, username: &str, joined_seq: u64) -> Result<()> {
+    self.change(Change::MemberAdded {
+      room_id: room_id.to_owned(),
+      username: username.to_owned(),
+      joined_seq,
+    })
+  }
+
+  fn remove_member(&self, room_id: &str, username: &str) -> Result<()> {
+    self.change(Change::MemberRemoved {
+      room_id: room_id.to_owned(),
+      username: username.to_owned(),
+    })
+  }
+
+  fn members(&self) -> Result<Vec<StoredMember>> {
+    self.memory.members()
+  }
+
+  fn memberships(&self, username: &str) -> Result<Vec<StoredMember>> {
+    self.memory.memberships(username)
+  }
+
+  fn acknowledge(&self, room_id: &str, username: &str, receipt: Receipt, until: u64) -> Result<()> {
+    self.change(Change::Acknowledged {
+      room_id: room_id.to_owned(),
+      username: username.to_owned(),
+      receipt,
+      until,
+    })
+  }
+
+  fn create_user(&self, user: &StoredUser) -> Result<bool> {
+    let mut journal = self.journal.lock().unwrap();
+    if self.memory.user(&user.username)?.is_some() {
+      return Ok(false);
+    }
+    self.apply(&mut journal, Change::UserCreated(user.clone()))?;
+    Ok(true)
+  }
+
+  fn user(&self, username: &str) -> Result<Option<StoredUser>> {
+    self.memory.user(username)
+  }
+
+  fn save_user(&self, username: &str, at: DateTime<Utc>) -> Result<()> {
+    self.change(Change::UserSeen {
+      username: username.to_owned(),
+      at,
+    })
+  }
+
+  fn create_token(&self, token: &StoredToken) -> Result<()> {
+    self.change(Change::TokenCreated(token.clone()))
+  }
+
+  fn token(&self, token_hash: &str) -> Result<Option<StoredToken>> {
+    self.memory.token(token_hash)
+  }
+
+  fn tokens(&self, username: &str) -> Result<Vec<StoredToken>> {
+    self.memory.tokens(username)
+  }
+
+  fn touch_token(&self, token_id: u64, at: DateTime<Utc>, expires_at: DateTime<Utc>) -> Result<()> {
+    self.change(Change::TokenTouched {
+      token_id,
+      at,
+      expires_at,
+    })
+  }
+
+  fn delete_token(&self, token_id: u64) -> Result<()> {
+    self.change(Change::TokenDeleted(token_id))
+  }
+
+  fn delete_expired_tokens(&self, username: &str, now: DateTime<Utc>) -> Result<()> {
+    self.change(Change::ExpiredTokensDeleted {
+      username: username.to_owned(),
+      now,
+    })
+  }
+
+  fn sync(&self) -> Result<()> {
+    let logs: Vec<_> = self
+      .logs
+      .lock()
+      .unwrap()
+      .iter()
+      .map(|(room_id, log)| (room_id.clone(), Arc::clone(log)))
+      .collect();
+
+    for (room_id, log) in logs {
+      let file = log.lock().unwrap().take_unsynced();
+      // Synced without the lock of the log, messages keep being appended meanwhile.
+      if let Some(file) = file {
+        if let Err(err) = file.sync_data() {
+          warn!(
+            "unable to sync room log. room_id={} error={:?}",
+            room_id, err
+          );
+        }
+      }
+    }
+
+    if let Err(err) = self.journal.lock().unwrap().sync() {
+      warn!("unable to sync journal. error={:?}", err);
+    }
+
+    Ok(())
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use std::fs;
+
+  use super::*;
+
+  #[test]
+  fn accounts_are_kept_across_restarts() {
+    let dir = std::env::temp_dir().join(format!("log-storage-{}-accounts", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    let user = StoredUser {
+      username: "ann".to_owned(),
+      password_hash: "$argon2id$hash".to_owned(),
+      registered_at: Utc::now(),
+    };
+    let room = StoredRoom {
+      room_id: "room".to_owned(),
+      display_name: "room".to_owned(),
+      topic: String::new(),
+      created_at: Utc::now(),
+      persistent: false,
+    };
+
+    let storage = LogStorage::open(&dir, FsyncPolicy::Always).unwrap();
+    assert!(storage.create_user(&user).unwrap());
+    storage.create_room(&room).unwrap();
+    storage.add_member("room", "ann", 0).unwrap();
+    drop(storage);
+
+    let storage = LogStorage::open(&dir, FsyncPolicy::Always).unwrap();
+    assert!(!storage.create_user(&user).unwrap());
+    assert_eq!(
+      storage.user("ann").unwrap().unwrap().password_hash,
+      user.password_hash
+    );
+    assert_eq!(storage.memberships("ann").unwrap().len(), 1);
+    assert_eq!(storage.rooms().unwrap().len(), 1);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
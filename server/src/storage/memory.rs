@@ -0,0 +1,346 @@
+use std::{
+  collections::{BTreeMap, HashMap},
+  sync::Mutex,
+};
+
+use anyhow::Result;
+use chrono::{DateTime, Utc};
+
+use super::{Receipt, Storage, StoredMember, StoredMessage, StoredRoom, StoredToken, StoredUser};
+use crate::retention::{RetentionPolicy, Trimmed};
+
+/// Keeps everything in memory, for tests and servers that don't need to keep anything.
+#[derive(Default)]
+pub(crate) struct MemoryStorage {
+  state: Mutex<State>,
+}
+
+#[derive(Default)]
+struct State {
+  rooms: HashMap<String, Room>,
+  /// The `delivered_until` and `read_until` of the members of every room by username,
+  /// stored apart from rooms for `LogStorage`.
+  members: HashMap<String, BTreeMap<String, (u64, u64)>>,
+  /// When every user last connected.
+  users: HashMap<String, DateTime<Utc>>,
+  accounts: HashMap<String, StoredUser>,
+  tokens: HashMap<u64, StoredToken>,
+}
+
+struct Room {
+  room: StoredRoom,
+  messages: Vec<StoredMessage>,
+  /// The messages before this seq have been deleted.
+  trimmed_until: u64,
+}
+
+impl Room {
+  fn last_seq(&self) -> Option<u64> {
+    self
+      .messages
+      .last()
+      .map(|message| message.seq)
+      .or_else(|| self.trimmed_until.checked_sub(1))
+  }
+}
+
+impl MemoryStorage {
+  /// Returns every account.
+  pub(super) fn accounts(&self) -> Vec<StoredUser> {
+    let state = self.state.lock().unwrap();
+    state.accounts.values().cloned().collect()
+  }
+
+  /// Returns when every user last connected.
+  pub(super) fn last_connected(&self) -> Vec<(String, DateTime<Utc>)> {
+    let state = self.state.lock().unwrap();
+    state
+      .users
+      .iter()
+      .map(|(username, &at)| (username.clone(), at))
+      .collect()
+  }
+
+  /// Returns the tokens of every user.
+  pub(super) fn all_tokens(&self) -> Vec<StoredToken> {
+    let state = self.state.lock().unwrap();
+    state.tokens.values().cloned().collect()
+  }
+}
+
+impl Storage for MemoryStorage {
+  fn create_room(&self, room: &StoredRoom) -> Result<()> {
+    let mut state = self.state.lock().unwrap();
+    state.rooms.insert(
+      room.room_id.clone(),
+      Room {
+        room: room.clone(),
+        messages: Vec::new(),
+        trimmed_until: 0,
+      },
+    );
+    state.members.remove(&room.room_id);
+    Ok(())
+  }
+
+  fn delete_room(&self, room_id: &str) -> Result<()> {
+    let mut state = self.state.lock().unwrap();
+    state.rooms.remove(room_id);
+    state.members.remove(room_id);
+    Ok(())
+  }
+
+  fn rooms(&self) -> Result<Vec<(StoredRoom, Option<u64>)>> {
+    let state = self.state.lock().unwrap();
+    Ok(
+      state
+        .rooms
+        .values()
+        .map(|room| (room.room.clone(), room.last_seq()))
+        .collect(),
+    )
+  }
+
+  fn append_message(&self, room_id: &str, message: &StoredMessage) -> Result<()> {
+    let mut state = self.state.lock().unwrap();
+    match state.rooms.get_mut(room_id) {
+      Some(room) => {
+        room.messages.push(message.clone());
+        Ok(())
+      }
+      None => anyhow::bail!("room {room_id} is not stored"),
+    }
+  }
+
+  fn messages(
+    &self,
+    room_id: &str,
+    after: Option<u64>,
+    before: Option<u64>,
+    limit: usize,
+  ) -> Result<Vec<StoredMessage>> {
+    let state = self.state.lock().unwrap();
+    let messages = match state.rooms.get(room_id) {
+      Some(room) => &room.messages,
+      None => return Ok(Vec::new()),
+    };
+
+    let in_range = |message: &&StoredMessage| {
+      after.is_none_or(|after| message.seq > after)
+        && before.is_none_or(|before| message.seq < before)
+    };
+    let mut page: Vec<_> = if after.is_some() {
+      messages
+        .iter()
+        .filter(in_range)
+        .take(limit)
+        .cloned()
+        .collect()
+    } else {
+      messages
+        .iter()
+        .rev()
+        .filter(in_range)
+        .take(limit)
+        .cloned()
+        .collect()
+    };
+    page.sort_unstable_by_key(|message| message.seq);
+    Ok(page)
+  }
+
+  fn apply_retention(
+    &self,
+    room_id: &str,
+    policy: &RetentionPolicy,
+    now: DateTime<Utc>,
+  ) -> Result<Trimmed> {
+    let mut state = self.state.lock().unwrap();
+    let room = match state.rooms.get_mut(room_id) {
+      Some(room) => room,
+      None => return Ok(Trimmed::default()),
+    };
+
+    let cutoff = policy.cutoff(
+      now,
+      room
+        .messages
+        .iter()
+        .rev()
+        .map(|message| (message.seq, message.sent_at, message.size())),
+    );
+    let cutoff = match cutoff {
+      Some(cutoff) => cutoff,
+      None => return Ok(Trimmed::default()),
+    };
+
+    let len = room
+      .messages
+      .partition_point(|message| message.seq < cutoff);
+    let mut trimmed = Trimmed {
+      until: cutoff,
+      ..Trimmed::default()
+    };
+    for message in room.messages.drain(..len) {
+      trimmed.messages += 1;
+      trimmed.bytes += message.size();
+    }
+    room.trimmed_until = room.trimmed_until.max(cutoff);
+    Ok(trimmed)
+  }
+
+  fn compact(&self) -> Result<u64> {
+    // Deleted messages are freed right away.
+    Ok(0)
+  }
+
+  fn add_member(&self, room_id: &str, username: &str, joined_seq: u64) -> Result<()> {
+    let mut state = self.state.lock().unwrap();
+    state
+      .members
+      .entry(room_id.to_owned())
+      .or_default()
+      .entry(username.to_owned())
+      .or_insert((joined_seq, joined_seq));
+    Ok(())
+  }
+
+  fn remove_member(&self, room_id: &str, username: &str) -> Result<()> {
+    let mut state = self.state.lock().unwrap();
+    if let Some(members) = state.members.get_mut(room_id) {
+      members.remove(username);
+    }
+    Ok(())
+  }
+
+  fn members(&self) -> Result<Vec<StoredMember>> {
+    let state = self.state.lock().unwrap();
+    Ok(
+      state
+        .members
+        .iter()
+        .flat_map(|(room_id, members)| {
+          members
+            .iter()
+            .map(|(username, &positions)| member(room_id, username, positions))
+        })
+        .collect(),
+    )
+  }
+
+  fn memberships(&self, username: &str) -> Result<Vec<StoredMember>> {
+    let state = self.state.lock().unwrap();
+    Ok(
+      state
+        .members
+        .iter()
+        .filter_map(|(room_id, members)| {
+          let &positions = members.get(username)?;
+          Some(member(room_id, username, positions))
+        })
+        .collect(),
+    )
+  }
+
+  fn acknowledge(&self, room_id: &str, username: &str, receipt: Receipt, until: u64) -> Result<()> {
+    let mut state = self.state.lock().unwrap();
+    if let Some((delivered_until, read_until)) = state
+      .members
+      .get_mut(room_id)
+      .and_then(|members| members.get_mut(username))
+    {
+      *delivered_until = (*delivered_until).max(until);
+      if receipt == Receipt::Read {
+        *read_until = (*read_until).max(until);
+      }
+    }
+    Ok(())
+  }
+
+  fn create_user(&self, user: &StoredUser) -> Result<bool> {
+    let mut state = self.state.lock().unwrap();
+    if state.accounts.contains_key(&user.username) {
+      return Ok(false);
+    }
+    state.accounts.insert(user.username.clone(), user.clone());
+    Ok(true)
+  }
+
+  fn user(&self, username: &str) -> Result<Option<StoredUser>> {
+    Ok(self.state.lock().unwrap().accounts.get(username).cloned())
+  }
+
+  fn save_user(&self, username: &str, at: DateTime<Utc>) -> Result<()> {
+    let mut state = self.state.lock().unwrap();
+    state.users.insert(username.to_owned(), at);
+    Ok(())
+  }
+
+  fn create_token(&self, token: &StoredToken) -> Result<()> {
+    let mut state = self.state.lock().unwrap();
+    state.tokens.insert(token.token_id, token.clone());
+    Ok(())
+  }
+
+  fn token(&self, token_hash: &str) -> Result<Option<StoredToken>> {
+    let state = self.state.lock().unwrap();
+    Ok(
+      state
+        .tokens
+        .values()
+        .find(|token| token.token_hash == token_hash)
+        .cloned(),
+    )
+  }
+
+  fn tokens(&self, username: &str) -> Result<Vec<StoredToken>> {
+    let state = self.state.lock().unwrap();
+    Ok(
+      state
+        .tokens
+        .values()
+        .filter(|token| token.username == username)
+        .cloned()
+        .collect(),
+    )
+  }
+
+  fn touch_token(&self, token_id: u64, at: DateTime<Utc>, expires_at: DateTime<Utc>) -> Result<()> {
+    let mut state = self.state.lock().unwrap();
+    if let Some(token) = state.tokens.get_mut(&token_id) {
+      token.last_used_at = at;
+      token.expires_at = expires_at;
+    }
+    Ok(())
+  }
+
+  fn delete_token(&self, token_id: u64) -> Result<()> {
+    self.state.lock().unwrap().tokens.remove(&token_id);
+    Ok(())
+  }
+
+  fn delete_expired_tokens(&self, username: &str, now: DateTime<Utc>) -> Result<()> {
+    let mut state = self.state.lock().unwrap();
+    state
+      .tokens
+      .retain(|_, token| token.username != username || token.expires_at > now);
+    Ok(())
+  }
+
+  fn sync(&self) -> Result<()> {
+    Ok(())
+  }
+}
+
+fn member(
+  room_id: &str,
+  username: &str,
+  (delivered_until, read_until): (u64, u64),
+) -> StoredMember {
+  StoredMember {
+    room_id: room_id.to_owned(),
+    username: username.to_owned(),
+    delivered_until,
+    read_until,
+  }
+}
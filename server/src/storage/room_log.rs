@@ -0,0 +1,834 @@
+//! The messages of a room, stored on disk.
+//!
+//! Every room has its own append-only file of records, named after a hash of the room id since
+//! ids can be longer than file names. The id is in the first record. A record is a `u32` length,
+//! the crc32 of the body and the body. A crash while appending leaves a partial record
+//! at the end of the file, it's truncated when the log is opened again. An invalid record
+//! anywhere else means the file is corrupt, it's moved aside and the server refuses to start.
+//!
+//! Messages are deleted by appending a `Trimmed` record, a tombstone for every message before a seq.
+//! Once deleted messages take half of the file, it's compacted: the records that are left are
+//! written to a new file that replaces it.
+
+use std::{
+  collections::BTreeMap,
+  fs::{self, File, OpenOptions},
+  io::{self, BufReader, BufWriter, Read, Seek, SeekFrom, Write},
+  ops::Bound,
+  path::{Path, PathBuf},
+  sync::Arc,
+};
+
+use blake2::{digest::consts::U16, Blake2b, Digest};
+use bytes::{Buf, BufMut, BytesMut};
+use chrono::{DateTime, TimeZone, Utc};
+use tracing::{error, warn};
+
+use super::{StoredMessage, StoredRoom};
+use crate::retention::{RetentionPolicy, Trimmed};
+
+/// Records larger than this are considered corrupt.
+const MAX_RECORD_BYTES: usize = 64 * 1024;
+
+const HEADER_BYTES: usize = 8;
+
+const CREATED: u8 = 0;
+const MESSAGE: u8 = 1;
+const TRIMMED: u8 = 2;
+
+#[derive(Debug)]
+enum Record {
+  /// The first record of every log.
+  Created(StoredRoom),
+  Message(StoredMessage),
+  /// The messages before this seq are deleted.
+  Trimmed(u64),
+}
+
+/// Where the record of a message is in the file, and what retention needs to know about the message.
+#[derive(Debug, Clone, Copy)]
+struct Entry {
+  offset: u64,
+  len: u64,
+  sent_at: DateTime<Utc>,
+  size: u64,
+}
+
+/// What's next in a file of records.
+pub(super) enum Next<T> {
+  /// A valid record and its size in the file.
+  Record(T, usize),
+  /// The end of the file.
+  End,
+  /// A record at the end of the file that wasn't completely written.
+  Torn,
+  /// An invalid record followed by more of the file.
+  Corrupt,
+}
+
+pub(super) struct RoomLog {
+  /// The first record of the log.
+  pub(super) room: StoredRoom,
+  /// The record of every message that isn't deleted, by seq.
+  entries: BTreeMap<u64, Entry>,
+  /// The messages before this seq are deleted.
+  trimmed_until: u64,
+  /// The length of the last `Trimmed` record, it's dead once another one is appended.
+  tombstone_len: u64,
+  /// The length of the records of deleted messages and of the `Trimmed` records that were replaced.
+  dead_len: u64,
+  path: PathBuf,
+  file: Arc<File>,
+  /// The length of the file once every record appended so far is written.
+  len: u64,
+  /// Records have been appended since the file was last synced.
+  dirty: bool,
+  /// The file has been removed, it must not be written again by a compaction.
+  deleted: bool,
+}
+
+impl RoomLog {
+  /// Creates the log of a new room in `dir`, replacing any file left by a room that failed to be created.
+  pub(super) fn create(dir: &Path, room: StoredRoom) -> io::Result<Self> {
+    let path = path_for(dir, &room.room_id);
+    File::create(&path)?;
+    // Opened again because a file can't be truncated and opened in append mode at once.
+    let file = OpenOptions::new().append(true).open(&path)?;
+    let mut log = Self {
+      room: room.clone(),
+      entries: BTreeMap::new(),
+      trimmed_until: 0,
+      tombstone_len: 0,
+      dead_len: 0,
+      path,
+      file: Arc::new(file),
+      len: 0,
+      dirty: false,
+      deleted: false,
+    };
+
+    log.append(Record::Created(room), true)?;
+    // Makes the new file itself durable.
+    File::open(dir)?.sync_all()?;
+
+    Ok(log)
+  }
+
+  /// Opens every log in `dir`. Logs without a valid first record are skipped,
+  /// fails if a log is corrupt or can't be read.
+  pub(super) fn open_all(dir: &Path) -> io::Result<Vec<Self>> {
+    let entries = match fs::read_dir(dir) {
+      Ok(entries) => entries,
+      Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Vec::new()),
+      Err(err) => return Err(err),
+    };
+
+    let mut logs = Vec::new();
+    for entry in entries {
+      let path = entry?.path();
+      if path.extension().is_some_and(|extension| extension == "tmp") {
+        // Left by a compaction that didn't finish, the log it was replacing is intact.
+        fs::remove_file(&path)?;
+        continue;
+      }
+      if path.extension().is_none_or(|extension| extension != "log") {
+        continue;
+      }
+
+      match Self::open(&path)? {
+        Some(log) => logs.push(log),
+        None => warn!("room log has no valid header, ignoring it. path={:?}", path),
+      }
+    }
+
+    Ok(logs)
+  }
+
+  /// Reads the log at `path` and truncates a record torn by a crash at its end.
+  /// Returns `None` if the log doesn't start with a valid `Created` record,
+  /// fails and renames the file to `*.log.corrupt` if an invalid record is followed by others.
+  fn open(path: &Path) -> io::Result<Option<Self>> {
+    let file = OpenOptions::new().read(true).append(true).open(path)?;
+    let file_len = file.metadata()?.len();
+
+    let mut reader = BufReader::new(&file);
+    let mut len = 0;
+    let mut room = None;
+    let mut entries = BTreeMap::new();
+    let (mut trimmed_until, mut tombstone_len, mut dead_len) = (0, 0, 0);
+
+    loop {
+      let (record, record_len) = match read_record(&mut reader, file_len - len, decode_record)? {
+        Next::Record(record, record_len) => (record, record_len),
+        Next::End | Next::Torn => break,
+        Next::Corrupt => {
+          drop(reader);
+          drop(file);
+          return Err(set_aside(path, len));
+        }
+      };
+      let record_len = record_len as u64;
+      match (record, &room) {
+        (Record::Created(created), None) => room = Some(created),
+        (Record::Message(message), Some(_)) => {
+          entries.insert(message.seq, Entry::new(&message, len, record_len));
+        }
+        (Record::Trimmed(until), Some(_)) => {
+          dead_len += trim(&mut entries, until).1 + tombstone_len;
+          trimmed_until = trimmed_until.max(until);
+          tombstone_len = record_len;
+        }
+        (record, _) => {
+          warn!(
+            "unexpected record in room log. path={:?} record={:?}",
+            path, record
+          );
+          drop(reader);
+          drop(file);
+          return Err(set_aside(path, len));
+        }
+      }
+      len += record_len;
+    }
+
+    let room = match room {
+      Some(room) => room,
+      None => return Ok(None),
+    };
+
+    if len < file_len {
+      warn!(
+        "room log ends with a partial record, truncating it. path={:?} len={} valid_len={}",
+        path, file_len, len
+      );
+      file.set_len(len)?;
+      file.sync_all()?;
+    }
+
+    Ok(Some(Self {
+      room,
+      entries,
+      trimmed_until,
+      tombstone_len,
+      dead_len,
+      path: path.to_owned(),
+      file: Arc::new(file),
+      len,
+      dirty: false,
+      deleted: false,
+    }))
+  }
+
+  /// Appends a message, it's flushed to the disk before returning if `sync` is true.
+  pub(super) fn append_message(&mut self, message: StoredMessage, sync: bool) -> io::Result<()> {
+    let offset = self.len;
+    let (seq, mut entry) = (message.seq, Entry::new(&message, offset, 0));
+    entry.len = self.append(Record::Message(message), sync)?;
+    self.entries.insert(seq, entry);
+    Ok(())
+  }
+
+  /// The seq of the last message in the log, even if it was deleted.
+  pub(super) fn last_seq(&self) -> Option<u64> {
+    self
+      .entries
+      .last_key_value()
+      .map(|(&seq, _)| seq)
+      .or_else(|| self.trimmed_until.checked_sub(1))
+  }
+
+  /// Deletes the oldest messages that `policy` doesn't keep at `now` with a `Trimmed` record.
+  pub(super) fn apply_retention(
+    &mut self,
+    policy: &RetentionPolicy,
+    now: DateTime<Utc>,
+    sync: bool,
+  ) -> io::Result<Trimmed> {
+    let cutoff = policy.cutoff(
+      now,
+      self
+        .entries
+        .iter()
+        .rev()
+        .map(|(&seq, entry)| (seq, entry.sent_at, entry.size)),
+    );
+    let cutoff = match cutoff {
+      Some(cutoff) if cutoff > self.trimmed_until => cutoff,
+      _ => return Ok(Trimmed::default()),
+    };
+
+    // The messages are only deleted from the index once the tombstone is written.
+    let record_len = self.append(Record::Trimmed(cutoff), sync)?;
+    let (mut trimmed, len) = trim(&mut self.entries, cutoff);
+    trimmed.until = cutoff;
+    self.dead_len += len + self.tombstone_len;
+    self.trimmed_until = cutoff;
+    self.tombstone_len = record_len;
+    Ok(trimmed)
+  }
+
+  /// Writes the records that aren't dead to a new file replacing the log,
+  /// once dead records take half of the file. Returns how many bytes were reclaimed.
+  pub(super) fn compact(&mut self, dir: &Path) -> io::Result<u64> {
+    if self.deleted || self.dead_len == 0 || self.dead_len * 2 < self.len {
+      return Ok(0);
+    }
+
+    let tmp_path = self.path.with_extension("log.tmp");
+    let result = self.write_compacted(&tmp_path);
+    let (entries, len) = match result {
+      Ok(compacted) => compacted,
+      Err(err) => {
+        let _ = fs::remove_file(&tmp_path);
+        return Err(err);
+      }
+    };
+
+    fs::rename(&tmp_path, &self.path)?;
+    File::open(dir)?.sync_all()?;
+    let file = OpenOptions::new().append(true).open(&self.path)?;
+
+    let reclaimed = self.len.saturating_sub(len);
+    self.entries = entries;
+    self.dead_len = 0;
+    self.file = Arc::new(file);
+    self.len = len;
+    self.dirty = false;
+    Ok(reclaimed)
+  }
+
+  /// Writes the `Created` record, the last `Trimmed` record and the records of the messages
+  /// that aren't deleted to a synced file at `path`. Returns their entries in the new file and its length.
+  fn write_compacted(&self, path: &Path) -> io::Result<(BTreeMap<u64, Entry>, u64)> {
+    let mut writer = BufWriter::new(File::create(path)?);
+
+    let mut buf = BytesMut::new();
+    encode_record(&Record::Created(self.room.clone()), &mut buf);
+    if self.trimmed_until > 0 {
+      encode_record(&Record::Trimmed(self.trimmed_until), &mut buf);
+    }
+    writer.write_all(&buf)?;
+    let mut len = buf.len() as u64;
+
+    // The records of messages are copied as they are, checksum included.
+    let mut reader = BufReader::new(File::open(&self.path)?);
+    let mut entries = BTreeMap::new();
+    for (&seq, entry) in &self.entries {
+      reader.seek(SeekFrom::Start(entry.offset))?;
+      let copied = io::copy(&mut (&mut reader).take(entry.len), &mut writer)?;
+      if copied != entry.len {
+        return Err(io::Error::new(
+          io::ErrorKind::UnexpectedEof,
+          format!("record of message {seq} is truncated"),
+        ));
+      }
+      entries.insert(
+        seq,
+        Entry {
+          offset: len,
+          ..*entry
+        },
+      );
+      len += entry.len;
+    }
+
+    writer
+      .into_inner()
+      .map_err(|err| err.into_error())?
+      .sync_all()?;
+    Ok((entries, len))
+  }
+
+  /// Reads up to `limit` messages whose seq is between `after` and `before`, excluded, ordered by seq.
+  /// The first ones if `after` is set, the last ones otherwise.
+  pub(super) fn read_messages(
+    &self,
+    after: Option<u64>,
+    before: Option<u64>,
+    limit: usize,
+  ) -> io::Result<Vec<StoredMessage>> {
+    let range = self.entries.range((
+      after.map_or(Bound::Unbounded, Bound::Excluded),
+      before.map_or(Bound::Unbounded, Bound::Excluded),
+    ));
+    let mut offsets: Vec<u64> = if after.is_some() {
+      range.take(limit).map(|(_, entry)| entry.offset).collect()
+    } else {
+      range
+        .rev()
+        .take(limit)
+        .map(|(_, entry)| entry.offset)
+        .collect()
+    };
+    offsets.sort_unstable();
+
+    let mut reader = BufReader::new(File::open(&self.path)?);
+    let mut messages = Vec::with_capacity(offsets.len());
+    for offset in offsets {
+      reader.seek(SeekFrom::Start(offset))?;
+      match read_record(&mut reader, self.len - offset, decode_record)? {
+        Next::Record(Record::Message(message), _) => messages.push(message),
+        _ => {
+          return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("no message at offset {offset}"),
+          ))
+        }
+      }
+    }
+
+    Ok(messages)
+  }
+
+  /// Returns the length of the record.
+  fn append(&mut self, record: Record, sync: bool) -> io::Result<u64> {
+    let mut buf = BytesMut::new();
+    encode_record(&record, &mut buf);
+
+    let result = (&*self.file).write_all(&buf).and_then(|()| {
+      if sync {
+        self.file.sync_data()?;
+      }
+      Ok(())
+    });
+
+    match result {
+      Ok(()) => {
+        self.len += buf.len() as u64;
+        self.dirty = !sync;
+        Ok(buf.len() as u64)
+      }
+      Err(err) => {
+        // A partial record would hide the records appended after it.
+        if let Err(err) = self.file.set_len(self.len) {
+          warn!(
+            "unable to remove partial record from room log. path={:?} error={:?}",
+            self.path, err
+          );
+        }
+        Err(err)
+      }
+    }
+  }
+
+  /// Removes the file of the log.
+  pub(super) fn delete(&mut self) -> io::Result<()> {
+    self.deleted = true;
+    fs::remove_file(&self.path)
+  }
+
+  /// Returns the file to sync if records have been appended since the last sync.
+  /// The file can be synced without holding the lock of the log.
+  pub(super) fn take_unsynced(&mut self) -> Option<Arc<File>> {
+    if !self.dirty {
+      return None;
+    }
+    self.dirty = false;
+    Some(Arc::clone(&self.file))
+  }
+}
+
+impl Entry {
+  fn new(message: &StoredMessage, offset: u64, len: u64) -> Self {
+    Self {
+      offset,
+      len,
+      sent_at: message.sent_at,
+      size: message.size(),
+    }
+  }
+}
+
+/// Removes the entries of the messages before `until`.
+/// Returns what was deleted and the length of their records.
+fn trim(entries: &mut BTreeMap<u64, Entry>, until: u64) -> (Trimmed, u64) {
+  let kept = entries.split_off(&until);
+  let deleted = std::mem::replace(entries, kept);
+
+  let mut trimmed = Trimmed::default();
+  let mut len = 0;
+  for entry in deleted.values() {
+    trimmed.messages += 1;
+    trimmed.bytes += entry.size;
+    len += entry.len;
+  }
+  (trimmed, len)
+}
+
+/// Moves the corrupt file of records at `path` out of the way of the server, to `*.corrupt`,
+/// returns the error to fail with. Nothing is truncated, whoever repairs it still has every record.
+pub(super) fn set_aside(path: &Path, offset: u64) -> io::Error {
+  let mut corrupt = path.as_os_str().to_owned();
+  corrupt.push(".corrupt");
+  let corrupt = PathBuf::from(corrupt);
+  error!(
+    "file has an invalid record before its end, moving it aside. path={:?} offset={} moved_to={:?}",
+    path, offset, corrupt
+  );
+  if let Err(err) = fs::rename(path, &corrupt) {
+    error!(
+      "unable to move corrupt file aside. path={:?} error={:?}",
+      path, err
+    );
+  }
+  io::Error::new(
+    io::ErrorKind::InvalidData,
+    format!("{path:?} is corrupt at offset {offset}, moved to {corrupt:?}"),
+  )
+}
+
+/// Room ids can contain any character and be longer than a file name can,
+/// file names use the hex encoding of their hash.
+fn path_for(dir: &Path, room_id: &str) -> PathBuf {
+  let hash = Blake2b::<U16>::digest(room_id.as_bytes());
+  let name: String = hash.iter().map(|byte| format!("{byte:02x}")).collect();
+  dir.join(format!("{name}.log"))
+}
+
+fn encode_record(record: &Record, dst: &mut BytesMut) {
+  let mut body = BytesMut::new();
+  match record {
+    Record::Created(room) => {
+      body.put_u8(CREATED);
+      put_string(&mut body, &room.room_id);
+      put_string(&mut body, &room.display_name);
+      put_string(&mut body, &room.topic);
+      body.put_i64(room.created_at.timestamp_millis());
+      body.put_u8(room.persistent as u8);
+    }
+    Record::Message(message) => {
+      body.put_u8(MESSAGE);
+      body.put_u64(message.seq);
+      put_string(&mut body, &message.username);
+      put_string(&mut body, &message.contents);
+      body.put_i64(message.sent_at.timestamp_millis());
+    }
+    Record::Trimmed(until) => {
+      body.put_u8(TRIMMED);
+      body.put_u64(*until);
+    }
+  }
+
+  put_frame(&body, dst);
+}
+
+/// Writes the length and crc of the body of a record, then the body.
+pub(super) fn put_frame(body: &[u8], dst: &mut BytesMut) {
+  dst.put_u32(body.len() as u32);
+  dst.put_u32(crc32fast::hash(body));
+  dst.put_slice(body);
+}
+
+/// Reads the next record and decodes its body with `decode`, `remaining` is what's left of the file.
+///
+/// Appends are never interleaved, only the last record can be incomplete: an invalid record is
+/// torn if it's the last one, corrupt otherwise.
+pub(super) fn read_record<T>(
+  reader: &mut impl Read,
+  remaining: u64,
+  decode: impl FnOnce(&[u8]) -> Option<T>,
+) -> io::Result<Next<T>> {
+  if remaining == 0 {
+    return Ok(Next::End);
+  }
+  if remaining < HEADER_BYTES as u64 {
+    return Ok(Next::Torn);
+  }
+
+  let mut header = [0; HEADER_BYTES];
+  reader.read_exact(&mut header)?;
+  let mut header = &header[..];
+  let len = header.get_u32() as usize;
+  let crc = header.get_u32();
+  // Never appended, a length this large was overwritten.
+  if len > MAX_RECORD_BYTES {
+    return Ok(Next::Corrupt);
+  }
+  let record_len = HEADER_BYTES + len;
+  if record_len as u64 > remaining {
+    return Ok(Next::Torn);
+  }
+
+  let mut body = vec![0; len];
+  reader.read_exact(&mut body)?;
+  let record = (crc32fast::hash(&body) == crc)
+    .then(|| decode(&body))
+    .flatten();
+
+  Ok(match record {
+    Some(record) => Next::Record(record, record_len),
+    None if record_len as u64 == remaining => Next::Torn,
+    None => Next::Corrupt,
+  })
+}
+
+fn decode_record(mut body: &[u8]) -> Option<Record> {
+  let record = match get_u8(&mut body)? {
+    CREATED => Record::Created(StoredRoom {
+      room_id: get_string(&mut body)?,
+      display_name: get_string(&mut body)?,
+      topic: get_string(&mut body)?,
+      created_at: get_timestamp(&mut body)?,
+      persistent: get_u8(&mut body)? != 0,
+    }),
+    MESSAGE => Record::Message(StoredMessage {
+      seq: get_u64(&mut body)?,
+      username: get_string(&mut body)?,
+      contents: get_string(&mut body)?,
+      sent_at: get_timestamp(&mut body)?,
+    }),
+    TRIMMED => Record::Trimmed(get_u64(&mut body)?),
+    _ => return None,
+  };
+
+  Some(record)
+}
+
+pub(super) fn put_string(dst: &mut BytesMut, value: &str) {
+  dst.put_u32(value.len() as u32);
+  dst.put_slice(value.as_bytes());
+}
+
+pub(super) fn get_u8(src: &mut &[u8]) -> Option<u8> {
+  (src.remaining() >= 1).then(|| src.get_u8())
+}
+
+pub(super) fn get_u64(src: &mut &[u8]) -> Option<u64> {
+  (src.remaining() >= 8).then(|| src.get_u64())
+}
+
+pub(super) fn get_timestamp(src: &mut &[u8]) -> Option<DateTime<Utc>> {
+  let millis = (src.remaining() >= 8).then(|| src.get_i64())?;
+  Utc.timestamp_millis_opt(millis).single()
+}
+
+pub(super) fn get_string(src: &mut &[u8]) -> Option<String> {
+  let len = (src.remaining() >= 4).then(|| src.get_u32() as usize)?;
+  if src.remaining() < len {
+    return None;
+  }
+  let value = String::from_utf8(src[..len].to_vec()).ok()?;
+  src.advance(len);
+  Some(value)
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  /// An empty directory of its own for the test.
+  fn test_dir(name: &str) -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("room-log-{}-{name}", std::process::id()));
+    let _ = fs::remove_dir_all(&dir);
+    fs::create_dir_all(&dir).unwrap();
+    dir
+  }
+
+  fn room(room_id: &str) -> StoredRoom {
+    StoredRoom {
+      room_id: room_id.to_owned(),
+      display_name: room_id.to_owned(),
+      topic: String::new(),
+      created_at: Utc.timestamp_millis_opt(0).unwrap(),
+      persistent: false,
+    }
+  }
+
+  fn message(seq: u64) -> StoredMessage {
+    StoredMessage {
+      seq,
+      username: "ann".to_owned(),
+      contents: format!("message {seq}"),
+      sent_at: Utc.timestamp_millis_opt(seq as i64 * 1000).unwrap(),
+    }
+  }
+
+  fn seqs(log: &RoomLog) -> Vec<u64> {
+    log
+      .read_messages(None, None, usize::MAX)
+      .unwrap()
+      .iter()
+      .map(|message| message.seq)
+      .collect()
+  }
+
+  /// Creates the log of a room with messages `0..count`, returns the path of its file.
+  fn log_with_messages(dir: &Path, count: u64) -> PathBuf {
+    let mut log = RoomLog::create(dir, room("room")).unwrap();
+    for seq in 0..count {
+      log.append_message(message(seq), false).unwrap();
+    }
+    log.path.clone()
+  }
+
+  fn open_room(dir: &Path) -> RoomLog {
+    let mut logs = RoomLog::open_all(dir).unwrap();
+    assert_eq!(logs.len(), 1);
+    logs.pop().unwrap()
+  }
+
+  #[test]
+  fn rooms_with_the_longest_ids_are_recovered() {
+    let dir = test_dir("long-ids");
+    let room_id = "r".repeat(messages::MAX_ROOM_ID_BYTES);
+    let mut log = RoomLog::create(&dir, room(&room_id)).unwrap();
+    log.append_message(message(0), false).unwrap();
+    drop(log);
+
+    let log = open_room(&dir);
+    assert_eq!(log.room.room_id, room_id);
+    assert_eq!(log.last_seq(), Some(0));
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn last_records_with_a_wrong_crc_are_truncated() {
+    let dir = test_dir("crc");
+    let path = log_with_messages(&dir, 3);
+    let len = fs::metadata(&path).unwrap().len();
+
+    // Changes the last byte of the last record, the timestamp of message 2.
+    let mut bytes = fs::read(&path).unwrap();
+    *bytes.last_mut().unwrap() ^= 1;
+    fs::write(&path, &bytes).unwrap();
+
+    let mut log = open_room(&dir);
+    assert_eq!(log.last_seq(), Some(1));
+    assert!(fs::metadata(&path).unwrap().len() < len);
+
+    // What's appended next follows the last valid record.
+    log.append_message(message(2), false).unwrap();
+    drop(log);
+    assert_eq!(seqs(&open_room(&dir)), [0, 1, 2]);
+    assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn partial_records_are_truncated() {
+    let dir = test_dir("partial");
+    let path = log_with_messages(&dir, 2);
+    let len = fs::metadata(&path).unwrap().len();
+
+    let mut buf = BytesMut::new();
+    encode_record(&Record::Message(message(2)), &mut buf);
+    // A record cut in its header, then in its body.
+    for cut in [HEADER_BYTES - 1, buf.len() - 1] {
+      let mut file = OpenOptions::new().append(true).open(&path).unwrap();
+      file.write_all(&buf[..cut]).unwrap();
+      drop(file);
+
+      let log = open_room(&dir);
+      assert_eq!(seqs(&log), [0, 1]);
+      assert_eq!(log.len, len);
+      assert_eq!(fs::metadata(&path).unwrap().len(), len);
+    }
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn logs_corrupt_before_their_end_are_moved_aside() {
+    let dir = test_dir("corrupt");
+    let path = log_with_messages(&dir, 3);
+    let bytes = fs::read(&path).unwrap();
+
+    // The first byte of the contents of message 1, then of the room id in the first record.
+    let mut buf = BytesMut::new();
+    encode_record(&Record::Created(room("room")), &mut buf);
+    encode_record(&Record::Message(message(0)), &mut buf);
+    let message_offset = buf.len() + HEADER_BYTES + 1 + 8 + 4 + "ann".len() + 4;
+    for offset in [message_offset, HEADER_BYTES + 1 + 4] {
+      let mut corrupt = bytes.clone();
+      corrupt[offset] ^= 1;
+      fs::write(&path, &corrupt).unwrap();
+
+      let err = RoomLog::open_all(&dir).err().unwrap();
+      assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+      // Every record is still there for whoever repairs it.
+      assert!(!path.exists());
+      let corrupt_path = path.with_extension("log.corrupt");
+      assert_eq!(fs::read(&corrupt_path).unwrap(), corrupt);
+      fs::remove_file(&corrupt_path).unwrap();
+
+      // The server starts again without the room.
+      assert!(RoomLog::open_all(&dir).unwrap().is_empty());
+    }
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn logs_without_a_complete_first_record_are_ignored() {
+    let dir = test_dir("header");
+    let path = log_with_messages(&dir, 0);
+    let bytes = fs::read(&path).unwrap();
+    fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+
+    assert!(RoomLog::open_all(&dir).unwrap().is_empty());
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn trimmed_messages_stay_deleted_once_compacted() {
+    let dir = test_dir("compacted");
+    log_with_messages(&dir, 10);
+    let mut log = open_room(&dir);
+    let policy = RetentionPolicy {
+      max_messages: Some(8),
+      ..RetentionPolicy::default()
+    };
+    let now = Utc.timestamp_millis_opt(10_000).unwrap();
+
+    let trimmed = log.apply_retention(&policy, now, false).unwrap();
+    assert_eq!((trimmed.messages, log.trimmed_until), (2, 2));
+    assert_eq!(seqs(&log), (2..10).collect::<Vec<_>>());
+    // Dead records take less than half of the file.
+    assert_eq!(log.compact(&dir).unwrap(), 0);
+    drop(log);
+
+    let mut log = open_room(&dir);
+    assert_eq!(seqs(&log), (2..10).collect::<Vec<_>>());
+    let policy = RetentionPolicy {
+      max_messages: Some(1),
+      ..RetentionPolicy::default()
+    };
+    let trimmed = log.apply_retention(&policy, now, false).unwrap();
+    assert_eq!((trimmed.messages, log.trimmed_until), (7, 9));
+    let len = log.len;
+    let reclaimed = log.compact(&dir).unwrap();
+    assert!(reclaimed > 0);
+    assert_eq!(log.len, len - reclaimed);
+    assert_eq!(fs::metadata(&log.path).unwrap().len(), log.len);
+    assert_eq!(seqs(&log), [9]);
+
+    // The messages that follow continue the sequence.
+    log.append_message(message(10), false).unwrap();
+    drop(log);
+    let log = open_room(&dir);
+    assert_eq!(seqs(&log), [9, 10]);
+    assert_eq!(log.trimmed_until, 9);
+    fs::remove_dir_all(&dir).unwrap();
+  }
+
+  #[test]
+  fn fully_trimmed_logs_remember_their_last_seq() {
+    let dir = test_dir("emptied");
+    log_with_messages(&dir, 3);
+    let mut log = open_room(&dir);
+    let policy = RetentionPolicy {
+      max_age_secs: Some(1),
+      ..RetentionPolicy::default()
+    };
+    let now = Utc.timestamp_millis_opt(100_000).unwrap();
+    assert_eq!(
+      log.apply_retention(&policy, now, false).unwrap().messages,
+      3
+    );
+    assert!(log.compact(&dir).unwrap() > 0);
+    drop(log);
+
+    let log = open_room(&dir);
+    assert!(seqs(&log).is_empty());
+    assert_eq!(log.last_seq(), Some(2));
+    fs::remove_dir_all(&dir).unwrap();
+  }
+}
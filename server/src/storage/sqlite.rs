@@ -0,0 +1,628 @@
+use std::{path::Path, sync::Mutex};
+
+use anyhow::{Context, Result};
+use chrono::{DateTime, TimeZone, Utc};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use super::{
+  FsyncPolicy, Receipt, Storage, StoredMember, StoredMessage, StoredRoom, StoredToken, StoredUser,
+};
+use crate::retention::{RetentionPolicy, Trimmed};
+
+const SCHEMA: &str = "
+  CREATE TABLE IF NOT EXISTS rooms (
+    room_id TEXT PRIMARY KEY,
+    display_name TEXT NOT NULL,
+    topic TEXT NOT NULL,
+    created_at INTEGER NOT NULL,
+    persistent INTEGER NOT NULL,
+    -- The messages before this seq have been deleted.
+    trimmed_until INTEGER NOT NULL DEFAULT 0
+  );
+
+  CREATE TABLE IF NOT EXISTS messages (
+    room_id TEXT NOT NULL REFERENCES rooms ON DELETE CASCADE,
+    seq INTEGER NOT NULL,
+    username TEXT NOT NULL,
+    contents TEXT NOT NULL,
+    sent_at INTEGER NOT NULL,
+    PRIMARY KEY (room_id, seq)
+  ) WITHOUT ROWID;
+
+  CREATE TABLE IF NOT EXISTS members (
+    room_id TEXT NOT NULL REFERENCES rooms ON DELETE CASCADE,
+    username TEXT NOT NULL,
+    delivered_until INTEGER NOT NULL,
+    read_until INTEGER NOT NULL,
+    PRIMARY KEY (room_id, username)
+  ) WITHOUT ROWID;
+
+  CREATE INDEX IF NOT EXISTS members_by_username ON members (username);
+
+  CREATE TABLE IF NOT EXISTS users (
+    username TEXT PRIMARY KEY,
+    first_seen INTEGER NOT NULL,
+    last_seen INTEGER NOT NULL,
+    -- NULL until the user registers.
+    password_hash TEXT,
+    registered_at INTEGER
+  );
+
+  CREATE TABLE IF NOT EXISTS tokens (
+    token_id INTEGER PRIMARY KEY,
+    username TEXT NOT NULL,
+    token_hash TEXT NOT NULL UNIQUE,
+    created_at INTEGER NOT NULL,
+    last_used_at INTEGER NOT NULL,
+    expires_at INTEGER NOT NULL
+  );
+
+  CREATE INDEX IF NOT EXISTS tokens_by_username ON tokens (username);
+";
+
+/// Stores everything in an SQLite database.
+pub(crate) struct SqliteStorage {
+  /// Only one statement runs at a time, rooms are written under their own lock anyway.
+  connection: Mutex<Connection>,
+}
+
+impl SqliteStorage {
+  pub(crate) fn open(path: &Path, fsync_policy: FsyncPolicy) -> Result<Self> {
+    if let Some(dir) = path.parent() {
+      super::create_dir(dir)?;
+    }
+
+    let connection =
+      Connection::open(path).with_context(|| format!("unable to open database {path:?}"))?;
+    let synchronous = match fsync_policy {
+      FsyncPolicy::Always => "FULL",
+      // With a write-ahead log, commits are synced at checkpoints.
+      FsyncPolicy::Interval => "NORMAL",
+      FsyncPolicy::Never => "OFF",
+    };
+    // Only applies to new databases, the pages of deleted messages are freed by `compact`.
+    connection.pragma_update(None, "auto_vacuum", "INCREMENTAL")?;
+    connection.pragma_update(None, "journal_mode", "WAL")?;
+    connection.pragma_update(None, "synchronous", synchronous)?;
+    connection.pragma_update(None, "foreign_keys", true)?;
+    connection
+      .execute_batch(SCHEMA)
+      .context("unable to create database schema")?;
+    // Databases created before messages could be deleted don't have `rooms.trimmed_until`.
+    if connection
+      .prepare("SELECT trimmed_until FROM rooms")
+      .is_err()
+    {
+      connection
+        .execute(
+          "ALTER TABLE rooms ADD COLUMN trimmed_until INTEGER NOT NULL DEFAULT 0",
+          [],
+        )
+        .context("unable to migrate database schema")?;
+    }
+    // Users of databases created before accounts existed register like new users,
+    // they keep their rooms.
+    if connection
+      .prepare("SELECT password_hash FROM users")
+      .is_err()
+    {
+      connection
+        .execute_batch(
+          "ALTER TABLE users ADD COLUMN password_hash TEXT;
+            ALTER TABLE users ADD COLUMN registered_at INTEGER;",
+        )
+        .context("unable to migrate database schema")?;
+    }
+
+    Ok(Self {
+      connection: Mutex::new(connection),
+    })
+  }
+}
+
+impl Storage for SqliteStorage {
+  fn create_room(&self, room: &StoredRoom) -> Result<()> {
+    let mut connection = self.connection.lock().unwrap();
+    let transaction = connection.transaction()?;
+    // Deleting the room also deletes what's left of a room that failed to be created.
+    transaction.execute("DELETE FROM rooms WHERE room_id = ?1", [&room.room_id])?;
+    transaction.execute(
+      "INSERT INTO rooms (room_id, display_name, topic, created_at, persistent)
+        VALUES (?1, ?2, ?3, ?4, ?5)",
+      params![
+        room.room_id,
+        room.display_name,
+        room.topic,
+        room.created_at.timestamp_millis(),
+        room.persistent,
+      ],
+    )?;
+    transaction.commit()?;
+    Ok(())
+  }
+
+  fn delete_room(&self, room_id: &str) -> Result<()> {
+    let connection = self.connection.lock().unwrap();
+    connection.execute("DELETE FROM rooms WHERE room_id = ?1", [room_id])?;
+    Ok(())
+  }
+
+  fn rooms(&self) -> Result<Vec<(StoredRoom, Option<u64>)>> {
+    let connection = self.connection.lock().unwrap();
+    let mut statement = connection.prepare(
+      "SELECT room_id, display_name, topic, created_at, persistent,
+          MAX(
+            COALESCE((SELECT MAX(seq) FROM messages WHERE messages.room_id = rooms.room_id), -1),
+            trimmed_until - 1
+          )
+        FROM rooms",
+    )?;
+    let rooms = statement
+      .query_map([], |row| {
+        Ok((
+          StoredRoom {
+            room_id: row.get(0)?,
+            display_name: row.get(1)?,
+            topic: row.get(2)?,
+            created_at: timestamp(row.get(3)?),
+            persistent: row.get(4)?,
+          },
+          u64::try_from(row.get::<_, i64>(5)?).ok(),
+        ))
+      })?
+      .collect::<rusqlite::Result<_>>()?;
+    Ok(rooms)
+  }
+
+  fn append_message(&self, room_id: &str, message: &StoredMessage) -> Result<()> {
+    let connection = self.connection.lock().unwrap();
+    connection
+      .prepare_cached(
+        "INSERT INTO messages (room_id, seq, username, contents, sent_at)
+          VALUES (?1, ?2, ?3, ?4, ?5)",
+      )?
+      .execute(params![
+        room_id,
+        message.seq as i64,
+        message.username,
+        message.contents,
+        message.sent_at.timestamp_millis(),
+      ])?;
+    Ok(())
+  }
+
+  fn messages(
+    &self,
+    room_id: &str,
+    after: Option<u64>,
+    before: Option<u64>,
+    limit: usize,
+  ) -> Result<Vec<StoredMessage>> {
+    let connection = self.connection.lock().unwrap();
+    let sql = if after.is_some() {
+      "SELECT seq, username, contents, sent_at FROM messages
+        WHERE room_id = ?1 AND seq > ?2 AND seq < ?3
+        ORDER BY seq ASC LIMIT ?4"
+    } else {
+      "SELECT seq, username, contents, sent_at FROM messages
+        WHERE room_id = ?1 AND seq > ?2 AND seq < ?3
+        ORDER BY seq DESC LIMIT ?4"
+    };
+    let mut page = connection
+      .prepare_cached(sql)?
+      .query_map(
+        params![
+          room_id,
+          after.map_or(-1, |after| after as i64),
+          before.map_or(i64::MAX, |before| before as i64),
+          limit as i64,
+        ],
+        |row| {
+          Ok(StoredMessage {
+            seq: row.get::<_, i64>(0)? as u64,
+            username: row.get(1)?,
+            contents: row.get(2)?,
+            sent_at: timestamp(row.get(3)?),
+          })
+        },
+      )?
+      .collect::<rusqlite::Result<Vec<_>>>()?;
+    page.sort_unstable_by_key(|message| message.seq);
+    Ok(page)
+  }
+
+  fn apply_retention(
+    &self,
+    room_id: &str,
+    policy: &RetentionPolicy,
+    now: DateTime<Utc>,
+  ) -> Result<Trimmed> {
+    let mut connection = self.connection.lock().unwrap();
+    let transaction = connection.transaction()?;
+
+    let cutoff = {
+      let mut statement = transaction.prepare_cached(
+        "SELECT seq, sent_at, LENGTH(CAST(username AS BLOB)) + LENGTH(CAST(contents AS BLOB))
+          FROM messages WHERE room_id = ?1 ORDER BY seq DESC",
+      )?;
+      let rows = statement.query_map([room_id], |row| {
+        Ok((
+          row.get::<_, i64>(0)? as u64,
+          timestamp(row.get(1)?),
+          row.get::<_, i64>(2)? as u64,
+        ))
+      })?;
+      let mut error = None;
+      let cutoff = policy.cutoff(
+        now,
+        rows.map_while(|row| row.map_err(|err| error = Some(err)).ok()),
+      );
+      if let Some(err) = error {
+        return Err(err.into());
+      }
+      match cutoff {
+        Some(cutoff) => cutoff as i64,
+        None => return Ok(Trimmed::default()),
+      }
+    };
+
+    let trimmed = transaction.query_row(
+      "SELECT COUNT(*), COALESCE(SUM(LENGTH(CAST(username AS BLOB)) + LENGTH(CAST(contents AS BLOB))), 0)
+        FROM messages WHERE room_id = ?1 AND seq < ?2",
+      params![room_id, cutoff],
+      |row| {
+        Ok(Trimmed {
+          messages: row.get::<_, i64>(0)? as u64,
+          bytes: row.get::<_, i64>(1)? as u64,
+          until: cutoff as u64,
+        })
+      },
+    )?;
+    transaction.execute(
+      "DELETE FROM messages WHERE room_id = ?1 AND seq < ?2",
+      params![room_id, cutoff],
+    )?;
+    transaction.execute(
+      "UPDATE rooms SET trimmed_until = MAX(trimmed_until, ?2) WHERE room_id = ?1",
+      params![room_id, cutoff],
+    )?;
+    transaction.commit()?;
+
+    Ok(trimmed)
+  }
+
+  fn compact(&self) -> Result<u64> {
+    let connection = self.connection.lock().unwrap();
+    let free_pages =
+      || connection.query_row("PRAGMA freelist_count", [], |row| row.get::<_, i64>(0));
+    let page_size: i64 = connection.query_row("PRAGMA page_size", [], |row| row.get(0))?;
+
+    // Does nothing in databases created without `auto_vacuum`.
+    let before = free_pages()?;
+    connection.execute_batch("PRAGMA incremental_vacuum")?;
+    let after = free_pages()?;
+
+    Ok((before - after).max(0) as u64 * page_size as u64)
+  }
+
+  fn add_member(&self, room_id: &str, username: &str, joined_seq: u64) -> Result<()> {
+    let connection = self.connection.lock().unwrap();
+    connection
+      .prepare_cached(
+        "INSERT INTO members (room_id, username, delivered_until, read_until)
+          VALUES (?1, ?2, ?3, ?3)
+          ON CONFLICT DO NOTHING",
+      )?
+      .execute(params![room_id, username, joined_seq as i64])?;
+    Ok(())
+  }
+
+  fn remove_member(&self, room_id: &str, username: &str) -> Result<()> {
+    let connection = self.connection.lock().unwrap();
+    connection
+      .prepare_cached("DELETE FROM members WHERE room_id = ?1 AND username = ?2")?
+      .execute([room_id, username])?;
+    Ok(())
+  }
+
+  fn members(&self) -> Result<Vec<StoredMember>> {
+    let connection = self.connection.lock().unwrap();
+    let mut statement =
+      connection.prepare("SELECT room_id, username, delivered_until, read_until FROM members")?;
+    let members = statement
+      .query_map([], member)?
+      .collect::<rusqlite::Result<_>>()?;
+    Ok(members)
+  }
+
+  fn memberships(&self, username: &str) -> Result<Vec<StoredMember>> {
+    let connection = self.connection.lock().unwrap();
+    let members = connection
+      .prepare_cached(
+        "SELECT room_id, username, delivered_until, read_until FROM members WHERE username = ?1",
+      )?
+      .query_map([username], member)?
+      .collect::<rusqlite::Result<_>>()?;
+    Ok(members)
+  }
+
+  fn acknowledge(&self, room_id: &str, username: &str, receipt: Receipt, until: u64) -> Result<()> {
+    let connection = self.connection.lock().unwrap();
+    let sql = match receipt {
+      Receipt::Delivered => {
+        "UPDATE members SET delivered_until = MAX(delivered_until, ?3)
+          WHERE room_id = ?1 AND username = ?2"
+      }
+      Receipt::Read => {
+        "UPDATE members SET delivered_until = MAX(delivered_until, ?3), read_until = MAX(read_until, ?3)
+          WHERE room_id = ?1 AND username = ?2"
+      }
+    };
+    connection
+      .prepare_cached(sql)?
+      .execute(params![room_id, username, until as i64])?;
+    Ok(())
+  }
+
+  fn create_user(&self, user: &StoredUser) -> Result<bool> {
+    let connection = self.connection.lock().unwrap();
+    let created = connection
+      .prepare_cached(
+        "INSERT INTO users (username, first_seen, last_seen, password_hash, registered_at)
+          VALUES (?1, ?3, ?3, ?2, ?3)
+          ON CONFLICT (username) DO UPDATE
+            SET password_hash = excluded.password_hash, registered_at = excluded.registered_at
+            WHERE password_hash IS NULL",
+      )?
+      .execute(params![
+        user.username,
+        user.password_hash,
+        user.registered_at.timestamp_millis()
+      ])?;
+    Ok(created > 0)
+  }
+
+  fn user(&self, username: &str) -> Result<Option<StoredUser>> {
+    let connection = self.connection.lock().unwrap();
+    let user = connection
+      .prepare_cached(
+        "SELECT username, password_hash, registered_at FROM users
+          WHERE username = ?1 AND password_hash IS NOT NULL",
+      )?
+      .query_row([username], |row| {
+        Ok(StoredUser {
+          username: row.get(0)?,
+          password_hash: row.get(1)?,
+          registered_at: timestamp(row.get(2)?),
+        })
+      })
+      .optional()?;
+    Ok(user)
+  }
+
+  fn save_user(&self, username: &str, at: DateTime<Utc>) -> Result<()> {
+    let connection = self.connection.lock().unwrap();
+    connection
+      .prepare_cached(
+        "INSERT INTO users (username, first_seen, last_seen) VALUES (?1, ?2, ?2)
+          ON CONFLICT (username) DO UPDATE SET last_seen = excluded.last_seen",
+      )?
+      .execute(params![username, at.timestamp_millis()])?;
+    Ok(())
+  }
+
+  fn create_token(&self, token: &StoredToken) -> Result<()> {
+    let connection = self.connection.lock().unwrap();
+    connection
+      .prepare_cached(
+        "INSERT INTO tokens (token_id, username, token_hash, created_at, last_used_at, expires_at)
+          VALUES (?1, ?2, ?3, ?4, ?5, ?6)",
+      )?
+      .execute(params![
+        token.token_id as i64,
+        token.username,
+        token.token_hash,
+        token.created_at.timestamp_millis(),
+        token.last_used_at.timestamp_millis(),
+        token.expires_at.timestamp_millis(),
+      ])?;
+    Ok(())
+  }
+
+  fn token(&self, token_hash: &str) -> Result<Option<StoredToken>> {
+    let connection = self.connection.lock().unwrap();
+    let token = connection
+      .prepare_cached(
+        "SELECT token_id, username, token_hash, created_at, last_used_at, expires_at FROM tokens
+          WHERE token_hash = ?1",
+      )?
+      .query_row([token_hash], token)
+      .optional()?;
+    Ok(token)
+  }
+
+  fn tokens(&self, username: &str) -> Result<Vec<StoredToken>> {
+    let connection = self.connection.lock().unwrap();
+    let tokens = connection
+      .prepare_cached(
+        "SELECT token_id, username, token_hash, created_at, last_used_at, expires_at FROM tokens
+          WHERE username = ?1",
+      )?
+      .query_map([username], token)?
+      .collect::<rusqlite::Result<_>>()?;
+    Ok(tokens)
+  }
+
+  fn touch_token(&self, token_id: u64, at: DateTime<Utc>, expires_at: DateTime<Utc>) -> Result<()> {
+    let connection = self.connection.lock().unwrap();
+    connection
+      .prepare_cached("UPDATE tokens SET last_used_at = ?2, expires_at = ?3 WHERE token_id = ?1")?
+      .execute(params![
+        token_id as i64,
+        at.timestamp_millis(),
+        expires_at.timestamp_millis()
+      ])?;
+    Ok(())
+  }
+
+  fn delete_token(&self, token_id: u64) -> Result<()> {
+    let connection = self.connection.lock().unwrap();
+    connection
+      .prepare_cached("DELETE FROM tokens WHERE token_id = ?1")?
+      .execute([token_id as i64])?;
+    Ok(())
+  }
+
+  fn delete_expired_tokens(&self, username: &str, now: DateTime<Utc>) -> Result<()> {
+    let connection = self.connection.lock().unwrap();
+    connection
+      .prepare_cached("DELETE FROM tokens WHERE username = ?1 AND expires_at <= ?2")?
+      .execute(params![username, now.timestamp_millis()])?;
+    Ok(())
+  }
+
+  fn sync(&self) -> Result<()> {
+    // Syncs the write-ahead log, with `FsyncPolicy::Interval` commits aren't synced.
+    let connection = self.connection.lock().unwrap();
+    connection
+      .query_row("PRAGMA wal_checkpoint(PASSIVE)", [], |_| Ok(()))
+      .optional()?;
+    Ok(())
+  }
+}
+
+fn member(row: &rusqlite::Row<'_>) -> rusqlite::Result<StoredMember> {
+  Ok(StoredMember {
+    room_id: row.get(0)?,
+    username: row.get(1)?,
+    delivered_until: row.get::<_, i64>(2)? as u64,
+    read_until: row.get::<_, i64>(3)? as u64,
+  })
+}
+
+fn token(row: &rusqlite::Row<'_>) -> rusqlite::Result<StoredToken> {
+  Ok(StoredToken {
+    token_id: row.get::<_, i64>(0)? as u64,
+    username: row.get(1)?,
+    token_hash: row.get(2)?,
+    created_at: timestamp(row.get(3)?),
+    last_used_at: timestamp(row.get(4)?),
+    expires_at: timestamp(row.get(5)?),
+  })
+}
+
+fn timestamp(millis: i64) -> DateTime<Utc> {
+  Utc
+    .timestamp_millis_opt(millis)
+    .single()
+    .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn room(room_id: &str) -> StoredRoom {
+    StoredRoom {
+      room_id: room_id.to_owned(),
+      display_name: "The lobby".to_owned(),
+      topic: "say hi".to_owned(),
+      created_at: timestamp(1_000),
+      persistent: true,
+    }
+  }
+
+  fn message(seq: u64) -> StoredMessage {
+    StoredMessage {
+      seq,
+      username: "ann".to_owned(),
+      contents: format!("message {seq}"),
+      sent_at: timestamp(seq as i64 * 1000),
+    }
+  }
+
+  /// The delivered_until and read_until of every member of the room, by username.
+  fn members(storage: &SqliteStorage, room_id: &str) -> Vec<(String, i64, i64)> {
+    let connection = storage.connection.lock().unwrap();
+    let mut statement = connection
+      .prepare(
+        "SELECT username, delivered_until, read_until FROM members
+          WHERE room_id = ?1 ORDER BY username",
+      )
+      .unwrap();
+    statement
+      .query_map([room_id], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))
+      .unwrap()
+      .collect::<rusqlite::Result<_>>()
+      .unwrap()
+  }
+
+  #[test]
+  fn what_is_stored_is_there_once_reopened() {
+    let dir = std::env::temp_dir().join(format!("sqlite-storage-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&dir);
+    let path = dir.join("chat.db");
+
+    let storage = SqliteStorage::open(&path, FsyncPolicy::Always).unwrap();
+    storage.create_room(&room("lobby")).unwrap();
+    storage.create_room(&room("empty")).unwrap();
+    for seq in 0..3 {
+      storage.append_message("lobby", &message(seq)).unwrap();
+    }
+    storage.add_member("lobby", "ann", 0).unwrap();
+    storage.add_member("lobby", "bob", 1).unwrap();
+    storage
+      .acknowledge("lobby", "bob", Receipt::Read, 2)
+      .unwrap();
+    storage
+      .acknowledge("lobby", "bob", Receipt::Delivered, 1)
+      .unwrap();
+    storage.save_user("ann", timestamp(0)).unwrap();
+    drop(storage);
+
+    let storage = SqliteStorage::open(&path, FsyncPolicy::Always).unwrap();
+    let mut rooms = storage.rooms().unwrap();
+    rooms.sort_unstable_by(|(a, _), (b, _)| a.room_id.cmp(&b.room_id));
+    let rooms: Vec<_> = rooms
+      .iter()
+      .map(|(room, last_seq)| {
+        (
+          room.room_id.as_str(),
+          room.display_name.as_str(),
+          room.topic.as_str(),
+          room.created_at,
+          room.persistent,
+          *last_seq,
+        )
+      })
+      .collect();
+    assert_eq!(
+      rooms,
+      [
+        ("empty", "The lobby", "say hi", timestamp(1_000), true, None),
+        (
+          "lobby",
+          "The lobby",
+          "say hi",
+          timestamp(1_000),
+          true,
+          Some(2)
+        ),
+      ]
+    );
+    // Reading delivers, receipts never go backward.
+    assert_eq!(
+      members(&storage, "lobby"),
+      [("ann".to_owned(), 0, 0), ("bob".to_owned(), 2, 2)]
+    );
+
+    // Members and messages go with their room.
+    storage.delete_room("lobby").unwrap();
+    assert_eq!(storage.rooms().unwrap().len(), 1);
+    assert!(members(&storage, "lobby").is_empty());
+    storage.create_room(&room("lobby")).unwrap();
+    storage.append_message("lobby", &message(0)).unwrap();
+    drop(storage);
+    std::fs::remove_dir_all(&dir).unwrap();
+  }
+}